@@ -1,6 +1,7 @@
 use crate::utils::read_json;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::error;
 use std::path::PathBuf;
 
 #[derive(Deserialize)]
@@ -11,15 +12,44 @@ pub struct InputFile {
     pub switching: String,
     pub nodes: Vec<NodeInfo>,
     pub packets: Vec<PacketInfo>,
-    pub neighbors: HashMap<String, Vec<String>>,
+    // radioを指定した場合は省略し，ノードの位置から隣接関係を求める
+    pub neighbors: Option<HashMap<String, Vec<String>>>,
+    pub radio: Option<RadioInfo>,
+    pub mobility: Option<MobilityInfo>,
     pub routing: Option<String>,
+    // "assigned"ならコーディネータがjoin時にアドレスを割り当て，"random"ならランダムなidを使う
+    pub address_mode: Option<String>,
     pub log_range: Option<Vec<u32>>,
+    pub phases: Option<PhasesInfo>,
+    pub stop_conditions: Option<StopConditionsInfo>,
+    pub topology_events: Option<Vec<TopologyEventInfo>>,
+    pub node_events: Option<Vec<NodeEventInfo>>,
+    pub failure_model: Option<FailureModelInfo>,
+    pub energy: Option<EnergyInfo>,
+    // パケットのヘッダのバイト数，制御パケットの大きさになる
+    pub header_bytes: Option<u32>,
+    // 指定するとユーザーのunicastパケットをトランスポート層で再送して確実に届ける
+    pub transport: Option<TransportInfo>,
+    // トラフィッククラスと仮想チャネルの調停方式
+    pub qos: Option<QosInfo>,
+    // パケットが通れる最大のホップ数，省略すると64
+    pub ttl: Option<u8>,
+    // デバッグ用，trueなら同じノードを再び通ったパケットを記録する
+    pub loop_detection: Option<bool>,
+    // 指定するとデッドロックとライブロックを調べる
+    pub monitor: Option<MonitorInfo>,
+    // trueなら実行後にチャネルごとの木とその深さなどを出力する
+    pub tree_report: Option<bool>,
+    // 指定すると同じ入力から同じ結果が得られる
+    pub seed: Option<u64>,
 }
 
 #[derive(Deserialize)]
 pub struct NodeInfo {
     pub node_id: String,
     pub node_type: String,
+    // 2次元または3次元の座標
+    pub position: Option<Vec<f64>>,
 }
 
 #[derive(Deserialize)]
@@ -28,13 +58,176 @@ pub struct PacketInfo {
     pub src_id: String,
     pub dest_id: String,
     pub msg: String,
+    // dest_idがbroadcastのとき，指定されたノードのみに配信する(multicast)
+    pub dest_ids: Option<Vec<String>>,
+    // ペイロードのバイト数，フリット数はmessageの長さではなくこれとヘッダの大きさから決める
+    // ペイロードの中身は作らず，バイト数だけを数える
+    pub size_bytes: Option<u32>,
+    // ペイロードのパターン，"dots"('.'，省略時), "zeros"('0'), "random"(英数字)のいずれか
+    pub payload: Option<String>,
+    // 大きいほど送信元のキューで先に送られる，省略すると0
+    pub priority: Option<u8>,
+    // qosで定義したトラフィッククラスの名前，クラスの仮想チャネルで送る
+    pub class: Option<String>,
+}
+
+// warmup_cyclesの後のmeasurement_cyclesの間に生成したパケットを計測する
+// 計測区間の後は新規パケットを生成せず，計測したパケットが届くか失われるまで続ける(ドレイン)
+#[derive(Deserialize)]
+pub struct PhasesInfo {
+    pub warmup_cycles: u32,
+    pub measurement_cycles: u32,
+    // 計測区間の終了からドレインを打ち切るまでのサイクル数，省略するとtotal_cyclesまで
+    pub drain_limit_cycles: Option<u32>,
+}
+
+// total_cyclesより前にシミュレーションを終える条件，いずれかを満たしたら終わる
+#[derive(Deserialize)]
+pub struct StopConditionsInfo {
+    // 生きているノードがすべてネットワークに参加した
+    pub all_joined: Option<bool>,
+    // すべてのパケットを生成し終え，ユーザーのunicastパケットがすべて届いた(または故障で失われた)
+    pub all_delivered: Option<bool>,
+    pub steady_latency: Option<SteadyLatencyInfo>,
+    // 実行時間(秒)の上限
+    pub wall_clock_seconds: Option<f64>,
+}
+
+// window_cyclesごとの平均遅延が，直前の区間との相対差tolerance以内になったら定常とみなす
+#[derive(Deserialize)]
+pub struct SteadyLatencyInfo {
+    pub window_cycles: u32,
+    pub tolerance: f64,
+}
+
+// ノードの位置から隣接関係を決める電波のモデル
+// modelはunit_diskまたはquasi_unit_disk
+// quasi_unit_diskではinner_range以下は必ず，range以下はlink_probabilityの確率でリンクがある
+#[derive(Deserialize)]
+pub struct RadioInfo {
+    pub model: String,
+    pub range: f64,
+    pub inner_range: Option<f64>,
+    pub link_probability: Option<f64>,
+}
+
+// ノードの移動モデル
+// modelはrandom_waypoint, random_direction, group, trajectoryのいずれか
+// 位置はupdate_intervalサイクルごとに進め，そのたびに隣接関係を計算し直す
+#[derive(Deserialize)]
+pub struct MobilityInfo {
+    pub model: String,
+    pub update_interval: u32,
+    // 移動範囲の各軸の最大値(最小値は0)
+    pub area: Option<Vec<f64>>,
+    // 1サイクルあたりの移動距離
+    pub min_speed: Option<f64>,
+    pub max_speed: Option<f64>,
+    // 目的地に着いたり範囲の端に着いたりしてから，次に動き出すまでのサイクル数
+    pub pause_cycles: Option<u32>,
+    // 移動するノード，省略するとコーディネータ以外のすべてのノード
+    pub node_ids: Option<Vec<String>>,
+    // groupで一緒に動くノード群，先頭のノードがリーダー
+    pub groups: Option<Vec<Vec<String>>>,
+    // groupでメンバーがリーダーとの位置関係からずれる最大の距離(各軸)
+    pub group_radius: Option<f64>,
+    // trajectoryで読み込むファイル，入力ファイルからの相対パス
+    // ノードごとにWaypointInfoのリストを持つ
+    pub trajectory_file: Option<String>,
+}
+
+// ノードがcycle_numのサイクルにpositionにいる
+#[derive(Deserialize)]
+pub struct WaypointInfo {
+    pub cycle_num: u32,
+    pub position: Vec<f64>,
+}
+
+// cycle_numのサイクルの開始時にネットワークの形を変える
+// eventはadd_link, remove_link, attach, detachのいずれか
+#[derive(Deserialize)]
+pub struct TopologyEventInfo {
+    pub cycle_num: u32,
+    pub event: String,
+    pub links: Option<Vec<(String, String)>>,
+    pub node_ids: Option<Vec<String>>,
+}
+
+// cycle_numのサイクルの開始時にノードを故障(fail)・再起動(restart)させる
+#[derive(Deserialize)]
+pub struct NodeEventInfo {
+    pub cycle_num: u32,
+    pub node_id: String,
+    pub event: String,
+}
+
+// ランダムにノードを故障・再起動させる
+// mtbf, mttrはサイクル数，node_idsを省略するとコーディネータ以外のすべてのノード
+#[derive(Deserialize)]
+pub struct FailureModelInfo {
+    pub mtbf: f64,
+    pub mttr: f64,
+    pub node_ids: Option<Vec<String>>,
+}
+
+// ハードウェアの状態ごとの消費電力 [W] と，送受信の1ビットあたりのエネルギー [J]
+// battery_budget [J] を指定すると，最初のノードが電池を使い切るまでのサイクル数を求める
+#[derive(Deserialize)]
+pub struct EnergyInfo {
+    // 1サイクルの長さ [s]
+    pub cycle_seconds: f64,
+    pub idle_power: f64,
+    pub sending_power: f64,
+    pub receiving_power: f64,
+    pub waiting_power: f64,
+    pub reply_ack_power: f64,
+    pub tx_energy_per_bit: f64,
+    pub rx_energy_per_bit: f64,
+    // ペイロードに加えてフリットごとに送受信するビット数(ackを含む)，省略すると0
+    pub header_bits: Option<u32>,
+    pub battery_budget: Option<f64>,
+}
+
+// 宛先ごとにwindow_sizeまでACKを待たずに送り，retransmit_cyclesの間ACKが来なければ再送する
+#[derive(Deserialize)]
+pub struct TransportInfo {
+    pub window_size: u32,
+    pub retransmit_cycles: u32,
+    // この回数再送しても届かなければ諦める，省略すると8
+    pub max_retransmissions: Option<u32>,
+}
+
+// パケットをトラフィッククラスごとの仮想チャネルで送り，チャネル間をarbitrationで調停する
+// arbitrationはstrict_priority(省略時), round_robin, weighted_round_robin, oldest_firstのいずれか
+#[derive(Deserialize)]
+pub struct QosInfo {
+    pub arbitration: Option<String>,
+    pub classes: Vec<TrafficClassInfo>,
+}
+
+#[derive(Deserialize)]
+pub struct TrafficClassInfo {
+    pub name: String,
+    pub channel: u8,
+    // strict_priorityで大きいほど優先される，省略すると0
+    pub priority: Option<u32>,
+    // weighted_round_robinでチャネルが続けて送るパケット数，省略すると1
+    pub weight: Option<u32>,
+}
+
+// interval_cyclesごとに，送信中のフリットの待ち関係の閉路(デッドロック)と
+// 中継され続けて届かないパケット(ライブロック)を調べる
+#[derive(Deserialize)]
+pub struct MonitorInfo {
+    pub interval_cycles: u32,
+    // 届かないままこのホップ数を超えて中継されたらライブロックとみなす，省略するとノード数
+    pub livelock_hops: Option<u32>,
 }
 
 impl InputFile {
-    pub fn new(path: PathBuf) -> Self {
+    pub fn new(path: PathBuf) -> Result<Self, Box<dyn error::Error>> {
         // pathからファイルを読み込み、InputFileを作成する
         read_json::<InputFile>(path.clone())
-            .map_err(|e| panic!("erro while reading {path:?}: {}", e))
-            .expect("failed to read json file")
+            .map_err(|e| format!("error while reading {path:?}: {e}").into())
     }
 }
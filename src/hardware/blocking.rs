@@ -1,16 +1,20 @@
 use crate::network::flit::Flit;
+use serde::{Deserialize, Serialize};
 
 use super::switching::Switching;
 
 pub const BLOCK_FLIT: bool = false;
 pub const RECEIVE_FLIT: bool = true;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Blocking {
     switching: Switching,
     is_receiving: bool,
     receiving_packet_next_id: String,
     receiving_packet_id: u32,
+    // 受信中のパケットを送ってきたノードとチャネル
+    receiving_packet_prev_id: String,
+    receiving_channel_id: u8,
     cur_flit_num: u32,
 }
 
@@ -21,10 +25,24 @@ impl Blocking {
             is_receiving: false,
             receiving_packet_next_id: "".to_string(),
             receiving_packet_id: 0,
+            receiving_packet_prev_id: "".to_string(),
+            receiving_channel_id: 0,
             cur_flit_num: 0,
         }
     }
 
+    // 受信中で他のフリットをブロックしているパケットの(prev_id, channel_id, packet_id)
+    pub fn held_by(&self) -> Option<(String, u8, u32)> {
+        if !self.is_receiving {
+            return None;
+        }
+        Some((
+            self.receiving_packet_prev_id.clone(),
+            self.receiving_channel_id,
+            self.receiving_packet_id,
+        ))
+    }
+
     pub fn check_received_flit(&mut self, flit: &Flit) -> bool {
         if !self.is_block_mode() {
             return RECEIVE_FLIT;
@@ -39,6 +57,8 @@ impl Blocking {
                     self.is_receiving = true;
                     self.receiving_packet_next_id = flit.get_next_id().unwrap();
                     self.receiving_packet_id = flit.get_packet_id().unwrap();
+                    self.receiving_packet_prev_id = flit.get_prev_id().unwrap();
+                    self.receiving_channel_id = flit.get_channel_id().unwrap();
                     self.cur_flit_num = 0;
                 }
                 RECEIVE_FLIT
@@ -90,6 +110,8 @@ impl Blocking {
         self.is_receiving = false;
         self.receiving_packet_next_id = "".to_string();
         self.receiving_packet_id = 0;
+        self.receiving_packet_prev_id = "".to_string();
+        self.receiving_channel_id = 0;
         self.cur_flit_num = 0;
     }
 
@@ -111,6 +133,7 @@ impl Default for Blocking {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hardware::constants::DEFAULT_TTL;
     use crate::network::flit::{AckFlit, DataFlit, HeaderFlit, TailFlit};
 
     #[test]
@@ -125,6 +148,7 @@ mod tests {
             packet_id: 0,
             flits_len: 3,
             channel_id: 0,
+            ttl: DEFAULT_TTL,
             data: vec![0; 64],
         });
 
@@ -137,6 +161,7 @@ mod tests {
             flit_num: 1,
             resend_num: 0,
             channel_id: 0,
+            ttl: DEFAULT_TTL,
             data: vec![0; 64],
         });
 
@@ -149,6 +174,7 @@ mod tests {
             flit_num: 1,
             resend_num: 0,
             channel_id: 0,
+            ttl: DEFAULT_TTL,
             data: vec![0; 64],
         });
 
@@ -161,6 +187,7 @@ mod tests {
             flit_num: 2,
             resend_num: 0,
             channel_id: 0,
+            ttl: DEFAULT_TTL,
             data: vec![0; 64],
         });
 
@@ -173,9 +200,11 @@ mod tests {
         });
 
         assert_eq!(blocking.check_received_flit(&header_flit), RECEIVE_FLIT);
+        assert_eq!(blocking.held_by(), Some(("0".to_string(), 0, 0)));
         assert_eq!(blocking.check_received_flit(&block_data_flit), BLOCK_FLIT);
         assert_eq!(blocking.check_received_flit(&data_flit), RECEIVE_FLIT);
         assert_eq!(blocking.check_received_flit(&tail_flit), RECEIVE_FLIT);
+        assert_eq!(blocking.held_by(), None);
         assert_eq!(blocking.check_received_flit(&ack_flit), RECEIVE_FLIT);
     }
 }
@@ -1,3 +1,11 @@
 pub(crate) const MAX_RESEND_TIMES: u8 = 5;
 pub(crate) const WAIT_ACK_CYCLES: u32 = 2;
 pub(crate) const DATA_BYTE_PER_FLIT: u32 = 64;
+// size_bytesを指定したパケットのヘッダ(送信元・宛先・パケット番号など)の大きさ
+pub(crate) const PACKET_HEADER_BYTES: u32 = 16;
+// 組み立て中のパケットにこのサイクル数フリットが届かなければ組み立てを諦める
+pub(crate) const REASSEMBLY_TIMEOUT_CYCLES: u32 = 1000;
+// MAX_RESEND_TIMESの再送がこの回数続けて失敗したらリンクが切れたとみなす
+pub(crate) const LINK_LOSS_THRESHOLD: u8 = 16;
+// パケットが通れる最大のホップ数，中継するたびに1減らし，0になったら経路のループとみなして捨てる
+pub(crate) const DEFAULT_TTL: u8 = 64;
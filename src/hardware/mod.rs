@@ -7,10 +7,15 @@ use self::{
     state::{NodeState, State},
     switching::Switching,
 };
+use crate::log::post_event;
 use crate::network::flit::{AckFlit, Flit};
+use crate::rng::sim_rng;
+use crate::sim::observer::SimEvent;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::mem::discriminant;
 
-#[derive(Default, Clone, Debug)]
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct Hardware {
     id: String,
     pub state: NodeState,
@@ -19,6 +24,12 @@ pub struct Hardware {
     received_msg_is_broadcast: bool,
     received_msg_is_ack: bool,
     blocking: blocking::Blocking,
+    // retransmission_bufferのフリットでMAX_RESEND_TIMESの再送に失敗した回数
+    failed_rounds: u8,
+    // 切れたとみなしたリンクに送ろうとして破棄したフリット
+    lost_links: Vec<Flit>,
+    // retransmission_bufferのフリットを送信した回数
+    send_times: u32,
 }
 
 impl Hardware {
@@ -31,6 +42,9 @@ impl Hardware {
             received_msg_is_broadcast: false,
             received_msg_is_ack: false,
             blocking: blocking::Blocking::new(switching),
+            failed_rounds: 0,
+            lost_links: Vec::new(),
+            send_times: 0,
         }
     }
 }
@@ -41,9 +55,34 @@ impl Hardware {
         assert!(self.retransmission_buffer.is_empty());
 
         self.retransmission_buffer = flit.clone();
+        self.failed_rounds = 0;
+        self.send_times = 0;
         Ok(flit.clone())
     }
 
+    // retransmission_bufferのフリットを送信するたびに呼び，何回目の送信かを返す
+    pub fn count_transmission(&mut self) -> u32 {
+        self.send_times += 1;
+        self.send_times
+    }
+
+    // retransmission_bufferのフリットを送信した回数
+    pub fn send_times(&self) -> u32 {
+        self.send_times
+    }
+
+    // 受信中のパケットで入力が押さえられていれば，その(prev_id, channel_id, packet_id)
+    // 押さえられている間は他のパケットのフリットもackも受け取らない
+    pub fn held_input(&self) -> Option<(String, u8, u32)> {
+        self.blocking.held_by()
+    }
+
+    // 前回呼ばれてから切れたとみなしたリンクで破棄したフリットを返す
+    // リンクの相手はフリットのnext_id
+    pub fn take_lost_links(&mut self) -> Vec<Flit> {
+        std::mem::take(&mut self.lost_links)
+    }
+
     pub fn send_ack(&mut self) -> Result<Flit, Box<dyn std::error::Error>> {
         let ack = self.ack_buffer.clone();
 
@@ -60,6 +99,15 @@ impl Hardware {
         &mut self,
         flit: &Flit,
     ) -> Result<Option<Flit>, Box<dyn std::error::Error>> {
+        // 再送先のノードから何か受信できていればリンクは切れていない
+        let sender_id = match flit {
+            Flit::Ack(ack_flit) => Some(ack_flit.source_id.clone()),
+            _ => flit.get_prev_id(),
+        };
+        if sender_id.is_some() && sender_id == self.retransmission_buffer.get_next_id() {
+            self.failed_rounds = 0;
+        }
+
         // Data, Header Flitの場合はackを生成する
         // Ack Flitの場合はtransmission_bufferを更新する
         if let Some(next_id) = flit.get_next_id() {
@@ -102,31 +150,38 @@ impl Hardware {
                 // retransmission_bufferが空でない場合は送信状態へ遷移
                 if let Flit::Data(_) | Flit::Header(_) | Flit::Tail(_) = self.retransmission_buffer
                 {
-                    self.state.next(&State::Sending);
+                    self.set_state(&State::Sending);
                 }
             }
             State::Receiving => {
                 if self.received_msg_is_ack {
                     self.state.set_resend_times(0);
 
-                    self.state.next(&State::Idle);
+                    self.set_state(&State::Idle);
                 } else {
-                    self.state.next(&State::ReplyAck);
+                    self.set_state(&State::ReplyAck);
                 }
             }
             State::ReplyAck => {
-                self.state.next(&State::Idle);
+                self.set_state(&State::Idle);
             }
             State::Sending => {
                 // resend_times
                 let resend_times = self.state.get_resend_times();
                 if resend_times < constants::MAX_RESEND_TIMES {
-                    self.state
-                        .next(&State::waiting_state(self.calc_wait_cycles()));
+                    self.set_state(&State::waiting_state(self.calc_wait_cycles()));
                     self.state.set_resend_times(resend_times + 1);
                 } else {
                     self.state.set_resend_times(0);
-                    self.state.next(&State::waiting_state(0));
+                    self.set_state(&State::waiting_state(0));
+
+                    // 再送の失敗が続いたらリンクが切れたとみなし，フリットを破棄する
+                    self.failed_rounds += 1;
+                    if self.failed_rounds >= constants::LINK_LOSS_THRESHOLD {
+                        self.lost_links.push(self.retransmission_buffer.clone());
+                        self.retransmission_buffer.clear();
+                        self.failed_rounds = 0;
+                    }
                 }
             }
             State::Waiting(_) => {
@@ -138,12 +193,12 @@ impl Hardware {
                     if let Flit::Data(_) | Flit::Header(_) | Flit::Tail(_) =
                         self.retransmission_buffer
                     {
-                        self.state.next(&State::Sending);
+                        self.set_state(&State::Sending);
                     } else {
-                        self.state.next(&State::Idle);
+                        self.set_state(&State::Idle);
                     }
                 } else {
-                    self.state.next(&State::waiting_state(remaining_cycles - 1));
+                    self.set_state(&State::waiting_state(remaining_cycles - 1));
                 }
             }
         }
@@ -151,7 +206,17 @@ impl Hardware {
     }
 
     pub fn set_state(&mut self, state: &State) {
+        let from = self.state.get().clone();
         self.state.next(state);
+
+        // Waitingの残りサイクルが減っただけなら状態遷移とみなさない
+        if discriminant(&from) != discriminant(state) {
+            post_event(|| SimEvent::StateTransition {
+                node_id: self.id.clone(),
+                from,
+                to: state.clone(),
+            });
+        }
     }
 }
 
@@ -191,6 +256,12 @@ impl Hardware {
         if let Flit::Ack(ack_flit) = flit {
             let ack_flit = ack_flit.clone();
 
+            // リンク切れとみなして破棄したフリットへの遅れたackは無視する
+            if self.retransmission_buffer.is_empty() {
+                self.set_state(&State::Idle);
+                return Ok(flit.clone());
+            }
+
             let (prev_id, next_id, packet_id, flit_num) = (
                 self.retransmission_buffer.get_prev_id().unwrap(),
                 self.retransmission_buffer.get_next_id().unwrap(),
@@ -205,6 +276,7 @@ impl Hardware {
             {
                 // ackを受信したのでretransmission_bufferをクリアする
                 self.retransmission_buffer.clear();
+                self.failed_rounds = 0;
                 self.set_state(&State::Idle);
 
                 Ok(flit.clone())
@@ -227,7 +299,7 @@ impl Hardware {
             return constants::WAIT_ACK_CYCLES;
         }
 
-        let mut rng = rand::thread_rng();
+        let mut rng = sim_rng();
         let begin = 2i32.pow(resend_times as u32 - 1);
         let end = 2i32.pow(resend_times as u32 + 1);
 
@@ -240,6 +312,7 @@ impl Hardware {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hardware::constants::DEFAULT_TTL;
     use crate::network::flit::{DataFlit, HeaderFlit};
     #[test]
     fn test_send_flit() {
@@ -255,6 +328,7 @@ mod tests {
             packet_id: 0,
             flit_num: 0,
             channel_id: 0,
+            ttl: DEFAULT_TTL,
             data: vec![0; 8],
         });
 
@@ -282,20 +356,50 @@ mod tests {
             packet_id: 0,
             flits_len: 1,
             channel_id: 0,
+            ttl: DEFAULT_TTL,
             data: vec![0; 8],
         });
 
         let received_flit = hardware.receive_flit(&flit).unwrap();
         assert_eq!(received_flit, Some(flit.clone()));
-        assert_eq!(hardware.ack_buffer.is_empty(), false);
-        assert_eq!(hardware.ack_buffer.is_ack(), true);
+        assert!(!hardware.ack_buffer.is_empty());
+        assert!(hardware.ack_buffer.is_ack());
 
         let mut hardware = Hardware::new("dest_id", &Switching::CutThrough);
 
         let received_flit = hardware.receive_flit(&flit).unwrap();
         assert_eq!(received_flit, Some(flit.clone()));
-        assert_eq!(hardware.ack_buffer.is_empty(), false);
-        assert_eq!(hardware.ack_buffer.is_ack(), true);
+        assert!(!hardware.ack_buffer.is_empty());
+        assert!(hardware.ack_buffer.is_ack());
+    }
+
+    #[test]
+    fn test_link_loss() {
+        let mut hardware = Hardware::new("source_id", &Switching::StoreAndForward);
+
+        let flit = Flit::Header(HeaderFlit {
+            source_id: "source_id".to_string(),
+            dest_id: "dest_id".to_string(),
+            next_id: "next_id".to_string(),
+            prev_id: "source_id".to_string(),
+            packet_id: 0,
+            flits_len: 1,
+            channel_id: 0,
+            ttl: DEFAULT_TTL,
+            data: vec![0; 8],
+        });
+        hardware.send_flit(&flit).unwrap();
+
+        // ackが返ってこないまま再送を続ける
+        let mut cycles = 0;
+        while !hardware.retransmission_buffer.is_empty() {
+            hardware.update_state().unwrap();
+            cycles += 1;
+            assert!(cycles < 10000);
+        }
+
+        assert_eq!(hardware.take_lost_links(), vec![flit]);
+        assert!(hardware.take_lost_links().is_empty());
     }
 
     // calc_wait_cyclesのテスト
@@ -308,15 +412,15 @@ mod tests {
         for _ in 0..100 {
             hardware.state.set_resend_times(1);
             let val = hardware.calc_wait_cycles();
-            assert!(3 <= val && val <= 5);
+            assert!((3..=5).contains(&val));
 
             hardware.state.set_resend_times(2);
             let val = hardware.calc_wait_cycles();
-            assert!(4 <= val && val <= 9);
+            assert!((4..=9).contains(&val));
 
             hardware.state.set_resend_times(3);
             let val = hardware.calc_wait_cycles();
-            assert!(6 <= val && val <= 17);
+            assert!((6..=17).contains(&val));
         }
     }
 }
@@ -1,4 +1,6 @@
-#[derive(Default, Clone, Debug)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct NodeState {
     state: State,
     resend_times: u8,
@@ -88,7 +90,7 @@ impl NodeState {
     }
 }
 
-#[derive(PartialEq, Debug, Clone, Default)]
+#[derive(PartialEq, Debug, Clone, Default, Serialize, Deserialize)]
 pub enum State {
     #[default]
     Idle,
@@ -104,7 +106,7 @@ impl State {
     }
 }
 
-#[derive(PartialEq, Debug, Clone, Default)]
+#[derive(PartialEq, Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Waiting {
     pub remaining_cycles: u32,
 }
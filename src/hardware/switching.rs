@@ -1,5 +1,6 @@
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub enum Switching {
     CutThrough,
     #[default]
@@ -3,5 +3,7 @@ pub mod gen;
 pub mod hardware;
 pub mod log;
 pub mod network;
+pub mod rng;
 pub mod sim;
 pub mod utils;
+pub mod validate;
@@ -1,8 +1,10 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error;
 use std::sync::Mutex;
 
 use crate::recsimu_dbg;
+use crate::sim::observer::SimEvent;
 
 cfg_if::cfg_if!(
     if #[cfg(not(test))]
@@ -14,7 +16,7 @@ cfg_if::cfg_if!(
     else {
         use std::cell::Cell;
         thread_local! {
-            static LOCAL_LOG: Cell<Option<&'static Mutex<Log>>> = Cell::new(None);
+            static LOCAL_LOG: Cell<Option<&'static Mutex<Log>>> = const { Cell::new(None) };
         }
 
         struct LogProxy;
@@ -41,10 +43,26 @@ cfg_if::cfg_if!(
     }
 );
 
-#[derive(Debug, Clone, Default)]
-struct Log {
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Log {
     packets_info: HashMap<String, PacketLog>,
     collision_info: Vec<CollisionInfo>,
+    broadcast_info: HashMap<String, BroadcastLog>,
+    rejoin_info: Vec<RejoinLog>,
+    // 割り当てるアドレスがなく参加を拒否したノード
+    join_reject_info: Vec<String>,
+    topology_info: Vec<TopologyLog>,
+    failure_info: Vec<FailureLog>,
+    link_change_info: Vec<LinkChangeLog>,
+    reassembly_info: ReassemblyReport,
+    transport_info: TransportLog,
+    loop_info: Vec<LoopLog>,
+    // オブザーバに渡すイベント，オブザーバが登録されているときのみ記録する
+    // オブザーバはチェックポイントに含めないので保存しない
+    #[serde(skip)]
+    events: Vec<SimEvent>,
+    #[serde(skip)]
+    events_enabled: bool,
 }
 
 impl Log {
@@ -52,11 +70,22 @@ impl Log {
         Self {
             packets_info: HashMap::new(),
             collision_info: Vec::new(),
+            broadcast_info: HashMap::new(),
+            rejoin_info: Vec::new(),
+            join_reject_info: Vec::new(),
+            topology_info: Vec::new(),
+            failure_info: Vec::new(),
+            link_change_info: Vec::new(),
+            reassembly_info: ReassemblyReport::default(),
+            transport_info: TransportLog::default(),
+            loop_info: Vec::new(),
+            events: Vec::new(),
+            events_enabled: false,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PacketLog {
     packet_id: String,
     from_id: String,
@@ -67,11 +96,25 @@ pub struct PacketLog {
     route_info: Vec<String>,
     flit_logs: Vec<FlitLog>,
     is_delivered: bool,
+    // ノードやリンクの故障で失われたか
+    lost_by_failure: bool,
+    // TTLを使い切り，経路のループとして捨てられたか
+    lost_by_loop: bool,
     message: String,
+    // 入力で指定されたペイロードのバイト数，指定がなければmessageの長さを使う
+    size_bytes: Option<u32>,
+    // 参加プロトコルの制御パケットか
+    is_control: bool,
+    // トランスポート層のセグメントの識別子，再送しても同じになる
+    segment_id: Option<String>,
     channel_id: u8,
+    // 入力で指定された生成サイクル，制御パケットなどではNone
+    inject_cycle: Option<u32>,
+    // 入力で指定されたトラフィッククラス
+    class: Option<String>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FlitLog {
     received_cycle: u32,
     from_id: String,
@@ -85,7 +128,12 @@ pub struct NewPacketLogInfo {
     pub dest_id: String,
     pub flits_len: u32,
     pub message: String,
+    pub size_bytes: Option<u32>,
+    pub is_control: bool,
+    pub segment_id: Option<String>,
     pub channel_id: u8,
+    pub inject_cycle: Option<u32>,
+    pub class: Option<String>,
 }
 
 pub fn post_new_packet_log(
@@ -103,8 +151,15 @@ pub fn post_new_packet_log(
         route_info: vec![packet_info.from_id.clone()],
         flit_logs: Vec::new(),
         is_delivered: false,
+        lost_by_failure: false,
+        lost_by_loop: false,
         message: packet_info.message.clone(),
+        size_bytes: packet_info.size_bytes,
+        is_control: packet_info.is_control,
+        segment_id: packet_info.segment_id.clone(),
         channel_id: packet_info.channel_id,
+        inject_cycle: packet_info.inject_cycle,
+        class: packet_info.class.clone(),
     };
 
     LOG.lock()
@@ -198,10 +253,53 @@ pub fn get_all_log() -> Vec<PacketLog> {
 pub fn clear_log() {
     let mut log = LOG.lock().expect("failed to lock log");
     log.packets_info.clear();
+    log.broadcast_info.clear();
+    log.rejoin_info.clear();
+    log.join_reject_info.clear();
+    log.topology_info.clear();
+    log.failure_info.clear();
+    log.link_change_info.clear();
+    log.reassembly_info = ReassemblyReport::default();
+    log.transport_info = TransportLog::default();
+    log.loop_info.clear();
+    log.events.clear();
+    log.events_enabled = false;
+}
+
+// チェックポイント用にログ全体を取り出す，戻す
+// イベントの記録はオブザーバを登録し直したときに有効にする
+pub(crate) fn get_log_state() -> Log {
+    let log = LOG.lock().expect("failed to lock log");
+    Log {
+        events: Vec::new(),
+        events_enabled: false,
+        ..log.clone()
+    }
+}
+
+pub(crate) fn set_log_state(log: Log) {
+    *LOG.lock().expect("failed to lock log") = log;
+}
+
+pub fn enable_events() {
+    LOG.lock().expect("failed to lock log").events_enabled = true;
+}
+
+// イベントを記録する．記録しないときにイベントを作らないようにクロージャで受け取る
+pub fn post_event(event: impl FnOnce() -> SimEvent) {
+    let mut log = LOG.lock().expect("failed to lock log");
+    if log.events_enabled {
+        log.events.push(event());
+    }
+}
+
+// 記録されたイベントを古い順に取り出す
+pub fn take_events() -> Vec<SimEvent> {
+    std::mem::take(&mut LOG.lock().expect("failed to lock log").events)
 }
 
 #[allow(unused)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct CollisionInfo {
     cycle: u32,
     from_ids: Vec<String>,
@@ -226,6 +324,633 @@ pub fn post_collision_info(info: &NewCollisionInfo) {
     log.collision_info.push(collision_info);
 }
 
+// ユーザーのbroadcast/multicastの配信状況
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct BroadcastLog {
+    packet_id: String,
+    from_id: String,
+    // 空ならbroadcast，そうでなければmulticastの宛先
+    dest_ids: Vec<String>,
+    // 各ノードが最初に受信したサイクル
+    first_receive_cycles: HashMap<String, u32>,
+    // 重複を含む受信回数
+    receive_count: u32,
+}
+
+pub struct NewBroadcastLogInfo {
+    pub packet_id: String,
+    pub from_id: String,
+    pub dest_ids: Vec<String>,
+}
+
+pub fn post_broadcast_log(info: &NewBroadcastLogInfo) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    let broadcast_log = BroadcastLog {
+        packet_id: info.packet_id.clone(),
+        from_id: info.from_id.clone(),
+        dest_ids: info.dest_ids.clone(),
+        first_receive_cycles: HashMap::new(),
+        receive_count: 0,
+    };
+
+    log.broadcast_info
+        .insert(info.packet_id.clone(), broadcast_log);
+}
+
+pub fn update_broadcast_log(packet_id: &str, receiver_id: &str, cycle: u32) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    if let Some(broadcast_log) = log.broadcast_info.get_mut(packet_id) {
+        broadcast_log.receive_count += 1;
+        broadcast_log
+            .first_receive_cycles
+            .entry(receiver_id.to_string())
+            .or_insert(cycle);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BroadcastReport {
+    pub packet_id: String,
+    pub from_id: String,
+    pub target_count: u32,
+    pub delivered_count: u32,
+    // 宛先のうち受信できたノードの割合
+    pub delivery_ratio: f64,
+    // 受信したノード1つあたりの受信回数(重複がなければ1)
+    // multicastでは宛先ノードでの受信のみ数える
+    pub redundancy: f64,
+    // 宛先に届くまでの平均サイクル数
+    pub average_cycle: f64,
+}
+
+// broadcast/multicastごとの集計
+// node_idsはbroadcastの宛先となる全ノード
+pub fn aggregate_broadcast_log(node_ids: &[String]) -> Vec<BroadcastReport> {
+    let log = LOG.lock().expect("failed to lock log");
+
+    let mut reports: Vec<BroadcastReport> = log
+        .broadcast_info
+        .values()
+        .map(|broadcast_log| {
+            let targets: HashSet<&String> = if broadcast_log.dest_ids.is_empty() {
+                node_ids.iter().collect()
+            } else {
+                broadcast_log.dest_ids.iter().collect()
+            };
+            let targets: Vec<&String> = targets
+                .into_iter()
+                .filter(|id| **id != broadcast_log.from_id)
+                .collect();
+
+            let send_cycle = log
+                .packets_info
+                .get(&broadcast_log.packet_id)
+                .and_then(|packet_log| packet_log.send_cycle);
+
+            let delivered: Vec<u32> = targets
+                .iter()
+                .filter_map(|id| broadcast_log.first_receive_cycles.get(*id).copied())
+                .collect();
+
+            let latencies: Vec<u32> = match send_cycle {
+                Some(send_cycle) => delivered.iter().map(|c| c - send_cycle).collect(),
+                None => vec![],
+            };
+
+            let reached = broadcast_log.first_receive_cycles.len();
+
+            BroadcastReport {
+                packet_id: broadcast_log.packet_id.clone(),
+                from_id: broadcast_log.from_id.clone(),
+                target_count: targets.len() as u32,
+                delivered_count: delivered.len() as u32,
+                delivery_ratio: if targets.is_empty() {
+                    0.0
+                } else {
+                    delivered.len() as f64 / targets.len() as f64
+                },
+                redundancy: if reached == 0 {
+                    0.0
+                } else {
+                    broadcast_log.receive_count as f64 / reached as f64
+                },
+                average_cycle: average(&latencies),
+            }
+        })
+        .collect();
+
+    reports.sort_by(|a, b| a.packet_id.cmp(&b.packet_id));
+    reports
+}
+
+// サイクル数などの平均，値がなければ0
+fn average(values: &[u32]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<u32>() as f64 / values.len() as f64
+    }
+}
+
+// 参加済みのノードがネットワークから外れ，再参加するまでの記録
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RejoinLog {
+    node_id: String,
+    left_cycle: u32,
+    rejoined_cycle: Option<u32>,
+}
+
+pub fn post_rejoin_log(node_id: &str, left_cycle: u32) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    log.rejoin_info.push(RejoinLog {
+        node_id: node_id.to_string(),
+        left_cycle,
+        rejoined_cycle: None,
+    });
+}
+
+// node_idの再参加待ちの記録に再参加したサイクルを記録する
+pub fn update_rejoin_log(node_id: &str, rejoined_cycle: u32) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    if let Some(rejoin_log) = log
+        .rejoin_info
+        .iter_mut()
+        .rev()
+        .find(|rejoin_log| rejoin_log.node_id == node_id && rejoin_log.rejoined_cycle.is_none())
+    {
+        rejoin_log.rejoined_cycle = Some(rejoined_cycle);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RejoinReport {
+    // ネットワークから外れた回数
+    pub rejoin_count: u32,
+    // そのうち再参加できた回数
+    pub repaired_count: u32,
+    // 外れてから再参加するまでの平均サイクル数
+    pub average_repair_cycle: f64,
+}
+
+pub fn aggregate_rejoin_log() -> RejoinReport {
+    let log = LOG.lock().expect("failed to lock log");
+
+    let repair_cycles: Vec<u32> = log
+        .rejoin_info
+        .iter()
+        .filter_map(|rejoin_log| {
+            rejoin_log
+                .rejoined_cycle
+                .map(|rejoined_cycle| rejoined_cycle - rejoin_log.left_cycle)
+        })
+        .collect();
+
+    RejoinReport {
+        rejoin_count: log.rejoin_info.len() as u32,
+        repaired_count: repair_cycles.len() as u32,
+        average_repair_cycle: average(&repair_cycles),
+    }
+}
+
+// コーディネータが割り当てるアドレスがなく，node_idの参加を拒否した
+pub fn post_join_reject_log(node_id: &str) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    log.join_reject_info.push(node_id.to_string());
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinRejectReport {
+    // 参加を拒否した回数
+    pub reject_count: u32,
+    // 拒否されたノード
+    pub node_ids: Vec<String>,
+}
+
+pub fn aggregate_join_reject_log() -> JoinRejectReport {
+    let log = LOG.lock().expect("failed to lock log");
+
+    let mut node_ids = log.join_reject_info.clone();
+    node_ids.sort();
+    node_ids.dedup();
+
+    JoinRejectReport {
+        reject_count: log.join_reject_info.len() as u32,
+        node_ids,
+    }
+}
+
+// 適用したトポロジ変更の記録
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopologyLog {
+    pub cycle: u32,
+    pub event: String,
+}
+
+pub fn post_topology_log(cycle: u32, event: &str) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    log.topology_info.push(TopologyLog {
+        cycle,
+        event: event.to_string(),
+    });
+}
+
+pub fn get_topology_log() -> Vec<TopologyLog> {
+    let log = LOG.lock().expect("failed to lock log");
+
+    log.topology_info.clone()
+}
+
+// ノードの移動で隣接関係を計算し直したときのリンクの増減の記録
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct LinkChangeLog {
+    cycle: u32,
+    added_links: u32,
+    removed_links: u32,
+}
+
+pub fn post_link_change_log(cycle: u32, added_links: u32, removed_links: u32) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    log.link_change_info.push(LinkChangeLog {
+        cycle,
+        added_links,
+        removed_links,
+    });
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkChangeReport {
+    // 隣接関係を計算し直した回数
+    pub update_count: u32,
+    // 張られたリンクと切れたリンクの合計
+    pub added_links: u32,
+    pub removed_links: u32,
+}
+
+pub fn aggregate_link_change_log() -> LinkChangeReport {
+    let log = LOG.lock().expect("failed to lock log");
+
+    LinkChangeReport {
+        update_count: log.link_change_info.len() as u32,
+        added_links: log
+            .link_change_info
+            .iter()
+            .map(|change| change.added_links)
+            .sum(),
+        removed_links: log
+            .link_change_info
+            .iter()
+            .map(|change| change.removed_links)
+            .sum(),
+    }
+}
+
+// 宛先でのフリットからパケットへの組み立ての結果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReassemblyEvent {
+    Completed,
+    // 末尾までのフリット番号に欠けがあった
+    Partial,
+    // 既に受け取ったフリットがもう一度届いた
+    Duplicate,
+    // 揃わないまま時間切れで破棄した
+    Dropped,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReassemblyReport {
+    pub completed: u32,
+    pub partial: u32,
+    pub duplicate: u32,
+    pub dropped: u32,
+}
+
+// 件数だけ数え，長い実行でもログが増えないようにする
+pub fn post_reassembly_log(event: ReassemblyEvent) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    let report = &mut log.reassembly_info;
+    match event {
+        ReassemblyEvent::Completed => report.completed += 1,
+        ReassemblyEvent::Partial => report.partial += 1,
+        ReassemblyEvent::Duplicate => report.duplicate += 1,
+        ReassemblyEvent::Dropped => report.dropped += 1,
+    }
+}
+
+pub fn aggregate_reassembly_log() -> ReassemblyReport {
+    LOG.lock()
+        .expect("failed to lock log")
+        .reassembly_info
+        .clone()
+}
+
+// トランスポート層での送受信
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportEvent {
+    Sent,
+    Retransmitted,
+    // 再送を繰り返しても届かず諦めた
+    GaveUp,
+    AckSent,
+    // 初めて届いたセグメントとメッセージのバイト数
+    Delivered(usize),
+    // 既に届いていたセグメントとメッセージのバイト数
+    Duplicate(usize),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct TransportLog {
+    sent: u32,
+    retransmitted: u32,
+    gave_up: u32,
+    acks: u32,
+    delivered: u32,
+    duplicates: u32,
+    delivered_bytes: u64,
+    duplicate_bytes: u64,
+}
+
+pub fn post_transport_log(event: TransportEvent) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    let transport = &mut log.transport_info;
+    match event {
+        TransportEvent::Sent => transport.sent += 1,
+        TransportEvent::Retransmitted => transport.retransmitted += 1,
+        TransportEvent::GaveUp => transport.gave_up += 1,
+        TransportEvent::AckSent => transport.acks += 1,
+        TransportEvent::Delivered(bytes) => {
+            transport.delivered += 1;
+            transport.delivered_bytes += bytes as u64;
+        }
+        TransportEvent::Duplicate(bytes) => {
+            transport.duplicates += 1;
+            transport.duplicate_bytes += bytes as u64;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransportReport {
+    // 再送を除いて送ったセグメントの数
+    pub sent_segments: u32,
+    pub retransmissions: u32,
+    pub gave_up_segments: u32,
+    pub acks: u32,
+    // 重複を除いて届いたセグメントの数
+    pub delivered_segments: u32,
+    pub duplicate_segments: u32,
+    // 重複を除いて届いたメッセージの1サイクルあたりのバイト数
+    pub goodput: f64,
+    // 重複を含めて届いたメッセージの1サイクルあたりのバイト数
+    pub throughput: f64,
+}
+
+pub fn aggregate_transport_log(cycles: u32) -> TransportReport {
+    let log = LOG.lock().expect("failed to lock log");
+    let transport = &log.transport_info;
+
+    TransportReport {
+        sent_segments: transport.sent,
+        retransmissions: transport.retransmitted,
+        gave_up_segments: transport.gave_up,
+        acks: transport.acks,
+        delivered_segments: transport.delivered,
+        duplicate_segments: transport.duplicates,
+        goodput: transport.delivered_bytes as f64 / cycles as f64,
+        throughput: (transport.delivered_bytes + transport.duplicate_bytes) as f64 / cycles as f64,
+    }
+}
+
+// ノードの故障と復旧の記録
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct FailureLog {
+    node_id: String,
+    failed_cycle: u32,
+    restarted_cycle: Option<u32>,
+}
+
+pub fn post_failure_log(node_id: &str, failed_cycle: u32) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    log.failure_info.push(FailureLog {
+        node_id: node_id.to_string(),
+        failed_cycle,
+        restarted_cycle: None,
+    });
+}
+
+// node_idの故障中の記録に復旧したサイクルを記録する
+pub fn update_failure_log(node_id: &str, restarted_cycle: u32) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    if let Some(failure_log) =
+        log.failure_info.iter_mut().rev().find(|failure_log| {
+            failure_log.node_id == node_id && failure_log.restarted_cycle.is_none()
+        })
+    {
+        failure_log.restarted_cycle = Some(restarted_cycle);
+    }
+}
+
+// 故障で失われたパケットを記録する
+// 届いた後に残っていたフリットが失われた場合は記録しない
+pub fn post_failure_loss_log(packet_id: &str) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    if let Some(packet_log) = log.packets_info.get_mut(packet_id) {
+        if !packet_log.is_delivered {
+            packet_log.lost_by_failure = true;
+        }
+    }
+}
+
+// TTLを使い切って捨てたパケットと，同じノードを再び通ったパケットの記録
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoopLog {
+    pub packet_id: String,
+    pub node_id: String,
+    pub cycle: u32,
+    // それまでにパケットが通ったノード
+    pub route_info: Vec<String>,
+    // falseなら捨てずに同じノードを再び通っただけ
+    pub dropped: bool,
+}
+
+// node_idでTTLを使い切ったパケットを記録する
+// 同じパケットの残りのフリットも捨てられるので，最初の1回だけ記録する
+pub fn post_loop_drop_log(packet_id: &str, node_id: &str, cycle: u32) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    let Some(packet_log) = log.packets_info.get_mut(packet_id) else {
+        return;
+    };
+    if packet_log.lost_by_loop || packet_log.is_delivered {
+        return;
+    }
+    packet_log.lost_by_loop = true;
+    let route_info = packet_log.route_info.clone();
+
+    log.loop_info.push(LoopLog {
+        packet_id: packet_id.to_string(),
+        node_id: node_id.to_string(),
+        cycle,
+        route_info,
+        dropped: true,
+    });
+}
+
+// パケットがすでに通ったnode_idにprev_idから戻ってきたら記録する
+pub fn post_revisit_log(packet_id: &str, node_id: &str, prev_id: &str, cycle: u32) {
+    let mut log = LOG.lock().expect("failed to lock log");
+
+    let Some(packet_log) = log.packets_info.get(packet_id) else {
+        return;
+    };
+    let route_info = &packet_log.route_info;
+    // ackが届かずprev_idが再送したフリットは，ループではなく同じフリットの重複
+    let retransmitted = route_info.len() >= 2
+        && route_info[route_info.len() - 2..] == [prev_id.to_string(), node_id.to_string()];
+    if retransmitted || !route_info.iter().any(|id| id == node_id) {
+        return;
+    }
+    let route_info = packet_log.route_info.clone();
+
+    log.loop_info.push(LoopLog {
+        packet_id: packet_id.to_string(),
+        node_id: node_id.to_string(),
+        cycle,
+        route_info,
+        dropped: false,
+    });
+}
+
+pub fn get_loop_log() -> Vec<LoopLog> {
+    let log = LOG.lock().expect("failed to lock log");
+    log.loop_info.clone()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailureReport {
+    // ノードが故障した回数
+    pub failure_count: u32,
+    // そのうち復旧した回数
+    pub restart_count: u32,
+    // 故障していた平均サイクル数
+    pub average_down_cycle: f64,
+}
+
+pub fn aggregate_failure_log() -> FailureReport {
+    let log = LOG.lock().expect("failed to lock log");
+
+    let down_cycles: Vec<u32> = log
+        .failure_info
+        .iter()
+        .filter_map(|failure_log| {
+            failure_log
+                .restarted_cycle
+                .map(|restarted_cycle| restarted_cycle - failure_log.failed_cycle)
+        })
+        .collect();
+
+    FailureReport {
+        failure_count: log.failure_info.len() as u32,
+        restart_count: down_cycles.len() as u32,
+        average_down_cycle: average(&down_cycles),
+    }
+}
+
+// 届いたか故障やループで失われたユーザーのunicastパケットの数
+// トランスポート層のセグメントは再送を含めて1つと数える
+pub fn count_finished_packets() -> u32 {
+    let log = LOG.lock().expect("failed to lock log");
+
+    let mut count = 0;
+    let mut finished_segments = HashSet::new();
+    for packet_log in log.packets_info.values() {
+        if packet_log.dest_id == "broadcast" || packet_log.is_control {
+            continue;
+        }
+
+        match &packet_log.segment_id {
+            // トランスポート層で再送したセグメントは一度だけ数え，届いたときに終わったとみなす
+            Some(segment_id) => {
+                if packet_log.is_delivered {
+                    finished_segments.insert(segment_id);
+                }
+            }
+            None => {
+                if packet_log.is_delivered || packet_log.lost_by_failure || packet_log.lost_by_loop
+                {
+                    count += 1;
+                }
+            }
+        }
+    }
+
+    count + finished_segments.len() as u32
+}
+
+// トランスポート層で再送したセグメントのコピーをまとめ，セグメントごとに一つだけ残す
+// どれかのコピーが届いていれば最初に届いたものを，届いていなければ送信中，故障，ループの順に残す
+fn unique_segments<'a>(packet_logs: impl Iterator<Item = &'a PacketLog>) -> Vec<&'a PacketLog> {
+    let rank = |packet_log: &PacketLog| {
+        if packet_log.is_delivered {
+            (0, packet_log.last_receive_cycle.unwrap_or(u32::MAX))
+        } else if packet_log.lost_by_failure {
+            (2, 0)
+        } else if packet_log.lost_by_loop {
+            (3, 0)
+        } else {
+            (1, 0)
+        }
+    };
+
+    let mut unique = Vec::new();
+    let mut segments: HashMap<&str, &PacketLog> = HashMap::new();
+    for packet_log in packet_logs {
+        let Some(segment_id) = &packet_log.segment_id else {
+            unique.push(packet_log);
+            continue;
+        };
+        segments
+            .entry(segment_id)
+            .and_modify(|kept| {
+                if rank(packet_log) < rank(kept) {
+                    *kept = packet_log;
+                }
+            })
+            .or_insert(packet_log);
+    }
+    unique.extend(segments.into_values());
+    unique
+}
+
+// 届いたユーザーのunicastパケットのメッセージのビット数の合計
+pub fn count_delivered_bits() -> u64 {
+    let log = LOG.lock().expect("failed to lock log");
+
+    log.packets_info
+        .values()
+        .filter(|packet_log| {
+            packet_log.dest_id != "broadcast" && !packet_log.is_control && packet_log.is_delivered
+        })
+        .map(|packet_log| {
+            packet_log
+                .size_bytes
+                .map_or(packet_log.message.len() as u64, u64::from)
+                * 8
+        })
+        .sum()
+}
+
 // ログの集計
 pub fn aggregate_log(begin: u32, end: u32) -> HashMap<String, f64> {
     // 必要な情報は，パケットの送信にかかった平均サイクル数
@@ -234,18 +959,24 @@ pub fn aggregate_log(begin: u32, end: u32) -> HashMap<String, f64> {
     let mut sum = 0.0;
     let mut count = 0;
     let mut undelivered_count = 0;
+    let mut lost_by_failure_count = 0;
+    let mut lost_by_loop_count = 0;
 
     let mut packet_count = 0;
     let mut flits_count = 0;
+    // 制御パケットとデータパケットを分けて数える
+    let mut control_packet_count = 0;
+    let mut control_flits_count = 0;
 
-    for (_, packet_log) in log.packets_info.iter() {
-        if packet_log.send_cycle.is_none() {
-            continue;
-        }
-
-        if packet_log.send_cycle.unwrap() < begin || packet_log.send_cycle.unwrap() >= end {
-            continue;
-        }
+    let packet_logs = log.packets_info.values().filter(|packet_log| {
+        // broadcast/multicastはaggregate_broadcast_logで集計する
+        packet_log
+            .send_cycle
+            .is_some_and(|send_cycle| begin <= send_cycle && send_cycle < end)
+            && !log.broadcast_info.contains_key(&packet_log.packet_id)
+    });
+    // 再送したセグメントは一度だけ数える
+    for packet_log in unique_segments(packet_logs) {
         if packet_log.is_delivered {
             if packet_log.last_receive_cycle.unwrap() < packet_log.send_cycle.unwrap() {
                 panic!("{:?}", packet_log);
@@ -256,11 +987,19 @@ pub fn aggregate_log(begin: u32, end: u32) -> HashMap<String, f64> {
             count += 1;
 
             recsimu_dbg!("{:?}", packet_log);
+        } else if packet_log.lost_by_failure {
+            lost_by_failure_count += 1;
+        } else if packet_log.lost_by_loop {
+            lost_by_loop_count += 1;
         } else {
             undelivered_count += 1;
         }
         packet_count += 1;
         flits_count += packet_log.flits_len;
+        if packet_log.is_control {
+            control_packet_count += 1;
+            control_flits_count += packet_log.flits_len;
+        }
     }
 
     let mut result = HashMap::new();
@@ -269,12 +1008,35 @@ pub fn aggregate_log(begin: u32, end: u32) -> HashMap<String, f64> {
 
     result.insert("average_cycle".to_string(), sum / count as f64);
     result.insert("undelivered_packets".to_string(), undelivered_count as f64);
+    result.insert(
+        "lost_by_failure_packets".to_string(),
+        lost_by_failure_count as f64,
+    );
+    result.insert(
+        "lost_by_loop_packets".to_string(),
+        lost_by_loop_count as f64,
+    );
     result.insert("total_packets".to_string(), packet_count as f64);
     result.insert("total_flits".to_string(), flits_count as f64);
     result.insert(
         "average_flits_len".to_string(),
         flits_count as f64 / packet_count as f64,
     );
+    result.insert("control_packets".to_string(), control_packet_count as f64);
+    result.insert("control_flits".to_string(), control_flits_count as f64);
+    result.insert(
+        "data_packets".to_string(),
+        (packet_count - control_packet_count) as f64,
+    );
+    result.insert(
+        "data_flits".to_string(),
+        (flits_count - control_flits_count) as f64,
+    );
+    // 全フリットのうち制御パケットが占める割合
+    result.insert(
+        "control_overhead".to_string(),
+        control_flits_count as f64 / flits_count as f64,
+    );
     result.insert(
         "collision_count".to_string(),
         log.collision_info.len() as f64,
@@ -283,6 +1045,159 @@ pub fn aggregate_log(begin: u32, end: u32) -> HashMap<String, f64> {
     result
 }
 
+// 計測区間に生成したユーザーのunicastパケットの集計
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeasurementReport {
+    // 計測区間に生成されたパケットの数
+    pub measured_packets: u32,
+    pub delivered_packets: u32,
+    pub lost_by_failure_packets: u32,
+    pub lost_by_loop_packets: u32,
+    // 送信元でフリットに分割されたが，終了時に届いていないパケットの数
+    pub in_flight_packets: u32,
+    // 送信元のキューに残ったまま送信されなかったパケットの数
+    pub unsent_packets: u32,
+    // 生成から受信までの平均サイクル数(送信元でのキュー待ちを含む)
+    pub average_latency: f64,
+    // 送信開始から受信までの平均サイクル数
+    pub average_network_latency: f64,
+}
+
+// 生成サイクルがbegin以上end未満のパケットを集計する
+// measured_packetsは入力から数えた，その区間に生成されたパケットの数
+pub fn aggregate_measurement_log(begin: u32, end: u32, measured_packets: u32) -> MeasurementReport {
+    let log = LOG.lock().expect("failed to lock log");
+
+    let mut latencies = Vec::new();
+    let mut network_latencies = Vec::new();
+    let mut lost_by_failure_count = 0;
+    let mut lost_by_loop_count = 0;
+    let mut in_flight_count = 0;
+
+    // 再送したセグメントは一度だけ数え，最初に届いたコピーの遅延を使う
+    for packet_log in unique_segments(log.packets_info.values()) {
+        let Some(inject_cycle) = packet_log.inject_cycle else {
+            continue;
+        };
+        if packet_log.dest_id == "broadcast" || inject_cycle < begin || inject_cycle >= end {
+            continue;
+        }
+
+        if packet_log.is_delivered {
+            let last_receive_cycle = packet_log.last_receive_cycle.unwrap();
+            latencies.push(last_receive_cycle - inject_cycle);
+            network_latencies.push(last_receive_cycle - packet_log.send_cycle.unwrap());
+        } else if packet_log.lost_by_failure {
+            lost_by_failure_count += 1;
+        } else if packet_log.lost_by_loop {
+            lost_by_loop_count += 1;
+        } else {
+            in_flight_count += 1;
+        }
+    }
+
+    let logged_count =
+        latencies.len() as u32 + lost_by_failure_count + lost_by_loop_count + in_flight_count;
+
+    MeasurementReport {
+        measured_packets,
+        delivered_packets: latencies.len() as u32,
+        lost_by_failure_packets: lost_by_failure_count,
+        lost_by_loop_packets: lost_by_loop_count,
+        in_flight_packets: in_flight_count,
+        unsent_packets: measured_packets.saturating_sub(logged_count),
+        average_latency: average(&latencies),
+        average_network_latency: average(&network_latencies),
+    }
+}
+
+// 送信を始めて，まだ届いても失われてもいないunicastパケット
+#[derive(Debug, Clone, PartialEq)]
+pub struct InFlightPacket {
+    pub packet_id: String,
+    pub channel_id: u8,
+    pub route_info: Vec<String>,
+}
+
+pub fn get_in_flight_packets() -> Vec<InFlightPacket> {
+    let log = LOG.lock().expect("failed to lock log");
+
+    log.packets_info
+        .values()
+        .filter(|packet_log| {
+            packet_log.dest_id != "broadcast"
+                && packet_log.send_cycle.is_some()
+                && !packet_log.is_delivered
+                && !packet_log.lost_by_failure
+                && !packet_log.lost_by_loop
+        })
+        .map(|packet_log| InFlightPacket {
+            packet_id: packet_log.packet_id.clone(),
+            channel_id: packet_log.channel_id,
+            route_info: packet_log.route_info.clone(),
+        })
+        .collect()
+}
+
+// トラフィッククラスごとのユーザーのunicastパケットの集計
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassReport {
+    pub class: String,
+    pub delivered_packets: u32,
+    // 届いておらず，故障やループで失われてもいないパケットの数
+    pub undelivered_packets: u32,
+    pub lost_by_failure_packets: u32,
+    pub lost_by_loop_packets: u32,
+    // 生成(生成サイクルがなければ送信開始)から受信までの平均と最大のサイクル数
+    pub average_latency: f64,
+    pub max_latency: u32,
+}
+
+// クラスを指定して送信したパケットをクラスごとに集計し，クラス名の順に並べる
+pub fn aggregate_class_log() -> Vec<ClassReport> {
+    let log = LOG.lock().expect("failed to lock log");
+
+    let mut reports: BTreeMap<String, (Vec<u32>, u32, u32, u32)> = BTreeMap::new();
+    for packet_log in log.packets_info.values() {
+        let (Some(class), Some(send_cycle)) = (&packet_log.class, packet_log.send_cycle) else {
+            continue;
+        };
+        if packet_log.dest_id == "broadcast" {
+            continue;
+        }
+
+        let (latencies, undelivered_count, lost_by_failure_count, lost_by_loop_count) =
+            reports.entry(class.clone()).or_default();
+        if packet_log.is_delivered {
+            let begin = packet_log.inject_cycle.unwrap_or(send_cycle);
+            latencies.push(packet_log.last_receive_cycle.unwrap() - begin);
+        } else if packet_log.lost_by_failure {
+            *lost_by_failure_count += 1;
+        } else if packet_log.lost_by_loop {
+            *lost_by_loop_count += 1;
+        } else {
+            *undelivered_count += 1;
+        }
+    }
+
+    reports
+        .into_iter()
+        .map(
+            |(class, (latencies, undelivered_count, lost_by_failure_count, lost_by_loop_count))| {
+                ClassReport {
+                    class,
+                    delivered_packets: latencies.len() as u32,
+                    undelivered_packets: undelivered_count,
+                    lost_by_failure_packets: lost_by_failure_count,
+                    lost_by_loop_packets: lost_by_loop_count,
+                    average_latency: average(&latencies),
+                    max_latency: latencies.iter().copied().max().unwrap_or(0),
+                }
+            },
+        )
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -295,7 +1210,12 @@ mod tests {
             dest_id: "dest_id".to_string(),
             flits_len: 2,
             message: "test".to_string(),
+            size_bytes: None,
+            is_control: false,
+            segment_id: None,
             channel_id: 0,
+            inject_cycle: None,
+            class: None,
         };
         let packet_log = post_new_packet_log(&packet_info).unwrap();
         assert_eq!(packet_log.packet_id, "packet_id");
@@ -305,7 +1225,7 @@ mod tests {
         assert_eq!(packet_log.last_receive_cycle, None);
         assert_eq!(packet_log.route_info, vec!["from_id"]);
         assert_eq!(packet_log.flit_logs, Vec::<FlitLog>::new());
-        assert_eq!(packet_log.is_delivered, false);
+        assert!(!packet_log.is_delivered);
     }
 
     #[test]
@@ -316,7 +1236,12 @@ mod tests {
             dest_id: "dest_id".to_string(),
             flits_len: 3,
             message: "test".to_string(),
+            size_bytes: None,
+            is_control: false,
+            segment_id: None,
             channel_id: 0,
+            inject_cycle: None,
+            class: None,
         };
         let packet_log = post_new_packet_log(&packet_info).unwrap();
 
@@ -335,7 +1260,143 @@ mod tests {
         assert_eq!(packet_log.last_receive_cycle, Some(1));
         assert_eq!(packet_log.route_info, vec!["from_id", "route_info"]);
         assert_eq!(packet_log.flit_logs, Vec::<FlitLog>::new());
-        assert_eq!(packet_log.is_delivered, true);
+        assert!(packet_log.is_delivered);
+    }
+
+    #[test]
+    fn test_aggregate_broadcast_log() {
+        let packet_info = NewPacketLogInfo {
+            packet_id: "a_0".to_string(),
+            from_id: "a".to_string(),
+            dest_id: "broadcast".to_string(),
+            flits_len: 1,
+            message: "test".to_string(),
+            size_bytes: None,
+            is_control: false,
+            segment_id: None,
+            channel_id: 0,
+            inject_cycle: None,
+            class: None,
+        };
+        post_new_packet_log(&packet_info).unwrap();
+        update_packet_log(
+            "a_0",
+            &UpdatePacketLogInfo {
+                send_cycle: Some(10),
+                last_receive_cycle: None,
+                route_info: None,
+                is_delivered: None,
+                flit_log: None,
+            },
+        )
+        .unwrap();
+        post_broadcast_log(&NewBroadcastLogInfo {
+            packet_id: "a_0".to_string(),
+            from_id: "a".to_string(),
+            dest_ids: vec![],
+        });
+
+        update_broadcast_log("a_0", "b", 12);
+        update_broadcast_log("a_0", "c", 14);
+        update_broadcast_log("a_0", "b", 15);
+        update_broadcast_log("a_0", "a", 16);
+
+        let node_ids: Vec<String> = ["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect();
+        let reports = aggregate_broadcast_log(&node_ids);
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].target_count, 3);
+        assert_eq!(reports[0].delivered_count, 2);
+        assert_eq!(reports[0].delivery_ratio, 2.0 / 3.0);
+        assert_eq!(reports[0].redundancy, 4.0 / 3.0);
+        assert_eq!(reports[0].average_cycle, 3.0);
+
+        // 誰も受信していなければ0を報告する
+        post_broadcast_log(&NewBroadcastLogInfo {
+            packet_id: "b_0".to_string(),
+            from_id: "b".to_string(),
+            dest_ids: vec!["b".to_string()],
+        });
+        let reports = aggregate_broadcast_log(&node_ids);
+        assert_eq!(reports[1].target_count, 0);
+        assert_eq!(reports[1].delivery_ratio, 0.0);
+        assert_eq!(reports[1].average_cycle, 0.0);
+    }
+
+    #[test]
+    fn test_aggregate_log_control_data() {
+        // 制御メッセージと同じ文字列でもデータパケットとして数える
+        for (packet_id, message, is_control, flits_len) in [
+            ("a_0", "preq", true, 1),
+            ("a_1", "jack", false, 3),
+            ("b_0", "pack", true, 1),
+        ] {
+            post_new_packet_log(&NewPacketLogInfo {
+                packet_id: packet_id.to_string(),
+                from_id: "a".to_string(),
+                dest_id: "b".to_string(),
+                flits_len,
+                message: message.to_string(),
+                size_bytes: None,
+                is_control,
+                segment_id: None,
+                channel_id: 0,
+                inject_cycle: None,
+                class: None,
+            })
+            .unwrap();
+            update_packet_log(
+                packet_id,
+                &UpdatePacketLogInfo {
+                    send_cycle: Some(10),
+                    last_receive_cycle: Some(20),
+                    route_info: None,
+                    is_delivered: Some(true),
+                    flit_log: None,
+                },
+            )
+            .unwrap();
+        }
+
+        let result = aggregate_log(0, 100);
+        assert_eq!(result["total_packets"], 3.0);
+        assert_eq!(result["control_packets"], 2.0);
+        assert_eq!(result["control_flits"], 2.0);
+        assert_eq!(result["data_packets"], 1.0);
+        assert_eq!(result["data_flits"], 3.0);
+        assert_eq!(result["control_overhead"], 0.4);
+        assert_eq!(count_finished_packets(), 1);
+    }
+
+    #[test]
+    fn test_aggregate_rejoin_log() {
+        // 再参加していなければ平均は0
+        post_rejoin_log("a", 10);
+        assert_eq!(aggregate_rejoin_log().average_repair_cycle, 0.0);
+
+        post_rejoin_log("b", 20);
+        update_rejoin_log("a", 25);
+        post_rejoin_log("a", 40);
+
+        let report = aggregate_rejoin_log();
+        assert_eq!(report.rejoin_count, 3);
+        assert_eq!(report.repaired_count, 1);
+        assert_eq!(report.average_repair_cycle, 15.0);
+    }
+
+    #[test]
+    fn test_aggregate_failure_log() {
+        // 復旧していなければ平均は0
+        post_failure_log("a", 10);
+        assert_eq!(aggregate_failure_log().average_down_cycle, 0.0);
+
+        post_failure_log("b", 20);
+        update_failure_log("a", 16);
+
+        let report = aggregate_failure_log();
+        assert_eq!(report.failure_count, 2);
+        assert_eq!(report.restart_count, 1);
+        assert_eq!(report.average_down_cycle, 6.0);
     }
 
     #[test]
@@ -346,7 +1407,12 @@ mod tests {
             dest_id: "dest_id".to_string(),
             flits_len: 1,
             message: "test".to_string(),
+            size_bytes: None,
+            is_control: false,
+            segment_id: None,
             channel_id: 0,
+            inject_cycle: None,
+            class: None,
         };
         let packet_log = post_new_packet_log(&packet_info).unwrap();
 
@@ -358,6 +1424,105 @@ mod tests {
         assert_eq!(get_packet_log.last_receive_cycle, None);
         assert_eq!(get_packet_log.route_info, vec!["from_id"]);
         assert_eq!(get_packet_log.flit_logs, Vec::<FlitLog>::new());
-        assert_eq!(get_packet_log.is_delivered, false);
+        assert!(!get_packet_log.is_delivered);
+    }
+
+    #[test]
+    fn test_aggregate_measurement_log() {
+        // 計測区間[10, 20)に生成した3つのうち，1つは届き，1つは送信中，1つは未送信
+        // a_0は計測区間の前に生成された
+        for (packet_id, inject_cycle) in [("a_0", 5), ("a_1", 10), ("a_2", 15)] {
+            post_new_packet_log(&NewPacketLogInfo {
+                packet_id: packet_id.to_string(),
+                from_id: "a".to_string(),
+                dest_id: "b".to_string(),
+                flits_len: 1,
+                message: "test".to_string(),
+                size_bytes: None,
+                is_control: false,
+                segment_id: None,
+                channel_id: 0,
+                inject_cycle: Some(inject_cycle),
+                class: None,
+            })
+            .unwrap();
+        }
+        update_packet_log(
+            "a_1",
+            &UpdatePacketLogInfo {
+                send_cycle: Some(14),
+                last_receive_cycle: Some(18),
+                route_info: None,
+                is_delivered: Some(true),
+                flit_log: None,
+            },
+        )
+        .unwrap();
+
+        let report = aggregate_measurement_log(10, 20, 3);
+        assert_eq!(report.delivered_packets, 1);
+        assert_eq!(report.in_flight_packets, 1);
+        assert_eq!(report.unsent_packets, 1);
+        assert_eq!(report.average_latency, 8.0);
+        assert_eq!(report.average_network_latency, 4.0);
+    }
+
+    #[test]
+    fn test_aggregate_class_log() {
+        // classなしのa_3は集計しない
+        for (packet_id, class, inject_cycle) in [
+            ("a_0", Some("voice"), Some(0)),
+            ("a_1", Some("voice"), Some(2)),
+            ("a_2", Some("bulk"), None),
+            ("a_3", None, Some(0)),
+            ("a_4", Some("bulk"), None),
+        ] {
+            post_new_packet_log(&NewPacketLogInfo {
+                packet_id: packet_id.to_string(),
+                from_id: "a".to_string(),
+                dest_id: "b".to_string(),
+                flits_len: 1,
+                message: "test".to_string(),
+                size_bytes: None,
+                is_control: false,
+                segment_id: None,
+                channel_id: 0,
+                inject_cycle,
+                class: class.map(str::to_string),
+            })
+            .unwrap();
+        }
+        for (packet_id, send_cycle, last_receive_cycle) in [
+            ("a_0", 1, Some(5)),
+            ("a_1", 3, Some(12)),
+            ("a_2", 4, None),
+            ("a_3", 1, Some(9)),
+            ("a_4", 2, None),
+        ] {
+            update_packet_log(
+                packet_id,
+                &UpdatePacketLogInfo {
+                    send_cycle: Some(send_cycle),
+                    last_receive_cycle,
+                    route_info: None,
+                    is_delivered: Some(last_receive_cycle.is_some()),
+                    flit_log: None,
+                },
+            )
+            .unwrap();
+        }
+        post_loop_drop_log("a_4", "b", 6);
+
+        let reports = aggregate_class_log();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].class, "bulk");
+        assert_eq!(reports[0].delivered_packets, 0);
+        assert_eq!(reports[0].undelivered_packets, 1);
+        assert_eq!(reports[0].average_latency, 0.0);
+        assert_eq!(reports[0].lost_by_loop_packets, 1);
+        assert_eq!(reports[1].class, "voice");
+        assert_eq!(reports[1].delivered_packets, 2);
+        assert_eq!(reports[1].average_latency, 7.5);
+        assert_eq!(reports[1].max_latency, 10);
     }
 }
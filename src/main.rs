@@ -1,10 +1,26 @@
 use clap::{Arg, ArgAction, Command};
+use recsimu::file::InputFile;
 use recsimu::gen::config::Config;
-use recsimu::sim::SimBuilder;
+use recsimu::rng::seed_rng;
+use recsimu::sim::{Sim, SimBuilder};
 use recsimu::utils;
+use recsimu::validate::{validate, Severity};
 use std::error;
-use std::path::PathBuf;
-fn main() -> Result<(), Box<dyn error::Error>> {
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+// エラーは複数行になることがあるのでそのまま表示する
+fn main() -> ExitCode {
+    match run_command() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_command() -> Result<(), Box<dyn error::Error>> {
     let matches = Command::new("recsimu")
         .about("simulator for shape-changeable computer system")
         .version("0.1.0")
@@ -43,6 +59,59 @@ fn main() -> Result<(), Box<dyn error::Error>> {
                         .num_args(1)
                         .required(true),
                 )
+                .arg(
+                    Arg::new("verbose")
+                        .long("verbose")
+                        .action(ArgAction::SetTrue)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("checkpoint_at")
+                        .long("checkpoint-at")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(u32))
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("checkpoint_file_path")
+                        .long("checkpoint-file")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("validate")
+                .about("check a simulation input file")
+                .arg(
+                    Arg::new("input_file_path")
+                        .short('i')
+                        .long("input")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("resume")
+                .about("resume simulation from a checkpoint")
+                .arg(
+                    Arg::new("checkpoint_file_path")
+                        .short('c')
+                        .long("checkpoint")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .action(ArgAction::Set)
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(u64))
+                        .required(false),
+                )
                 .arg(
                     Arg::new("verbose")
                         .long("verbose")
@@ -73,6 +142,57 @@ fn main() -> Result<(), Box<dyn error::Error>> {
             *utils::DEBUG_ENABLED.lock().unwrap() = run_matches.get_flag("verbose");
             let mut sim = SimBuilder::new(&PathBuf::from(input)).build()?;
 
+            // 指定したサイクルまで実行したら状態を保存して続ける
+            if let Some(&cycle) = run_matches.get_one::<u32>("checkpoint_at") {
+                let default_checkpoint = format!("{input}.checkpoint");
+                let checkpoint = run_matches
+                    .get_one::<String>("checkpoint_file_path")
+                    .unwrap_or(&default_checkpoint);
+
+                match sim.run_until(cycle) {
+                    Some(reason) => eprintln!(
+                        "simulation ended at cycle {} ({reason:?}) before checkpoint",
+                        sim.cur_cycles
+                    ),
+                    None => sim.save_checkpoint(&PathBuf::from(checkpoint))?,
+                }
+            }
+
+            sim.run();
+        }
+        Some(("validate", validate_matches)) => {
+            let input = validate_matches
+                .get_one::<String>("input_file_path")
+                .unwrap();
+            let path = PathBuf::from(input);
+            let input = InputFile::new(path.clone())?;
+
+            let base_dir = path.parent().unwrap_or(Path::new("."));
+            let problems = validate(&input, base_dir);
+            for problem in problems.iter() {
+                println!("{problem}");
+            }
+            let errors = problems
+                .iter()
+                .filter(|problem| problem.severity == Severity::Error)
+                .count();
+            if errors > 0 {
+                return Err(format!("found {errors} errors").into());
+            }
+            println!("ok ({} warnings)", problems.len());
+        }
+        Some(("resume", resume_matches)) => {
+            let checkpoint = resume_matches
+                .get_one::<String>("checkpoint_file_path")
+                .unwrap();
+            *utils::DEBUG_ENABLED.lock().unwrap() = resume_matches.get_flag("verbose");
+            let mut sim = Sim::load_checkpoint(&PathBuf::from(checkpoint))?;
+
+            // シードを変えると同じ状態から別の結果を得られる
+            if let Some(&seed) = resume_matches.get_one::<u64>("seed") {
+                seed_rng(seed);
+            }
+
             sim.run();
         }
         _ => unreachable!(),
@@ -105,7 +225,7 @@ mod tests {
                     sim.run();
 
                     for node in sim.nodes.nodes.iter() {
-                        assert_eq!(node.network.is_joined(), true, "{:?}", path.clone());
+                        assert!(node.network.is_joined(), "{:?}", path.clone());
                     }
                 }
             }
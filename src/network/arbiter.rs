@@ -0,0 +1,297 @@
+use crate::file::QosInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// 送信待ちのフリットがある仮想チャネルのうち，次のパケットを送るチャネルの選び方
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Arbitration {
+    // 優先度の高いクラスのチャネルから送る，同じ優先度やクラスのないチャネルは番号の小さい順
+    #[default]
+    StrictPriority,
+    // チャネルを順番に1パケットずつ送る
+    RoundRobin,
+    // チャネルを順番に重みの数のパケットずつ送る
+    WeightedRoundRobin,
+    // 先頭のフリットが最も長く待っているチャネルから送る
+    OldestFirst,
+}
+
+impl FromStr for Arbitration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict_priority" => Ok(Self::StrictPriority),
+            "round_robin" => Ok(Self::RoundRobin),
+            "weighted_round_robin" => Ok(Self::WeightedRoundRobin),
+            "oldest_first" => Ok(Self::OldestFirst),
+            _ => Err(format!("{} is not a valid arbitration", s)),
+        }
+    }
+}
+
+// トラフィッククラスと，そのパケットを送る仮想チャネル
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrafficClass {
+    pub name: String,
+    pub channel_id: u8,
+    pub priority: u32,
+    pub weight: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Arbiter {
+    arbitration: Arbitration,
+    classes: Vec<TrafficClass>,
+    // 優先度の高い順のチャネル，クラスのないチャネルは後ろにid順で並べる
+    priorities: Vec<u8>,
+    // チャネルが続けて送るパケット数，同じチャネルのクラスの重みは合計する
+    weights: HashMap<u8, u32>,
+    // 最後に選んだチャネルと，そのチャネルから続けて送ったパケット数
+    last: Option<(u8, u32)>,
+}
+
+impl Arbiter {
+    // クラスのないチャネル0..channel_numを番号の小さい順に送る
+    pub fn strict_priority(channel_num: u8) -> Self {
+        Self {
+            arbitration: Arbitration::StrictPriority,
+            classes: vec![],
+            priorities: (0..channel_num).collect(),
+            weights: HashMap::new(),
+            last: None,
+        }
+    }
+
+    pub fn new(info: &QosInfo, channel_num: u8) -> Result<Self, String> {
+        let arbitration = info
+            .arbitration
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default();
+
+        let mut classes: Vec<TrafficClass> = Vec::new();
+        for class in info.classes.iter() {
+            if class.channel >= channel_num {
+                return Err(format!(
+                    "class {}: channel {} is not below channel_num ({channel_num})",
+                    class.name, class.channel
+                ));
+            }
+            if classes.iter().any(|c| c.name == class.name) {
+                return Err(format!("duplicate class {}", class.name));
+            }
+            let weight = class.weight.unwrap_or(1);
+            if weight == 0 {
+                return Err(format!("class {}: weight must be at least 1", class.name));
+            }
+            let priority = class.priority.unwrap_or(0);
+            if let Some(other) = classes
+                .iter()
+                .find(|c| c.channel_id == class.channel && c.priority != priority)
+            {
+                return Err(format!(
+                    "class {}: priority differs from class {} on channel {}",
+                    class.name, other.name, class.channel
+                ));
+            }
+            classes.push(TrafficClass {
+                name: class.name.clone(),
+                channel_id: class.channel,
+                priority,
+                weight,
+            });
+        }
+
+        let mut weights = HashMap::new();
+        for class in classes.iter() {
+            *weights.entry(class.channel_id).or_insert(0) += class.weight;
+        }
+        let mut classified: Vec<&TrafficClass> = classes.iter().collect();
+        classified.sort_by_key(|class| (std::cmp::Reverse(class.priority), class.channel_id));
+        let mut priorities: Vec<u8> = Vec::new();
+        for class in classified {
+            if !priorities.contains(&class.channel_id) {
+                priorities.push(class.channel_id);
+            }
+        }
+        for channel_id in 0..channel_num {
+            if !priorities.contains(&channel_id) {
+                priorities.push(channel_id);
+            }
+        }
+
+        Ok(Self {
+            arbitration,
+            classes,
+            priorities,
+            weights,
+            last: None,
+        })
+    }
+
+    // クラスのパケットを送るチャネル
+    pub fn class_channel(&self, name: &str) -> Option<u8> {
+        self.classes
+            .iter()
+            .find(|class| class.name == name)
+            .map(|class| class.channel_id)
+    }
+
+    // 送信待ちのフリットがあるチャネルと，その先頭のフリットがバッファに入ったサイクルから
+    // 次のパケットを送るチャネルを選ぶ
+    pub fn select(&mut self, heads: &HashMap<u8, u32>) -> Option<u8> {
+        if heads.is_empty() {
+            return None;
+        }
+
+        let channel_id = match self.arbitration {
+            Arbitration::StrictPriority => self
+                .priorities
+                .iter()
+                .copied()
+                .find(|channel_id| heads.contains_key(channel_id))?,
+            Arbitration::RoundRobin => self.next_channel(heads),
+            Arbitration::WeightedRoundRobin => match self.last {
+                Some((channel_id, served))
+                    if heads.contains_key(&channel_id)
+                        && served < self.weights.get(&channel_id).copied().unwrap_or(1) =>
+                {
+                    channel_id
+                }
+                _ => self.next_channel(heads),
+            },
+            Arbitration::OldestFirst => {
+                let (&channel_id, _) = heads
+                    .iter()
+                    .min_by_key(|(&channel_id, &cycle)| (cycle, channel_id))?;
+                channel_id
+            }
+        };
+
+        let served = match self.last {
+            Some((last_channel_id, served)) if last_channel_id == channel_id => served + 1,
+            _ => 1,
+        };
+        self.last = Some((channel_id, served));
+        Some(channel_id)
+    }
+
+    // 最後に選んだチャネルの次から，送信待ちのフリットがあるチャネルを探す
+    fn next_channel(&self, heads: &HashMap<u8, u32>) -> u8 {
+        let mut channel_ids: Vec<u8> = heads.keys().copied().collect();
+        channel_ids.sort();
+        let Some((last_channel_id, _)) = self.last else {
+            return channel_ids[0];
+        };
+        channel_ids
+            .iter()
+            .copied()
+            .find(|&channel_id| channel_id > last_channel_id)
+            .unwrap_or(channel_ids[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::TrafficClassInfo;
+
+    fn arbiter(arbitration: &str) -> Arbiter {
+        let info = QosInfo {
+            arbitration: Some(arbitration.to_string()),
+            classes: vec![
+                TrafficClassInfo {
+                    name: "bulk".to_string(),
+                    channel: 0,
+                    priority: None,
+                    weight: None,
+                },
+                TrafficClassInfo {
+                    name: "control".to_string(),
+                    channel: 2,
+                    priority: Some(1),
+                    weight: Some(3),
+                },
+            ],
+        };
+        Arbiter::new(&info, 3).unwrap()
+    }
+
+    fn select_n(arbiter: &mut Arbiter, heads: &HashMap<u8, u32>, n: usize) -> Vec<u8> {
+        (0..n).map(|_| arbiter.select(heads).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_arbitration() {
+        let heads = HashMap::from([(0, 5), (1, 2), (2, 9)]);
+
+        let mut strict = arbiter("strict_priority");
+        assert_eq!(strict.class_channel("control"), Some(2));
+        assert_eq!(strict.class_channel("unknown"), None);
+        // 書いた順ではなくpriorityで優先する
+        assert_eq!(select_n(&mut strict, &heads, 2), vec![2, 2]);
+        // クラスのないチャネルは最後
+        assert_eq!(strict.select(&HashMap::from([(0, 5), (1, 2)])), Some(0));
+        // qosを指定しなければ番号の小さいチャネルから送る
+        let mut default = Arbiter::strict_priority(3);
+        assert_eq!(select_n(&mut default, &heads, 2), vec![0, 0]);
+        assert_eq!(default.select(&HashMap::from([(1, 5), (2, 2)])), Some(1));
+
+        let mut round_robin = arbiter("round_robin");
+        assert_eq!(select_n(&mut round_robin, &heads, 4), vec![0, 1, 2, 0]);
+
+        let mut weighted = arbiter("weighted_round_robin");
+        assert_eq!(
+            select_n(&mut weighted, &heads, 7),
+            vec![0, 1, 2, 2, 2, 0, 1]
+        );
+
+        let mut oldest = arbiter("oldest_first");
+        assert_eq!(oldest.select(&heads), Some(1));
+        assert_eq!(oldest.select(&HashMap::from([(0, 5), (2, 5)])), Some(0));
+        assert_eq!(oldest.select(&HashMap::new()), None);
+    }
+
+    #[test]
+    fn test_invalid_qos() {
+        let info = QosInfo {
+            arbitration: Some("fifo".to_string()),
+            classes: vec![],
+        };
+        assert!(Arbiter::new(&info, 2).is_err());
+
+        let info = QosInfo {
+            arbitration: None,
+            classes: vec![TrafficClassInfo {
+                name: "bulk".to_string(),
+                channel: 2,
+                priority: None,
+                weight: None,
+            }],
+        };
+        assert!(Arbiter::new(&info, 2).is_err());
+
+        // 同じチャネルのクラスは同じ優先度でなければならない
+        let info = QosInfo {
+            arbitration: None,
+            classes: vec![
+                TrafficClassInfo {
+                    name: "voice".to_string(),
+                    channel: 1,
+                    priority: Some(2),
+                    weight: None,
+                },
+                TrafficClassInfo {
+                    name: "video".to_string(),
+                    channel: 1,
+                    priority: None,
+                    weight: None,
+                },
+            ],
+        };
+        assert!(Arbiter::new(&info, 2).is_err());
+    }
+}
@@ -1,16 +1,27 @@
-use super::packets::{InjectionPacket, Packet};
+use super::flooding::Flooding;
+use super::node_behavior::NodeBehavior;
+use super::packets::{ControlKind, InjectionPacket, Packet, PacketKind};
+use super::up_down::UpDown;
+use super::{
+    injection_vids, pid_routes, priority_position, RoutingTable, BROADCAST_ID, SHORT_ADDRESS_LIMIT,
+};
+use crate::hardware::constants::DEFAULT_TTL;
+use crate::log::post_join_reject_log;
 use crate::network::core_functions::packets::DefaultPacket;
 use crate::network::flit::Flit;
 use crate::network::vid::get_pid;
 use crate::network::vid::get_vid;
 use crate::recsimu_dbg;
+use crate::rng::sim_rng;
 use crate::sim::node_type::NodeType;
 use rand::Rng;
-use std::collections::{HashMap, VecDeque};
-const BROADCAST_ID: u32 = u32::MAX;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 const COORDINATOR_ID: u32 = 0;
+// packを受け付けてからjackが届くまで待つ最大サイクル数
+const JOIN_TIMEOUT_CYCLES: u32 = 500;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[allow(dead_code)]
 pub struct DefaultFunction {
     pub(crate) id: u32,
@@ -19,9 +30,32 @@ pub struct DefaultFunction {
     network_joined: bool,
     node_type: NodeType,
     table: HashMap<u32, u32>,
+    // tableの宛先のidをノードのpidごとにまとめたもの
+    // 仮のidと割り当てられたアドレスのように，同じノードの別のidの経路を探すのに使う
+    table_ids: HashMap<String, HashSet<u32>>,
     packet_num_cnt: u32,
     parent_id: Option<u32>,
     children_id: Vec<u32>,
+    // up*/down*ルーティングを行う場合のみSome
+    up_down: Option<UpDown>,
+    // ユーザーのbroadcast/multicastの中継
+    flooding: Flooding<DefaultPacket>,
+    // packを受け付けてから経過したサイクル数
+    join_wait_cycles: u32,
+    // 経路がないため参加を待っている新規パケット
+    pending_packets: VecDeque<InjectionPacket>,
+    // 自分が生成したユーザーパケット(source_id, packet_id)の生成サイクル
+    inject_cycles: HashMap<(u32, u32), u32>,
+    // 送信キューにある自分が生成したユーザーパケットの優先度
+    priorities: HashMap<(u32, u32), u8>,
+    // 自分が生成したユーザーパケットのトラフィッククラス
+    classes: HashMap<(u32, u32), String>,
+    // コーディネータがjackでアドレスを割り当てるかどうか
+    assign_address: bool,
+    // 次に割り当てるアドレス
+    next_address: u32,
+    // 割り当て済みのアドレス (仮のid, アドレス)
+    assigned_addresses: HashMap<u32, u32>,
 }
 
 impl DefaultFunction {
@@ -33,18 +67,24 @@ impl DefaultFunction {
         if let NodeType::Coordinator = node_type {
             network_joined = true;
         } else {
-            // idはランダムな整数
-            let mut rng = rand::thread_rng();
-            id = rng.gen();
+            // 参加するまでのidは割り当て用のアドレスと重ならないランダムな整数
+            let mut rng = sim_rng();
+            id = rng.gen_range(SHORT_ADDRESS_LIMIT..BROADCAST_ID);
 
             send_packet_buffer.push_back(DefaultPacket {
-                message: "preq".to_string(),
+                kind: PacketKind::Control(ControlKind::Preq),
                 packet_id: u32::MAX, // todo
                 dest_id: BROADCAST_ID,
                 source_id: id,
                 prev_id: id,
                 channel_id: 0,
                 next_id: BROADCAST_ID,
+                dest_ids: vec![],
+                assigned_id: None,
+                links: vec![],
+                up_down_routes: None,
+                size_bytes: None,
+                segment: None,
             });
         }
 
@@ -55,29 +95,106 @@ impl DefaultFunction {
             network_joined,
             node_type: node_type.clone(),
             table: HashMap::new(),
+            table_ids: HashMap::new(),
             packet_num_cnt: 0,
             parent_id: None,
             children_id: Vec::new(),
+            up_down: None,
+            flooding: Flooding::new(),
+            join_wait_cycles: 0,
+            pending_packets: VecDeque::new(),
+            inject_cycles: HashMap::new(),
+            priorities: HashMap::new(),
+            classes: HashMap::new(),
+            assign_address: true,
+            next_address: COORDINATOR_ID + 1,
+            assigned_addresses: HashMap::new(),
         }
     }
 
+    pub fn new_up_down(node_type: &NodeType) -> Self {
+        let mut function = Self::new(node_type);
+        function.up_down = Some(UpDown::default());
+        function
+    }
+
+    // falseならjoin時に割り当てを行わず，ランダムなidをそのまま使う
+    pub fn set_address_assignment(&mut self, assign_address: bool) {
+        self.assign_address = assign_address;
+    }
+
     pub fn is_joined(&self) -> bool {
         self.network_joined
     }
 
-    pub fn push_new_packet(&mut self, packet: &InjectionPacket) {
-        let dest_vid = get_vid(&packet.dest_id).unwrap();
+    pub fn routing_table(&self) -> RoutingTable {
+        RoutingTable {
+            parent_ids: vec![self.parent_id.and_then(get_pid)],
+            roots: vec![self.node_type == NodeType::Coordinator],
+            children_ids: vec![self
+                .children_id
+                .iter()
+                .filter_map(|child_id| get_pid(*child_id))
+                .collect()],
+            routes: vec![pid_routes(&self.table)],
+        }
+    }
+
+    pub fn queued_packets(&self) -> usize {
+        self.send_packet_buffer.len() + self.pending_packets.len()
+    }
+
+    pub fn push_new_packet(&mut self, packet: &InjectionPacket) -> Result<(), String> {
+        let (dest_vid, dest_vids) = injection_vids(packet)?;
         let channel_id = self.channel_id(dest_vid);
-        let next_vid = self.next_node_id(dest_vid, channel_id);
+        // up*/down*の経路があればそれを優先する
+        let Some(next_vid) = self
+            .up_down_next_node_id(dest_vid, self.id)
+            .or_else(|| self.next_node_id(dest_vid, channel_id))
+        else {
+            // 再参加して経路ができるまで待つ
+            self.pending_packets.push_back(packet.clone());
+            return Ok(());
+        };
+
+        let mut default_packet = self.gen_packet(
+            self.id,
+            dest_vid,
+            next_vid,
+            PacketKind::Data(packet.message.clone()),
+        );
 
-        let default_packet = self.gen_packet(self.id, dest_vid, next_vid, packet.message.clone());
+        default_packet.size_bytes = packet.size_bytes;
+        default_packet.segment = packet.segment;
 
-        self.send_packet_buffer.push_back(default_packet);
+        if dest_vid == BROADCAST_ID {
+            default_packet.dest_ids = dest_vids;
+            self.flooding.record(&default_packet);
+        }
+
+        if let Some(inject_cycle) = packet.inject_cycle {
+            self.inject_cycles.insert(
+                (default_packet.source_id, default_packet.packet_id),
+                inject_cycle,
+            );
+        }
+        let key = (default_packet.source_id, default_packet.packet_id);
+        if let Some(class) = &packet.class {
+            self.classes.insert(key, class.clone());
+        }
+        self.priorities.insert(key, packet.priority);
+        let position = priority_position(&self.send_packet_buffer, packet.priority, |p| {
+            self.priorities.get(&(p.source_id, p.packet_id)).copied()
+        });
+        self.send_packet_buffer.insert(position, default_packet);
         self.packet_num_cnt += 1;
+        Ok(())
     }
 
     pub fn send_packet(&mut self) -> Option<Packet> {
         if let Some(packet) = self.send_packet_buffer.pop_front() {
+            self.priorities
+                .remove(&(packet.source_id, packet.packet_id));
             let data = bincode::serialize(&packet)
                 .map_err(|e| {
                     panic!("error occured while serializing a packet: {e:?}");
@@ -97,32 +214,76 @@ impl DefaultFunction {
                 next_id: next_pid,
                 prev_id: prev_pid,
                 channel_id: self.channel_id(packet.dest_id),
+                inject_cycle: self
+                    .inject_cycles
+                    .remove(&(packet.source_id, packet.packet_id)),
+                size_bytes: match packet.kind {
+                    // 隣接ノードや経路を運ぶ制御パケットはその大きさで送る
+                    PacketKind::Control(_)
+                        if !packet.links.is_empty() || packet.up_down_routes.is_some() =>
+                    {
+                        None
+                    }
+                    // 制御パケットはヘッダのみ
+                    PacketKind::Control(_) => Some(0),
+                    PacketKind::Data(_) => packet.size_bytes,
+                },
+                class: self.classes.remove(&(packet.source_id, packet.packet_id)),
+                ttl: DEFAULT_TTL,
             });
         }
         None
     }
 
     pub fn receive_packet(&mut self, packet: &Packet) {
+        if let Some(up_down) = &mut self.up_down {
+            up_down.add_neighbor(&packet.prev_id);
+        }
         let packet = DefaultPacket::from_general(packet);
         self.received_packet_buffer.push_back(packet);
     }
 
     pub fn update(&mut self) {
         // ランダムな確率でpackを送信
-        let mut rng = rand::thread_rng();
+        let mut rng = sim_rng();
         let p: f64 = rng.gen();
-        if self.parent_id.is_none() && self.node_type == NodeType::Router && p < 0.1 {
+        if self.parent_id.is_none() && self.node_type != NodeType::Coordinator && p < 0.1 {
             self.send_packet_buffer.push_back(DefaultPacket {
-                message: "preq".to_string(),
+                kind: PacketKind::Control(ControlKind::Preq),
                 packet_id: u32::MAX, // todo
                 dest_id: BROADCAST_ID,
                 source_id: self.id,
                 prev_id: self.id,
                 channel_id: 0,
                 next_id: BROADCAST_ID,
+                dest_ids: vec![],
+                assigned_id: None,
+                links: vec![],
+                up_down_routes: None,
+                size_bytes: None,
+                segment: None,
             });
         }
 
+        // jackが届かなければ参加をやり直す
+        if self.parent_id.is_some() && !self.is_joined() {
+            self.join_wait_cycles += 1;
+            if self.join_wait_cycles > JOIN_TIMEOUT_CYCLES {
+                self.leave_parent();
+            }
+        }
+
+        // 参加が完了したら待たせていたパケットを送信する
+        if self.is_joined() {
+            for packet in std::mem::take(&mut self.pending_packets) {
+                // 宛先は待たせる前に確かめてある
+                let _ = self.push_new_packet(&packet);
+            }
+        }
+
+        // 待ち時間が終わったbroadcastを送信する
+        self.send_packet_buffer.extend(self.flooding.take_ready());
+
         // received_packet_bufferをすべて処理
         while let Some(packet) = self.received_packet_buffer.pop_front() {
             let reply = self.process_received_packet(&packet);
@@ -130,16 +291,40 @@ impl DefaultFunction {
                 self.send_packet_buffer.push_back(packet);
             }
         }
+
+        self.exchange_up_down_routes();
     }
 
-    pub fn forward_flit(&mut self, flit: &Flit) -> Flit {
+    pub fn forward_flit(&mut self, flit: &Flit) -> Option<Flit> {
         let dest_vid = get_vid(&flit.get_dest_id().unwrap()).unwrap();
-        let next_vid = self.next_node_id(dest_vid, flit.get_channel_id().unwrap());
-
         let source_vid = get_vid(&flit.get_source_id().unwrap()).unwrap();
         let prev_vid = get_vid(&flit.get_prev_id().unwrap()).unwrap();
 
-        self.update_table(source_vid, prev_vid);
+        if let Some(up_down) = &mut self.up_down {
+            up_down.add_neighbor(&flit.get_prev_id().unwrap());
+        }
+
+        // 親から届いたパケットは下りなので，送信元への経路は学習しない
+        if !self.is_parent(prev_vid) {
+            self.update_table(source_vid, prev_vid);
+            self.add_child(source_vid, prev_vid);
+        }
+
+        let Some(next_vid) = self
+            .up_down_next_node_id(dest_vid, prev_vid)
+            .or_else(|| self.next_node_id(dest_vid, flit.get_channel_id().unwrap()))
+        else {
+            // 再起動などで親を失っていれば，自分を親としている送信元に通知する
+            if self.parent_id.is_none() && self.node_type != NodeType::Coordinator {
+                self.notify_lost(prev_vid);
+            }
+            return None;
+        };
+
+        // 親から届いたパケットを親に送り返すと，親が誤った経路を学習するので破棄する
+        if self.is_parent(prev_vid) && self.is_parent(next_vid) {
+            return None;
+        }
 
         let next_pid = get_pid(next_vid).unwrap();
 
@@ -155,31 +340,101 @@ impl DefaultFunction {
             panic!("error occured while setting next_id: {e:?}");
         });
 
-        new_flit
+        Some(new_flit)
+    }
+
+    // next_idへのリンクが切れたら，そこを経由する経路を消し，親なら参加をやり直す
+    pub fn handle_link_loss(&mut self, next_id: u32) {
+        if let (Some(up_down), Some(next_pid)) = (&mut self.up_down, get_pid(next_id)) {
+            up_down.remove_neighbor(&next_pid);
+        }
+        self.table.retain(|_, node_id| *node_id != next_id);
+        self.forget_table_ids();
+        self.children_id.retain(|&child_id| child_id != next_id);
+
+        if self.is_parent(next_id) {
+            self.leave_parent();
+        }
     }
 }
 
 // private functions
 #[allow(unused_variables)]
 impl DefaultFunction {
-    fn next_node_id(&self, dest_id: u32, channel_id: u8) -> u32 {
+    // 参加したときの木での次のノード
+    // 制御パケットは，up*/down*の経路が配られている途中でも木に沿って送る
+    fn next_node_id(&self, dest_id: u32, channel_id: u8) -> Option<u32> {
         // tableにdest_idがあればそれに対応するnode_idを返す
         // なければparent_idを返す
-        if let Some(node_id) = self.table.get(&dest_id) {
-            return *node_id;
+        if let Some(node_id) = self.table_next_id(dest_id) {
+            return Some(node_id);
         }
         if dest_id == BROADCAST_ID {
-            return BROADCAST_ID;
+            return Some(BROADCAST_ID);
         }
-        if self.parent_id.is_none() {
-            if self.node_type == NodeType::Router {
-                panic!("Router: parent_id is not set");
-            } else {
-                panic!("Coordinator: parent_id is not set");
-            }
+        // 親を失って再参加中の場合はNone
+        self.parent_id
+    }
+
+    // prev_idから届いたパケットのup*/down*経路での次ホップ
+    fn up_down_next_node_id(&self, dest_id: u32, prev_id: u32) -> Option<u32> {
+        let routes = &self.up_down.as_ref()?.routes;
+        let next_pid = routes.next_hop(&get_pid(dest_id)?, &get_pid(prev_id)?)?;
+        get_vid(&next_pid)
+    }
+
+    // up*/down*ルーティングで，参加した後に失った隣接ノードをコーディネータに報告し，
+    // コーディネータなら報告から計算し直した経路を各ノードに配る
+    // エンドデバイスは中継しないので報告しない
+    fn exchange_up_down_routes(&mut self) {
+        if self.up_down.is_none()
+            || !self.is_joined()
+            || self.node_type.base_type() == NodeType::EndDevice
+        {
+            return;
+        }
+
+        if self.node_type != NodeType::Coordinator {
+            let Some(next_id) = self.next_node_id(COORDINATOR_ID, self.channel_id(COORDINATOR_ID))
+            else {
+                return;
+            };
+            let Some(links) = self.up_down.as_mut().unwrap().take_lost_links() else {
+                return;
+            };
+            let mut packet = self.gen_packet(
+                self.id,
+                COORDINATOR_ID,
+                next_id,
+                PacketKind::Control(ControlKind::Links),
+            );
+            packet.links = links;
+            self.send_packet_buffer.push_back(packet);
+            return;
         }
 
-        self.parent_id.unwrap()
+        let root = get_pid(self.id).unwrap();
+        let up_down = self.up_down.as_mut().unwrap();
+        if let Some(links) = up_down.take_changed_links() {
+            up_down.update_links(&root, self.id, &links);
+        }
+        for (id, routes) in up_down.take_changed_routes(&root, |id| get_pid(id).is_some()) {
+            if id == self.id {
+                self.up_down.as_mut().unwrap().routes = routes;
+                continue;
+            }
+            let Some(next_id) = self.next_node_id(id, self.channel_id(id)) else {
+                continue;
+            };
+            let mut packet = self.gen_packet(
+                self.id,
+                id,
+                next_id,
+                PacketKind::Control(ControlKind::Routes),
+            );
+            packet.up_down_routes = Some(routes);
+            self.send_packet_buffer.push_back(packet);
+        }
     }
 
     // フリットごとにチャネルを選択
@@ -194,10 +449,24 @@ impl DefaultFunction {
             return vec![];
         }
 
-        match self.node_type {
+        // アドレスが割り当てられる前の仮のid宛てなら自分宛てとして扱う
+        if packet.dest_id != self.id
+            && packet.dest_id != BROADCAST_ID
+            && get_pid(packet.dest_id).is_some()
+            && get_pid(packet.dest_id) == get_pid(self.id)
+        {
+            let packet = DefaultPacket {
+                dest_id: self.id,
+                ..packet.clone()
+            };
+            return self.process_received_packet(&packet);
+        }
+
+        match self.node_type.base_type() {
             NodeType::Coordinator => self.process_received_packet_coordinator(packet),
             NodeType::Router => self.process_received_packet_router(packet),
-            _ => panic!("unknown node type"),
+            NodeType::EndDevice => self.process_received_packet_end_device(packet),
+            NodeType::UserType(_) => unreachable!("base type cannot be user type"),
         }
     }
     #[allow(unreachable_code)]
@@ -205,94 +474,167 @@ impl DefaultFunction {
         &mut self,
         packet: &DefaultPacket,
     ) -> Vec<DefaultPacket> {
-        match (packet.dest_id, packet.message.as_str()) {
+        match (packet.dest_id, &packet.kind) {
             // BROADCAST "preq"
-            (BROADCAST_ID, "preq") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Preq)) => {
                 // packを返す
                 let channel_id = self.channel_id(packet.source_id);
                 let packet = self.gen_packet(
                     self.id,
                     packet.source_id,
                     packet.source_id,
-                    "pack".to_string(),
+                    PacketKind::Control(ControlKind::Pack),
                 );
                 return vec![packet];
             }
 
             // BROADCAST "pack"
-            (BROADCAST_ID, "pack") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Pack)) => {
                 panic!("pack destination cannot be broadcast");
             }
 
             // BROADCAST "jreq"
-            (BROADCAST_ID, "jreq") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Jreq)) => {
                 panic!("jreq destination cannot be broadcast");
             }
 
             // BROADCAST "jack"
-            (BROADCAST_ID, "jack") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Jack)) => {
                 panic!("jack destination cannot be broadcast");
             }
 
+            // BROADCAST "lost"
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Lost)) => {
+                panic!("lost destination cannot be broadcast");
+            }
+
+            // BROADCAST "links", "routes"
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Links | ControlKind::Routes)) => {
+                panic!("{} destination cannot be broadcast", packet.kind.message());
+            }
+
             // BROADCAST user message
-            (BROADCAST_ID, _) => {
-                panic!("Broadcast message by user is not supported");
+            (BROADCAST_ID, PacketKind::Data(_)) => {
+                return self.flood(packet);
             }
 
             // address to me, "preq"
-            (id, "preq") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Preq)) if id == self.id => {
                 panic!("preq destination must be broadcast");
             }
 
             // address to me, "pack"
-            (id, "pack") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Pack)) if id == self.id => {
                 panic!("pack destination cannot be coordinator");
             }
 
             // address to me, "jreq"
-            (id, "jreq") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Jreq)) if id == self.id => {
                 self.update_table(packet.source_id, packet.prev_id);
+                // アドレスが足りなければ参加を拒否する
+                // 要求したノードはjackが届かないので参加をやり直す
+                let assigned_id = match self.assign(packet.source_id) {
+                    Ok(assigned_id) => assigned_id,
+                    Err(_) => {
+                        if let Some(source_pid) = get_pid(packet.source_id) {
+                            post_join_reject_log(&source_pid);
+                        }
+                        return vec![];
+                    }
+                };
+                self.add_child(packet.source_id, packet.prev_id);
+                // jreqで報告された隣接ノードを経路の計算に使う
+                // 隣接ノードのないjreqはエンドデバイスからなので経路を配らない
+                if let (Some(up_down), Some(source_pid)) =
+                    (&mut self.up_down, get_pid(packet.source_id))
+                {
+                    if !packet.links.is_empty() {
+                        let id = assigned_id.unwrap_or(packet.source_id);
+                        up_down.update_links(&source_pid, id, &packet.links);
+                    }
+                }
                 // jackを返す
                 let channel_id = self.channel_id(packet.source_id);
-                let next_id = self.next_node_id(packet.source_id, channel_id);
+                let next_id = self.next_node_id(packet.source_id, channel_id).unwrap();
 
-                let packet =
-                    self.gen_packet(self.id, packet.source_id, next_id, "jack".to_string());
+                let mut packet = self.gen_packet(
+                    self.id,
+                    packet.source_id,
+                    next_id,
+                    PacketKind::Control(ControlKind::Jack),
+                );
+                packet.assigned_id = assigned_id;
                 return vec![packet];
             }
 
             // address to me, "jack"
-            (id, "jack") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Jack)) if id == self.id => {
                 panic!("jack destination cannot be coordinator");
             }
 
+            // address to me, "lost"
+            (id, PacketKind::Control(ControlKind::Lost)) if id == self.id => {
+                panic!("coordinator has no parent");
+            }
+
+            // address to me, "links"
+            (id, PacketKind::Control(ControlKind::Links)) if id == self.id => {
+                // 報告された隣接ノードを経路の計算に使う
+                if let (Some(up_down), Some(source_pid)) =
+                    (&mut self.up_down, get_pid(packet.source_id))
+                {
+                    up_down.update_links(&source_pid, packet.source_id, &packet.links);
+                }
+                return vec![];
+            }
+
+            // address to me, "routes"
+            (id, PacketKind::Control(ControlKind::Routes)) if id == self.id => {
+                panic!("routes destination cannot be coordinator");
+            }
+
             // address to me, user packet
-            (id, message) if id == self.id => {
+            (id, PacketKind::Data(_)) if id == self.id => {
                 // message arrived
                 // なにもしない
                 return vec![];
             }
 
             // address to others, "preq"
-            (_, "preq") => {
+            (_, PacketKind::Control(ControlKind::Preq)) => {
                 panic!("preq destination must be broadcast");
             }
 
             // address to others, "pack"
-            (_, "pack") => {
+            (_, PacketKind::Control(ControlKind::Pack)) => {
                 panic!("pack cannot be reached to coordinator");
             }
 
             // address to others, "jreq"
-            (_, "jreq") => {
+            (_, PacketKind::Control(ControlKind::Jreq)) => {
                 panic!("jreq destination must be coordinator");
             }
 
             // address to others, "jack"
-            (_, "jack") => {
+            (_, PacketKind::Control(ControlKind::Jack)) => {
                 panic!("jack cannot be reached to coordinator");
             }
 
+            // address to others, "lost"
+            (_, PacketKind::Control(ControlKind::Lost)) => {
+                panic!("lost destination must be a neighbor");
+            }
+
+            // address to others, "links"
+            (_, PacketKind::Control(ControlKind::Links)) => {
+                panic!("links destination must be coordinator");
+            }
+
+            // address to others, "routes"
+            (_, PacketKind::Control(ControlKind::Routes)) => {
+                panic!("routes cannot be reached to coordinator");
+            }
+
             // address to others, user packet
             _ => {
                 // ルーティングを行う
@@ -305,10 +647,14 @@ impl DefaultFunction {
 
     #[allow(unreachable_code)]
     fn process_received_packet_router(&mut self, packet: &DefaultPacket) -> Vec<DefaultPacket> {
-        match (packet.dest_id, packet.message.as_str()) {
+        match (packet.dest_id, &packet.kind) {
             // BROADCAST "preq"
-            (BROADCAST_ID, "preq") => {
-                if !self.is_joined() {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Preq)) => {
+                // preqを送る子は親を失っているので，子とそこを経由する経路を消す
+                self.forget_child(packet.source_id);
+
+                // 親を失った親に応答すると親子が循環するので応答しない
+                if !self.is_joined() || self.is_parent(packet.source_id) {
                     return vec![];
                 }
 
@@ -317,103 +663,176 @@ impl DefaultFunction {
                     self.id,
                     packet.source_id,
                     packet.source_id,
-                    "pack".to_string(),
+                    PacketKind::Control(ControlKind::Pack),
                 );
                 return vec![packet];
             }
 
             // BROADCAST "pack"
-            (BROADCAST_ID, "pack") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Pack)) => {
                 panic!("pack destination cannot be broadcast");
             }
 
             // BROADCAST "jreq"
-            (BROADCAST_ID, "jreq") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Jreq)) => {
                 panic!("jreq destination cannot be broadcast");
             }
 
             // BROADCAST "jack"
-            (BROADCAST_ID, "jack") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Jack)) => {
                 panic!("jack destination cannot be broadcast");
             }
 
+            // BROADCAST "lost"
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Lost)) => {
+                panic!("lost destination cannot be broadcast");
+            }
+
+            // BROADCAST "links", "routes"
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Links | ControlKind::Routes)) => {
+                panic!("{} destination cannot be broadcast", packet.kind.message());
+            }
+
             // BROADCAST user message
-            (BROADCAST_ID, _) => {
-                panic!("Broadcast message by user is not supported");
+            (BROADCAST_ID, PacketKind::Data(_)) => {
+                return self.flood(packet);
             }
 
             // BROADCAST "preq"
-            (id, "preq") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Preq)) if id == self.id => {
                 panic!("preq destination must be broadcast");
             }
 
             // address to me, "pack"
-            (id, "pack") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Pack)) if id == self.id => {
                 // もし親IDが設定されていれば
                 if self.parent_id.is_some() {
                     return vec![];
                 }
 
+                // up*/down*ではBFS木の親になりうるノードからのpackのみ受け付ける
+                if let Some(UpDown { routes, .. }) = &self.up_down {
+                    let source_pid = get_pid(packet.source_id).unwrap_or_default();
+                    if !routes.parent_candidates.is_empty()
+                        && !routes.parent_candidates.contains(&source_pid)
+                    {
+                        return vec![];
+                    }
+                }
+
                 // 親IDを設定
                 self.parent_id = Some(packet.source_id);
 
                 // jreqを送信
-                let packet = self.gen_packet(
+                // up*/down*では受信したことのある隣接ノードを載せる
+                let mut packet = self.gen_packet(
                     self.id,
                     COORDINATOR_ID,
                     packet.source_id,
-                    "jreq".to_string(),
+                    PacketKind::Control(ControlKind::Jreq),
                 );
+                // エンドデバイスは中継しないので報告しない
+                if let Some(up_down) = &self.up_down {
+                    if self.node_type.base_type() != NodeType::EndDevice {
+                        packet.links = up_down.links();
+                    }
+                }
 
                 return vec![packet];
             }
 
             // address to me, "jreq"
-            (id, "jreq") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Jreq)) if id == self.id => {
                 panic!("jreq destination must be coordinator");
             }
 
             // address to me, "jack"
-            (id, "jack") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Jack)) if id == self.id => {
+                // 参加をやり直した後に届いた古いjackは無視する
+                if self.parent_id.is_none() {
+                    return vec![];
+                }
+
+                // 割り当てられたアドレスに切り替える
+                if let Some(assigned_id) = packet.assigned_id {
+                    self.id = assigned_id;
+                }
+
                 // ネットワーク参加完了
                 self.network_joined = true;
+                self.join_wait_cycles = 0;
                 recsimu_dbg!("network joined");
                 return vec![];
             }
 
+            // address to me, "lost"
+            (id, PacketKind::Control(ControlKind::Lost)) if id == self.id => {
+                // 親が経路を失ったので参加をやり直す
+                if self.is_parent(packet.source_id) {
+                    self.leave_parent();
+                }
+                return vec![];
+            }
+
+            // address to me, "links"
+            (id, PacketKind::Control(ControlKind::Links)) if id == self.id => {
+                panic!("links destination must be coordinator");
+            }
+
+            // address to me, "routes"
+            (id, PacketKind::Control(ControlKind::Routes)) if id == self.id => {
+                // コーディネータが計算した経路に切り替える
+                if let (Some(up_down), Some(routes)) = (&mut self.up_down, &packet.up_down_routes) {
+                    up_down.routes = routes.clone();
+                }
+                return vec![];
+            }
+
             // address to me, user packet
-            (id, message) if id == self.id => {
+            (id, PacketKind::Data(_)) if id == self.id => {
                 // message arrived
-                // なにもしない
+                self.notify_user_type_hook(packet);
                 return vec![];
             }
 
             // address to others, "preq"
-            (_, "preq") => {
+            (_, PacketKind::Control(ControlKind::Preq)) => {
                 panic!("preq destination must be broadcast");
             }
 
             // address to others, "pack"
-            (_, "pack") => {
+            (_, PacketKind::Control(ControlKind::Pack)) => {
                 panic!("pack cannot be reached to other router");
             }
 
             // address to others, "jreq"
-            (_, "jreq") => {
+            (_, PacketKind::Control(ControlKind::Jreq)) => {
                 // テーブルに登録する
                 self.update_table(packet.source_id, packet.prev_id);
+                self.add_child(packet.source_id, packet.prev_id);
                 // ルーティングを行う
                 return self.routing(packet);
             }
 
-            // address to others, "jack"
-            (_, "jack") => {
+            // address to others, "jack", "links", "routes"
+            (
+                _,
+                PacketKind::Control(ControlKind::Jack | ControlKind::Links | ControlKind::Routes),
+            ) => {
                 // ルーティングを行う
                 return self.routing(packet);
             }
 
+            // address to others, "lost"
+            (_, PacketKind::Control(ControlKind::Lost)) => {
+                panic!("lost destination must be a neighbor");
+            }
+
             // address to others, user packet
             _ => {
+                if !self.forwards(packet.source_id, packet.dest_id) {
+                    return vec![];
+                }
                 // ルーティングを行う
                 return self.routing(packet);
             }
@@ -427,82 +846,275 @@ impl DefaultFunction {
         src_id: u32,
         dest_id: u32,
         next_id: u32,
-        message: String,
+        kind: PacketKind,
     ) -> DefaultPacket {
         let packet_id = self.packet_num_cnt;
         self.packet_num_cnt += 1;
 
         DefaultPacket {
-            message,
+            kind,
             packet_id,
             dest_id,
             source_id: src_id,
             prev_id: self.id,
             channel_id: self.channel_id(dest_id),
             next_id,
+            dest_ids: vec![],
+            assigned_id: None,
+            links: vec![],
+            up_down_routes: None,
+            size_bytes: None,
+            segment: None,
         }
     }
 
-    fn update_table(&mut self, dest_id: u32, next_id: u32) {
-        // todo すでにあったら場合
+    // jreqの送信元に割り当てるアドレス
+    // 割り当て済みのアドレスから届いた場合はそのまま使い続ける
+    // 割り当てられるアドレスが残っていなければエラー
+    fn assign(&mut self, source_id: u32) -> Result<Option<u32>, String> {
+        if !self.assign_address {
+            return Ok(None);
+        }
+        if source_id < SHORT_ADDRESS_LIMIT {
+            return Ok(Some(source_id));
+        }
+        if let Some(address) = self.assigned_addresses.get(&source_id) {
+            return Ok(Some(*address));
+        }
+
+        // 再起動したコーディネータが使用中のアドレスを割り当てないようにする
+        while self.next_address < SHORT_ADDRESS_LIMIT && get_pid(self.next_address).is_some() {
+            self.next_address += 1;
+        }
+        if self.next_address >= SHORT_ADDRESS_LIMIT {
+            return Err("no more short addresses to assign".to_string());
+        }
+        let address = self.next_address;
+        self.next_address += 1;
+        self.assigned_addresses.insert(source_id, address);
+        // jackが届くまでは仮のidと同じ経路を使う
+        if let Some(next_id) = self.table.get(&source_id).copied() {
+            self.update_table(address, next_id);
+        }
+        Ok(Some(address))
+    }
+
+    // tableからdest_idへの次のノードを取得する
+    // アドレスが割り当てられたノードは，jackを中継したときの仮のidの経路を使う
+    fn table_next_id(&self, dest_id: u32) -> Option<u32> {
+        if let Some(node_id) = self.table.get(&dest_id) {
+            return Some(*node_id);
+        }
+        let dest_pid = get_pid(dest_id)?;
+        self.table_ids
+            .get(&dest_pid)?
+            .iter()
+            .find_map(|id| self.table.get(id).copied())
+    }
 
+    fn update_table(&mut self, dest_id: u32, next_id: u32) {
+        // すでにあれば新しい経路で上書きする
         self.table.insert(dest_id, next_id);
+        if let Some(dest_pid) = get_pid(dest_id) {
+            self.table_ids.entry(dest_pid).or_default().insert(dest_id);
+        }
+    }
+
+    // tableから消えた宛先のidを忘れる
+    fn forget_table_ids(&mut self) {
+        let table = &self.table;
+        self.table_ids.retain(|_, ids| {
+            ids.retain(|id| table.contains_key(id));
+            !ids.is_empty()
+        });
+    }
+
+    // 隣接ノードが自分で送信したパケットを中継したら，送信元を子として記録する
+    // 親以外の隣接ノードが自分を経由させるのは，自分を親としている場合だけ
+    // アドレスが割り当てられた子は仮のidを新しいアドレスで置き換える
+    fn add_child(&mut self, source_id: u32, prev_id: u32) {
+        if source_id != prev_id || self.is_parent(source_id) {
+            return;
+        }
+        if let Some(source_pid) = get_pid(source_id) {
+            self.children_id.retain(|&child_id| {
+                child_id == source_id || get_pid(child_id).as_ref() != Some(&source_pid)
+            });
+        }
+        if !self.children_id.contains(&source_id) {
+            self.children_id.push(source_id);
+        }
+    }
+
+    // 子でなくなったノードを子から外し，そこを経由する経路を消す
+    fn forget_child(&mut self, id: u32) {
+        let Some(pid) = get_pid(id) else {
+            return;
+        };
+        let is_node = |node_id: &u32| get_pid(*node_id).as_ref() == Some(&pid);
+        if !self.children_id.iter().any(is_node) {
+            return;
+        }
+        self.children_id.retain(|child_id| !is_node(child_id));
+        self.table.retain(|_, node_id| !is_node(node_id));
+        self.forget_table_ids();
+    }
+
+    // idが親のものかどうか
+    // 親が再起動してidが変わっていても同じノードなら親とみなす
+    fn is_parent(&self, id: u32) -> bool {
+        let Some(parent_id) = self.parent_id else {
+            return false;
+        };
+        parent_id == id || get_pid(parent_id).is_some_and(|pid| get_pid(id) == Some(pid))
+    }
+
+    // 親を失ったことをnode_idに通知する
+    fn notify_lost(&mut self, node_id: u32) {
+        let queued = self.send_packet_buffer.iter().any(|packet| {
+            packet.kind == PacketKind::Control(ControlKind::Lost) && packet.dest_id == node_id
+        });
+        if !queued {
+            let packet = self.gen_packet(
+                self.id,
+                node_id,
+                node_id,
+                PacketKind::Control(ControlKind::Lost),
+            );
+            self.send_packet_buffer.push_back(packet);
+        }
+    }
+
+    // 親との接続を解除して参加をやり直す
+    // 子には親を失ったことを通知し，子も参加をやり直す
+    fn leave_parent(&mut self) {
+        self.parent_id = None;
+        self.network_joined = false;
+        self.join_wait_cycles = 0;
+
+        for child_id in std::mem::take(&mut self.children_id) {
+            self.notify_lost(child_id);
+        }
+
+        self.send_packet_buffer.push_back(DefaultPacket {
+            kind: PacketKind::Control(ControlKind::Preq),
+            packet_id: u32::MAX, // todo
+            dest_id: BROADCAST_ID,
+            source_id: self.id,
+            prev_id: self.id,
+            channel_id: 0,
+            next_id: BROADCAST_ID,
+            dest_ids: vec![],
+            assigned_id: None,
+            links: vec![],
+            up_down_routes: None,
+            size_bytes: None,
+            segment: None,
+        });
     }
 
     fn routing(&mut self, packet: &DefaultPacket) -> Vec<DefaultPacket> {
         assert!(packet.dest_id != self.id);
         assert!(packet.dest_id != BROADCAST_ID);
 
-        // todo ここは，table用のget関数を用意する
+        // ユーザーパケットはup*/down*の経路があればそれに従う
+        if let PacketKind::Data(_) = packet.kind {
+            if let Some(next_id) = self.up_down_next_node_id(packet.dest_id, packet.prev_id) {
+                return vec![self.relay_packet(packet, next_id)];
+            }
+        }
+
         // もし宛先がテーブルにあれば
-        if self.table.contains_key(&packet.dest_id) {
-            // テーブルから次のノードを取得
-            let next_id = self.table.get(&packet.dest_id).copied().unwrap();
-            // パケットを生成
-            let routing_packet = self.gen_packet(
-                packet.source_id,
-                packet.dest_id,
-                next_id,
-                packet.message.clone(),
-            );
-            return vec![routing_packet];
+        if let Some(next_id) = self.table_next_id(packet.dest_id) {
+            return vec![self.relay_packet(packet, next_id)];
         } else {
             // 親ノードあて
             if let Some(parent_id) = self.parent_id {
-                // パケットを生成
-                let routing_packet = self.gen_packet(
-                    packet.source_id,
-                    packet.dest_id,
-                    parent_id,
-                    packet.message.clone(),
-                );
-                return vec![routing_packet];
+                return vec![self.relay_packet(packet, parent_id)];
             }
         }
 
         vec![]
     }
+
+    // 中継するパケットを生成し，パケットが運ぶ情報を引き継ぐ
+    fn relay_packet(&mut self, packet: &DefaultPacket, next_id: u32) -> DefaultPacket {
+        let mut routing_packet = self.gen_packet(
+            packet.source_id,
+            packet.dest_id,
+            next_id,
+            packet.kind.clone(),
+        );
+        routing_packet.assigned_id = packet.assigned_id;
+        routing_packet.links = packet.links.clone();
+        routing_packet.up_down_routes = packet.up_down_routes.clone();
+        routing_packet.size_bytes = packet.size_bytes;
+        routing_packet.segment = packet.segment;
+        routing_packet
+    }
+}
+
+impl NodeBehavior for DefaultFunction {
+    type Packet = DefaultPacket;
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn node_type(&self) -> &NodeType {
+        &self.node_type
+    }
+
+    fn flooding(&mut self) -> &mut Flooding<DefaultPacket> {
+        &mut self.flooding
+    }
+
+    fn relays_broadcast(&self) -> bool {
+        // ネットワークに参加していなければ中継しない
+        self.is_joined()
+    }
+
+    fn push_new_packet(&mut self, packet: &InjectionPacket) -> Result<(), String> {
+        DefaultFunction::push_new_packet(self, packet)
+    }
+
+    fn process_received_packet_router(&mut self, packet: &DefaultPacket) -> Vec<DefaultPacket> {
+        DefaultFunction::process_received_packet_router(self, packet)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::network::vid::add_to_vid_table;
+
+    // source_idからdest_idへ1ホップで届くパケット
+    fn packet(kind: PacketKind, source_id: u32, dest_id: u32) -> DefaultPacket {
+        DefaultPacket {
+            kind,
+            packet_id: 0,
+            dest_id,
+            source_id,
+            prev_id: source_id,
+            channel_id: 0,
+            next_id: dest_id,
+            dest_ids: vec![],
+            assigned_id: None,
+            links: vec![],
+            up_down_routes: None,
+            size_bytes: None,
+            segment: None,
+        }
+    }
+
     #[test]
     fn test_process_received_packet_coordinator() {
         let mut protocol = DefaultFunction::new(&NodeType::Coordinator);
-        let rec_packet = DefaultPacket {
-            message: "preq".to_string(),
-            packet_id: 0,
-            dest_id: BROADCAST_ID,
-            source_id: 1,
-            prev_id: 0,
-            channel_id: 0,
-            next_id: 0,
-        };
+        let rec_packet = packet(PacketKind::Control(ControlKind::Preq), 1, BROADCAST_ID);
         let packets = protocol.process_received_packet_coordinator(&rec_packet);
 
         assert_eq!(packets.len(), 1);
-        assert_eq!(packets[0].message, "pack");
+        assert_eq!(packets[0].kind, PacketKind::Control(ControlKind::Pack));
         assert_eq!(packets[0].dest_id, 1);
     }
 
@@ -511,19 +1123,424 @@ mod tests {
     fn test_process_received_packet_router() {
         let mut protocol = DefaultFunction::new(&NodeType::Router);
         protocol.network_joined = true;
-        let rec_packet = DefaultPacket {
-            message: "preq".to_string(),
-            packet_id: 0,
-            dest_id: BROADCAST_ID,
-            source_id: 1,
-            prev_id: 0,
-            channel_id: 0,
-            next_id: 0,
-        };
+        let rec_packet = packet(PacketKind::Control(ControlKind::Preq), 1, BROADCAST_ID);
         let packets = protocol.process_received_packet_router(&rec_packet);
 
         assert_eq!(packets.len(), 1);
-        assert_eq!(packets[0].message, "pack");
+        assert_eq!(packets[0].kind, PacketKind::Control(ControlKind::Pack));
         assert_eq!(packets[0].dest_id, 1);
     }
+
+    #[test]
+    fn test_up_down_routes_exchange() {
+        use crate::network::vid::add_to_vid_table;
+
+        let mut coordinator = DefaultFunction::new_up_down(&NodeType::Coordinator);
+        let mut router = DefaultFunction::new_up_down(&NodeType::Router);
+        let temp_id = router.id;
+        add_to_vid_table(COORDINATOR_ID, "coordinator");
+        add_to_vid_table(temp_id, "router");
+        add_to_vid_table(2, "other");
+
+        // 参加するルータは受信したことのある隣接ノードをjreqに載せる
+        router.up_down.as_mut().unwrap().add_neighbor("coordinator");
+        router.up_down.as_mut().unwrap().add_neighbor("other");
+        let pack = packet(
+            PacketKind::Control(ControlKind::Pack),
+            COORDINATOR_ID,
+            temp_id,
+        );
+        let jreq = router.process_received_packet(&pack);
+        assert_eq!(jreq[0].kind, PacketKind::Control(ControlKind::Jreq));
+        assert_eq!(jreq[0].links, vec!["coordinator", "other"]);
+
+        coordinator.up_down.as_mut().unwrap().add_neighbor("router");
+        let jack = coordinator.process_received_packet(&jreq[0]);
+        let address = jack[0].assigned_id.unwrap();
+        add_to_vid_table(address, "router");
+        router.process_received_packet(&jack[0]);
+        assert_eq!(router.id, address);
+
+        // コーディネータは報告が落ち着いてから経路を計算し，routesで配る
+        while coordinator.send_packet_buffer.is_empty() {
+            coordinator.exchange_up_down_routes();
+        }
+        let routes = coordinator.send_packet_buffer.pop_front().unwrap();
+        assert_eq!(routes.kind, PacketKind::Control(ControlKind::Routes));
+        assert_eq!(routes.dest_id, address);
+        let routes = DefaultPacket {
+            next_id: router.id,
+            ..routes
+        };
+        assert!(router.process_received_packet(&routes).is_empty());
+        let router_routes = &router.up_down.as_ref().unwrap().routes;
+        assert_eq!(router_routes.level, 1);
+        assert_eq!(
+            router_routes.parent_candidates,
+            HashSet::from(["coordinator".to_string()])
+        );
+        assert_eq!(
+            router.up_down_next_node_id(COORDINATOR_ID, router.id),
+            Some(COORDINATOR_ID)
+        );
+        assert_eq!(router.up_down_next_node_id(2, router.id), Some(2));
+
+        // 参加した後に失った隣接ノードはlinksで報告する
+        router.handle_link_loss(2);
+        let queued = router.send_packet_buffer.len();
+        while router.send_packet_buffer.len() == queued {
+            router.exchange_up_down_routes();
+        }
+        let links = router.send_packet_buffer.pop_back().unwrap();
+        assert_eq!(links.kind, PacketKind::Control(ControlKind::Links));
+        assert_eq!(links.next_id, COORDINATOR_ID);
+        assert_eq!(links.links, vec!["coordinator"]);
+    }
+
+    #[test]
+    fn test_flood_broadcast() {
+        let mut protocol = DefaultFunction::new(&NodeType::Router);
+        protocol.network_joined = true;
+        let rec_packet = DefaultPacket {
+            packet_id: 3,
+            ..packet(PacketKind::Data("hello".to_string()), 1, BROADCAST_ID)
+        };
+        protocol.process_received_packet_router(&rec_packet);
+
+        assert_eq!(protocol.flooding.pending().len(), 1);
+        let packet = protocol.flooding.pending()[0];
+        assert_eq!(packet.source_id, 1);
+        assert_eq!(packet.packet_id, 3);
+        assert_eq!(packet.prev_id, protocol.id);
+        assert_eq!(packet.next_id, BROADCAST_ID);
+
+        // 2回目以降は再送しない
+        protocol.process_received_packet_router(&rec_packet);
+        assert_eq!(protocol.flooding.pending().len(), 1);
+    }
+
+    // 知らないmulticastの宛先はpanicせずエラー
+    #[test]
+    fn test_unknown_multicast_destination() {
+        add_to_vid_table(BROADCAST_ID, "broadcast");
+        add_to_vid_table(1, "node1");
+        let mut protocol = DefaultFunction::new(&NodeType::Router);
+        let buffered = protocol.send_packet_buffer.len();
+        let packet = InjectionPacket {
+            message: "hello".to_string(),
+            dest_id: "broadcast".to_string(),
+            source_id: "node1".to_string(),
+            dest_ids: vec!["node1".to_string(), "unknown".to_string()],
+            inject_cycle: None,
+            priority: 0,
+            size_bytes: None,
+            payload: None,
+            class: None,
+            segment: None,
+        };
+
+        assert_eq!(
+            protocol.push_new_packet(&packet),
+            Err("unknown multicast destination: unknown".to_string())
+        );
+        assert_eq!(protocol.send_packet_buffer.len(), buffered);
+    }
+
+    #[test]
+    fn test_handle_link_loss() {
+        let mut protocol = DefaultFunction::new(&NodeType::Router);
+        protocol.network_joined = true;
+        protocol.parent_id = Some(1);
+        protocol.send_packet_buffer.clear();
+
+        // 子5のjreqを中継する
+        let jreq = DefaultPacket {
+            next_id: protocol.id,
+            ..packet(PacketKind::Control(ControlKind::Jreq), 5, COORDINATOR_ID)
+        };
+        protocol.process_received_packet_router(&jreq);
+        assert_eq!(protocol.children_id, vec![5]);
+        protocol.update_table(7, 1);
+        protocol.send_packet_buffer.clear();
+
+        // 兄弟へのリンクが切れても参加は続ける
+        protocol.handle_link_loss(3);
+        assert!(protocol.is_joined());
+
+        // 親へのリンクが切れたら経路を消して参加をやり直す
+        protocol.handle_link_loss(1);
+        assert!(!protocol.is_joined());
+        assert_eq!(protocol.parent_id, None);
+        assert_eq!(protocol.table.get(&7), None);
+        assert_eq!(protocol.table.get(&5), Some(&5));
+
+        let kinds: Vec<(PacketKind, u32)> = protocol
+            .send_packet_buffer
+            .iter()
+            .map(|packet| (packet.kind.clone(), packet.dest_id))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (PacketKind::Control(ControlKind::Lost), 5),
+                (PacketKind::Control(ControlKind::Preq), BROADCAST_ID)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_user_message_named_like_control() {
+        // "jack"というユーザーのメッセージは参加手順として扱わない
+        let mut protocol = DefaultFunction::new(&NodeType::Coordinator);
+        let rec_packet = packet(PacketKind::Data("jack".to_string()), 1, protocol.id);
+        assert!(protocol.process_received_packet(&rec_packet).is_empty());
+        assert!(protocol.children_id.is_empty());
+    }
+
+    #[test]
+    fn test_receive_lost() {
+        let mut protocol = DefaultFunction::new(&NodeType::Router);
+        protocol.network_joined = true;
+        protocol.parent_id = Some(1);
+        protocol.send_packet_buffer.clear();
+
+        let mut lost = packet(PacketKind::Control(ControlKind::Lost), 2, protocol.id);
+        // 親以外からの通知は無視する
+        protocol.process_received_packet(&lost);
+        assert!(protocol.is_joined());
+
+        lost.source_id = 1;
+        lost.prev_id = 1;
+        protocol.process_received_packet(&lost);
+        assert!(!protocol.is_joined());
+        assert_eq!(protocol.parent_id, None);
+
+        // 参加をやり直した後に届いた古いjackは無視する
+        let jack = DefaultPacket {
+            kind: PacketKind::Control(ControlKind::Jack),
+            source_id: COORDINATOR_ID,
+            prev_id: 1,
+            ..lost
+        };
+        protocol.process_received_packet(&jack);
+        assert!(!protocol.is_joined());
+    }
+
+    #[test]
+    fn test_address_assignment() {
+        let mut coordinator = DefaultFunction::new(&NodeType::Coordinator);
+        let mut router = DefaultFunction::new(&NodeType::Router);
+        let temp_id = router.id;
+        assert!(temp_id >= SHORT_ADDRESS_LIMIT);
+        router.parent_id = Some(COORDINATOR_ID);
+
+        let jreq = packet(
+            PacketKind::Control(ControlKind::Jreq),
+            temp_id,
+            COORDINATOR_ID,
+        );
+        let jack = coordinator.process_received_packet(&jreq);
+        assert_eq!(jack[0].dest_id, temp_id);
+        assert_eq!(jack[0].assigned_id, Some(1));
+        assert_eq!(coordinator.table.get(&1), Some(&temp_id));
+
+        // jackの再送でも同じアドレスを返す
+        let jack = coordinator.process_received_packet(&jreq);
+        assert_eq!(jack[0].assigned_id, Some(1));
+
+        // 割り当てられたアドレスに切り替えて参加する
+        router.process_received_packet(&jack[0]);
+        assert_eq!(router.id, 1);
+        assert!(router.is_joined());
+
+        // 参加をやり直すときは割り当て済みのアドレスを使い続ける
+        let rejoin = DefaultPacket {
+            source_id: 1,
+            prev_id: 1,
+            ..jreq.clone()
+        };
+        let jack = coordinator.process_received_packet(&rejoin);
+        assert_eq!(jack[0].assigned_id, Some(1));
+
+        // 割り当てを行わない場合はランダムなidのまま参加する
+        coordinator.set_address_assignment(false);
+        let jack = coordinator.process_received_packet(&jreq);
+        assert_eq!(jack[0].assigned_id, None);
+    }
+
+    #[test]
+    fn test_relay_jack() {
+        let mut router = DefaultFunction::new(&NodeType::Router);
+        router.network_joined = true;
+        let temp_id = SHORT_ADDRESS_LIMIT + 3;
+        router.update_table(temp_id, temp_id);
+
+        // 2ホップ以上離れたノードにも割り当てたアドレスが届く
+        let jack = DefaultPacket {
+            next_id: router.id,
+            assigned_id: Some(3),
+            ..packet(
+                PacketKind::Control(ControlKind::Jack),
+                COORDINATOR_ID,
+                temp_id,
+            )
+        };
+        let relayed = router.process_received_packet(&jack);
+        assert_eq!(relayed.len(), 1);
+        assert_eq!(relayed[0].next_id, temp_id);
+        assert_eq!(relayed[0].assigned_id, Some(3));
+    }
+
+    #[test]
+    fn test_table_next_id_by_other_id() {
+        use crate::network::vid::add_to_vid_table;
+
+        let mut protocol = DefaultFunction::new(&NodeType::Router);
+        add_to_vid_table(SHORT_ADDRESS_LIMIT + 7, "node");
+        add_to_vid_table(7, "node");
+        add_to_vid_table(2, "neighbor");
+
+        // 仮のidで学習した経路を割り当てられたアドレス宛てにも使う
+        protocol.update_table(SHORT_ADDRESS_LIMIT + 7, 2);
+        assert_eq!(protocol.table_next_id(7), Some(2));
+
+        // 経路が消えたら別のidも忘れる
+        protocol.handle_link_loss(2);
+        assert_eq!(protocol.table_next_id(7), None);
+        assert!(protocol.table_ids.is_empty());
+    }
+
+    #[test]
+    fn test_address_exhausted() {
+        use crate::log::aggregate_join_reject_log;
+
+        let mut coordinator = DefaultFunction::new(&NodeType::Coordinator);
+        coordinator.next_address = SHORT_ADDRESS_LIMIT;
+        let router = DefaultFunction::new(&NodeType::Router);
+        add_to_vid_table(router.id, "router");
+
+        // アドレスが残っていなければjackを返さず，子にもしない
+        let jreq = packet(
+            PacketKind::Control(ControlKind::Jreq),
+            router.id,
+            COORDINATOR_ID,
+        );
+        assert!(coordinator.process_received_packet(&jreq).is_empty());
+        assert!(coordinator.children_id.is_empty());
+
+        let report = aggregate_join_reject_log();
+        assert_eq!(report.reject_count, 1);
+        assert_eq!(report.node_ids, vec!["router"]);
+    }
+
+    #[test]
+    fn test_process_received_packet_end_device() {
+        let mut protocol = DefaultFunction::new(&NodeType::EndDevice);
+        protocol.network_joined = true;
+        let mut rec_packet = packet(PacketKind::Control(ControlKind::Preq), 1, BROADCAST_ID);
+        // preqには応答しない
+        assert!(protocol.process_received_packet(&rec_packet).is_empty());
+
+        // 他ノード宛てのパケットは中継しない
+        rec_packet.kind = PacketKind::Data("hello".to_string());
+        rec_packet.dest_id = 2;
+        rec_packet.next_id = protocol.id;
+        assert!(protocol.process_received_packet(&rec_packet).is_empty());
+    }
+
+    #[test]
+    fn test_user_type_hook() {
+        use crate::sim::node_type::{register_user_type, UserTypeHook};
+        use std::sync::Arc;
+
+        // 中継せず，受信したら送信元に返信するノード
+        struct Echo;
+        impl UserTypeHook for Echo {
+            fn on_receive(
+                &self,
+                node_id: &str,
+                source_id: &str,
+                message: &str,
+            ) -> Vec<InjectionPacket> {
+                vec![InjectionPacket {
+                    message: message.to_string(),
+                    dest_id: source_id.to_string(),
+                    source_id: node_id.to_string(),
+                    dest_ids: vec![],
+                    inject_cycle: None,
+                    priority: 0,
+                    size_bytes: None,
+                    payload: None,
+                    class: None,
+                    segment: None,
+                }]
+            }
+            fn forwards(&self, _: &str, _: &str, _: &str) -> bool {
+                false
+            }
+        }
+        register_user_type("echo", Arc::new(Echo)).unwrap();
+
+        let mut protocol = DefaultFunction::new(&NodeType::new("echo"));
+        protocol.network_joined = true;
+        protocol.parent_id = Some(1);
+        add_to_vid_table(protocol.id, "echo");
+        add_to_vid_table(1, "parent");
+        add_to_vid_table(2, "other");
+
+        let mut rec_packet = DefaultPacket {
+            next_id: protocol.id,
+            ..packet(PacketKind::Data("hello".to_string()), 1, 2)
+        };
+        assert!(protocol.process_received_packet(&rec_packet).is_empty());
+
+        rec_packet.dest_id = protocol.id;
+        protocol.send_packet_buffer.clear();
+        protocol.process_received_packet(&rec_packet);
+        assert_eq!(protocol.send_packet_buffer.len(), 1);
+        assert_eq!(protocol.send_packet_buffer[0].dest_id, 1);
+        assert_eq!(
+            protocol.send_packet_buffer[0].kind,
+            PacketKind::Data("hello".to_string())
+        );
+    }
+
+    #[test]
+    fn test_push_new_packet_priority() {
+        use crate::network::vid::add_to_vid_table;
+
+        let mut protocol = DefaultFunction::new(&NodeType::Router);
+        protocol.network_joined = true;
+        protocol.parent_id = Some(1);
+        protocol.send_packet_buffer.clear();
+        add_to_vid_table(protocol.id, "router");
+        add_to_vid_table(1, "parent");
+
+        // 優先度の高いパケットが先に，同じ優先度なら生成した順に送られる
+        for (message, priority) in [("a", 0), ("b", 2), ("c", 1), ("d", 2)] {
+            protocol
+                .push_new_packet(&InjectionPacket {
+                    message: message.to_string(),
+                    dest_id: "parent".to_string(),
+                    source_id: "router".to_string(),
+                    dest_ids: vec![],
+                    inject_cycle: None,
+                    priority,
+                    size_bytes: None,
+                    payload: None,
+                    class: None,
+                    segment: None,
+                })
+                .unwrap();
+        }
+        let messages: Vec<String> = protocol
+            .send_packet_buffer
+            .iter()
+            .map(|packet| packet.kind.message())
+            .collect();
+        assert_eq!(messages, vec!["b", "d", "c", "a"]);
+
+        protocol.send_packet().unwrap();
+        assert_eq!(protocol.priorities.len(), 3);
+    }
 }
@@ -0,0 +1,63 @@
+use super::packets::ProtocolPacket;
+use crate::rng::sim_rng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// broadcastを中継するまでの最大待ちサイクル数
+const FLOODING_JITTER_CYCLES: u32 = 32;
+
+// ユーザーのbroadcast/multicastの重複を除き，ランダムに待ってから隣接ノードへ中継する
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Flooding<P> {
+    // 受信済みのbroadcast (source_id, packet_id)
+    history: HashSet<(u32, u32)>,
+    // 中継待ちのbroadcast (残りサイクル数, パケット)
+    buffer: Vec<(u32, P)>,
+}
+
+impl<P: ProtocolPacket> Flooding<P> {
+    pub(crate) fn new() -> Self {
+        Self {
+            history: HashSet::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    // 初めて見たbroadcastならtrue
+    pub(crate) fn record(&mut self, packet: &P) -> bool {
+        self.history
+            .insert((packet.source_id(), packet.packet_id()))
+    }
+
+    // node_idから隣接ノード全体へ中継する
+    // 隣接ノードが同時に中継して衝突しないよう，ランダムに待ってから送信する
+    pub(crate) fn relay(&mut self, node_id: u32, broadcast_id: u32, packet: &P) {
+        let mut flooding_packet = packet.clone();
+        flooding_packet.set_hop(node_id, broadcast_id);
+
+        let mut rng = sim_rng();
+        self.buffer
+            .push((rng.gen_range(0..FLOODING_JITTER_CYCLES), flooding_packet));
+    }
+
+    // 待ち時間が終わったbroadcastを取り出す
+    pub(crate) fn take_ready(&mut self) -> Vec<P> {
+        let mut ready = Vec::new();
+        let mut buffer = Vec::new();
+        for (remaining_cycles, packet) in std::mem::take(&mut self.buffer) {
+            if remaining_cycles == 0 {
+                ready.push(packet);
+            } else {
+                buffer.push((remaining_cycles - 1, packet));
+            }
+        }
+        self.buffer = buffer;
+        ready
+    }
+
+    #[cfg(test)]
+    pub(crate) fn pending(&self) -> Vec<&P> {
+        self.buffer.iter().map(|(_, packet)| packet).collect()
+    }
+}
@@ -1,12 +1,36 @@
 pub mod default;
+mod flooding;
 pub mod multi_tree;
+mod node_behavior;
 pub mod packets;
+pub mod up_down;
 
+use crate::network::vid::{get_pid, get_vid};
 use crate::{network::flit::Flit, sim::node_type::NodeType};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 
 use self::packets::{InjectionPacket, Packet};
 
-#[derive(Debug, Clone)]
+const BROADCAST_ID: u32 = u32::MAX;
+// コーディネータが割り当てるアドレスはこれ未満，参加前の仮のidはこれ以上
+const SHORT_ADDRESS_LIMIT: u32 = 0x1_0000;
+
+// 外部から参照するためのルーティング表，idはすべてpid
+// チャネルごとの値を持ち，defaultではチャネルは1つ
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoutingTable {
+    // 参加していない，またはコーディネータならNone
+    pub parent_ids: Vec<Option<String>>,
+    // チャネルの木の根かどうか
+    pub roots: Vec<bool>,
+    pub children_ids: Vec<Vec<String>>,
+    // 宛先 -> 次ホップ
+    pub routes: Vec<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
 pub enum CoreFunction {
     DefaultFunction(default::DefaultFunction),
     MultiTreeFunction(multi_tree::MultiTreeFunction),
@@ -21,6 +45,9 @@ impl CoreFunction {
                 node_type,
                 channel_num,
             )),
+            "up_down" => {
+                CoreFunction::DefaultFunction(default::DefaultFunction::new_up_down(node_type))
+            }
             _ => panic!("invalid routing function kind: {}", rf_kind),
         }
     }
@@ -32,7 +59,7 @@ impl CoreFunction {
         }
     }
 
-    pub(crate) fn push_new_packet(&mut self, packet: &InjectionPacket) {
+    pub(crate) fn push_new_packet(&mut self, packet: &InjectionPacket) -> Result<(), String> {
         match self {
             CoreFunction::DefaultFunction(rf) => rf.push_new_packet(packet),
             CoreFunction::MultiTreeFunction(rf) => rf.push_new_packet(packet),
@@ -53,7 +80,7 @@ impl CoreFunction {
         }
     }
 
-    pub(crate) fn forward_flit(&mut self, flit: &Flit) -> Flit {
+    pub(crate) fn forward_flit(&mut self, flit: &Flit) -> Option<Flit> {
         match self {
             CoreFunction::DefaultFunction(rf) => rf.forward_flit(flit),
             CoreFunction::MultiTreeFunction(rf) => rf.forward_flit(flit),
@@ -74,23 +101,132 @@ impl CoreFunction {
         }
     }
 
-    pub(crate) fn get_message(&self, packet: &Packet) -> String {
+    pub(crate) fn handle_link_loss(&mut self, next_id: u32) {
+        match self {
+            CoreFunction::DefaultFunction(rf) => rf.handle_link_loss(next_id),
+            CoreFunction::MultiTreeFunction(rf) => rf.handle_link_loss(next_id),
+        }
+    }
+
+    pub(crate) fn set_address_assignment(&mut self, assign_address: bool) {
+        match self {
+            CoreFunction::DefaultFunction(rf) => rf.set_address_assignment(assign_address),
+            CoreFunction::MultiTreeFunction(rf) => rf.set_address_assignment(assign_address),
+        }
+    }
+
+    pub(crate) fn routing_table(&self) -> RoutingTable {
+        match self {
+            CoreFunction::DefaultFunction(rf) => rf.routing_table(),
+            CoreFunction::MultiTreeFunction(rf) => rf.routing_table(),
+        }
+    }
+
+    // フリットに分割される前の送信待ちパケットの数
+    pub(crate) fn queued_packets(&self) -> usize {
+        match self {
+            CoreFunction::DefaultFunction(rf) => rf.queued_packets(),
+            CoreFunction::MultiTreeFunction(rf) => rf.queued_packets(),
+        }
+    }
+
+    pub(crate) fn is_control_packet(&self, packet: &Packet) -> bool {
+        self.get_kind(packet).is_control()
+    }
+
+    // トランスポート層のヘッダ
+    pub(crate) fn get_segment(&self, packet: &Packet) -> Option<packets::Segment> {
+        match self {
+            CoreFunction::DefaultFunction(_) => {
+                packets::DefaultPacket::from_general(packet).segment
+            }
+            CoreFunction::MultiTreeFunction(_) => {
+                packets::MultiTreePacket::from_general(packet).segment
+            }
+        }
+    }
+
+    // 入力で指定されたペイロードのバイト数，受信したパケットでも分かる
+    pub(crate) fn get_size_bytes(&self, packet: &Packet) -> Option<u32> {
+        match self {
+            CoreFunction::DefaultFunction(_) => {
+                packets::DefaultPacket::from_general(packet).size_bytes
+            }
+            CoreFunction::MultiTreeFunction(_) => {
+                packets::MultiTreePacket::from_general(packet).size_bytes
+            }
+        }
+    }
+
+    // multicastの宛先(pid)を返す．broadcastなら空
+    pub(crate) fn get_dest_ids(&self, packet: &Packet) -> Vec<String> {
+        let dest_ids = match self {
+            CoreFunction::DefaultFunction(_) => {
+                packets::DefaultPacket::from_general(packet).dest_ids
+            }
+            CoreFunction::MultiTreeFunction(_) => {
+                packets::MultiTreePacket::from_general(packet).dest_ids
+            }
+        };
+
+        dest_ids
+            .iter()
+            .filter_map(|dest_id| get_pid(*dest_id))
+            .collect()
+    }
+
+    pub(crate) fn get_kind(&self, packet: &Packet) -> packets::PacketKind {
         match self {
             CoreFunction::DefaultFunction(_) => {
                 let p = packets::DefaultPacket::from_general(packet);
-                p.message
+                p.kind
             }
 
             CoreFunction::MultiTreeFunction(_) => {
                 let p = packets::MultiTreePacket::from_general(packet);
-                p.message
+                p.kind
             }
         }
     }
 }
 
+// 優先度priorityの新規パケットを送信キューに入れる位置
+// 優先度の低いユーザーパケットより前に入れ，制御パケットと同じ優先度のパケットは追い越さない
+fn priority_position<P>(
+    buffer: &VecDeque<P>,
+    priority: u8,
+    priority_of: impl Fn(&P) -> Option<u8>,
+) -> usize {
+    buffer
+        .iter()
+        .position(|packet| priority_of(packet).is_some_and(|p| p < priority))
+        .unwrap_or(buffer.len())
+}
+
+// vidのルーティング表をpidに変換する．VID_TABLEにないidは除く
+fn pid_routes(table: &HashMap<u32, u32>) -> HashMap<String, String> {
+    table
+        .iter()
+        .filter_map(|(dest_id, next_id)| Some((get_pid(*dest_id)?, get_pid(*next_id)?)))
+        .collect()
+}
+
 impl Default for CoreFunction {
     fn default() -> Self {
         Self::new("default", &NodeType::Router, 1)
     }
 }
+
+// 新規パケットの宛先とmulticastの宛先のvid，知らないノードがあればエラー
+pub(crate) fn injection_vids(packet: &InjectionPacket) -> Result<(u32, Vec<u32>), String> {
+    let dest_vid = get_vid(&packet.dest_id)
+        .ok_or_else(|| format!("unknown destination node: {}", packet.dest_id))?;
+    let dest_vids = packet
+        .dest_ids
+        .iter()
+        .map(|dest_id| {
+            get_vid(dest_id).ok_or_else(|| format!("unknown multicast destination: {dest_id}"))
+        })
+        .collect::<Result<Vec<u32>, String>>()?;
+    Ok((dest_vid, dest_vids))
+}
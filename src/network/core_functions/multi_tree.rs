@@ -1,20 +1,43 @@
-use super::packets::{InjectionPacket, Packet};
+use super::flooding::Flooding;
+use super::node_behavior::NodeBehavior;
+use super::packets::{ControlKind, InjectionPacket, Packet, PacketKind};
+use super::{
+    injection_vids, pid_routes, priority_position, RoutingTable, BROADCAST_ID, SHORT_ADDRESS_LIMIT,
+};
+use crate::hardware::constants::DEFAULT_TTL;
+use crate::log::post_join_reject_log;
 use crate::network::core_functions::packets::MultiTreePacket;
 use crate::network::flit::Flit;
 use crate::network::vid::get_pid;
 use crate::network::vid::get_vid;
+use crate::rng::sim_rng;
 use crate::sim::node_type::NodeType;
 use once_cell::sync::Lazy;
 use rand::Rng;
-use std::collections::{HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Mutex;
 use std::vec;
 
-const BROADCAST_ID: u32 = u32::MAX;
+// packを受け付けてからjackが届くまで待つ最大サイクル数
+const JOIN_TIMEOUT_CYCLES: u32 = 500;
 
 static COORDINATOR_CNT: Lazy<Mutex<u32>> = Lazy::new(|| Mutex::new(1));
 
-#[derive(Clone, Debug)]
+// チェックポイント用に次のcoordinatorのidを取り出す，戻す
+pub(crate) fn get_coordinator_cnt() -> u32 {
+    *COORDINATOR_CNT
+        .lock()
+        .expect("failed to lock COORDINATOR_CNT")
+}
+
+pub(crate) fn set_coordinator_cnt(cnt: u32) {
+    *COORDINATOR_CNT
+        .lock()
+        .expect("failed to lock COORDINATOR_CNT") = cnt;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[allow(dead_code)]
 pub struct MultiTreeFunction {
     pub(crate) id: u32,
@@ -24,10 +47,35 @@ pub struct MultiTreeFunction {
     network_joined: Vec<bool>,
     node_type: NodeType,
     tables: Vec<HashMap<u32, u32>>,
+    // チャネルごとにtableの宛先のidをノードのpidごとにまとめたもの
+    // 仮のidと割り当てられたアドレスのように，同じノードの別のidの経路を探すのに使う
+    table_ids: Vec<HashMap<String, HashSet<u32>>>,
     packet_num_cnt: u32,
     parent_ids: Vec<u32>,
-    children_id: Vec<u32>,
+    // チャネルごとの子ノード
+    children_ids: Vec<Vec<u32>>,
     channel_history: u8,
+    // ユーザーのbroadcast/multicastの中継
+    flooding: Flooding<MultiTreePacket>,
+    // チャネルごとにpackを受け付けてから経過したサイクル数
+    join_wait_cycles: Vec<u32>,
+    // 経路がないため参加を待っている新規パケット
+    pending_packets: VecDeque<InjectionPacket>,
+    // 自分が生成したユーザーパケット(source_id, packet_id)の生成サイクル
+    inject_cycles: HashMap<(u32, u32), u32>,
+    // 送信キューにある自分が生成したユーザーパケットの優先度
+    priorities: HashMap<(u32, u32), u8>,
+    // 自分が生成したユーザーパケットのトラフィッククラス
+    classes: HashMap<(u32, u32), String>,
+    // 割り当てられたアドレスに切り替える前の仮のid
+    temp_id: Option<u32>,
+    // コーディネータがjackでアドレスを割り当てるかどうか
+    assign_address: bool,
+    // 次に割り当てるアドレス
+    // コーディネータごとにchannel_num個おきのアドレスを使い，割り当てが重ならないようにする
+    next_address: u32,
+    // 割り当て済みのアドレス (仮のid, アドレス)
+    assigned_addresses: HashMap<u32, u32>,
 }
 
 impl MultiTreeFunction {
@@ -36,6 +84,7 @@ impl MultiTreeFunction {
         let id;
         let mut send_packet_buffer = VecDeque::new();
         let mut parent_ids = vec![0; channel_num as usize];
+        let mut next_address = SHORT_ADDRESS_LIMIT;
 
         if let NodeType::Coordinator = node_type {
             let mut coordinator_cnt = COORDINATOR_CNT
@@ -58,20 +107,24 @@ impl MultiTreeFunction {
 
             parent_ids[id as usize - 1] = id;
             network_joined[id as usize - 1] = true;
+            next_address = channel_num as u32 + id;
         } else {
-            // idはランダムな整数
-            let mut rng = rand::thread_rng();
-            // 2以上のランダムな整数
-            id = rng.gen_range(channel_num as u32 + 1..u32::MAX);
+            // 参加するまでのidは割り当て用のアドレスと重ならないランダムな整数
+            let mut rng = sim_rng();
+            id = rng.gen_range(SHORT_ADDRESS_LIMIT..BROADCAST_ID);
 
             send_packet_buffer.push_back(MultiTreePacket {
-                message: "preq".to_string(),
+                kind: PacketKind::Control(ControlKind::Preq),
                 packet_id: u32::MAX, // todo
                 dest_id: BROADCAST_ID,
                 source_id: id,
                 prev_id: id,
                 channel_id: u8::MAX,
                 next_id: BROADCAST_ID,
+                dest_ids: vec![],
+                assigned_id: None,
+                size_bytes: None,
+                segment: None,
             });
         }
 
@@ -84,36 +137,111 @@ impl MultiTreeFunction {
             node_type: node_type.clone(),
             // channel_num個のHashMapを持つようなVec
             tables: vec![HashMap::new(); channel_num as usize],
+            table_ids: vec![HashMap::new(); channel_num as usize],
             packet_num_cnt: 0,
             parent_ids,
-            children_id: Vec::new(),
+            children_ids: vec![Vec::new(); channel_num as usize],
             channel_history: channel_num - 1,
+            flooding: Flooding::new(),
+            join_wait_cycles: vec![0; channel_num as usize],
+            pending_packets: VecDeque::new(),
+            inject_cycles: HashMap::new(),
+            priorities: HashMap::new(),
+            classes: HashMap::new(),
+            temp_id: None,
+            assign_address: true,
+            next_address,
+            assigned_addresses: HashMap::new(),
         }
     }
 
+    // falseならjoin時に割り当てを行わず，ランダムなidをそのまま使う
+    pub fn set_address_assignment(&mut self, assign_address: bool) {
+        self.assign_address = assign_address;
+    }
+
     pub fn is_joined(&self) -> bool {
         self.network_joined.iter().all(|&x| x)
     }
 
-    pub fn push_new_packet(&mut self, packet: &InjectionPacket) {
-        let dest_vid = get_vid(&packet.dest_id).unwrap();
+    pub fn routing_table(&self) -> RoutingTable {
+        RoutingTable {
+            // 親がいなければ0，コーディネータでは自分のid
+            parent_ids: self
+                .parent_ids
+                .iter()
+                .map(|&parent_id| {
+                    if parent_id == 0 || parent_id == self.id {
+                        None
+                    } else {
+                        get_pid(parent_id)
+                    }
+                })
+                .collect(),
+            roots: self
+                .parent_ids
+                .iter()
+                .map(|&parent_id| parent_id == self.id)
+                .collect(),
+            children_ids: self
+                .children_ids
+                .iter()
+                .map(|children| children.iter().filter_map(|id| get_pid(*id)).collect())
+                .collect(),
+            routes: self.tables.iter().map(pid_routes).collect(),
+        }
+    }
+
+    pub fn queued_packets(&self) -> usize {
+        self.send_packet_buffer.len() + self.pending_packets.len()
+    }
+
+    pub fn push_new_packet(&mut self, packet: &InjectionPacket) -> Result<(), String> {
+        let (dest_vid, dest_vids) = injection_vids(packet)?;
         let channel_id = self.channel_id(dest_vid);
-        let next_vid = self.next_node_id(dest_vid, channel_id);
+        let Some(next_vid) = self.next_node_id(dest_vid, channel_id) else {
+            // 再参加して経路ができるまで待つ
+            self.pending_packets.push_back(packet.clone());
+            return Ok(());
+        };
 
-        let packet = self.gen_packet(
+        let mut new_packet = self.gen_packet(
             self.id,
             dest_vid,
             next_vid,
             channel_id,
-            packet.message.clone(),
+            PacketKind::Data(packet.message.clone()),
         );
 
-        self.send_packet_buffer.push_back(packet);
+        new_packet.segment = packet.segment;
+        new_packet.size_bytes = packet.size_bytes;
+
+        if dest_vid == BROADCAST_ID {
+            new_packet.dest_ids = dest_vids;
+            self.flooding.record(&new_packet);
+        }
+
+        if let Some(inject_cycle) = packet.inject_cycle {
+            self.inject_cycles
+                .insert((new_packet.source_id, new_packet.packet_id), inject_cycle);
+        }
+        let key = (new_packet.source_id, new_packet.packet_id);
+        if let Some(class) = &packet.class {
+            self.classes.insert(key, class.clone());
+        }
+        self.priorities.insert(key, packet.priority);
+        let position = priority_position(&self.send_packet_buffer, packet.priority, |p| {
+            self.priorities.get(&(p.source_id, p.packet_id)).copied()
+        });
+        self.send_packet_buffer.insert(position, new_packet);
         self.packet_num_cnt += 1;
+        Ok(())
     }
 
     pub fn send_packet(&mut self) -> Option<Packet> {
         if let Some(packet) = self.send_packet_buffer.pop_front() {
+            self.priorities
+                .remove(&(packet.source_id, packet.packet_id));
             let data = bincode::serialize(&packet)
                 .map_err(|e| {
                     panic!("error occured while serializing a packet: {e:?}");
@@ -133,6 +261,16 @@ impl MultiTreeFunction {
                 next_id: next_pid,
                 prev_id: prev_pid,
                 channel_id: packet.channel_id,
+                inject_cycle: self
+                    .inject_cycles
+                    .remove(&(packet.source_id, packet.packet_id)),
+                size_bytes: match packet.kind {
+                    // 制御パケットはヘッダのみ
+                    PacketKind::Control(_) => Some(0),
+                    PacketKind::Data(_) => packet.size_bytes,
+                },
+                class: self.classes.remove(&(packet.source_id, packet.packet_id)),
+                ttl: DEFAULT_TTL,
             });
         }
         None
@@ -145,7 +283,7 @@ impl MultiTreeFunction {
 
     pub fn update(&mut self) {
         // ランダムな確率でpackを送信
-        let mut rng = rand::thread_rng();
+        let mut rng = sim_rng();
         let p: f64 = rng.gen();
         // pは確率
         // 一定の確率で次のような動作を行うために用いる
@@ -162,14 +300,14 @@ impl MultiTreeFunction {
                     BROADCAST_ID,
                     BROADCAST_ID,
                     u8::MAX,
-                    "preq".to_string(),
+                    PacketKind::Control(ControlKind::Preq),
                 );
 
                 self.send_packet_buffer.push_back(new_packet);
-            } else if self.parent_ids.iter().any(|&x| x == 0) {
+            } else if self.parent_ids.contains(&0) {
                 // 0があれば
                 // 0の要素のindexからランダムに選択
-                let mut rng = rand::thread_rng();
+                let mut rng = sim_rng();
                 let indices: Vec<usize> = self
                     .parent_ids
                     .iter()
@@ -187,13 +325,34 @@ impl MultiTreeFunction {
                     BROADCAST_ID,
                     BROADCAST_ID,
                     random_channel_id as u8,
-                    "preq".to_string(),
+                    PacketKind::Control(ControlKind::Preq),
                 );
 
                 self.send_packet_buffer.push_back(new_packet);
             }
         }
 
+        // jackが届かなければそのチャネルの参加をやり直す
+        for channel_id in 0..self.channel_num as usize {
+            if self.parent_ids[channel_id] != 0 && !self.network_joined[channel_id] {
+                self.join_wait_cycles[channel_id] += 1;
+                if self.join_wait_cycles[channel_id] > JOIN_TIMEOUT_CYCLES {
+                    self.leave_parent(channel_id as u8);
+                }
+            }
+        }
+
+        // 参加が完了したら待たせていたパケットを送信する
+        if self.is_joined() {
+            for packet in std::mem::take(&mut self.pending_packets) {
+                // 宛先は待たせる前に確かめてある
+                let _ = self.push_new_packet(&packet);
+            }
+        }
+
+        // 待ち時間が終わったbroadcastを送信する
+        self.send_packet_buffer.extend(self.flooding.take_ready());
+
         // received_packet_bufferをすべて処理
         while let Some(packet) = self.received_packet_buffer.pop_front() {
             let reply = self.process_received_packet(&packet);
@@ -203,14 +362,19 @@ impl MultiTreeFunction {
         }
     }
 
-    pub fn forward_flit(&mut self, flit: &Flit) -> Flit {
+    pub fn forward_flit(&mut self, flit: &Flit) -> Option<Flit> {
         let dest_vid = get_vid(&flit.get_dest_id().unwrap()).unwrap();
-        let next_vid = self.next_node_id(dest_vid, flit.get_channel_id().unwrap());
-
         let source_vid = get_vid(&flit.get_source_id().unwrap()).unwrap();
         let prev_vid = get_vid(&flit.get_prev_id().unwrap()).unwrap();
 
-        self.update_table(source_vid, prev_vid, flit.get_channel_id().unwrap());
+        // 親から届いたパケットは下りなので，送信元への経路は学習しない
+        let channel_id = flit.get_channel_id().unwrap();
+        if self.parent_ids.get(channel_id as usize) != Some(&prev_vid) {
+            self.update_table(source_vid, prev_vid, channel_id);
+            self.add_child(source_vid, prev_vid, channel_id);
+        }
+
+        let next_vid = self.next_node_id(dest_vid, flit.get_channel_id().unwrap())?;
 
         let next_pid = get_pid(next_vid).unwrap();
 
@@ -226,37 +390,40 @@ impl MultiTreeFunction {
             panic!("error occured while setting next_id: {e:?}");
         });
 
-        new_flit
+        Some(new_flit)
+    }
+
+    // next_idへのリンクが切れたら，そこを経由する経路を消し，親ならそのチャネルの参加をやり直す
+    pub fn handle_link_loss(&mut self, next_id: u32) {
+        for channel_id in 0..self.channel_num as usize {
+            self.tables[channel_id].retain(|_, node_id| *node_id != next_id);
+            self.forget_table_ids(channel_id as u8);
+            self.children_ids[channel_id].retain(|&child_id| child_id != next_id);
+
+            if self.parent_ids[channel_id] == next_id {
+                self.leave_parent(channel_id as u8);
+            }
+        }
     }
 }
 
 // private functions
 #[allow(unused_variables)]
 impl MultiTreeFunction {
-    fn next_node_id(&self, dest_id: u32, channel_id: u8) -> u32 {
+    fn next_node_id(&self, dest_id: u32, channel_id: u8) -> Option<u32> {
         // tableにdest_idがあればそれに対応するnode_idを返す
         // なければparent_idを返す
-        let node_id = self
-            .tables
-            .get(channel_id as usize)
-            .unwrap()
-            .get(&dest_id)
-            .copied();
-        if let Some(node_id) = node_id {
-            return node_id;
+        if let Some(node_id) = self.table_next_id(dest_id, channel_id) {
+            return Some(node_id);
         }
         if dest_id == BROADCAST_ID {
-            return BROADCAST_ID;
+            return Some(BROADCAST_ID);
         }
-        if self.parent_ids[channel_id as usize] == 0 {
-            if self.node_type == NodeType::Router {
-                panic!("Router: parent_id of channel {} is not set", channel_id);
-            } else {
-                panic!("Coordinator: parent_id is not set");
-            }
+        // 親を失って再参加中の場合はNone
+        match self.parent_ids[channel_id as usize] {
+            0 => None,
+            parent_id => Some(parent_id),
         }
-
-        self.parent_ids[channel_id as usize]
     }
 
     // フリットごとにチャネルを選択
@@ -272,10 +439,20 @@ impl MultiTreeFunction {
         if packet.next_id != self.id && packet.next_id != BROADCAST_ID {
             return vec![];
         }
-        match self.node_type {
+        // 仮のid宛てに送られていたパケットは自分宛てとして扱う
+        if self
+            .temp_id
+            .is_some_and(|temp_id| packet.dest_id == temp_id)
+        {
+            let mut packet = packet.clone();
+            packet.dest_id = self.id;
+            return self.process_received_packet(&packet);
+        }
+        match self.node_type.base_type() {
             NodeType::Coordinator => self.process_received_packet_coordinator(packet),
             NodeType::Router => self.process_received_packet_router(packet),
-            _ => panic!("unknown node type"),
+            NodeType::EndDevice => self.process_received_packet_end_device(packet),
+            NodeType::UserType(_) => unreachable!("base type cannot be user type"),
         }
     }
     #[allow(unreachable_code)]
@@ -283,9 +460,9 @@ impl MultiTreeFunction {
         &mut self,
         packet: &MultiTreePacket,
     ) -> Vec<MultiTreePacket> {
-        match (packet.dest_id, packet.message.as_str()) {
+        match (packet.dest_id, &packet.kind) {
             // BROADCAST "preq"
-            (BROADCAST_ID, "preq") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Preq)) => {
                 // packを返す
                 let mut channel_id = packet.channel_id;
 
@@ -302,38 +479,43 @@ impl MultiTreeFunction {
                     packet.source_id,
                     packet.source_id,
                     channel_id,
-                    "pack".to_string(),
+                    PacketKind::Control(ControlKind::Pack),
                 );
                 return vec![packet];
             }
 
             // BROADCAST "pack"
-            (BROADCAST_ID, "pack") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Pack)) => {
                 panic!("pack destination cannot be broadcast");
             }
 
             // BROADCAST "jreq"
-            (BROADCAST_ID, "jreq") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Jreq)) => {
                 panic!("jreq destination cannot be broadcast");
             }
 
             // BROADCAST "jack"
-            (BROADCAST_ID, "jack") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Jack)) => {
                 panic!("jack destination cannot be broadcast");
             }
 
+            // BROADCAST "lost"
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Lost)) => {
+                panic!("lost destination cannot be broadcast");
+            }
+
             // BROADCAST user message
-            (BROADCAST_ID, _) => {
-                panic!("Broadcast message by user is not supported");
+            (BROADCAST_ID, PacketKind::Data(_)) => {
+                return self.flood(packet);
             }
 
             // address to me, "preq"
-            (id, "preq") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Preq)) if id == self.id => {
                 panic!("preq destination must be broadcast");
             }
 
             // address to me, "pack"
-            (id, "pack") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Pack)) if id == self.id => {
                 // もし親IDが設定されていれば
                 if self.parent_ids[packet.channel_id as usize] != 0 {
                     return vec![];
@@ -348,43 +530,62 @@ impl MultiTreeFunction {
                     packet.channel_id as u32 + 1, // ここではchannel_idとcoordinator_idを一対一対応させている
                     packet.source_id,
                     packet.channel_id,
-                    "jreq".to_string(),
+                    PacketKind::Control(ControlKind::Jreq),
                 );
 
                 return vec![packet];
             }
 
             // address to me, "jreq"
-            (id, "jreq") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Jreq)) if id == self.id => {
                 if packet.channel_id as u32 + 1 != self.id {
                     return vec![];
                 }
                 self.update_table(packet.source_id, packet.prev_id, packet.channel_id);
+                // アドレスが足りなければ参加を拒否する
+                // 要求したノードはjackが届かないのでそのチャネルの参加をやり直す
+                let assigned_id = match self.assign(packet.source_id, packet.channel_id) {
+                    Ok(assigned_id) => assigned_id,
+                    Err(_) => {
+                        if let Some(source_pid) = get_pid(packet.source_id) {
+                            post_join_reject_log(&source_pid);
+                        }
+                        return vec![];
+                    }
+                };
+                self.add_child(packet.source_id, packet.prev_id, packet.channel_id);
                 // jackを返す
                 let channel_id = packet.channel_id;
-                let next_id = self.next_node_id(packet.source_id, channel_id);
+                let next_id = self.next_node_id(packet.source_id, channel_id).unwrap();
 
-                let packet = self.gen_packet(
+                let mut packet = self.gen_packet(
                     self.id,
                     packet.source_id,
                     next_id,
                     packet.channel_id,
-                    "jack".to_string(),
+                    PacketKind::Control(ControlKind::Jack),
                 );
+                packet.assigned_id = assigned_id;
                 return vec![packet];
             }
 
             // address to me, "jack"
-            (id, "jack") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Jack)) if id == self.id => {
+                // 参加をやり直した後に届いた古いjackは無視する
+                if self.parent_ids[packet.channel_id as usize] == 0 {
+                    return vec![];
+                }
+
                 // channel_id番目のnetwork_joinedをtrue
                 self.network_joined[packet.channel_id as usize] = true;
+                self.join_wait_cycles[packet.channel_id as usize] = 0;
 
                 if self.is_joined() {
                     // recsimu_dbg!("{} {:?}", get_pid(self.id).unwrap(), self.parent_ids.iter().map(|&x| get_pid(x).unwrap()).collect::<Vec<String>>());
                 }
 
-                if self.parent_ids.iter().any(|&x| x == 0) {
-                    let mut rng = rand::thread_rng();
+                if self.parent_ids.contains(&0) {
+                    let mut rng = sim_rng();
                     let indices: Vec<usize> = self
                         .parent_ids
                         .iter()
@@ -403,7 +604,7 @@ impl MultiTreeFunction {
                         BROADCAST_ID,
                         BROADCAST_ID,
                         random_channel_id,
-                        "preq".to_string(),
+                        PacketKind::Control(ControlKind::Preq),
                     );
 
                     return vec![packet];
@@ -411,31 +612,49 @@ impl MultiTreeFunction {
                 return vec![];
             }
 
+            // address to me, "lost"
+            (id, PacketKind::Control(ControlKind::Lost)) if id == self.id => {
+                // 親が経路を失ったのでそのチャネルの参加をやり直す
+                if self.parent_ids[packet.channel_id as usize] == packet.source_id {
+                    self.leave_parent(packet.channel_id);
+                }
+                return vec![];
+            }
+
             // address to me, user packet
-            (id, message) if id == self.id => {
+            (id, PacketKind::Data(_)) if id == self.id => {
                 // message arrived
                 // なにもしない
                 return vec![];
             }
 
             // address to others, "preq"
-            (_, "preq") => {
+            (_, PacketKind::Control(ControlKind::Preq)) => {
                 panic!("preq destination must be broadcast");
             }
 
             // address to others, "pack"
-            (_, "pack") => {
+            (_, PacketKind::Control(ControlKind::Pack)) => {
                 panic!("pack cannot be reached to coordinator");
             }
 
             // address to others, "jreq"
-            (_, "jreq") => {
-                panic!("jreq destination must be coordinator");
+            (_, PacketKind::Control(ControlKind::Jreq)) => {
+                // 他のチャネルの木では中継を行う
+                self.update_table(packet.source_id, packet.prev_id, packet.channel_id);
+                self.add_child(packet.source_id, packet.prev_id, packet.channel_id);
+                return self.routing(packet);
             }
 
             // address to others, "jack"
-            (_, "jack") => {
-                panic!("jack cannot be reached to coordinator");
+            (_, PacketKind::Control(ControlKind::Jack)) => {
+                // 他のチャネルの木では中継を行う
+                return self.routing(packet);
+            }
+
+            // address to others, "lost"
+            (_, PacketKind::Control(ControlKind::Lost)) => {
+                panic!("lost destination must be a neighbor");
             }
 
             // address to others, user packet
@@ -450,9 +669,9 @@ impl MultiTreeFunction {
 
     #[allow(unreachable_code)]
     fn process_received_packet_router(&mut self, packet: &MultiTreePacket) -> Vec<MultiTreePacket> {
-        match (packet.dest_id, packet.message.as_str()) {
+        match (packet.dest_id, &packet.kind) {
             // BROADCAST "preq"
-            (BROADCAST_ID, "preq") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Preq)) => {
                 if self.network_joined.iter().all(|&x| !x) {
                     // すべてfalseなら
                     return vec![];
@@ -464,7 +683,7 @@ impl MultiTreeFunction {
                     return vec![];
                 }
 
-                let mut rng = rand::thread_rng();
+                let mut rng = sim_rng();
                 let indices: Vec<usize> = self
                     .parent_ids
                     .iter()
@@ -483,38 +702,43 @@ impl MultiTreeFunction {
                     packet.source_id,
                     packet.source_id,
                     random_channel_id,
-                    "pack".to_string(),
+                    PacketKind::Control(ControlKind::Pack),
                 );
                 return vec![packet];
             }
 
             // BROADCAST "pack"
-            (BROADCAST_ID, "pack") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Pack)) => {
                 panic!("pack destination cannot be broadcast");
             }
 
             // BROADCAST "jreq"
-            (BROADCAST_ID, "jreq") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Jreq)) => {
                 panic!("jreq destination cannot be broadcast");
             }
 
             // BROADCAST "jack"
-            (BROADCAST_ID, "jack") => {
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Jack)) => {
                 panic!("jack destination cannot be broadcast");
             }
 
+            // BROADCAST "lost"
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Lost)) => {
+                panic!("lost destination cannot be broadcast");
+            }
+
             // BROADCAST user message
-            (BROADCAST_ID, _) => {
-                panic!("Broadcast message by user is not supported");
+            (BROADCAST_ID, PacketKind::Data(_)) => {
+                return self.flood(packet);
             }
 
             // BROADCAST "preq"
-            (id, "preq") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Preq)) if id == self.id => {
                 panic!("preq destination must be broadcast");
             }
 
             // address to me, "pack"
-            (id, "pack") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Pack)) if id == self.id => {
                 // もし親IDが設定されていれば
                 if self.parent_ids[packet.channel_id as usize] != 0 {
                     return vec![];
@@ -529,28 +753,43 @@ impl MultiTreeFunction {
                     packet.channel_id as u32 + 1, // ここではchannel_idとcoordinator_idを一対一対応させている
                     packet.source_id,
                     packet.channel_id,
-                    "jreq".to_string(),
+                    PacketKind::Control(ControlKind::Jreq),
                 );
 
                 return vec![packet];
             }
 
             // address to me, "jreq"
-            (id, "jreq") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Jreq)) if id == self.id => {
                 panic!("jreq destination must be coordinator");
             }
 
             // address to me, "jack"
-            (id, "jack") if id == self.id => {
+            (id, PacketKind::Control(ControlKind::Jack)) if id == self.id => {
+                // 参加をやり直した後に届いた古いjackは無視する
+                if self.parent_ids[packet.channel_id as usize] == 0 {
+                    return vec![];
+                }
+
+                // 最初に割り当てられたアドレスに切り替える
+                // 他のチャネルのコーディネータがあとから割り当てたアドレスは使わない
+                if let Some(assigned_id) = packet.assigned_id {
+                    if self.id >= SHORT_ADDRESS_LIMIT {
+                        self.temp_id = Some(self.id);
+                        self.id = assigned_id;
+                    }
+                }
+
                 // channel_id番目のnetwork_joinedをtrue
                 self.network_joined[packet.channel_id as usize] = true;
+                self.join_wait_cycles[packet.channel_id as usize] = 0;
 
                 if self.is_joined() {
                     // recsimu_dbg!("{} {:?}", get_pid(self.id).unwrap(), self.parent_ids.iter().map(|&x| get_pid(x).unwrap()).collect::<Vec<String>>());
                 }
 
-                if self.parent_ids.iter().any(|&x| x == 0) {
-                    let mut rng = rand::thread_rng();
+                if self.parent_ids.contains(&0) {
+                    let mut rng = sim_rng();
                     let indices: Vec<usize> = self
                         .parent_ids
                         .iter()
@@ -569,7 +808,7 @@ impl MultiTreeFunction {
                         BROADCAST_ID,
                         BROADCAST_ID,
                         random_channel_id,
-                        "preq".to_string(),
+                        PacketKind::Control(ControlKind::Preq),
                     );
 
                     return vec![packet];
@@ -577,39 +816,57 @@ impl MultiTreeFunction {
                 return vec![];
             }
 
+            // address to me, "lost"
+            (id, PacketKind::Control(ControlKind::Lost)) if id == self.id => {
+                // 親が経路を失ったのでそのチャネルの参加をやり直す
+                if self.parent_ids[packet.channel_id as usize] == packet.source_id {
+                    self.leave_parent(packet.channel_id);
+                }
+                return vec![];
+            }
+
             // address to me, user packet
-            (id, message) if id == self.id => {
+            (id, PacketKind::Data(_)) if id == self.id => {
                 // message arrived
-                // なにもしない
+                self.notify_user_type_hook(packet);
                 return vec![];
             }
 
             // address to others, "preq"
-            (_, "preq") => {
+            (_, PacketKind::Control(ControlKind::Preq)) => {
                 panic!("preq destination must be broadcast");
             }
 
             // address to others, "pack"
-            (_, "pack") => {
+            (_, PacketKind::Control(ControlKind::Pack)) => {
                 panic!("pack cannot be reached to other router");
             }
 
             // address to others, "jreq"
-            (_, "jreq") => {
+            (_, PacketKind::Control(ControlKind::Jreq)) => {
                 // テーブルに登録する
                 self.update_table(packet.source_id, packet.prev_id, packet.channel_id);
+                self.add_child(packet.source_id, packet.prev_id, packet.channel_id);
                 // ルーティングを行う
                 return self.routing(packet);
             }
 
             // address to others, "jack"
-            (_, "jack") => {
+            (_, PacketKind::Control(ControlKind::Jack)) => {
                 // ルーティングを行う
                 return self.routing(packet);
             }
 
+            // address to others, "lost"
+            (_, PacketKind::Control(ControlKind::Lost)) => {
+                panic!("lost destination must be a neighbor");
+            }
+
             // address to others, user packet
             _ => {
+                if !self.forwards(packet.source_id, packet.dest_id) {
+                    return vec![];
+                }
                 // ルーティングを行う
                 return self.routing(packet);
             }
@@ -624,63 +881,179 @@ impl MultiTreeFunction {
         dest_id: u32,
         next_id: u32,
         channel_id: u8,
-        message: String,
+        kind: PacketKind,
     ) -> MultiTreePacket {
         let packet_id = self.packet_num_cnt;
         self.packet_num_cnt += 1;
 
         MultiTreePacket {
-            message,
+            kind,
             packet_id,
             dest_id,
             source_id: src_id,
             prev_id: self.id,
             channel_id,
             next_id,
+            dest_ids: vec![],
+            assigned_id: None,
+            size_bytes: None,
+            segment: None,
         }
     }
 
-    fn update_table(&mut self, dest_id: u32, next_id: u32, channel_id: u8) {
-        // todo すでにあったら場合
+    // jreqの送信元に割り当てるアドレス
+    // 割り当て済みのアドレスから届いた場合はそのまま使い続ける
+    // 割り当てられるアドレスが残っていなければエラー
+    fn assign(&mut self, source_id: u32, channel_id: u8) -> Result<Option<u32>, String> {
+        if !self.assign_address {
+            return Ok(None);
+        }
+        if source_id < SHORT_ADDRESS_LIMIT {
+            return Ok(Some(source_id));
+        }
+        if let Some(address) = self.assigned_addresses.get(&source_id) {
+            return Ok(Some(*address));
+        }
+
+        // 再起動したコーディネータが使用中のアドレスを割り当てないようにする
+        while self.next_address < SHORT_ADDRESS_LIMIT && get_pid(self.next_address).is_some() {
+            self.next_address += self.channel_num as u32;
+        }
+        if self.next_address >= SHORT_ADDRESS_LIMIT {
+            return Err("no more short addresses to assign".to_string());
+        }
+        let address = self.next_address;
+        self.next_address += self.channel_num as u32;
+        self.assigned_addresses.insert(source_id, address);
+        // jackが届くまでは仮のidと同じ経路を使う
+        if let Some(next_id) = self.tables[channel_id as usize].get(&source_id).copied() {
+            self.update_table(address, next_id, channel_id);
+        }
+        Ok(Some(address))
+    }
 
-        // テーブルに登録
+    // channel_idのtableからdest_idへの次のノードを取得する
+    // アドレスが割り当てられたノードは，jreqを中継したときの仮のidの経路を使う
+    fn table_next_id(&self, dest_id: u32, channel_id: u8) -> Option<u32> {
+        let table = self.tables.get(channel_id as usize)?;
+        if let Some(node_id) = table.get(&dest_id) {
+            return Some(*node_id);
+        }
+        let dest_pid = get_pid(dest_id)?;
+        self.table_ids[channel_id as usize]
+            .get(&dest_pid)?
+            .iter()
+            .find_map(|id| table.get(id).copied())
+    }
+
+    fn update_table(&mut self, dest_id: u32, next_id: u32, channel_id: u8) {
+        // テーブルに登録．すでにあれば新しい経路で上書きする
         self.tables
             .get_mut(channel_id as usize)
             .unwrap()
             .insert(dest_id, next_id);
+        if let Some(dest_pid) = get_pid(dest_id) {
+            self.table_ids[channel_id as usize]
+                .entry(dest_pid)
+                .or_default()
+                .insert(dest_id);
+        }
+    }
+
+    // channel_idのtableから消えた宛先のidを忘れる
+    fn forget_table_ids(&mut self, channel_id: u8) {
+        let table = &self.tables[channel_id as usize];
+        self.table_ids[channel_id as usize].retain(|_, ids| {
+            ids.retain(|id| table.contains_key(id));
+            !ids.is_empty()
+        });
+    }
+
+    // 隣接ノードが自分で送信したパケットを中継したら，送信元をそのチャネルの子として記録する
+    // 親以外の隣接ノードが自分を経由させるのは，自分を親としている場合だけ
+    // アドレスが割り当てられた子は仮のidを新しいアドレスで置き換える
+    fn add_child(&mut self, source_id: u32, prev_id: u32, channel_id: u8) {
+        let Some(children_id) = self.children_ids.get_mut(channel_id as usize) else {
+            return;
+        };
+        if source_id != prev_id
+            || self.parent_ids[channel_id as usize] == source_id
+            || children_id.contains(&source_id)
+        {
+            return;
+        }
+        if let Some(source_pid) = get_pid(source_id) {
+            children_id.retain(|&child_id| get_pid(child_id).as_ref() != Some(&source_pid));
+        }
+        children_id.push(source_id);
+    }
+
+    // channel_idの親との接続を解除して参加をやり直す
+    // そのチャネルの子には親を失ったことを通知し，子も参加をやり直す
+    fn leave_parent(&mut self, channel_id: u8) {
+        let index = channel_id as usize;
+        self.parent_ids[index] = 0;
+        self.network_joined[index] = false;
+        self.join_wait_cycles[index] = 0;
+
+        for child_id in std::mem::take(&mut self.children_ids[index]) {
+            let packet = self.gen_packet(
+                self.id,
+                child_id,
+                child_id,
+                channel_id,
+                PacketKind::Control(ControlKind::Lost),
+            );
+            self.send_packet_buffer.push_back(packet);
+        }
+
+        let packet = self.gen_packet(
+            self.id,
+            BROADCAST_ID,
+            BROADCAST_ID,
+            channel_id,
+            PacketKind::Control(ControlKind::Preq),
+        );
+        self.send_packet_buffer.push_back(packet);
     }
 
     fn routing(&mut self, packet: &MultiTreePacket) -> Vec<MultiTreePacket> {
         assert!(packet.dest_id != self.id);
         assert!(packet.dest_id != BROADCAST_ID);
 
-        // todo ここは，table用のget関数を用意する
         // もし宛先がテーブルにあれば
-        let table = self.tables.get(packet.channel_id as usize).unwrap();
-
-        if table.contains_key(&packet.dest_id) {
-            // テーブルから次のノードを取得
-            let next_id = table.get(&packet.dest_id).copied().unwrap();
+        if let Some(next_id) = self.table_next_id(packet.dest_id, packet.channel_id) {
             // パケットを生成
-            let routing_packet = self.gen_packet(
+            let mut routing_packet = self.gen_packet(
                 packet.source_id,
                 packet.dest_id,
                 next_id,
                 packet.channel_id,
-                packet.message.clone(),
+                packet.kind.clone(),
             );
+            routing_packet.segment = packet.segment;
+            routing_packet.size_bytes = packet.size_bytes;
+            routing_packet.assigned_id = packet.assigned_id;
             return vec![routing_packet];
         } else {
             // 親ノードあて
-            if let Some(parent_id) = self.parent_ids.get(packet.channel_id as usize).copied() {
+            if let Some(parent_id) = self
+                .parent_ids
+                .get(packet.channel_id as usize)
+                .copied()
+                .filter(|&parent_id| parent_id != 0)
+            {
                 // パケットを生成
-                let routing_packet = self.gen_packet(
+                let mut routing_packet = self.gen_packet(
                     packet.source_id,
                     packet.dest_id,
                     parent_id,
                     packet.channel_id,
-                    packet.message.clone(),
+                    packet.kind.clone(),
                 );
+                routing_packet.segment = packet.segment;
+                routing_packet.size_bytes = packet.size_bytes;
+                routing_packet.assigned_id = packet.assigned_id;
                 return vec![routing_packet];
             }
         }
@@ -688,3 +1061,117 @@ impl MultiTreeFunction {
         vec![]
     }
 }
+
+impl NodeBehavior for MultiTreeFunction {
+    type Packet = MultiTreePacket;
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn node_type(&self) -> &NodeType {
+        &self.node_type
+    }
+
+    fn flooding(&mut self) -> &mut Flooding<MultiTreePacket> {
+        &mut self.flooding
+    }
+
+    fn relays_broadcast(&self) -> bool {
+        // どのチャネルにも参加していなければ中継しない
+        self.network_joined.iter().any(|&x| x)
+    }
+
+    fn push_new_packet(&mut self, packet: &InjectionPacket) -> Result<(), String> {
+        MultiTreeFunction::push_new_packet(self, packet)
+    }
+
+    fn process_received_packet_router(&mut self, packet: &MultiTreePacket) -> Vec<MultiTreePacket> {
+        MultiTreeFunction::process_received_packet_router(self, packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::vid::add_to_vid_table;
+
+    // COORDINATOR_CNTに依存しないように，channel_id番目の木のコーディネータを作る
+    fn coordinator(channel_id: u8, channel_num: u8) -> MultiTreeFunction {
+        let mut coordinator = MultiTreeFunction::new(&NodeType::Coordinator, channel_num);
+        let id = channel_id as u32 + 1;
+        coordinator.id = id;
+        coordinator.parent_ids = vec![0; channel_num as usize];
+        coordinator.parent_ids[channel_id as usize] = id;
+        coordinator.network_joined = vec![false; channel_num as usize];
+        coordinator.network_joined[channel_id as usize] = true;
+        coordinator.next_address = channel_num as u32 + id;
+        coordinator
+    }
+
+    fn jreq(source_id: u32, channel_id: u8) -> MultiTreePacket {
+        MultiTreePacket {
+            kind: PacketKind::Control(ControlKind::Jreq),
+            packet_id: 0,
+            dest_id: channel_id as u32 + 1,
+            source_id,
+            prev_id: source_id,
+            channel_id,
+            next_id: channel_id as u32 + 1,
+            dest_ids: vec![],
+            assigned_id: None,
+            size_bytes: None,
+            segment: None,
+        }
+    }
+
+    #[test]
+    fn test_address_assignment() {
+        let mut coordinators = [coordinator(0, 2), coordinator(1, 2)];
+        let mut router = MultiTreeFunction::new(&NodeType::Router, 2);
+        let temp_id = router.id;
+        assert!(temp_id >= SHORT_ADDRESS_LIMIT);
+        add_to_vid_table(temp_id, "multi_tree_router");
+        router.parent_ids = vec![1, 2];
+
+        // 2つの木に同時に参加しても，コーディネータごとに重ならないアドレスを割り当てる
+        let jack0 = coordinators[0].process_received_packet(&jreq(temp_id, 0));
+        let jack1 = coordinators[1].process_received_packet(&jreq(temp_id, 1));
+        assert_eq!(jack0[0].assigned_id, Some(3));
+        assert_eq!(jack1[0].assigned_id, Some(4));
+        assert_eq!(coordinators[0].tables[0].get(&3), Some(&temp_id));
+
+        // 最初に届いたアドレスに切り替え，あとから届いたアドレスは使わない
+        router.process_received_packet(&jack0[0]);
+        assert_eq!(router.id, 3);
+        add_to_vid_table(3, "multi_tree_router");
+        let mut jack1 = jack1[0].clone();
+        // next_idは受信時にpidから今のidに変換される
+        jack1.next_id = router.id;
+        router.process_received_packet(&jack1);
+        assert_eq!(router.id, 3);
+        assert!(router.is_joined());
+
+        // 他の木では仮のidで学習した経路を割り当てられたアドレス宛てにも使う
+        assert_eq!(coordinators[1].table_next_id(3, 1), Some(temp_id));
+    }
+
+    #[test]
+    fn test_address_exhausted() {
+        use crate::log::aggregate_join_reject_log;
+
+        let mut coordinator = coordinator(0, 2);
+        coordinator.next_address = SHORT_ADDRESS_LIMIT;
+        let router = MultiTreeFunction::new(&NodeType::Router, 2);
+        add_to_vid_table(router.id, "multi_tree_rejected");
+
+        // アドレスが残っていなければjackを返さず，子にもしない
+        assert!(coordinator
+            .process_received_packet(&jreq(router.id, 0))
+            .is_empty());
+        assert!(coordinator.children_ids[0].is_empty());
+        let report = aggregate_join_reject_log();
+        assert_eq!(report.reject_count, 1);
+        assert_eq!(report.node_ids, vec!["multi_tree_rejected"]);
+    }
+}
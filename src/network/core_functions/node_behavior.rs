@@ -0,0 +1,147 @@
+use super::flooding::Flooding;
+use super::packets::{ControlKind, InjectionPacket, PacketKind, ProtocolPacket};
+use super::BROADCAST_ID;
+use crate::network::vid::get_pid;
+use crate::sim::node_type::NodeType;
+
+// defaultとmulti_treeで共通の，ノード種別ごとのパケットの扱い
+// エンドデバイスとユーザー定義のノード種別の振る舞いがルーティング方式で変わらないようにする
+pub(crate) trait NodeBehavior {
+    type Packet: ProtocolPacket;
+
+    fn id(&self) -> u32;
+    fn node_type(&self) -> &NodeType;
+    fn flooding(&mut self) -> &mut Flooding<Self::Packet>;
+    // broadcastを中継できるだけネットワークに参加しているか
+    fn relays_broadcast(&self) -> bool;
+    fn push_new_packet(&mut self, packet: &InjectionPacket) -> Result<(), String>;
+    fn process_received_packet_router(&mut self, packet: &Self::Packet) -> Vec<Self::Packet>;
+
+    #[allow(unreachable_code)]
+    fn process_received_packet_end_device(&mut self, packet: &Self::Packet) -> Vec<Self::Packet> {
+        match (packet.dest_id(), packet.kind()) {
+            // BROADCAST "preq"
+            (BROADCAST_ID, PacketKind::Control(ControlKind::Preq)) => {
+                // エンドデバイスは子を持たないので応答しない
+                return vec![];
+            }
+
+            // BROADCAST "pack", "jreq", "jack", "lost", "links", "routes"
+            (
+                BROADCAST_ID,
+                PacketKind::Control(
+                    ControlKind::Pack
+                    | ControlKind::Jreq
+                    | ControlKind::Jack
+                    | ControlKind::Lost
+                    | ControlKind::Links
+                    | ControlKind::Routes,
+                ),
+            ) => {
+                panic!(
+                    "{} destination cannot be broadcast",
+                    packet.kind().message()
+                );
+            }
+
+            // BROADCAST user message
+            (BROADCAST_ID, PacketKind::Data(_)) => {
+                return self.flood(packet);
+            }
+
+            // address to me, "pack", "jack", "lost"
+            (
+                id,
+                PacketKind::Control(ControlKind::Pack | ControlKind::Jack | ControlKind::Lost),
+            ) if id == self.id() => {
+                // 参加手順はルータと同じ
+                return self.process_received_packet_router(packet);
+            }
+
+            // address to me, "preq", "jreq", "links", "routes"
+            (
+                id,
+                PacketKind::Control(
+                    ControlKind::Preq
+                    | ControlKind::Jreq
+                    | ControlKind::Links
+                    | ControlKind::Routes,
+                ),
+            ) if id == self.id() => {
+                panic!(
+                    "{} cannot be reached to end device",
+                    packet.kind().message()
+                );
+            }
+
+            // address to me, user packet
+            (id, PacketKind::Data(_)) if id == self.id() => {
+                // message arrived
+                self.notify_user_type_hook(packet);
+                return vec![];
+            }
+
+            // address to others
+            _ => {
+                // エンドデバイスは中継しない
+                return vec![];
+            }
+        }
+
+        vec![] // should be unreachable
+    }
+
+    // ユーザー定義のノード種別なら受信をフックに通知し，返されたパケットを送信する
+    fn notify_user_type_hook(&mut self, packet: &Self::Packet) {
+        let Some(hook) = self.node_type().user_type_hook() else {
+            return;
+        };
+        let PacketKind::Data(message) = packet.kind() else {
+            return;
+        };
+
+        let node_id = get_pid(self.id()).unwrap();
+        let source_id = get_pid(packet.source_id()).unwrap();
+        for new_packet in hook.on_receive(&node_id, &source_id, message) {
+            // フックが知らないノード宛てのパケットを返したら捨てる
+            if let Err(error) = self.push_new_packet(&new_packet) {
+                eprintln!("node {node_id}: dropped a packet from the user type hook: {error}");
+            }
+        }
+    }
+
+    // 他ノード宛てのパケットを中継するかどうか
+    fn forwards(&self, source_id: u32, dest_id: u32) -> bool {
+        if self.node_type().base_type() == NodeType::EndDevice {
+            return false;
+        }
+
+        match self.node_type().user_type_hook() {
+            Some(hook) => hook.forwards(
+                &get_pid(self.id()).unwrap(),
+                &get_pid(source_id).unwrap(),
+                &get_pid(dest_id).unwrap(),
+            ),
+            None => true,
+        }
+    }
+
+    // ユーザーのbroadcast/multicastを重複を除いて隣接ノードへ再送する
+    fn flood(&mut self, packet: &Self::Packet) -> Vec<Self::Packet> {
+        if !self.flooding().record(packet) {
+            return vec![];
+        }
+
+        if packet.dest_ids().is_empty() || packet.dest_ids().contains(&self.id()) {
+            self.notify_user_type_hook(packet);
+        }
+
+        if !self.relays_broadcast() || !self.forwards(packet.source_id(), BROADCAST_ID) {
+            return vec![];
+        }
+
+        let id = self.id();
+        self.flooding().relay(id, BROADCAST_ID, packet);
+        vec![]
+    }
+}
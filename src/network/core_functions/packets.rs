@@ -1,8 +1,70 @@
+use super::up_down::UpDownRoutes;
 use crate::hardware::constants::DATA_BYTE_PER_FLIT;
 use crate::utils::div_ceil;
 use serde::{Deserialize, Serialize};
 
 use crate::network::vid::get_vid;
+
+// 参加プロトコルで用いる制御メッセージ
+// lostは親を失ったことを子に通知する
+// linksとroutesはup*/down*ルーティングで使い，
+// linksで隣接ノードをコーディネータに報告し，routesでコーディネータが計算した経路を配る
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControlKind {
+    Preq,
+    Pack,
+    Jreq,
+    Jack,
+    Lost,
+    Links,
+    Routes,
+}
+
+impl ControlKind {
+    // ログやイベントで使う名前
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlKind::Preq => "preq",
+            ControlKind::Pack => "pack",
+            ControlKind::Jreq => "jreq",
+            ControlKind::Jack => "jack",
+            ControlKind::Lost => "lost",
+            ControlKind::Links => "links",
+            ControlKind::Routes => "routes",
+        }
+    }
+}
+
+// パケットの種類
+// ユーザーのメッセージが制御メッセージと同じ文字列でもデータとして扱う
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PacketKind {
+    Control(ControlKind),
+    Data(String),
+}
+
+impl PacketKind {
+    pub fn is_control(&self) -> bool {
+        matches!(self, PacketKind::Control(_))
+    }
+
+    // ログに記録するメッセージ
+    pub fn message(&self) -> String {
+        match self {
+            PacketKind::Control(kind) => kind.name().to_string(),
+            PacketKind::Data(message) => message.clone(),
+        }
+    }
+}
+
+// トランスポート層のヘッダ
+// seqは送信元と宛先の組ごとの通し番号で，ackならseqのセグメントを受け取ったことを知らせる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    pub seq: u32,
+    pub ack: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct Packet {
     pub data: Vec<u8>,
@@ -12,11 +74,24 @@ pub struct Packet {
     pub source_id: String,
     pub packet_id: u32,
     pub channel_id: u8,
+    // ユーザーがパケットを生成したサイクル，送信元でのみ分かる
+    pub inject_cycle: Option<u32>,
+    // 入力で指定されたペイロードのバイト数，受信したパケットではNone
+    // 指定されていればヘッダと合わせた大きさからフリット数を決める
+    // 制御パケットはヘッダのみとして0になる
+    pub size_bytes: Option<u32>,
+    // 入力で指定されたトラフィッククラス，送信元でのみ分かる
+    pub class: Option<String>,
+    // 残りのホップ数
+    pub ttl: u8,
 }
 
 impl Packet {
-    pub fn get_flits_len(&self) -> u32 {
-        div_ceil(self.data.len() as u32, DATA_BYTE_PER_FLIT)
+    pub fn get_flits_len(&self, header_bytes: u32) -> u32 {
+        match self.size_bytes {
+            Some(size_bytes) => div_ceil(size_bytes + header_bytes, DATA_BYTE_PER_FLIT),
+            None => div_ceil(self.data.len() as u32, DATA_BYTE_PER_FLIT),
+        }
     }
 }
 
@@ -25,18 +100,88 @@ pub struct InjectionPacket {
     pub message: String,
     pub dest_id: String,
     pub source_id: String,
+    // dest_idがbroadcastのとき，空でなければmulticastの宛先
+    #[serde(default)]
+    pub dest_ids: Vec<String>,
+    // 入力で指定された生成サイクル，送信元でのキュー待ちも遅延に含めるために使う
+    #[serde(default)]
+    pub inject_cycle: Option<u32>,
+    // 大きいほど送信元のキューで先に送られる
+    #[serde(default)]
+    pub priority: u8,
+    // ペイロードのバイト数，指定されていればmessageの長さではなくこれでフリット数を決める
+    // ペイロードの中身は作らず，バイト数だけを数える
+    #[serde(default)]
+    pub size_bytes: Option<u32>,
+    // ペイロードのパターン，size_bytesとともに指定する
+    #[serde(default)]
+    pub payload: Option<String>,
+    // トラフィッククラス，クラスの仮想チャネルで送る
+    #[serde(default)]
+    pub class: Option<String>,
+    // トランスポート層を使うときのヘッダ
+    #[serde(default)]
+    pub segment: Option<Segment>,
+}
+
+// defaultとmulti_treeのパケットで共通の操作
+pub(crate) trait ProtocolPacket: Clone {
+    fn kind(&self) -> &PacketKind;
+    fn dest_id(&self) -> u32;
+    fn source_id(&self) -> u32;
+    fn packet_id(&self) -> u32;
+    // multicastの宛先，空ならbroadcast
+    fn dest_ids(&self) -> &[u32];
+    fn set_hop(&mut self, prev_id: u32, next_id: u32);
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[allow(dead_code)]
 pub(crate) struct DefaultPacket {
-    pub(crate) message: String,
+    pub(crate) kind: PacketKind,
     pub(crate) dest_id: u32,
     pub(crate) prev_id: u32,
     pub(crate) next_id: u32,
     pub(crate) source_id: u32,
     pub(crate) packet_id: u32,
     pub(crate) channel_id: u8,
+    pub(crate) dest_ids: Vec<u32>,
+    // jackでコーディネータが割り当てたアドレス
+    pub(crate) assigned_id: Option<u32>,
+    // linksで報告する隣接ノードのpid
+    pub(crate) links: Vec<String>,
+    // routesでコーディネータが配るup*/down*の経路
+    pub(crate) up_down_routes: Option<UpDownRoutes>,
+    // 入力で指定されたペイロードのバイト数，ペイロードそのものは運ばない
+    pub(crate) size_bytes: Option<u32>,
+    pub(crate) segment: Option<Segment>,
+}
+
+impl ProtocolPacket for DefaultPacket {
+    fn kind(&self) -> &PacketKind {
+        &self.kind
+    }
+
+    fn dest_id(&self) -> u32 {
+        self.dest_id
+    }
+
+    fn source_id(&self) -> u32 {
+        self.source_id
+    }
+
+    fn packet_id(&self) -> u32 {
+        self.packet_id
+    }
+
+    fn dest_ids(&self) -> &[u32] {
+        &self.dest_ids
+    }
+
+    fn set_hop(&mut self, prev_id: u32, next_id: u32) {
+        self.prev_id = prev_id;
+        self.next_id = next_id;
+    }
 }
 
 impl DefaultPacket {
@@ -58,13 +203,46 @@ impl DefaultPacket {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[allow(dead_code)]
 pub(crate) struct MultiTreePacket {
-    pub(crate) message: String,
+    pub(crate) kind: PacketKind,
     pub(crate) dest_id: u32,
     pub(crate) prev_id: u32,
     pub(crate) next_id: u32,
     pub(crate) source_id: u32,
     pub(crate) packet_id: u32,
     pub(crate) channel_id: u8,
+    pub(crate) dest_ids: Vec<u32>,
+    // jackでコーディネータが割り当てたアドレス
+    pub(crate) assigned_id: Option<u32>,
+    // 入力で指定されたペイロードのバイト数，ペイロードそのものは運ばない
+    pub(crate) size_bytes: Option<u32>,
+    pub(crate) segment: Option<Segment>,
+}
+
+impl ProtocolPacket for MultiTreePacket {
+    fn kind(&self) -> &PacketKind {
+        &self.kind
+    }
+
+    fn dest_id(&self) -> u32 {
+        self.dest_id
+    }
+
+    fn source_id(&self) -> u32 {
+        self.source_id
+    }
+
+    fn packet_id(&self) -> u32 {
+        self.packet_id
+    }
+
+    fn dest_ids(&self) -> &[u32] {
+        &self.dest_ids
+    }
+
+    fn set_hop(&mut self, prev_id: u32, next_id: u32) {
+        self.prev_id = prev_id;
+        self.next_id = next_id;
+    }
 }
 
 impl MultiTreePacket {
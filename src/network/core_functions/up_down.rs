@@ -0,0 +1,502 @@
+use crate::sim::node::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// up*/down*ルーティングで各ノードが保持する経路情報
+// 経路は物理ID(pid)で保持する
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UpDownRoutes {
+    // BFS木の親になりうる隣接ノード(深さが1小さいノード)
+    pub parent_candidates: HashSet<NodeId>,
+    // BFS木での深さ
+    pub level: u32,
+    // このノードからupリンクで接続される隣接ノード
+    pub up_neighbors: HashSet<NodeId>,
+    // まだupリンクを使ってよいパケットの次ホップ
+    pub up_table: HashMap<NodeId, NodeId>,
+    // downリンクしか使えないパケットの次ホップ
+    pub down_table: HashMap<NodeId, NodeId>,
+}
+
+impl UpDownRoutes {
+    // prevからdownリンクで届いたパケットはdownリンクしか使えない
+    pub fn next_hop(&self, dest_id: &str, prev_id: &str) -> Option<NodeId> {
+        if self.up_neighbors.contains(prev_id) {
+            self.down_table.get(dest_id).cloned()
+        } else {
+            self.up_table.get(dest_id).cloned()
+        }
+    }
+}
+
+// 隣接ノードを失ってから，linksで報告するまでのサイクル数
+// 続けて失ったリンクはまとめて報告する
+const LINKS_DELAY_CYCLES: u32 = 50;
+// 報告が届かなくなってから，コーディネータが経路を計算し直すまでのサイクル数
+// 参加が続いている間は経路を配らず，トポロジが落ち着いてからまとめて配る
+const ROUTES_DELAY_CYCLES: u32 = 200;
+
+// up*/down*ルーティングの制御メッセージでやりとりする状態
+// 各ノードは受信したパケットの送信元から隣接ノードを知り，jreqに載せてコーディネータに報告する
+// 参加した後に隣接ノードを失ったときはlinksで報告し直す
+// コーディネータは報告をまとめたトポロジでBFS木を作って経路を計算し，routesで各ノードに配る
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UpDown {
+    // コーディネータから配られた経路
+    pub routes: UpDownRoutes,
+    // パケットを受信したことのある隣接ノード
+    neighbors: HashSet<NodeId>,
+    // 前回take_changed_linksを呼んでから隣接ノードが変わったか
+    neighbors_changed: bool,
+    // 隣接ノードを失ってから，linksで報告するまでのサイクル数
+    links_wait_cycles: Option<u32>,
+    // 以下はコーディネータのみ使う
+    // 報告してきたノードのidと，最後に報告された隣接ノード
+    reports: HashMap<NodeId, (u32, HashSet<NodeId>)>,
+    // どちらかの端のノードが報告したリンク，(小さい方のpid, 大きい方のpid)
+    edges: HashSet<(NodeId, NodeId)>,
+    // 経路を計算し直すまでのサイクル数
+    routes_wait_cycles: Option<u32>,
+    // 各ノードに最後に配った経路
+    sent_routes: HashMap<NodeId, UpDownRoutes>,
+}
+
+impl UpDown {
+    pub fn add_neighbor(&mut self, node_id: &str) {
+        if self.neighbors.insert(node_id.to_string()) {
+            self.neighbors_changed = true;
+        }
+    }
+
+    pub fn remove_neighbor(&mut self, node_id: &str) {
+        if self.neighbors.remove(node_id) {
+            self.neighbors_changed = true;
+            self.links_wait_cycles = Some(LINKS_DELAY_CYCLES);
+        }
+    }
+
+    // jreqやlinksで報告する隣接ノード
+    pub fn links(&self) -> Vec<NodeId> {
+        let mut links: Vec<NodeId> = self.neighbors.iter().cloned().collect();
+        links.sort();
+        links
+    }
+
+    // 前回呼ばれてから隣接ノードが変わっていれば，隣接ノードを返す
+    // コーディネータが自分のリンクを報告を待たずに反映するのに使う
+    pub fn take_changed_links(&mut self) -> Option<Vec<NodeId>> {
+        if !std::mem::take(&mut self.neighbors_changed) {
+            return None;
+        }
+        Some(self.links())
+    }
+
+    // 隣接ノードを失ってから待ち時間が過ぎていれば，linksで報告する隣接ノードを返す
+    pub fn take_lost_links(&mut self) -> Option<Vec<NodeId>> {
+        if !count_down(&mut self.links_wait_cycles) {
+            return None;
+        }
+        Some(self.links())
+    }
+
+    // 報告された隣接ノードをリンクに加え，前回の報告から消えたリンクを除いて，経路の計算を予約する
+    // 報告は参加し直したときにも届くので，そのノードには経路を配り直す
+    pub fn update_links(&mut self, node_id: &str, id: u32, links: &[NodeId]) {
+        let edge = |other: &NodeId| {
+            if node_id < other.as_str() {
+                (node_id.to_string(), other.clone())
+            } else {
+                (other.clone(), node_id.to_string())
+            }
+        };
+
+        let links: HashSet<NodeId> = links.iter().cloned().collect();
+        if let Some((_, reported)) = self.reports.get(node_id) {
+            for lost in reported.difference(&links) {
+                self.edges.remove(&edge(lost));
+            }
+        }
+        for link in links.iter() {
+            self.edges.insert(edge(link));
+        }
+        self.reports.insert(node_id.to_string(), (id, links));
+        self.sent_routes.remove(node_id);
+        self.routes_wait_cycles = Some(ROUTES_DELAY_CYCLES);
+    }
+
+    // 待ち時間が過ぎていれば，rootを根として経路を計算し直す
+    // 前回配ったものから変わった経路を，配り先のidの順に返す
+    // is_reachableがfalseのノード(jackがまだ届いていないなど)は次の計算まで送らない
+    pub fn take_changed_routes(
+        &mut self,
+        root: &str,
+        is_reachable: impl Fn(u32) -> bool,
+    ) -> Vec<(u32, UpDownRoutes)> {
+        if !count_down(&mut self.routes_wait_cycles) {
+            return vec![];
+        }
+
+        let mut neighbors: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
+        for (a, b) in self.edges.iter() {
+            neighbors.entry(a.clone()).or_default().push(b.clone());
+            neighbors.entry(b.clone()).or_default().push(a.clone());
+        }
+        // 報告していないノード(エンドデバイスなど)は隣接ノードが分からないので経由しない
+        let leaves: HashSet<NodeId> = neighbors
+            .keys()
+            .filter(|node_id| !self.reports.contains_key(*node_id))
+            .cloned()
+            .collect();
+        let routes = build_up_down_routes(root, &neighbors, &leaves);
+
+        let mut changed = Vec::new();
+        for (node_id, (id, _)) in self.reports.iter() {
+            let Some(node_routes) = routes.get(node_id) else {
+                continue;
+            };
+            if self.sent_routes.get(node_id) == Some(node_routes) {
+                continue;
+            }
+            if !is_reachable(*id) {
+                self.routes_wait_cycles = Some(ROUTES_DELAY_CYCLES);
+                continue;
+            }
+            self.sent_routes
+                .insert(node_id.clone(), node_routes.clone());
+            changed.push((*id, node_routes.clone()));
+        }
+        changed.sort_by_key(|(id, _)| *id);
+        changed
+    }
+}
+
+// 待ち時間を1サイクル進め，待ち終わったらtrueを返す
+fn count_down(wait_cycles: &mut Option<u32>) -> bool {
+    match *wait_cycles {
+        None => false,
+        Some(0) => {
+            *wait_cycles = None;
+            true
+        }
+        Some(cycles) => {
+            *wait_cycles = Some(cycles - 1);
+            false
+        }
+    }
+}
+
+// rootを根とするBFS木でリンクの向きを決め，down→upの折り返しを含まない最短経路を求める
+// leavesに含まれるノードは経路の端点にしかならない
+pub fn build_up_down_routes(
+    root: &str,
+    neighbors: &HashMap<NodeId, Vec<NodeId>>,
+    leaves: &HashSet<NodeId>,
+) -> HashMap<NodeId, UpDownRoutes> {
+    let sorted_neighbors = |id: &str| -> Vec<NodeId> {
+        let mut list = neighbors.get(id).cloned().unwrap_or_default();
+        list.sort();
+        list.dedup();
+        list
+    };
+
+    // BFSで深さを求める
+    let mut levels: HashMap<NodeId, u32> = HashMap::new();
+    let mut queue = VecDeque::new();
+
+    levels.insert(root.to_string(), 0);
+    queue.push_back(root.to_string());
+
+    while let Some(id) = queue.pop_front() {
+        if leaves.contains(&id) {
+            continue;
+        }
+        let level = levels[&id];
+        for neighbor in sorted_neighbors(&id) {
+            if levels.contains_key(&neighbor) {
+                continue;
+            }
+            levels.insert(neighbor.clone(), level + 1);
+            queue.push_back(neighbor);
+        }
+    }
+
+    // (深さ, id)が小さい方向をupとする
+    let key = |id: &str| (levels[id], id.to_string());
+    let mut order: Vec<NodeId> = levels.keys().cloned().collect();
+    order.sort_by_key(|id| key(id));
+
+    // 到達可能なノード間のリンクのみ扱う
+    let up_neighbors: HashMap<NodeId, Vec<NodeId>> = order
+        .iter()
+        .map(|id| {
+            let list = sorted_neighbors(id)
+                .into_iter()
+                .filter(|n| levels.contains_key(n) && key(n) < key(id))
+                .collect();
+            (id.clone(), list)
+        })
+        .collect();
+    let down_neighbors: HashMap<NodeId, Vec<NodeId>> = order
+        .iter()
+        .map(|id| {
+            let list = sorted_neighbors(id)
+                .into_iter()
+                .filter(|n| levels.contains_key(n) && key(n) > key(id))
+                .collect();
+            (id.clone(), list)
+        })
+        .collect();
+
+    let mut routes: HashMap<NodeId, UpDownRoutes> = order
+        .iter()
+        .map(|id| {
+            (
+                id.clone(),
+                UpDownRoutes {
+                    parent_candidates: up_neighbors[id]
+                        .iter()
+                        .filter(|n| levels[*n] + 1 == levels[id])
+                        .cloned()
+                        .collect(),
+                    level: levels[id],
+                    up_neighbors: up_neighbors[id].iter().cloned().collect(),
+                    up_table: HashMap::new(),
+                    down_table: HashMap::new(),
+                },
+            )
+        })
+        .collect();
+
+    for dest in order.iter() {
+        // leavesはdestである場合を除いて経由できない
+        let is_transit = |id: &NodeId| id == dest || !leaves.contains(id);
+
+        // downリンクのみを使ったdestまでの距離
+        // downリンクは(深さ, id)が大きくなる向きなので，大きい順に処理すればよい
+        let mut dist_down: HashMap<&NodeId, u32> = HashMap::new();
+        dist_down.insert(dest, 0);
+        for id in order.iter().rev() {
+            if id == dest {
+                continue;
+            }
+            if let Some(d) = down_neighbors[id]
+                .iter()
+                .filter(|n| is_transit(n))
+                .filter_map(|n| dist_down.get(n))
+                .min()
+            {
+                dist_down.insert(id, d + 1);
+            }
+        }
+
+        // up*down*を満たす経路でのdestまでの距離
+        let mut dist_any: HashMap<&NodeId, u32> = HashMap::new();
+        for id in order.iter() {
+            let via_up = up_neighbors[id]
+                .iter()
+                .filter(|n| is_transit(n))
+                .filter_map(|n| dist_any.get(n))
+                .min()
+                .map(|d| d + 1);
+            let best = match (dist_down.get(id).copied(), via_up) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            if let Some(best) = best {
+                dist_any.insert(id, best);
+            }
+        }
+
+        for id in order.iter() {
+            if id == dest {
+                continue;
+            }
+            let route = routes.get_mut(id).unwrap();
+
+            let best_down = down_neighbors[id]
+                .iter()
+                .filter(|n| is_transit(n))
+                .filter_map(|n| dist_down.get(n).map(|d| (d + 1, n)))
+                .min();
+            if let Some((_, next)) = best_down {
+                route.down_table.insert(dest.clone(), next.clone());
+            }
+
+            let best_up = up_neighbors[id]
+                .iter()
+                .filter(|n| is_transit(n))
+                .filter_map(|n| dist_any.get(n).map(|d| (d + 1, n)))
+                .min();
+            let best = match (best_down, best_up) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            if let Some((_, next)) = best {
+                route.up_table.insert(dest.clone(), next.clone());
+            }
+        }
+    }
+
+    routes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(edges: &[(&str, &str)]) -> HashMap<NodeId, Vec<NodeId>> {
+        let mut neighbors: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
+        for (a, b) in edges {
+            neighbors
+                .entry(a.to_string())
+                .or_default()
+                .push(b.to_string());
+            neighbors
+                .entry(b.to_string())
+                .or_default()
+                .push(a.to_string());
+        }
+        neighbors
+    }
+
+    // 経路をたどり，down→upの折り返しがないことと経路長を確認する
+    fn walk(routes: &HashMap<NodeId, UpDownRoutes>, src: &str, dest: &str) -> Vec<NodeId> {
+        let mut path = vec![src.to_string()];
+        let mut prev = src.to_string();
+        let mut cur = src.to_string();
+        let mut went_down = false;
+
+        while cur != dest {
+            let next = routes[&cur].next_hop(dest, &prev).unwrap();
+            let is_up = routes[&cur].up_neighbors.contains(&next);
+            assert!(!(went_down && is_up), "down->up turn at {cur}");
+            went_down |= !is_up;
+
+            prev = cur;
+            cur = next;
+            path.push(cur.clone());
+            assert!(path.len() <= routes.len());
+        }
+        path
+    }
+
+    #[test]
+    fn test_build_up_down_routes_tree() {
+        let neighbors = graph(&[("0", "1"), ("0", "2"), ("1", "3")]);
+        let routes = build_up_down_routes("0", &neighbors, &HashSet::new());
+
+        assert!(routes["0"].parent_candidates.is_empty());
+        assert_eq!(
+            routes["3"].parent_candidates,
+            HashSet::from(["1".to_string()])
+        );
+        assert_eq!(routes["3"].level, 2);
+        assert_eq!(walk(&routes, "3", "2"), vec!["3", "1", "0", "2"]);
+    }
+
+    #[test]
+    fn test_build_up_down_routes_shortcut() {
+        // 0-1-3, 0-2-4 の木に 3-4 のリンクを加える
+        let neighbors = graph(&[("0", "1"), ("0", "2"), ("1", "3"), ("2", "4"), ("3", "4")]);
+        let routes = build_up_down_routes("0", &neighbors, &HashSet::new());
+
+        // 深さが同じならidが小さい方がupなので，4->3はup，3->4はdown
+        assert_eq!(walk(&routes, "4", "3"), vec!["4", "3"]);
+        assert_eq!(walk(&routes, "1", "4"), vec!["1", "3", "4"]);
+
+        // 2->4(down)->3(up) は禁止されるので木を通る
+        assert_eq!(walk(&routes, "2", "3"), vec!["2", "0", "1", "3"]);
+
+        for src in ["0", "1", "2", "3", "4"] {
+            for dest in ["0", "1", "2", "3", "4"] {
+                if src != dest {
+                    walk(&routes, src, dest);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_up_down_routes_leaves() {
+        // 3はエンドデバイスなので 1-3-4 は使えない
+        let neighbors = graph(&[("0", "1"), ("0", "2"), ("1", "3"), ("2", "4"), ("3", "4")]);
+        let leaves = HashSet::from(["3".to_string()]);
+        let routes = build_up_down_routes("0", &neighbors, &leaves);
+
+        assert_eq!(walk(&routes, "1", "4"), vec!["1", "0", "2", "4"]);
+        assert_eq!(walk(&routes, "3", "4"), vec!["3", "4"]);
+        assert_eq!(routes["4"].level, 2);
+    }
+
+    #[test]
+    fn test_up_down_exchange() {
+        let mut router = UpDown::default();
+        router.add_neighbor("2");
+        router.add_neighbor("0");
+        assert_eq!(router.links(), vec!["0", "2"]);
+        assert_eq!(
+            router.take_changed_links(),
+            Some(vec!["0".to_string(), "2".to_string()])
+        );
+        assert_eq!(router.take_changed_links(), None);
+
+        // 隣接ノードを失ったら，待ち時間の後にlinksで報告する
+        assert_eq!(router.take_lost_links(), None);
+        router.remove_neighbor("2");
+        for _ in 0..LINKS_DELAY_CYCLES {
+            assert_eq!(router.take_lost_links(), None);
+        }
+        assert_eq!(router.take_lost_links(), Some(vec!["0".to_string()]));
+        assert_eq!(router.take_lost_links(), None);
+
+        // 0がコーディネータ，1と3がルータで，2は報告していない
+        let mut coordinator = UpDown::default();
+        coordinator.update_links("0", 0, &["1".to_string()]);
+        coordinator.update_links("1", 1, &["2".to_string()]);
+        coordinator.update_links("3", 3, &["1".to_string()]);
+        for _ in 0..ROUTES_DELAY_CYCLES {
+            assert!(coordinator.take_changed_routes("0", |_| true).is_empty());
+        }
+        let routes = coordinator.take_changed_routes("0", |_| true);
+        assert_eq!(
+            routes.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![0, 1, 3]
+        );
+        // 0-1のリンクは0だけが，1-3のリンクは3だけが報告している
+        assert_eq!(routes[0].1.up_table["2"], "1");
+        assert_eq!(routes[1].1.level, 1);
+        assert_eq!(routes[1].1.down_table["2"], "2");
+        assert_eq!(routes[2].1.up_table["0"], "1");
+        assert!(coordinator.take_changed_routes("0", |_| true).is_empty());
+
+        // 参加し直したノードには，経路が変わらなくても配り直す
+        // アドレスがまだ使えない間は送らずに待ち直す
+        coordinator.update_links("3", 3, &["1".to_string()]);
+        for _ in 0..ROUTES_DELAY_CYCLES {
+            coordinator.take_changed_routes("0", |_| true);
+        }
+        assert!(coordinator
+            .take_changed_routes("0", |id| id != 3)
+            .is_empty());
+        for _ in 0..ROUTES_DELAY_CYCLES {
+            assert!(coordinator.take_changed_routes("0", |_| true).is_empty());
+        }
+        let routes = coordinator.take_changed_routes("0", |_| true);
+        assert_eq!(
+            routes.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![3]
+        );
+
+        // 1が2へのリンクを失ったと報告したら，2への経路がなくなる
+        coordinator.update_links("1", 1, &[]);
+        for _ in 0..ROUTES_DELAY_CYCLES {
+            coordinator.take_changed_routes("0", |_| true);
+        }
+        let routes = coordinator.take_changed_routes("0", |_| true);
+        assert_eq!(
+            routes.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![0, 1, 3]
+        );
+        assert!(!routes[1].1.down_table.contains_key("2"));
+    }
+}
@@ -1,10 +1,11 @@
 use crate::hardware::constants::DATA_BYTE_PER_FLIT;
 use crate::utils::div_ceil;
+use serde::{Deserialize, Serialize};
 use std::error;
 
 use super::core_functions::packets::Packet;
 
-#[derive(Clone, Default, Debug, PartialEq)]
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Flit {
     Header(HeaderFlit),
     Data(DataFlit),
@@ -131,6 +132,24 @@ impl Flit {
         }
     }
 
+    pub fn get_ttl(&self) -> Option<u8> {
+        match self {
+            Flit::Header(flit) => Some(flit.ttl),
+            Flit::Data(flit) => Some(flit.ttl),
+            Flit::Tail(flit) => Some(flit.ttl),
+            Flit::Ack(_) | Flit::Empty => None,
+        }
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        match self {
+            Flit::Header(flit) => flit.ttl = ttl,
+            Flit::Data(flit) => flit.ttl = ttl,
+            Flit::Tail(flit) => flit.ttl = ttl,
+            Flit::Ack(_) | Flit::Empty => {}
+        }
+    }
+
     pub fn get_packet_id(&self) -> Option<u32> {
         match self {
             Flit::Header(flit) => Some(flit.packet_id),
@@ -161,12 +180,23 @@ impl Flit {
         }
     }
 
+    // フリットが運ぶペイロードのバイト数
+    pub fn data_len(&self) -> u32 {
+        match self {
+            Flit::Header(flit) => flit.data.len() as u32,
+            Flit::Data(flit) => flit.data.len() as u32,
+            Flit::Tail(flit) => flit.data.len() as u32,
+            Flit::Ack(_) => 0,
+            Flit::Empty => 0,
+        }
+    }
+
     pub fn is_last(&self) -> bool {
         self.is_tail() || (self.is_header() && self.get_flits_len().unwrap_or(0) == 1)
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct HeaderFlit {
     pub source_id: String,
     pub dest_id: String,
@@ -176,9 +206,11 @@ pub struct HeaderFlit {
     pub flits_len: u32,
     pub data: Vec<u8>,
     pub channel_id: u8,
+    // 残りのホップ数
+    pub ttl: u8,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct TailFlit {
     pub source_id: String,
     pub dest_id: String,
@@ -189,9 +221,11 @@ pub struct TailFlit {
     pub data: Vec<u8>,
     pub packet_id: u32,
     pub channel_id: u8,
+    // 残りのホップ数
+    pub ttl: u8,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct DataFlit {
     pub source_id: String,
     pub dest_id: String,
@@ -202,9 +236,11 @@ pub struct DataFlit {
     pub data: Vec<u8>,
     pub packet_id: u32,
     pub channel_id: u8,
+    // 残りのホップ数
+    pub ttl: u8,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct AckFlit {
     pub source_id: String,
     pub dest_id: String,
@@ -213,12 +249,23 @@ pub struct AckFlit {
     pub channel_id: u8,
 }
 
-pub fn packet_to_flits(packet: &Packet) -> Vec<Flit> {
+pub fn packet_to_flits(packet: &Packet, header_bytes: u32) -> Vec<Flit> {
     let mut flits = Vec::new();
-    let flits_len = div_ceil(packet.data.len() as u32, DATA_BYTE_PER_FLIT);
+    let flits_len = packet.get_flits_len(header_bytes);
 
     // DATA_BYTE_PER_FLITでdataを分割する
-    for (flit_num, data_chunk) in packet.data.chunks(DATA_BYTE_PER_FLIT as usize).enumerate() {
+    // size_bytesを指定したパケットはフリット数が決まっているので，dataをflits_len個に分ける
+    let chunk_size = match packet.size_bytes {
+        Some(_) => div_ceil(packet.data.len() as u32, flits_len).max(1),
+        None => DATA_BYTE_PER_FLIT,
+    } as usize;
+    let data_chunks = (0..flits_len as usize).map(|flit_num| {
+        let begin = (flit_num * chunk_size).min(packet.data.len());
+        let end = (begin + chunk_size).min(packet.data.len());
+        &packet.data[begin..end]
+    });
+
+    for (flit_num, data_chunk) in data_chunks.enumerate() {
         if flit_num == 0 {
             flits.push(Flit::Header(HeaderFlit {
                 source_id: packet.source_id.clone(),
@@ -229,6 +276,7 @@ pub fn packet_to_flits(packet: &Packet) -> Vec<Flit> {
                 data: data_chunk.to_vec(),
                 flits_len,
                 channel_id: packet.channel_id,
+                ttl: packet.ttl,
             }));
             continue;
         } else if flit_num == flits_len as usize - 1 {
@@ -242,6 +290,7 @@ pub fn packet_to_flits(packet: &Packet) -> Vec<Flit> {
                 data: data_chunk.to_vec(),
                 packet_id: packet.packet_id,
                 channel_id: packet.channel_id,
+                ttl: packet.ttl,
             }));
             break;
         }
@@ -255,6 +304,7 @@ pub fn packet_to_flits(packet: &Packet) -> Vec<Flit> {
             data: data_chunk.to_vec(),
             packet_id: packet.packet_id,
             channel_id: packet.channel_id,
+            ttl: packet.ttl,
         }));
     }
     flits
@@ -276,17 +326,25 @@ pub fn flits_to_data(flits: &Vec<Flit>) -> Vec<u8> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hardware::constants::{DEFAULT_TTL, PACKET_HEADER_BYTES};
     #[test]
     fn test_data_to_flits() {
-        let flits = packet_to_flits(&Packet {
-            data: vec![0; 100],
-            dest_id: "dest".to_string(),
-            prev_id: "prev".to_string(),
-            next_id: "next".to_string(),
-            source_id: "source".to_string(),
-            packet_id: 0,
-            channel_id: 0,
-        });
+        let flits = packet_to_flits(
+            &Packet {
+                data: vec![0; 100],
+                dest_id: "dest".to_string(),
+                prev_id: "prev".to_string(),
+                next_id: "next".to_string(),
+                source_id: "source".to_string(),
+                packet_id: 0,
+                channel_id: 0,
+                inject_cycle: None,
+                size_bytes: None,
+                class: None,
+                ttl: DEFAULT_TTL,
+            },
+            PACKET_HEADER_BYTES,
+        );
         match DATA_BYTE_PER_FLIT {
             32 => {
                 assert_eq!(flits.len(), 5);
@@ -306,17 +364,55 @@ mod tests {
     }
     #[test]
     fn test_flits_to_data() {
-        let flits = packet_to_flits(&Packet {
-            data: vec![0; 100],
+        let flits = packet_to_flits(
+            &Packet {
+                data: vec![0; 100],
+                dest_id: "dest".to_string(),
+                prev_id: "prev".to_string(),
+                next_id: "next".to_string(),
+                source_id: "source".to_string(),
+                packet_id: 0,
+                channel_id: 0,
+                inject_cycle: None,
+                size_bytes: None,
+                class: None,
+                ttl: DEFAULT_TTL,
+            },
+            PACKET_HEADER_BYTES,
+        );
+
+        let data = flits_to_data(&flits);
+        assert_eq!(data.len(), 100);
+    }
+
+    #[test]
+    fn test_size_bytes_to_flits() {
+        // ヘッダと合わせて216バイトなのでdataの長さによらず4フリットになる
+        let packet = Packet {
+            data: vec![1; 100],
             dest_id: "dest".to_string(),
             prev_id: "prev".to_string(),
             next_id: "next".to_string(),
             source_id: "source".to_string(),
             packet_id: 0,
             channel_id: 0,
-        });
+            inject_cycle: None,
+            size_bytes: Some(200),
+            class: None,
+            ttl: DEFAULT_TTL,
+        };
+        assert_eq!(
+            packet.get_flits_len(PACKET_HEADER_BYTES),
+            div_ceil(200 + PACKET_HEADER_BYTES, DATA_BYTE_PER_FLIT)
+        );
 
-        let data = flits_to_data(&flits);
-        assert_eq!(data.len(), 100);
+        let flits = packet_to_flits(&packet, PACKET_HEADER_BYTES);
+        assert_eq!(
+            flits.len() as u32,
+            packet.get_flits_len(PACKET_HEADER_BYTES)
+        );
+        assert_eq!(flits[0].get_flits_len(), Some(flits.len() as u32));
+        assert!(flits.last().unwrap().is_tail());
+        assert_eq!(flits_to_data(&flits), packet.data);
     }
 }
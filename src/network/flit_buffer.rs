@@ -1,13 +1,17 @@
 use crate::network::flit::flits_to_data;
 use crate::network::flit::Flit;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::collections::VecDeque;
 
 use super::core_functions::packets::Packet;
+use crate::hardware::constants::REASSEMBLY_TIMEOUT_CYCLES;
+use crate::log::{post_reassembly_log, ReassemblyEvent};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FlitBuffer {
-    flit_buffer: VecDeque<Flit>,
+    // フリットと，それがバッファに入ったサイクル
+    flit_buffer: VecDeque<(Flit, u32)>,
 }
 
 impl FlitBuffer {
@@ -17,26 +21,39 @@ impl FlitBuffer {
         }
     }
 
-    pub fn push(&mut self, flit: &Flit) {
-        self.flit_buffer.push_back(flit.clone());
+    pub fn push(&mut self, flit: &Flit, cur_cycle: u32) {
+        self.flit_buffer.push_back((flit.clone(), cur_cycle));
     }
 
     pub fn pop(&mut self) -> Option<Flit> {
-        self.flit_buffer.pop_front()
+        self.flit_buffer.pop_front().map(|(flit, _)| flit)
+    }
+
+    // 先頭のフリットがバッファに入ったサイクル
+    pub fn head_cycle(&self) -> Option<u32> {
+        self.flit_buffer.front().map(|(_, cycle)| *cycle)
     }
 
     pub fn clear(&mut self) {
         self.flit_buffer.clear();
     }
 
+    pub fn retain(&mut self, mut f: impl FnMut(&Flit) -> bool) {
+        self.flit_buffer.retain(|(flit, _)| f(flit));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Flit> {
+        self.flit_buffer.iter().map(|(flit, _)| flit)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.flit_buffer.is_empty()
     }
 
     fn remove_duplicate_and_sort(&mut self) {
-        let mut unique_elements: VecDeque<Flit> = VecDeque::new();
+        let mut unique_elements: VecDeque<(Flit, u32)> = VecDeque::new();
         for elem in self.flit_buffer.iter() {
-            if !unique_elements.contains(elem) {
+            if !unique_elements.iter().any(|(flit, _)| *flit == elem.0) {
                 unique_elements.push_back(elem.clone());
             }
         }
@@ -45,7 +62,7 @@ impl FlitBuffer {
 
         self.flit_buffer
             .make_contiguous()
-            .sort_by_key(|a| a.get_flit_num());
+            .sort_by_key(|(flit, _)| flit.get_flit_num());
     }
 }
 
@@ -55,62 +72,122 @@ impl Default for FlitBuffer {
     }
 }
 
+// 組み立て中のパケット
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Reassembly {
+    flits: FlitBuffer,
+    // 最後にフリットが届いたサイクル
+    last_cycle: u32,
+    // 欠けているフリット番号を検出したか
+    has_gap: bool,
+}
+
+impl Reassembly {
+    fn contains(&self, flit_num: u32) -> bool {
+        self.flits
+            .iter()
+            .any(|flit| flit.get_flit_num() == Some(flit_num))
+    }
+
+    // ヘッダが届いていればフリット数
+    fn flits_len(&self) -> Option<u32> {
+        self.flits
+            .iter()
+            .find(|flit| flit.is_header())
+            .and_then(|flit| flit.get_flits_len())
+    }
+
+    // 末尾のフリットより前で届いていないフリット番号
+    fn missing_flit_nums(&self) -> Vec<u32> {
+        let Some(last_num) = self
+            .flits
+            .iter()
+            .filter(|flit| flit.is_last())
+            .filter_map(|flit| flit.get_flit_num())
+            .max()
+        else {
+            return vec![];
+        };
+
+        (0..last_num).filter(|num| !self.contains(*num)).collect()
+    }
+
+    fn is_complete(&self) -> bool {
+        match self.flits_len() {
+            Some(flits_len) => (0..flits_len).all(|num| self.contains(num)),
+            None => false,
+        }
+    }
+}
+
 // 最終的に到達したフリットを格納するバッファ
-#[derive(Debug, Clone, Default)]
+// フリットは順不同で届いてもよく，すべてのフリット番号が揃ったらパケットを組み立てる
+// REASSEMBLY_TIMEOUT_CYCLESの間フリットが届かなかった組み立ては破棄する
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ReceivedFlitsBuffer {
-    buffer: HashMap<String, FlitBuffer>,
+    buffer: HashMap<String, Reassembly>,
+    // 組み立てを終えたパケットとそのサイクル，遅れて届いた重複フリットを捨てるために使う
+    completed: HashMap<String, u32>,
 }
 
 impl ReceivedFlitsBuffer {
     pub fn new() -> Self {
         ReceivedFlitsBuffer {
             buffer: HashMap::new(),
+            completed: HashMap::new(),
         }
     }
 
-    pub fn push_flit(&mut self, flit: &Flit) {
+    pub fn iter(&self) -> impl Iterator<Item = &Flit> {
+        self.buffer
+            .values()
+            .flat_map(|reassembly| reassembly.flits.iter())
+    }
+
+    // フリットを格納し，パケットが揃ったら組み立てて返す
+    pub fn push_flit(&mut self, flit: &Flit, cur_cycle: u32) -> Option<Packet> {
         let from_id = flit.get_source_id().unwrap();
         let packet_id = flit.get_packet_id().unwrap();
         let key = format!("{}-{}", from_id, packet_id);
+        let flit_num = flit.get_flit_num().unwrap();
 
-        if !self.buffer.contains_key(&key) {
-            self.buffer.insert(key.clone(), FlitBuffer::new());
+        // 組み立て済みのパケットのフリットはヘッダも含めて重複として捨てる
+        // broadcastとmulticastはfloodingで別の隣接ノードからもう一つのコピーが届くので，ヘッダから受け付け直す
+        let flooded_copy = flit.is_header() && flit.is_broadcast();
+        if !flooded_copy && !self.buffer.contains_key(&key) && self.completed.contains_key(&key) {
+            post_reassembly_log(ReassemblyEvent::Duplicate);
+            return None;
         }
 
-        self.buffer.get_mut(&key).unwrap().push(flit);
-    }
-
-    pub fn pop_packet(&mut self, from_id: &str, packet_id: u32) -> Option<Packet> {
-        let key = format!("{}-{}", from_id, packet_id);
-        if !self.buffer.contains_key(&key) {
+        let reassembly = self.buffer.entry(key.clone()).or_insert(Reassembly {
+            flits: FlitBuffer::new(),
+            last_cycle: cur_cycle,
+            has_gap: false,
+        });
+        if reassembly.contains(flit_num) {
+            post_reassembly_log(ReassemblyEvent::Duplicate);
             return None;
         }
+        reassembly.flits.push(flit, cur_cycle);
+        reassembly.last_cycle = cur_cycle;
 
-        self.buffer
-            .get_mut(&key)
-            .unwrap()
-            .remove_duplicate_and_sort();
-
-        let flits: Vec<Flit> = self
-            .buffer
-            .get_mut(&key)
-            .unwrap()
-            .flit_buffer
-            .clone()
-            .into();
-
-        let tail_flit = flits.last().unwrap();
-        assert!(tail_flit.is_tail() || (tail_flit.is_header() && flits.len() == 1));
-
-        // issue #48
-        if flits.first().is_none() || !flits.first().unwrap().is_header() {
+        if !reassembly.is_complete() {
+            if !reassembly.has_gap && !reassembly.missing_flit_nums().is_empty() {
+                reassembly.has_gap = true;
+                post_reassembly_log(ReassemblyEvent::Partial);
+            }
             return None;
         }
 
-        let data = flits_to_data(&flits);
+        let mut reassembly = self.buffer.remove(&key).unwrap();
+        self.completed.insert(key, cur_cycle);
+        reassembly.flits.remove_duplicate_and_sort();
 
-        self.buffer.get_mut(&key).unwrap().clear();
+        let flits: Vec<Flit> = reassembly.flits.iter().cloned().collect();
+        let data = flits_to_data(&flits);
+        let tail_flit = flits.last().unwrap();
 
+        post_reassembly_log(ReassemblyEvent::Completed);
         Some(Packet {
             data,
             source_id: tail_flit.get_source_id().unwrap(),
@@ -119,13 +196,35 @@ impl ReceivedFlitsBuffer {
             prev_id: tail_flit.get_prev_id().unwrap(),
             packet_id: tail_flit.get_packet_id().unwrap(),
             channel_id: tail_flit.get_channel_id().unwrap(),
+            inject_cycle: None,
+            size_bytes: None,
+            class: None,
+            ttl: tail_flit.get_ttl().unwrap(),
         })
     }
+
+    // 時間切れの組み立てを破棄し，組み立て済みの記録を消す
+    pub fn expire(&mut self, cur_cycle: u32) {
+        let timed_out =
+            |last_cycle: u32| cur_cycle.saturating_sub(last_cycle) >= REASSEMBLY_TIMEOUT_CYCLES;
+
+        self.buffer.retain(|_, reassembly| {
+            if !timed_out(reassembly.last_cycle) {
+                return true;
+            }
+            post_reassembly_log(ReassemblyEvent::Dropped);
+            false
+        });
+        self.completed
+            .retain(|_, completed_cycle| !timed_out(*completed_cycle));
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hardware::constants::DEFAULT_TTL;
+    use crate::log::aggregate_reassembly_log;
     use crate::network::flit::{Flit, HeaderFlit};
     use crate::network::vid::add_to_vid_table;
 
@@ -136,6 +235,7 @@ mod tests {
 
         let flit0 = &Flit::Header(HeaderFlit {
             channel_id: 0,
+            ttl: DEFAULT_TTL,
             packet_id: 0,
             dest_id: "".to_string(),
             source_id: "".to_string(),
@@ -144,10 +244,11 @@ mod tests {
             data: vec![],
             flits_len: 0,
         });
-        flit_buffer.push(flit0);
+        flit_buffer.push(flit0, 0);
 
         let flit1 = &Flit::Header(HeaderFlit {
             channel_id: 0,
+            ttl: DEFAULT_TTL,
             packet_id: 0,
             dest_id: "".to_string(),
             source_id: "".to_string(),
@@ -156,10 +257,11 @@ mod tests {
             data: vec![],
             flits_len: 0,
         });
-        flit_buffer.push(flit1);
+        flit_buffer.push(flit1, 0);
 
         let flit2 = &Flit::Header(HeaderFlit {
             channel_id: 0,
+            ttl: DEFAULT_TTL,
             packet_id: 0,
             dest_id: "".to_string(),
             source_id: "".to_string(),
@@ -169,7 +271,7 @@ mod tests {
             flits_len: 0,
         });
 
-        flit_buffer.push(flit2);
+        flit_buffer.push(flit2, 0);
 
         assert_eq!(flit_buffer.pop(), Some(flit0.clone()));
         assert_eq!(flit_buffer.pop(), Some(flit1.clone()));
@@ -184,6 +286,7 @@ mod tests {
 
         let header_flit = &Flit::Header(HeaderFlit {
             channel_id: 0,
+            ttl: DEFAULT_TTL,
             packet_id: 0,
             dest_id: "".to_string(),
             source_id: "".to_string(),
@@ -192,8 +295,8 @@ mod tests {
             data: vec![],
             flits_len: 0,
         });
-        flit_buffer.push(header_flit);
-        flit_buffer.push(header_flit);
+        flit_buffer.push(header_flit, 0);
+        flit_buffer.push(header_flit, 0);
         flit_buffer.remove_duplicate_and_sort();
 
         assert_eq!(flit_buffer.pop(), Some(header_flit.clone()));
@@ -202,6 +305,7 @@ mod tests {
         for i in [4, 5, 2, 9, 7, 1, 8, 3, 6, 1, 0, 4] {
             let data_flit = &Flit::Data(crate::network::flit::DataFlit {
                 channel_id: 0,
+                ttl: DEFAULT_TTL,
                 packet_id: 0,
                 dest_id: "".to_string(),
                 source_id: "".to_string(),
@@ -211,7 +315,7 @@ mod tests {
                 resend_num: 0,
                 data: vec![],
             });
-            flit_buffer.push(data_flit);
+            flit_buffer.push(data_flit, 0);
         }
 
         flit_buffer.remove_duplicate_and_sort();
@@ -222,4 +326,82 @@ mod tests {
             prev_flit_num = flit.get_flit_num().unwrap();
         }
     }
+
+    fn packet_flits(dest_id: &str, flits_len: u32) -> Vec<Flit> {
+        crate::network::flit::packet_to_flits(
+            &Packet {
+                data: (0..flits_len as u8 * 64).collect(),
+                dest_id: dest_id.to_string(),
+                prev_id: "prev".to_string(),
+                next_id: dest_id.to_string(),
+                source_id: "source".to_string(),
+                packet_id: 0,
+                channel_id: 0,
+                inject_cycle: None,
+                size_bytes: None,
+                class: None,
+                ttl: DEFAULT_TTL,
+            },
+            crate::hardware::constants::PACKET_HEADER_BYTES,
+        )
+    }
+
+    #[test]
+    fn test_reassemble_out_of_order() {
+        let flits = packet_flits("dest", 3);
+        let mut buffer = ReceivedFlitsBuffer::new();
+
+        // 末尾が先に届いても欠けているフリットを待つ
+        assert!(buffer.push_flit(&flits[0], 0).is_none());
+        assert!(buffer.push_flit(&flits[2], 1).is_none());
+        assert!(buffer.push_flit(&flits[2], 2).is_none());
+        let packet = buffer.push_flit(&flits[1], 3).unwrap();
+        assert_eq!(packet.data, flits_to_data(&flits));
+
+        // 組み立て後に遅れて届いたフリットは重複として捨てる
+        assert!(buffer.push_flit(&flits[1], 4).is_none());
+        assert_eq!(buffer.iter().count(), 0);
+
+        let report = aggregate_reassembly_log();
+        assert_eq!(report.completed, 1);
+        assert_eq!(report.partial, 1);
+        assert_eq!(report.duplicate, 2);
+        assert_eq!(report.dropped, 0);
+    }
+
+    #[test]
+    fn test_duplicate_single_flit() {
+        let mut buffer = ReceivedFlitsBuffer::new();
+
+        // 同じユニキャストのヘッダがもう一度届いても組み立てない
+        let flits = packet_flits("dest", 1);
+        assert_eq!(flits.len(), 1);
+        assert!(buffer.push_flit(&flits[0], 0).is_some());
+        assert!(buffer.push_flit(&flits[0], 1).is_none());
+        assert_eq!(aggregate_reassembly_log().duplicate, 1);
+
+        // floodingで届いたbroadcastのもう一つのコピーは受け付ける
+        let flits = packet_flits("broadcast", 1);
+        assert!(buffer.push_flit(&flits[0], 2).is_some());
+        assert!(buffer.push_flit(&flits[0], 3).is_some());
+    }
+
+    #[test]
+    fn test_reassembly_timeout() {
+        let flits = packet_flits("dest", 3);
+        let mut buffer = ReceivedFlitsBuffer::new();
+
+        assert!(buffer.push_flit(&flits[1], 0).is_none());
+        assert!(buffer.push_flit(&flits[2], 10).is_none());
+        buffer.expire(REASSEMBLY_TIMEOUT_CYCLES);
+        assert_eq!(buffer.iter().count(), 2);
+
+        buffer.expire(10 + REASSEMBLY_TIMEOUT_CYCLES);
+        assert_eq!(buffer.iter().count(), 0);
+        assert!(buffer.buffer.is_empty());
+
+        let report = aggregate_reassembly_log();
+        assert_eq!(report.partial, 1);
+        assert_eq!(report.dropped, 1);
+    }
 }
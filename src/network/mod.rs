@@ -1,22 +1,46 @@
+pub mod arbiter;
 pub mod core_functions;
 pub mod flit;
 pub mod flit_buffer;
+pub mod transport;
 pub mod vid;
 
+use self::arbiter::Arbiter;
 use self::core_functions::packets::InjectionPacket;
-use self::core_functions::CoreFunction;
+use self::core_functions::{injection_vids, CoreFunction, RoutingTable};
 use self::flit::packet_to_flits;
 use self::flit_buffer::{FlitBuffer, ReceivedFlitsBuffer};
+use self::transport::Transport;
 use self::vid::*;
+use crate::hardware::constants::{DEFAULT_TTL, PACKET_HEADER_BYTES};
 use crate::hardware::switching::Switching;
+use serde::{Deserialize, Serialize};
 
-use crate::log::{post_new_packet_log, update_packet_log, NewPacketLogInfo, UpdatePacketLogInfo};
+use crate::log::{
+    post_broadcast_log, post_event, post_failure_loss_log, post_loop_drop_log, post_new_packet_log,
+    post_rejoin_log, post_revisit_log, update_broadcast_log, update_packet_log, update_rejoin_log,
+    NewBroadcastLogInfo, NewPacketLogInfo, UpdatePacketLogInfo,
+};
 use crate::network::core_functions::packets::Packet;
 use crate::network::flit::Flit;
 use crate::sim::node_type::NodeType;
+use crate::sim::observer::SimEvent;
 use std::collections::HashMap;
 
-#[derive(Debug, Clone)]
+// バッファに入っているフリットとパケットの数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferOccupancy {
+    // 送信待ちのフリット
+    pub sending_flits: usize,
+    // 受信して転送を待っているフリット
+    pub receiving_flits: usize,
+    // 自分宛てで，組み立て途中のパケットのフリット
+    pub reassembling_flits: usize,
+    // フリットに分割される前の送信待ちパケット
+    pub queued_packets: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Network {
     id: String,
     cur_cycle: u32,
@@ -27,6 +51,19 @@ pub struct Network {
     received_flits_buffer: ReceivedFlitsBuffer,
     send_history: (bool, Flit),
     channel_num: u8,
+    // 前のサイクルでネットワークに参加していたか
+    was_joined: bool,
+    // VID_TABLEに登録されているcoreのid
+    vid: u32,
+    // パケットのヘッダのバイト数，制御パケットはヘッダのみ
+    header_bytes: u32,
+    transport: Option<Transport>,
+    // 次のパケットを送る仮想チャネルの選び方
+    arbiter: Arbiter,
+    // 送信するパケットのTTL
+    ttl: u8,
+    // デバッグ用，同じノードを再び通ったパケットを記録する
+    loop_detection: bool,
 }
 
 impl Network {
@@ -46,11 +83,16 @@ impl Network {
             sending_flit_buffer.insert(i, FlitBuffer::new());
             receiving_flit_buffer.insert(i, FlitBuffer::new());
         }
-        let core = CoreFunction::new(rf_kind, node_type, vc_num);
+        // ランダムなidが他のノードと重なったらidを選び直す
+        let mut core = CoreFunction::new(rf_kind, node_type, vc_num);
+        while let Err(e) = try_add_to_vid_table(core.get_id(), id) {
+            if let NodeType::Coordinator = node_type {
+                panic!("node {id}: {e}");
+            }
+            core = CoreFunction::new(rf_kind, node_type, vc_num);
+        }
         let vid = core.get_id();
 
-        add_to_vid_table(vid, id);
-
         Self {
             id: id.to_string(),
             cur_cycle: 0,
@@ -61,9 +103,26 @@ impl Network {
             received_flits_buffer: ReceivedFlitsBuffer::new(),
             send_history: (false, Flit::default()),
             channel_num: vc_num,
+            was_joined: false,
+            vid,
+            header_bytes: PACKET_HEADER_BYTES,
+            transport: None,
+            arbiter: Arbiter::strict_priority(vc_num),
+            ttl: DEFAULT_TTL,
+            loop_detection: false,
         }
     }
 
+    // チャネルごとに送信を待っている先頭のフリット
+    pub fn head_flits(&self) -> Vec<(u8, Flit)> {
+        self.sending_flit_buffer
+            .iter()
+            .filter_map(|(channel_id, buffer)| {
+                buffer.iter().next().map(|flit| (*channel_id, flit.clone()))
+            })
+            .collect()
+    }
+
     pub fn send_flit(&mut self) -> Option<Flit> {
         // sending_flit_bufferのchannel_id番目のFlitBufferからpopする
         let channel_id = self.select_channel();
@@ -85,11 +144,50 @@ impl Network {
         self.cur_cycle = cur_cycle;
 
         self.core.update();
+        self.received_flits_buffer.expire(cur_cycle);
+        if let Some(transport) = &mut self.transport {
+            for packet in transport.update(cur_cycle) {
+                // 宛先は最初に送ったときに確かめてある
+                let _ = self.core.push_new_packet(&packet);
+            }
+        }
+
+        // 参加時にアドレスが割り当てられたら登録する
+        // 古いidも届く途中のパケットのために残しておく
+        if self.core.get_id() != self.vid {
+            self.vid = self.core.get_id();
+            add_to_vid_table(self.vid, &self.id);
+        }
+
+        // ネットワークから外れたサイクルと再参加したサイクルを記録
+        let joined = self.core.is_joined();
+        if self.was_joined && !joined {
+            post_rejoin_log(&self.id, cur_cycle);
+            post_event(|| SimEvent::NodeLeft {
+                node_id: self.id.clone(),
+            });
+        } else if !self.was_joined && joined {
+            update_rejoin_log(&self.id, cur_cycle);
+            post_event(|| SimEvent::NodeJoined {
+                node_id: self.id.clone(),
+            });
+        }
+        self.was_joined = joined;
 
         // 送信待ちのパケットを取りに行く
-        if let Some(packet) = self.core.send_packet() {
+        if let Some(mut packet) = self.core.send_packet() {
+            // トラフィッククラスのパケットはクラスの仮想チャネルで送る
+            if let Some(channel_id) = packet
+                .class
+                .as_deref()
+                .and_then(|class| self.arbiter.class_channel(class))
+            {
+                packet.channel_id = channel_id;
+            }
+            packet.ttl = self.ttl;
+
             // packetをフリットに変換する
-            let flits = packet_to_flits(&packet);
+            let flits = packet_to_flits(&packet, self.header_bytes);
 
             // log
             self.log_handler(None, Some(&packet));
@@ -106,7 +204,7 @@ impl Network {
                 self.sending_flit_buffer
                     .get_mut(&channel_id)
                     .unwrap()
-                    .push(&flit);
+                    .push(&flit, cur_cycle);
             }
         }
 
@@ -134,17 +232,39 @@ impl Network {
 
         // 自分が最終的な宛先なら
         if flit.get_dest_id().unwrap() == self.id || flit.get_dest_id().unwrap() == "broadcast" {
-            // received_flits_bufferにpushする
-            self.received_flits_buffer.push_flit(flit);
+            // received_flits_bufferにpushし，揃ったら組み立てる
+            // フリットは順不同で届くことがある
+            if let Some(packet) = self.received_flits_buffer.push_flit(flit, self.cur_cycle) {
+                let packet_id = packet.source_id.clone() + "_" + &packet.packet_id.to_string();
+                let is_user_packet = !self.core.is_control_packet(&packet);
+                // トランスポート層のACKや重複したセグメントはユーザーに渡さない
+                let deliver = self.receive_segment(&packet);
+
+                // ユーザーのbroadcast/multicastなら受信を記録
+                let mut delivered = packet.dest_id != "broadcast";
+                if packet.dest_id == "broadcast" && is_user_packet {
+                    let dest_ids = self.core.get_dest_ids(&packet);
+                    if dest_ids.is_empty() || dest_ids.contains(&self.id) {
+                        update_broadcast_log(&packet_id, &self.id, self.cur_cycle);
+                        delivered = packet.source_id != self.id;
+                    }
+                }
+                if delivered && is_user_packet && deliver {
+                    post_event(|| SimEvent::PacketDelivered {
+                        node_id: self.id.clone(),
+                        source_id: packet.source_id.clone(),
+                        packet_id: packet_id.clone(),
+                        message: self.core.get_kind(&packet).message(),
+                    });
+                }
 
-            if flit.is_last() {
-                if let Some(packet) = self.received_flits_buffer.pop_packet(
-                    &flit.get_source_id().unwrap(),
-                    flit.get_packet_id().unwrap(),
-                ) {
+                if deliver {
                     self.core.receive_packet(&packet);
+                }
 
-                    // log
+                // log
+                // 自分が送信したbroadcastが戻ってきた場合は記録しない
+                if packet.source_id != self.id {
                     self.log_handler(Some(flit), None);
                 }
             }
@@ -153,7 +273,7 @@ impl Network {
             self.receiving_flit_buffer
                 .get_mut(&channel_id)
                 .unwrap()
-                .push(flit);
+                .push(flit, self.cur_cycle);
 
             if flit.is_last() {
                 while !self
@@ -168,13 +288,127 @@ impl Network {
         }
     }
 
-    pub fn send_new_packet(&mut self, packet: &InjectionPacket) {
-        self.core.push_new_packet(packet);
+    // 宛先を知らなければエラーを返し，トランスポート層にも渡さない
+    pub fn send_new_packet(&mut self, packet: &InjectionPacket) -> Result<(), String> {
+        injection_vids(packet)?;
+        post_event(|| SimEvent::PacketInjected {
+            node_id: self.id.clone(),
+            dest_id: packet.dest_id.clone(),
+            message: packet.message.clone(),
+        });
+        match &mut self.transport {
+            Some(transport) => {
+                for packet in transport.send(packet, self.cur_cycle) {
+                    self.core.push_new_packet(&packet)?;
+                }
+                Ok(())
+            }
+            None => self.core.push_new_packet(packet),
+        }
     }
 
     pub fn is_joined(&self) -> bool {
         self.core.is_joined()
     }
+
+    pub fn routing_table(&self) -> RoutingTable {
+        self.core.routing_table()
+    }
+
+    pub fn buffer_occupancy(&self) -> BufferOccupancy {
+        let count = |buffers: &HashMap<u8, FlitBuffer>| {
+            buffers.values().map(|buffer| buffer.iter().count()).sum()
+        };
+
+        BufferOccupancy {
+            sending_flits: count(&self.sending_flit_buffer),
+            receiving_flits: count(&self.receiving_flit_buffer),
+            reassembling_flits: self.received_flits_buffer.iter().count(),
+            queued_packets: self.core.queued_packets(),
+        }
+    }
+
+    // next_idへのリンクが切れたときに経路を修復する
+    pub fn handle_link_loss(&mut self, next_id: &str) {
+        // next_idへ送る予定のフリットは届かないので破棄する
+        for buffer in self.sending_flit_buffer.values_mut() {
+            buffer.retain(|flit| {
+                if flit.get_next_id().as_deref() != Some(next_id) {
+                    return true;
+                }
+                post_failure_loss_log(&packet_log_id(flit));
+                false
+            });
+        }
+        if self.send_history.1.get_next_id().as_deref() == Some(next_id) {
+            self.send_history.0 = false;
+        }
+
+        if let Some(next_vid) = get_vid(next_id) {
+            self.core.handle_link_loss(next_vid);
+        }
+    }
+
+    pub fn set_address_assignment(&mut self, assign_address: bool) {
+        self.core.set_address_assignment(assign_address);
+    }
+
+    pub fn set_header_bytes(&mut self, header_bytes: u32) {
+        self.header_bytes = header_bytes;
+    }
+
+    pub fn set_transport(&mut self, transport: Option<Transport>) {
+        self.transport = transport;
+    }
+
+    pub fn set_arbiter(&mut self, arbiter: Arbiter) {
+        self.arbiter = arbiter;
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+    }
+
+    pub fn set_loop_detection(&mut self, loop_detection: bool) {
+        self.loop_detection = loop_detection;
+    }
+
+    // トランスポート層のセグメントならACKを返し，ユーザーに渡すかを返す
+    fn receive_segment(&mut self, packet: &Packet) -> bool {
+        let (Some(transport), Some(segment)) = (&mut self.transport, self.core.get_segment(packet))
+        else {
+            return true;
+        };
+
+        let message_len = match self.core.get_size_bytes(packet) {
+            Some(size_bytes) => size_bytes as usize,
+            None => self.core.get_kind(packet).message().len(),
+        };
+        let received = transport.receive(&packet.source_id, segment, message_len);
+        if let Some(ack) = received.ack {
+            // 受信したセグメントの送信元に返す
+            let _ = self.core.push_new_packet(&ack);
+        }
+        received.deliver
+    }
+
+    // バッファに残っているフリットのパケットをすべて故障で失われたとして記録する
+    pub fn drop_held_packets(&mut self) {
+        let flits = self
+            .sending_flit_buffer
+            .values()
+            .chain(self.receiving_flit_buffer.values())
+            .flat_map(|buffer| buffer.iter())
+            .chain(self.received_flits_buffer.iter());
+        for flit in flits {
+            post_failure_loss_log(&packet_log_id(flit));
+        }
+    }
+}
+
+// ログでパケットを識別するid
+pub(crate) fn packet_log_id(flit: &Flit) -> String {
+    flit.get_source_id().unwrap() + "_" + &flit.get_packet_id().unwrap().to_string()
 }
 
 impl Network {
@@ -186,37 +420,48 @@ impl Network {
             .unwrap()
             .pop()
         {
-            let new_flit = self.core.forward_flit(&flit);
+            let packet_id = packet_log_id(&flit);
+            if self.loop_detection && flit.is_header() {
+                let prev_id = flit.get_prev_id().unwrap();
+                post_revisit_log(&packet_id, &self.id, &prev_id, self.cur_cycle);
+            }
+
+            // 中継するたびにTTLを減らし，使い切ったら経路のループとみなして破棄する
+            let ttl = flit.get_ttl().unwrap().saturating_sub(1);
+            if ttl == 0 {
+                post_loop_drop_log(&packet_id, &self.id, self.cur_cycle);
+                return;
+            }
+
+            // 経路がなければ破棄する
+            let Some(mut new_flit) = self.core.forward_flit(&flit) else {
+                return;
+            };
+            new_flit.set_ttl(ttl);
             let channel_id = new_flit.get_channel_id().unwrap();
 
             self.sending_flit_buffer
                 .get_mut(&channel_id)
                 .unwrap()
-                .push(&new_flit);
+                .push(&new_flit, self.cur_cycle);
         }
     }
 
-    fn select_channel(&self) -> u8 {
+    fn select_channel(&mut self) -> u8 {
         // historyを見て現在送信中のパケットがあったらそれが優先
         if !self.send_history.1.is_empty() && self.send_history.0 {
             return self.send_history.1.get_channel_id().unwrap();
         }
 
-        // 送信待ちのフリットがあるchannel_idを返す
-        let channel_ids: Vec<u8> = self.sending_flit_buffer.keys().cloned().collect();
-        for channel_id in channel_ids {
-            if !self
-                .sending_flit_buffer
-                .get(&channel_id)
-                .unwrap()
-                .is_empty()
-            {
-                return channel_id;
-            }
-        }
+        // 送信待ちのフリットがあるチャネルからarbiterで選ぶ
+        let heads: HashMap<u8, u32> = self
+            .sending_flit_buffer
+            .iter()
+            .filter_map(|(&channel_id, buffer)| Some((channel_id, buffer.head_cycle()?)))
+            .collect();
 
         // 送信待ちのフリットがない場合は適当に0を返す
-        0
+        self.arbiter.select(&heads).unwrap_or(0)
     }
 
     fn log_handler(&self, flit: Option<&Flit>, packet: Option<&Packet>) {
@@ -230,7 +475,8 @@ impl Network {
                 flit.get_source_id().unwrap() + "_" + &flit.get_packet_id().unwrap().to_string();
 
             let send_init = flit.get_source_id().unwrap() == self.id && flit.is_header();
-            let is_delivered = flit.get_dest_id().unwrap() == self.id && flit.is_last();
+            // 宛先では組み立てを終えたときだけ呼ばれるので，最後に届いたフリットが末尾とは限らない
+            let is_delivered = flit.get_dest_id().unwrap() == self.id;
 
             let update_log = UpdatePacketLogInfo {
                 send_cycle: if send_init {
@@ -258,14 +504,37 @@ impl Network {
         // 最初のパケット登録
         if let Some(packet) = packet {
             let packet_id = packet.source_id.clone() + "_" + &packet.packet_id.to_string();
+            let kind = self.core.get_kind(packet);
+            let segment = self.core.get_segment(packet);
+
+            if packet.dest_id == "broadcast" && !kind.is_control() {
+                // 他ノードのbroadcastを中継する場合は登録しない
+                if packet.source_id != self.id {
+                    return;
+                }
+
+                post_broadcast_log(&NewBroadcastLogInfo {
+                    packet_id: packet_id.clone(),
+                    from_id: packet.source_id.clone(),
+                    dest_ids: self.core.get_dest_ids(packet),
+                });
+            }
 
             let log = NewPacketLogInfo {
                 packet_id,
                 from_id: packet.source_id.clone(),
                 dest_id: packet.dest_id.clone(),
-                flits_len: packet.get_flits_len(),
-                message: self.core.get_message(packet),
+                flits_len: packet.get_flits_len(self.header_bytes),
+                message: kind.message(),
+                size_bytes: packet.size_bytes,
+                // トランスポート層のACKも制御パケットとして数える
+                is_control: kind.is_control() || segment.is_some_and(|segment| segment.ack),
+                segment_id: segment.filter(|segment| !segment.ack).map(|segment| {
+                    format!("{}>{}#{}", packet.source_id, packet.dest_id, segment.seq)
+                }),
                 channel_id: packet.channel_id,
+                inject_cycle: packet.inject_cycle,
+                class: packet.class.clone(),
             };
 
             let _ = post_new_packet_log(&log);
@@ -292,8 +561,15 @@ mod tests {
             message: "hello world".to_string(),
             dest_id: "broadcast".to_string(),
             source_id: "test".to_string(),
+            dest_ids: vec![],
+            inject_cycle: None,
+            priority: 0,
+            size_bytes: None,
+            payload: None,
+            class: None,
+            segment: None,
         };
-        network.send_new_packet(&packet);
+        network.send_new_packet(&packet).unwrap();
         network.update(0);
 
         // preq
@@ -325,8 +601,15 @@ mod tests {
             message: "hello world".to_string(),
             dest_id: "broadcast".to_string(),
             source_id: "test".to_string(),
+            dest_ids: vec![],
+            inject_cycle: None,
+            priority: 0,
+            size_bytes: None,
+            payload: None,
+            class: None,
+            segment: None,
         };
-        network.send_new_packet(&packet);
+        network.send_new_packet(&packet).unwrap();
         network.update(0);
 
         // preq
@@ -343,4 +626,70 @@ mod tests {
         assert_eq!(flit.get_flits_len().unwrap(), 1);
         assert_eq!(flit.get_channel_id().unwrap(), 0);
     }
+
+    #[test]
+    fn test_ttl_expired() {
+        let mut network = Network::new(
+            "relay",
+            1,
+            &Switching::StoreAndForward,
+            "default",
+            &NodeType::Router,
+        );
+        network.set_loop_detection(true);
+        // relayを一度通って戻ってきたパケット
+        post_new_packet_log(&NewPacketLogInfo {
+            packet_id: "src_0".to_string(),
+            from_id: "src".to_string(),
+            dest_id: "dst".to_string(),
+            flits_len: 1,
+            message: "hello".to_string(),
+            size_bytes: None,
+            is_control: false,
+            segment_id: None,
+            channel_id: 0,
+            inject_cycle: None,
+            class: None,
+        })
+        .unwrap();
+        for node_id in ["src", "relay", "other"] {
+            update_packet_log(
+                "src_0",
+                &UpdatePacketLogInfo {
+                    send_cycle: None,
+                    last_receive_cycle: None,
+                    route_info: Some(node_id.to_string()),
+                    is_delivered: None,
+                    flit_log: None,
+                },
+            )
+            .unwrap();
+        }
+
+        let flit = Flit::Header(flit::HeaderFlit {
+            source_id: "src".to_string(),
+            dest_id: "dst".to_string(),
+            next_id: "relay".to_string(),
+            prev_id: "other".to_string(),
+            packet_id: 0,
+            flits_len: 1,
+            data: vec![],
+            channel_id: 0,
+            ttl: 1,
+        });
+        network.cur_cycle = 5;
+        network.receive_flit(&flit, 0);
+
+        assert!(network.send_flit().is_none());
+        let loop_logs = crate::log::get_loop_log();
+        assert_eq!(loop_logs.len(), 2);
+        assert!(!loop_logs[0].dropped);
+        assert!(loop_logs[1].dropped);
+        assert_eq!(loop_logs[1].node_id, "relay");
+        assert_eq!(
+            loop_logs[1].route_info,
+            vec!["src", "src", "relay", "other"]
+        );
+        assert_eq!(crate::log::count_finished_packets(), 1);
+    }
 }
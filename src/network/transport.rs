@@ -0,0 +1,357 @@
+use crate::file::TransportInfo;
+use crate::log::{post_transport_log, TransportEvent};
+use crate::network::core_functions::packets::{InjectionPacket, Segment};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+const DEFAULT_MAX_RETRANSMISSIONS: u32 = 8;
+// 再送の待ち時間はretransmit_cyclesの2のこの乗まで伸ばす
+const MAX_BACKOFF_EXPONENT: u32 = 6;
+
+// ACKを待っているセグメント
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Unacked {
+    packet: InjectionPacket,
+    sent_cycle: u32,
+    retransmissions: u32,
+}
+
+impl Unacked {
+    fn is_acked_by(&self, source_id: &str, seq: u32) -> bool {
+        self.packet.dest_id == source_id && self.packet.segment.is_some_and(|s| s.seq == seq)
+    }
+}
+
+// 送信元から受け取ったシーケンス番号
+// next_seqより前はすべて受け取っていて，それより後に受け取った番号だけを覚える
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ReceivedSeqs {
+    next_seq: u32,
+    later: BTreeSet<u32>,
+}
+
+impl ReceivedSeqs {
+    // 初めて受け取った番号ならtrue
+    fn insert(&mut self, seq: u32) -> bool {
+        if seq < self.next_seq || !self.later.insert(seq) {
+            return false;
+        }
+        while self.later.remove(&self.next_seq) {
+            self.next_seq += 1;
+        }
+        true
+    }
+}
+
+// 受け取ったセグメントの扱い
+#[derive(Debug, Clone)]
+pub struct Received {
+    // 送信元に返すACK
+    pub ack: Option<InjectionPacket>,
+    // ユーザーに渡すか，重複やACKは渡さない
+    pub deliver: bool,
+}
+
+// ユーザーのunicastパケットを確実に届けるトランスポート層
+// 宛先ごとにwindow_sizeまでACKを待たずに送り，ACKが来なければ待ち時間を倍にしながら再送する
+// broadcast/multicastはそのまま送る
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transport {
+    node_id: String,
+    window_size: usize,
+    retransmit_cycles: u32,
+    max_retransmissions: u32,
+    // 宛先ごとの次のシーケンス番号
+    next_seq: HashMap<String, u32>,
+    // ウィンドウが空くのを待っているセグメント
+    waiting: VecDeque<InjectionPacket>,
+    unacked: Vec<Unacked>,
+    received: HashMap<String, ReceivedSeqs>,
+}
+
+impl Transport {
+    pub fn new(node_id: &str, info: &TransportInfo) -> Self {
+        Self {
+            node_id: node_id.to_string(),
+            window_size: info.window_size as usize,
+            retransmit_cycles: info.retransmit_cycles,
+            max_retransmissions: info
+                .max_retransmissions
+                .unwrap_or(DEFAULT_MAX_RETRANSMISSIONS),
+            next_seq: HashMap::new(),
+            waiting: VecDeque::new(),
+            unacked: Vec::new(),
+            received: HashMap::new(),
+        }
+    }
+
+    // ユーザーのパケットに番号を付け，今送れるパケットを返す
+    pub fn send(&mut self, packet: &InjectionPacket, cur_cycle: u32) -> Vec<InjectionPacket> {
+        if packet.dest_id == "broadcast" {
+            return vec![packet.clone()];
+        }
+
+        let next_seq = self.next_seq.entry(packet.dest_id.clone()).or_insert(0);
+        let mut packet = packet.clone();
+        packet.segment = Some(Segment {
+            seq: *next_seq,
+            ack: false,
+        });
+        *next_seq += 1;
+
+        self.waiting.push_back(packet);
+        self.release(cur_cycle)
+    }
+
+    // ACKが来ないセグメントを再送し，ウィンドウが空いたセグメントを送る
+    pub fn update(&mut self, cur_cycle: u32) -> Vec<InjectionPacket> {
+        let mut packets = Vec::new();
+
+        let retransmit_cycles = self.retransmit_cycles;
+        let max_retransmissions = self.max_retransmissions;
+        self.unacked.retain_mut(|unacked| {
+            let timeout = retransmit_cycles << unacked.retransmissions.min(MAX_BACKOFF_EXPONENT);
+            if cur_cycle - unacked.sent_cycle < timeout {
+                return true;
+            }
+            if unacked.retransmissions >= max_retransmissions {
+                post_transport_log(TransportEvent::GaveUp);
+                return false;
+            }
+
+            unacked.retransmissions += 1;
+            unacked.sent_cycle = cur_cycle;
+            post_transport_log(TransportEvent::Retransmitted);
+            packets.push(unacked.packet.clone());
+            true
+        });
+
+        packets.extend(self.release(cur_cycle));
+        packets
+    }
+
+    // 宛先のウィンドウに空きがあるセグメントを待ち行列の順に送る
+    fn release(&mut self, cur_cycle: u32) -> Vec<InjectionPacket> {
+        let mut released = Vec::new();
+        let mut waiting = VecDeque::new();
+
+        while let Some(packet) = self.waiting.pop_front() {
+            let in_flight = self
+                .unacked
+                .iter()
+                .filter(|unacked| unacked.packet.dest_id == packet.dest_id)
+                .count();
+            if in_flight >= self.window_size {
+                waiting.push_back(packet);
+                continue;
+            }
+
+            post_transport_log(TransportEvent::Sent);
+            self.unacked.push(Unacked {
+                packet: packet.clone(),
+                sent_cycle: cur_cycle,
+                retransmissions: 0,
+            });
+            released.push(packet);
+        }
+
+        self.waiting = waiting;
+        released
+    }
+
+    // source_idから届いたセグメントを処理する
+    // message_lenは統計のためのメッセージのバイト数
+    pub fn receive(&mut self, source_id: &str, segment: Segment, message_len: usize) -> Received {
+        if segment.ack {
+            self.unacked
+                .retain(|unacked| !unacked.is_acked_by(source_id, segment.seq));
+            return Received {
+                ack: None,
+                deliver: false,
+            };
+        }
+
+        let deliver = self
+            .received
+            .entry(source_id.to_string())
+            .or_default()
+            .insert(segment.seq);
+        post_transport_log(if deliver {
+            TransportEvent::Delivered(message_len)
+        } else {
+            TransportEvent::Duplicate(message_len)
+        });
+
+        // 重複でもACKが失われた可能性があるので返し直す
+        post_transport_log(TransportEvent::AckSent);
+        let ack = InjectionPacket {
+            message: String::new(),
+            dest_id: source_id.to_string(),
+            source_id: self.node_id.clone(),
+            dest_ids: vec![],
+            inject_cycle: None,
+            // ACKはユーザーのパケットより先に送る
+            priority: u8::MAX,
+            // ヘッダのみ
+            size_bytes: Some(0),
+            payload: None,
+            class: None,
+            segment: Some(Segment {
+                seq: segment.seq,
+                ack: true,
+            }),
+        };
+
+        Received {
+            ack: Some(ack),
+            deliver,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::{
+        aggregate_log, aggregate_measurement_log, aggregate_transport_log, post_new_packet_log,
+        update_packet_log, NewPacketLogInfo, UpdatePacketLogInfo,
+    };
+
+    fn info() -> TransportInfo {
+        TransportInfo {
+            window_size: 2,
+            retransmit_cycles: 10,
+            max_retransmissions: Some(1),
+        }
+    }
+
+    fn packet(message: &str) -> InjectionPacket {
+        InjectionPacket {
+            message: message.to_string(),
+            dest_id: "b".to_string(),
+            source_id: "a".to_string(),
+            dest_ids: vec![],
+            inject_cycle: None,
+            priority: 0,
+            size_bytes: None,
+            payload: None,
+            class: None,
+            segment: None,
+        }
+    }
+
+    fn seqs(packets: &[InjectionPacket]) -> Vec<u32> {
+        packets
+            .iter()
+            .map(|packet| packet.segment.unwrap().seq)
+            .collect()
+    }
+
+    #[test]
+    fn test_window_and_retransmission() {
+        let mut sender = Transport::new("a", &info());
+        let mut receiver = Transport::new("b", &info());
+
+        // ウィンドウは2なので3つ目は待つ
+        assert_eq!(seqs(&sender.send(&packet("x"), 0)), vec![0]);
+        assert_eq!(seqs(&sender.send(&packet("y"), 0)), vec![1]);
+        assert!(sender.send(&packet("z"), 0).is_empty());
+
+        // 0のACKが届くと2を送れる
+        let received = receiver.receive("a", Segment { seq: 0, ack: false }, 1);
+        assert!(received.deliver);
+        let ack = received.ack.unwrap();
+        assert_eq!(ack.dest_id, "a");
+        sender.receive("b", ack.segment.unwrap(), 0);
+        assert_eq!(seqs(&sender.update(1)), vec![2]);
+
+        // ACKが来ないので再送し，次は待ち時間を倍にする
+        assert_eq!(seqs(&sender.update(10)), vec![1]);
+        assert_eq!(seqs(&sender.update(11)), vec![2]);
+        assert!(sender.update(29).is_empty());
+        // 再送の上限に達したので諦める
+        assert!(sender.update(30).is_empty());
+        assert_eq!(sender.unacked.len(), 1);
+        assert!(sender.update(31).is_empty());
+        assert!(sender.unacked.is_empty());
+
+        let report = aggregate_transport_log(30);
+        assert_eq!(report.sent_segments, 3);
+        assert_eq!(report.retransmissions, 2);
+        assert_eq!(report.gave_up_segments, 2);
+    }
+
+    #[test]
+    fn test_duplicate_suppression() {
+        let mut receiver = Transport::new("b", &info());
+
+        for (seq, deliver) in [(1, true), (0, true), (1, false), (0, false), (2, true)] {
+            let received = receiver.receive("a", Segment { seq, ack: false }, 4);
+            assert_eq!(received.deliver, deliver);
+            assert!(received.ack.is_some());
+        }
+        assert_eq!(receiver.received["a"].next_seq, 3);
+        assert!(receiver.received["a"].later.is_empty());
+
+        let report = aggregate_transport_log(10);
+        assert_eq!(report.delivered_segments, 3);
+        assert_eq!(report.duplicate_segments, 2);
+        assert_eq!(report.goodput, 1.2);
+        assert_eq!(report.throughput, 2.0);
+    }
+
+    #[test]
+    fn test_retransmission_counted_once() {
+        let mut sender = Transport::new("a", &info());
+        let mut injected = packet("x");
+        injected.inject_cycle = Some(1000);
+
+        // ACKが来ないので同じセグメントを再送する
+        let mut copies = sender.send(&injected, 1000);
+        copies.extend(sender.update(1010));
+        assert_eq!(seqs(&copies), vec![0, 0]);
+
+        // 最初のコピーは届かず，再送したコピーが届く
+        let cycles = [(1000, None), (1010, Some(1014))];
+        for (i, (copy, (send_cycle, last_receive_cycle))) in copies.iter().zip(cycles).enumerate() {
+            let packet_id = format!("a_{i}");
+            post_new_packet_log(&NewPacketLogInfo {
+                packet_id: packet_id.clone(),
+                from_id: "a".to_string(),
+                dest_id: "b".to_string(),
+                flits_len: 1,
+                message: copy.message.clone(),
+                size_bytes: None,
+                is_control: false,
+                segment_id: Some(format!("a>b#{}", copy.segment.unwrap().seq)),
+                channel_id: 0,
+                inject_cycle: copy.inject_cycle,
+                class: None,
+            })
+            .unwrap();
+            update_packet_log(
+                &packet_id,
+                &UpdatePacketLogInfo {
+                    send_cycle: Some(send_cycle),
+                    last_receive_cycle,
+                    route_info: None,
+                    is_delivered: Some(last_receive_cycle.is_some()),
+                    flit_log: None,
+                },
+            )
+            .unwrap();
+        }
+
+        let report = aggregate_measurement_log(1000, 1001, 1);
+        assert_eq!(report.delivered_packets, 1);
+        assert_eq!(report.in_flight_packets, 0);
+        assert_eq!(report.unsent_packets, 0);
+        assert_eq!(report.average_latency, 14.0);
+        assert_eq!(report.average_network_latency, 4.0);
+
+        let result = aggregate_log(1000, 1100);
+        assert_eq!(result["total_packets"], 1.0);
+        assert_eq!(result["undelivered_packets"], 0.0);
+        assert_eq!(result["average_cycle"], 4.0);
+    }
+}
@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Mutex;
 
@@ -13,7 +14,7 @@ cfg_if::cfg_if!(
     else {
         use std::cell::Cell;
         thread_local! {
-            static LOCAL_VID_TABLE: Cell<Option<&'static Mutex<VIDTable>>> = Cell::new(None);
+            static LOCAL_VID_TABLE: Cell<Option<&'static Mutex<VIDTable>>> = const { Cell::new(None) };
         }
 
         struct VIDTableProxy;
@@ -42,8 +43,8 @@ cfg_if::cfg_if!(
 
 // broadcastのvidはu32::MAX，事前にVID_TABLEに登録しておく
 
-#[derive(Debug, Default)]
-struct VIDTable {
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct VIDTable {
     v_to_p: HashMap<u32, String>,
     p_to_v: HashMap<String, u32>,
 }
@@ -63,6 +64,19 @@ pub fn add_to_vid_table(vid: u32, pid: &str) {
     table.p_to_v.insert(pid.to_string(), vid);
 }
 
+// vidがすでに他のノードに割り当てられていればErrを返す
+pub fn try_add_to_vid_table(vid: u32, pid: &str) -> Result<(), String> {
+    let mut table = VID_TABLE.lock().expect("failed to lock VID_TABLE");
+    if let Some(owner) = table.v_to_p.get(&vid) {
+        if owner != pid {
+            return Err(format!("vid {vid} is already assigned to {owner}"));
+        }
+    }
+    table.v_to_p.insert(vid, pid.to_string());
+    table.p_to_v.insert(pid.to_string(), vid);
+    Ok(())
+}
+
 pub fn remove_from_vid_table(vid: u32, pid: &str) {
     let mut table = VID_TABLE.lock().expect("failed to lock VID_TABLE");
     table.v_to_p.remove(&vid);
@@ -80,6 +94,15 @@ pub fn clear_vid_table() {
     table.p_to_v.clear();
 }
 
+// チェックポイント用にテーブル全体を取り出す，戻す
+pub(crate) fn get_vid_table_state() -> VIDTable {
+    VID_TABLE.lock().expect("failed to lock VID_TABLE").clone()
+}
+
+pub(crate) fn set_vid_table_state(table: VIDTable) {
+    *VID_TABLE.lock().expect("failed to lock VID_TABLE") = table;
+}
+
 pub fn get_vid(pid: &str) -> Option<u32> {
     let table = VID_TABLE.lock().expect("failed to lock VID_TABLE");
     table.p_to_v.get(pid).cloned()
@@ -90,7 +113,32 @@ pub fn get_pid(vid: u32) -> Option<String> {
     table.v_to_p.get(&vid).cloned()
 }
 
+// pidに割り当てられたすべてのvid (古いidを含む)
+pub fn get_vids(pid: &str) -> Vec<u32> {
+    let table = VID_TABLE.lock().expect("failed to lock VID_TABLE");
+    table
+        .v_to_p
+        .iter()
+        .filter(|(_, owner)| owner.as_str() == pid)
+        .map(|(vid, _)| *vid)
+        .collect()
+}
+
 pub fn print_vid_table() {
     let table = VID_TABLE.lock().expect("failed to lock VID_TABLE");
     recsimu_dbg!("VID Table: {:?}", table);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_add_to_vid_table() {
+        assert!(try_add_to_vid_table(1, "a").is_ok());
+        assert!(try_add_to_vid_table(1, "a").is_ok());
+        assert!(try_add_to_vid_table(1, "b").is_err());
+        assert_eq!(get_pid(1), Some("a".to_string()));
+        assert_eq!(get_vid("b"), None);
+    }
+}
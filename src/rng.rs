@@ -0,0 +1,100 @@
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use std::sync::Mutex;
+
+cfg_if::cfg_if!(
+    if #[cfg(not(test))]
+    {
+        use once_cell::sync::Lazy;
+
+        static RNG: Lazy<Mutex<ChaCha12Rng>> = Lazy::new(|| Mutex::new(ChaCha12Rng::from_entropy()));
+    }
+    else {
+        use std::cell::Cell;
+        thread_local! {
+            static LOCAL_RNG: Cell<Option<&'static Mutex<ChaCha12Rng>>> = const { Cell::new(None) };
+        }
+
+        struct RngProxy;
+
+        impl std::ops::Deref for RngProxy {
+            type Target = Mutex<ChaCha12Rng>;
+
+            #[inline]
+            fn deref (&self) -> &Self::Target {
+                LOCAL_RNG.with(|rng| {
+                    if rng.get().is_none() {
+                        let l = Mutex::new(ChaCha12Rng::from_entropy());
+                        let b = Box::new(l);
+                        let static_ref = Box::leak(b);
+                        rng.set(Some(static_ref));
+                    }
+                    rng.get().unwrap()
+                }
+            )
+            }
+        }
+
+        static RNG: RngProxy = RngProxy;
+    }
+);
+
+// シミュレーション全体で共有する乱数生成器
+// シードを指定すれば結果を再現でき，状態をチェックポイントに保存できる
+pub struct SimRng;
+
+pub fn sim_rng() -> SimRng {
+    SimRng
+}
+
+impl RngCore for SimRng {
+    fn next_u32(&mut self) -> u32 {
+        RNG.lock().expect("failed to lock rng").next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        RNG.lock().expect("failed to lock rng").next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RNG.lock().expect("failed to lock rng").fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        RNG.lock().expect("failed to lock rng").try_fill_bytes(dest)
+    }
+}
+
+pub fn seed_rng(seed: u64) {
+    *RNG.lock().expect("failed to lock rng") = ChaCha12Rng::seed_from_u64(seed);
+}
+
+pub(crate) fn get_rng_state() -> ChaCha12Rng {
+    RNG.lock().expect("failed to lock rng").clone()
+}
+
+pub(crate) fn set_rng_state(rng: ChaCha12Rng) {
+    *RNG.lock().expect("failed to lock rng") = rng;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_seed_rng() {
+        seed_rng(42);
+        let first: Vec<u32> = (0..4).map(|_| sim_rng().gen()).collect();
+
+        seed_rng(42);
+        let state = get_rng_state();
+        let second: Vec<u32> = (0..4).map(|_| sim_rng().gen()).collect();
+        assert_eq!(first, second);
+
+        // 保存した状態に戻すと同じ乱数列になる
+        set_rng_state(state);
+        let third: Vec<u32> = (0..4).map(|_| sim_rng().gen()).collect();
+        assert_eq!(first, third);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::log::{get_log_state, set_log_state, Log};
+use crate::network::core_functions::multi_tree::{get_coordinator_cnt, set_coordinator_cnt};
+use crate::network::vid::{get_vid_table_state, set_vid_table_state, VIDTable};
+use crate::rng::{get_rng_state, set_rng_state};
+use crate::sim::Sim;
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::{error, path::Path};
+
+// 形式を変えたら上げる
+const CHECKPOINT_VERSION: u32 = 8;
+
+// シミュレーションの状態と，グローバルに持っている状態をまとめて保存する
+// 保存するときはSimを借用し，読み込むときは所有する
+#[derive(Serialize, Deserialize)]
+struct Checkpoint<S> {
+    sim: S,
+    vid_table: VIDTable,
+    log: Log,
+    rng: ChaCha12Rng,
+    coordinator_cnt: u32,
+}
+
+impl Sim {
+    // 現在のサイクルの状態をpathに保存する
+    pub fn save_checkpoint(&self, path: &Path) -> Result<(), Box<dyn error::Error>> {
+        let checkpoint = Checkpoint {
+            sim: self,
+            vid_table: get_vid_table_state(),
+            log: get_log_state(),
+            rng: get_rng_state(),
+            coordinator_cnt: get_coordinator_cnt(),
+        };
+        let mut writer = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(&mut writer, &CHECKPOINT_VERSION)?;
+        bincode::serialize_into(&mut writer, &checkpoint)?;
+        Ok(())
+    }
+
+    // 保存した状態から再開する
+    // 同じチェックポイントから何度でも再開できる
+    // オブザーバとユーザー定義のノード種別のフックは保存されないので登録し直す
+    pub fn load_checkpoint(path: &Path) -> Result<Sim, Box<dyn error::Error>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        // 形式が違えば中身を読む前に弾く
+        let version: u32 = bincode::deserialize_from(&mut reader)?;
+        if version != CHECKPOINT_VERSION {
+            return Err(format!(
+                "checkpoint version {version} is not supported (expected {CHECKPOINT_VERSION})"
+            )
+            .into());
+        }
+        let checkpoint: Checkpoint<Sim> = bincode::deserialize_from(reader)?;
+
+        set_vid_table_state(checkpoint.vid_table);
+        set_log_state(checkpoint.log);
+        set_rng_state(checkpoint.rng);
+        set_coordinator_cnt(checkpoint.coordinator_cnt);
+        Ok(checkpoint.sim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log::get_all_log;
+    use crate::rng::seed_rng;
+    use crate::sim::{Sim, SimBuilder, StopReason};
+    use std::path::PathBuf;
+
+    // 最後まで実行して，終わった理由とサイクル数，パケットのログを比較できる形で返す
+    fn run_to_end(sim: &mut Sim) -> (Option<StopReason>, u32, Vec<String>) {
+        let reason = sim.run_until(sim.total_cycles);
+        let mut log: Vec<String> = get_all_log()
+            .iter()
+            .map(|packet| format!("{packet:?}"))
+            .collect();
+        log.sort();
+        (reason, sim.cur_cycles, log)
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        seed_rng(1);
+        let path = PathBuf::from("tests/run/auto/6_d.json");
+        let mut sim = SimBuilder::new(&path).build().unwrap();
+        assert_eq!(sim.run_until(5000), None);
+
+        let checkpoint =
+            std::env::temp_dir().join(format!("recsimu_checkpoint_{}.bin", std::process::id()));
+        sim.save_checkpoint(&checkpoint).unwrap();
+        let expected = run_to_end(&mut sim);
+
+        // 同じチェックポイントから何度再開しても同じ結果になる
+        for _ in 0..2 {
+            let mut resumed = Sim::load_checkpoint(&checkpoint).unwrap();
+            assert_eq!(resumed.cur_cycles, 5000);
+            assert_eq!(run_to_end(&mut resumed), expected);
+        }
+        std::fs::remove_file(&checkpoint).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+use crate::file::EnergyInfo;
+use crate::hardware::state::State;
+use crate::network::flit::Flit;
+use crate::sim::node::{CycleNum, NodeId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// ハードウェアの状態にいた時間と送受信したビット数からノードの消費エネルギーを見積もる
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnergyModel {
+    cycle_seconds: f64,
+    idle_power: f64,
+    sending_power: f64,
+    receiving_power: f64,
+    waiting_power: f64,
+    reply_ack_power: f64,
+    tx_energy_per_bit: f64,
+    rx_energy_per_bit: f64,
+    header_bits: u32,
+    battery_budget: Option<f64>,
+    // ノードごとの消費エネルギー [J]
+    consumed: HashMap<NodeId, f64>,
+    // 最初に電池を使い切ったノードと，そのときまでに実行したサイクル数
+    depleted: Option<(NodeId, CycleNum)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnergyReport {
+    // ノードごとの消費エネルギー [J]，ノードidの順
+    pub node_energy: Vec<(NodeId, f64)>,
+    pub total_energy: f64,
+    // 最初のノードが電池を使い切るまでのサイクル数，使い切っていなければNone
+    pub lifetime_cycles: Option<CycleNum>,
+    pub first_depleted_node: Option<NodeId>,
+    // 届いたユーザーのメッセージ1ビットあたりのエネルギー [J]
+    pub energy_per_delivered_bit: f64,
+}
+
+impl EnergyModel {
+    pub fn new(info: &EnergyInfo, node_ids: &[NodeId]) -> Result<Self, String> {
+        if info.cycle_seconds <= 0.0 {
+            return Err("energy: cycle_seconds must be positive".to_string());
+        }
+        let costs = [
+            info.idle_power,
+            info.sending_power,
+            info.receiving_power,
+            info.waiting_power,
+            info.reply_ack_power,
+            info.tx_energy_per_bit,
+            info.rx_energy_per_bit,
+        ];
+        if costs.iter().any(|cost| *cost < 0.0) {
+            return Err("energy: power and energy per bit must be non-negative".to_string());
+        }
+        if info.battery_budget.is_some_and(|budget| budget <= 0.0) {
+            return Err("energy: battery_budget must be positive".to_string());
+        }
+
+        Ok(Self {
+            cycle_seconds: info.cycle_seconds,
+            idle_power: info.idle_power,
+            sending_power: info.sending_power,
+            receiving_power: info.receiving_power,
+            waiting_power: info.waiting_power,
+            reply_ack_power: info.reply_ack_power,
+            tx_energy_per_bit: info.tx_energy_per_bit,
+            rx_energy_per_bit: info.rx_energy_per_bit,
+            header_bits: info.header_bits.unwrap_or(0),
+            battery_budget: info.battery_budget,
+            consumed: node_ids.iter().map(|id| (id.clone(), 0.0)).collect(),
+            depleted: None,
+        })
+    }
+
+    fn state_power(&self, state: &State) -> f64 {
+        match state {
+            State::Idle => self.idle_power,
+            State::Sending => self.sending_power,
+            State::Receiving => self.receiving_power,
+            State::Waiting(_) => self.waiting_power,
+            State::ReplyAck => self.reply_ack_power,
+        }
+    }
+
+    fn flit_bits(&self, flit: &Flit) -> f64 {
+        (self.header_bits + flit.data_len() * 8) as f64
+    }
+
+    fn consume(&mut self, node_id: &str, energy: f64) {
+        *self.consumed.entry(node_id.to_string()).or_insert(0.0) += energy;
+    }
+
+    // フリットを1回送信した
+    pub fn record_transmission(&mut self, node_id: &str, flit: &Flit) {
+        self.consume(node_id, self.flit_bits(flit) * self.tx_energy_per_bit);
+    }
+
+    // フリットを1つ受信した
+    pub fn record_reception(&mut self, node_id: &str, flit: &Flit) {
+        self.consume(node_id, self.flit_bits(flit) * self.rx_energy_per_bit);
+    }
+
+    // cur_cycleの間stateにいた
+    // そのサイクルの送受信を記録した後に呼び，電池を使い切ったかを調べる
+    pub fn record_cycle(&mut self, node_id: &str, state: &State, cur_cycle: CycleNum) {
+        self.consume(node_id, self.state_power(state) * self.cycle_seconds);
+
+        if let (None, Some(budget)) = (&self.depleted, self.battery_budget) {
+            if self.consumed[node_id] >= budget {
+                self.depleted = Some((node_id.to_string(), cur_cycle + 1));
+            }
+        }
+    }
+
+    pub fn report(&self, delivered_bits: u64) -> EnergyReport {
+        let mut node_energy: Vec<(NodeId, f64)> = self
+            .consumed
+            .iter()
+            .map(|(id, energy)| (id.clone(), *energy))
+            .collect();
+        node_energy.sort_by(|a, b| a.0.cmp(&b.0));
+        let total_energy = node_energy.iter().map(|(_, energy)| energy).sum::<f64>();
+
+        EnergyReport {
+            node_energy,
+            total_energy,
+            lifetime_cycles: self.depleted.as_ref().map(|(_, cycle)| *cycle),
+            first_depleted_node: self.depleted.as_ref().map(|(id, _)| id.clone()),
+            energy_per_delivered_bit: total_energy / delivered_bits as f64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::flit::AckFlit;
+
+    fn energy_info() -> EnergyInfo {
+        EnergyInfo {
+            cycle_seconds: 0.5,
+            idle_power: 1.0,
+            sending_power: 4.0,
+            receiving_power: 3.0,
+            waiting_power: 2.0,
+            reply_ack_power: 4.0,
+            tx_energy_per_bit: 0.25,
+            rx_energy_per_bit: 0.125,
+            header_bits: Some(16),
+            battery_budget: Some(7.5),
+        }
+    }
+
+    #[test]
+    fn test_energy_model() {
+        let ids = vec!["a".to_string(), "b".to_string()];
+        let mut energy = EnergyModel::new(&energy_info(), &ids).unwrap();
+        let ack = Flit::Ack(AckFlit {
+            source_id: "a".to_string(),
+            dest_id: "b".to_string(),
+            packet_id: 0,
+            flit_num: 0,
+            channel_id: 0,
+        });
+
+        // ackはヘッダのビットのみ送受信する
+        energy.record_transmission("a", &ack);
+        energy.record_reception("b", &ack);
+        energy.record_cycle("a", &State::ReplyAck, 0);
+        energy.record_cycle("b", &State::Receiving, 0);
+
+        let report = energy.report(8);
+        assert_eq!(
+            report.node_energy,
+            vec![("a".to_string(), 4.0 + 2.0), ("b".to_string(), 2.0 + 1.5)]
+        );
+        assert_eq!(report.total_energy, 9.5);
+        assert_eq!(report.energy_per_delivered_bit, 9.5 / 8.0);
+        assert_eq!(report.lifetime_cycles, None);
+
+        // aが電池を使い切った最初のノードになる
+        for cycle in 1..3 {
+            energy.record_cycle("a", &State::Waiting(Default::default()), cycle);
+            energy.record_cycle("b", &State::Idle, cycle);
+        }
+        let report = energy.report(0);
+        assert_eq!(report.lifetime_cycles, Some(3));
+        assert_eq!(report.first_depleted_node, Some("a".to_string()));
+    }
+
+    #[test]
+    fn test_new_energy_model() {
+        let mut info = energy_info();
+        info.cycle_seconds = 0.0;
+        assert!(EnergyModel::new(&info, &[]).is_err());
+
+        let mut info = energy_info();
+        info.rx_energy_per_bit = -1.0;
+        assert!(EnergyModel::new(&info, &[]).is_err());
+
+        let mut info = energy_info();
+        info.battery_budget = Some(0.0);
+        assert!(EnergyModel::new(&info, &[]).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::file::{FailureModelInfo, NodeEventInfo};
+use crate::rng::sim_rng;
+use crate::sim::node::NodeId;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// 指定したサイクルにノードを故障・復旧させるイベント
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NodeEvent {
+    // ノードは送受信も状態の更新もしなくなる
+    Fail(NodeId),
+    // Network/Hardwareの状態を初期化して動作を再開する
+    Restart(NodeId),
+}
+
+impl NodeEvent {
+    pub fn new(info: &NodeEventInfo, node_ids: &HashSet<NodeId>) -> Result<Self, String> {
+        if !node_ids.contains(&info.node_id) {
+            return Err(format!(
+                "node event at cycle {}: unknown node {}",
+                info.cycle_num, info.node_id
+            ));
+        }
+
+        match info.event.as_str() {
+            "fail" => Ok(NodeEvent::Fail(info.node_id.clone())),
+            "restart" => Ok(NodeEvent::Restart(info.node_id.clone())),
+            event => Err(format!(
+                "node event at cycle {}: unknown event {event}",
+                info.cycle_num
+            )),
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        match self {
+            NodeEvent::Fail(node_id) | NodeEvent::Restart(node_id) => node_id,
+        }
+    }
+}
+
+// MTBF/MTTRにしたがってランダムにノードを故障・復旧させる
+// 故障と復旧はそれぞれ毎サイクル1/MTBF, 1/MTTRの確率で起こる
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FailureModel {
+    pub mtbf: f64,
+    pub mttr: f64,
+    // 故障しうるノード
+    pub node_ids: HashSet<NodeId>,
+}
+
+impl FailureModel {
+    // node_idsを省略した場合はdefault_idsのノードが故障しうる
+    pub fn new(
+        info: &FailureModelInfo,
+        node_ids: &HashSet<NodeId>,
+        default_ids: &HashSet<NodeId>,
+    ) -> Result<Self, String> {
+        if info.mtbf < 1.0 || info.mttr < 1.0 {
+            return Err("failure model: mtbf and mttr must be at least 1 cycle".to_string());
+        }
+
+        let target_ids = match &info.node_ids {
+            Some(target_ids) => {
+                if let Some(node_id) = target_ids.iter().find(|id| !node_ids.contains(*id)) {
+                    return Err(format!("failure model: unknown node {node_id}"));
+                }
+                target_ids.iter().cloned().collect()
+            }
+            None => default_ids.clone(),
+        };
+
+        Ok(Self {
+            mtbf: info.mtbf,
+            mttr: info.mttr,
+            node_ids: target_ids,
+        })
+    }
+
+    // このサイクルに起こるイベント
+    pub fn next_event(&self, node_id: &str, alive: bool) -> Option<NodeEvent> {
+        if !self.node_ids.contains(node_id) {
+            return None;
+        }
+
+        let mut rng = sim_rng();
+        let p: f64 = rng.gen();
+        if alive && p < 1.0 / self.mtbf {
+            return Some(NodeEvent::Fail(node_id.to_string()));
+        }
+        if !alive && p < 1.0 / self.mttr {
+            return Some(NodeEvent::Restart(node_id.to_string()));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_node_event() {
+        let node_ids: HashSet<NodeId> = ["a", "b"].iter().map(|s| s.to_string()).collect();
+        let mut info = NodeEventInfo {
+            cycle_num: 10,
+            node_id: "a".to_string(),
+            event: "fail".to_string(),
+        };
+        assert_eq!(
+            NodeEvent::new(&info, &node_ids),
+            Ok(NodeEvent::Fail("a".to_string()))
+        );
+
+        info.event = "restart".to_string();
+        assert_eq!(
+            NodeEvent::new(&info, &node_ids),
+            Ok(NodeEvent::Restart("a".to_string()))
+        );
+
+        info.node_id = "x".to_string();
+        assert!(NodeEvent::new(&info, &node_ids).is_err());
+    }
+
+    #[test]
+    fn test_failure_model() {
+        let node_ids: HashSet<NodeId> = ["a", "b"].iter().map(|s| s.to_string()).collect();
+        let default_ids: HashSet<NodeId> = ["b"].iter().map(|s| s.to_string()).collect();
+        let info = FailureModelInfo {
+            mtbf: 1.0,
+            mttr: 1.0,
+            node_ids: None,
+        };
+        let model = FailureModel::new(&info, &node_ids, &default_ids).unwrap();
+
+        // MTBF, MTTRが1サイクルなら毎サイクル故障・復旧する
+        assert_eq!(model.next_event("a", true), None);
+        assert_eq!(
+            model.next_event("b", true),
+            Some(NodeEvent::Fail("b".to_string()))
+        );
+        assert_eq!(
+            model.next_event("b", false),
+            Some(NodeEvent::Restart("b".to_string()))
+        );
+
+        let info = FailureModelInfo {
+            mtbf: 0.5,
+            mttr: 1.0,
+            node_ids: None,
+        };
+        assert!(FailureModel::new(&info, &node_ids, &default_ids).is_err());
+    }
+}
@@ -0,0 +1,526 @@
+use crate::file::{MobilityInfo, WaypointInfo};
+use crate::rng::sim_rng;
+use crate::sim::node::{CycleNum, NodeId};
+use crate::sim::radio::distance;
+use crate::utils::read_json;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MobilityModel {
+    // 範囲内のランダムな目的地へランダムな速さで進み，着いたら止まって次の目的地を選ぶ
+    RandomWaypoint,
+    // ランダムな向きへ進み，範囲の端に着いたら止まって次の向きを選ぶ
+    RandomDirection,
+    // 各グループの先頭のノードがランダムウェイポイントで動き，
+    // 残りのノードは最初のリーダーとの位置関係にradius以内のずれを加えて付いていく
+    Group {
+        groups: Vec<Vec<NodeId>>,
+        radius: f64,
+    },
+    // ノードごとの通過点の間を線形補間して動く
+    Trajectory(HashMap<NodeId, Vec<(CycleNum, Vec<f64>)>>),
+}
+
+// ランダムに動くノードの現在の動き
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Movement {
+    // random_waypointでは目的地，random_directionでは進む向きの単位ベクトル
+    target: Vec<f64>,
+    speed: f64,
+    // 動き出すまでの残りサイクル数
+    pause_cycles: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mobility {
+    pub model: MobilityModel,
+    pub update_interval: u32,
+    area: Vec<f64>,
+    min_speed: f64,
+    max_speed: f64,
+    pause_cycles: u32,
+    // ランダムに動くノード(groupではリーダー)
+    node_ids: Vec<NodeId>,
+    movements: HashMap<NodeId, Movement>,
+    // groupでリーダーから見たメンバーの最初の位置
+    offsets: HashMap<NodeId, Vec<f64>>,
+    // 前回位置を進めたサイクル
+    last_cycle: CycleNum,
+}
+
+impl Mobility {
+    // node_idsを省略した場合はdefault_idsのノードが動く
+    // trajectory_fileはbase_dirからの相対パス
+    pub fn new(
+        info: &MobilityInfo,
+        node_ids: &HashSet<NodeId>,
+        default_ids: &HashSet<NodeId>,
+        base_dir: &Path,
+    ) -> Result<Self, String> {
+        if info.update_interval == 0 {
+            return Err("mobility: update_interval must be at least 1".to_string());
+        }
+
+        let mut mobility = Self {
+            model: MobilityModel::RandomWaypoint,
+            update_interval: info.update_interval,
+            area: Vec::new(),
+            min_speed: 0.0,
+            max_speed: 0.0,
+            pause_cycles: info.pause_cycles.unwrap_or(0),
+            node_ids: Vec::new(),
+            movements: HashMap::new(),
+            offsets: HashMap::new(),
+            last_cycle: 0,
+        };
+
+        if info.model == "trajectory" {
+            let Some(file) = &info.trajectory_file else {
+                return Err("mobility: trajectory needs trajectory_file".to_string());
+            };
+            let path = base_dir.join(file);
+            let waypoints = read_json::<HashMap<NodeId, Vec<WaypointInfo>>>(path.clone())
+                .map_err(|e| format!("mobility: failed to read {path:?}: {e}"))?;
+            check_ids(waypoints.keys(), node_ids)?;
+
+            let mut trajectories = HashMap::new();
+            for (node_id, list) in waypoints {
+                if list.is_empty() {
+                    return Err(format!("mobility: node {node_id} has no waypoints"));
+                }
+                let mut list: Vec<(CycleNum, Vec<f64>)> = list
+                    .into_iter()
+                    .map(|waypoint| (waypoint.cycle_num, waypoint.position))
+                    .collect();
+                list.sort_by_key(|(cycle, _)| *cycle);
+                trajectories.insert(node_id, list);
+            }
+            mobility.model = MobilityModel::Trajectory(trajectories);
+            return Ok(mobility);
+        }
+
+        // ランダムに動くモデルは範囲と速さが必要
+        let (Some(area), Some(min_speed), Some(max_speed)) =
+            (&info.area, info.min_speed, info.max_speed)
+        else {
+            return Err(format!(
+                "mobility: {} needs area, min_speed and max_speed",
+                info.model
+            ));
+        };
+        if area.len() != 2 && area.len() != 3 || area.iter().any(|max| *max <= 0.0) {
+            return Err("mobility: area must be 2D or 3D and positive".to_string());
+        }
+        if min_speed <= 0.0 || max_speed < min_speed {
+            return Err("mobility: speeds must satisfy 0 < min_speed <= max_speed".to_string());
+        }
+        mobility.area = area.clone();
+        mobility.min_speed = min_speed;
+        mobility.max_speed = max_speed;
+
+        match info.model.as_str() {
+            "random_waypoint" | "random_direction" => {
+                let mut ids: Vec<NodeId> = match &info.node_ids {
+                    Some(ids) => {
+                        check_ids(ids, node_ids)?;
+                        ids.clone()
+                    }
+                    None => default_ids.iter().cloned().collect(),
+                };
+                ids.sort();
+                mobility.node_ids = ids;
+                if info.model == "random_direction" {
+                    mobility.model = MobilityModel::RandomDirection;
+                }
+            }
+            "group" => {
+                let Some(groups) = &info.groups else {
+                    return Err("mobility: group needs groups".to_string());
+                };
+                if groups.iter().any(|group| group.is_empty()) {
+                    return Err("mobility: groups must not be empty".to_string());
+                }
+                check_ids(groups.iter().flatten(), node_ids)?;
+                mobility.node_ids = groups.iter().map(|group| group[0].clone()).collect();
+                mobility.model = MobilityModel::Group {
+                    groups: groups.clone(),
+                    radius: info.group_radius.unwrap_or(0.0),
+                };
+            }
+            model => return Err(format!("mobility: unknown model {model}")),
+        }
+        Ok(mobility)
+    }
+
+    // cur_cycleが位置を進めるサイクルなら，positionsを進めてtrueを返す
+    pub fn step(&mut self, cur_cycle: CycleNum, positions: &mut HashMap<NodeId, Vec<f64>>) -> bool {
+        if cur_cycle == 0 || !cur_cycle.is_multiple_of(self.update_interval) {
+            return false;
+        }
+        let elapsed = cur_cycle - self.last_cycle;
+        self.last_cycle = cur_cycle;
+
+        match self.model.clone() {
+            MobilityModel::RandomWaypoint => {
+                for node_id in self.node_ids.clone() {
+                    self.move_to_waypoint(&node_id, elapsed, positions);
+                }
+            }
+            MobilityModel::RandomDirection => {
+                for node_id in self.node_ids.clone() {
+                    self.move_in_direction(&node_id, elapsed, positions);
+                }
+            }
+            MobilityModel::Group { groups, radius } => {
+                for group in groups.iter() {
+                    let Some(leader_position) = positions.get(&group[0]).cloned() else {
+                        continue;
+                    };
+                    // 最初のリーダーとの位置関係を覚えておく
+                    for member in group.iter().skip(1) {
+                        if let Some(position) = positions.get(member) {
+                            self.offsets.entry(member.clone()).or_insert_with(|| {
+                                position
+                                    .iter()
+                                    .zip(leader_position.iter())
+                                    .map(|(x, leader_x)| x - leader_x)
+                                    .collect()
+                            });
+                        }
+                    }
+
+                    self.move_to_waypoint(&group[0], elapsed, positions);
+                    let leader_position = positions[&group[0]].clone();
+
+                    let mut rng = sim_rng();
+                    for member in group.iter().skip(1) {
+                        let Some(offset) = self.offsets.get(member) else {
+                            continue;
+                        };
+                        let position = leader_position
+                            .iter()
+                            .zip(offset.iter())
+                            .map(|(leader_x, offset_x)| {
+                                leader_x + offset_x + rng.gen_range(-radius..=radius)
+                            })
+                            .collect();
+                        positions.insert(member.clone(), self.clamp(position));
+                    }
+                }
+            }
+            MobilityModel::Trajectory(trajectories) => {
+                for (node_id, waypoints) in trajectories.iter() {
+                    positions.insert(node_id.clone(), interpolate(waypoints, cur_cycle));
+                }
+            }
+        }
+        true
+    }
+}
+
+// 外部に公開しない関数
+impl Mobility {
+    fn move_to_waypoint(
+        &mut self,
+        node_id: &str,
+        mut cycles: u32,
+        positions: &mut HashMap<NodeId, Vec<f64>>,
+    ) {
+        let Some(mut position) = positions.get(node_id).cloned() else {
+            return;
+        };
+        let mut movement = match self.movements.remove(node_id) {
+            Some(movement) => movement,
+            None => self.new_waypoint(0),
+        };
+
+        while cycles > 0 {
+            if movement.pause_cycles > 0 {
+                let paused = movement.pause_cycles.min(cycles);
+                movement.pause_cycles -= paused;
+                cycles -= paused;
+                continue;
+            }
+
+            let remaining = distance(&position, &movement.target);
+            let reach = movement.speed * cycles as f64;
+            if reach < remaining {
+                position = position
+                    .iter()
+                    .zip(movement.target.iter())
+                    .map(|(x, target_x)| x + (target_x - x) * reach / remaining)
+                    .collect();
+                break;
+            }
+
+            // 目的地に着いたら止まって次の目的地を選ぶ
+            position = movement.target.clone();
+            let used = ((remaining / movement.speed).ceil() as u32).max(1);
+            cycles = cycles.saturating_sub(used);
+            movement = self.new_waypoint(self.pause_cycles);
+        }
+
+        positions.insert(node_id.to_string(), position);
+        self.movements.insert(node_id.to_string(), movement);
+    }
+
+    fn move_in_direction(
+        &mut self,
+        node_id: &str,
+        mut cycles: u32,
+        positions: &mut HashMap<NodeId, Vec<f64>>,
+    ) {
+        let Some(mut position) = positions.get(node_id).cloned() else {
+            return;
+        };
+        let mut movement = match self.movements.remove(node_id) {
+            Some(movement) => movement,
+            None => self.new_direction(0),
+        };
+
+        while cycles > 0 {
+            if movement.pause_cycles > 0 {
+                let paused = movement.pause_cycles.min(cycles);
+                movement.pause_cycles -= paused;
+                cycles -= paused;
+                continue;
+            }
+
+            // 範囲の端に着くまでのサイクル数
+            let to_edge = position
+                .iter()
+                .zip(movement.target.iter())
+                .zip(self.area.iter())
+                .map(|((x, direction), max)| {
+                    let velocity = direction * movement.speed;
+                    if velocity > 0.0 {
+                        (max - x).max(0.0) / velocity
+                    } else if velocity < 0.0 {
+                        x.max(0.0) / -velocity
+                    } else {
+                        f64::INFINITY
+                    }
+                })
+                .fold(f64::INFINITY, f64::min);
+
+            let moving = to_edge.min(cycles as f64);
+            position = position
+                .iter()
+                .zip(movement.target.iter())
+                .map(|(x, direction)| x + direction * movement.speed * moving)
+                .collect();
+            position = self.clamp(position);
+            if to_edge >= cycles as f64 {
+                break;
+            }
+
+            // 端に着いたら止まって次の向きを選ぶ
+            let used = (to_edge.ceil() as u32).max(1);
+            cycles = cycles.saturating_sub(used);
+            movement = self.new_direction(self.pause_cycles);
+        }
+
+        positions.insert(node_id.to_string(), position);
+        self.movements.insert(node_id.to_string(), movement);
+    }
+
+    fn new_waypoint(&self, pause_cycles: u32) -> Movement {
+        let mut rng = sim_rng();
+        Movement {
+            target: self
+                .area
+                .iter()
+                .map(|max| rng.gen_range(0.0..=*max))
+                .collect(),
+            speed: rng.gen_range(self.min_speed..=self.max_speed),
+            pause_cycles,
+        }
+    }
+
+    fn new_direction(&self, pause_cycles: u32) -> Movement {
+        let mut rng = sim_rng();
+        let direction = loop {
+            let direction: Vec<f64> = self
+                .area
+                .iter()
+                .map(|_| rng.gen_range(-1.0..=1.0))
+                .collect();
+            let norm = direction.iter().map(|x| x * x).sum::<f64>().sqrt();
+            if norm > 1e-6 {
+                break direction.iter().map(|x| x / norm).collect();
+            }
+        };
+        Movement {
+            target: direction,
+            speed: rng.gen_range(self.min_speed..=self.max_speed),
+            pause_cycles,
+        }
+    }
+
+    fn clamp(&self, position: Vec<f64>) -> Vec<f64> {
+        position
+            .iter()
+            .zip(self.area.iter())
+            .map(|(x, max)| x.clamp(0.0, *max))
+            .collect()
+    }
+}
+
+fn check_ids<'a>(
+    ids: impl IntoIterator<Item = &'a NodeId>,
+    node_ids: &HashSet<NodeId>,
+) -> Result<(), String> {
+    match ids.into_iter().find(|id| !node_ids.contains(*id)) {
+        Some(id) => Err(format!("mobility: unknown node {id}")),
+        None => Ok(()),
+    }
+}
+
+// waypointsを線形補間したcur_cycleでの位置
+// 最初の通過点より前は最初の位置，最後の通過点より後は最後の位置にとどまる
+fn interpolate(waypoints: &[(CycleNum, Vec<f64>)], cur_cycle: CycleNum) -> Vec<f64> {
+    let next = waypoints.partition_point(|(cycle, _)| *cycle <= cur_cycle);
+    if next == 0 {
+        return waypoints[0].1.clone();
+    }
+    if next == waypoints.len() {
+        return waypoints[next - 1].1.clone();
+    }
+
+    let (prev_cycle, prev_position) = &waypoints[next - 1];
+    let (next_cycle, next_position) = &waypoints[next];
+    let ratio = (cur_cycle - prev_cycle) as f64 / (next_cycle - prev_cycle) as f64;
+    prev_position
+        .iter()
+        .zip(next_position.iter())
+        .map(|(prev_x, next_x)| prev_x + (next_x - prev_x) * ratio)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mobility_info(model: &str) -> MobilityInfo {
+        MobilityInfo {
+            model: model.to_string(),
+            update_interval: 10,
+            area: Some(vec![100.0, 100.0]),
+            min_speed: Some(0.5),
+            max_speed: Some(1.0),
+            pause_cycles: Some(5),
+            node_ids: None,
+            groups: None,
+            group_radius: None,
+            trajectory_file: None,
+        }
+    }
+
+    fn ids(list: &[&str]) -> HashSet<NodeId> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let waypoints = vec![(10, vec![0.0, 0.0]), (20, vec![10.0, 20.0])];
+        assert_eq!(interpolate(&waypoints, 0), vec![0.0, 0.0]);
+        assert_eq!(interpolate(&waypoints, 15), vec![5.0, 10.0]);
+        assert_eq!(interpolate(&waypoints, 30), vec![10.0, 20.0]);
+    }
+
+    #[test]
+    fn test_random_mobility() {
+        for model in ["random_waypoint", "random_direction"] {
+            let mut mobility = Mobility::new(
+                &mobility_info(model),
+                &ids(&["a", "b"]),
+                &ids(&["b"]),
+                Path::new("."),
+            )
+            .unwrap();
+            let mut positions = HashMap::new();
+            positions.insert("a".to_string(), vec![50.0, 50.0]);
+            positions.insert("b".to_string(), vec![50.0, 50.0]);
+
+            // update_intervalのサイクル以外では動かない
+            assert!(!mobility.step(5, &mut positions));
+
+            for cycle in (10..1000).step_by(10) {
+                let before = positions["b"].clone();
+                assert!(mobility.step(cycle, &mut positions));
+
+                // 1回の更新で動けるのはmax_speed * update_intervalまで
+                assert!(distance(&before, &positions["b"]) <= 10.0 + 1e-9);
+                assert!(positions["b"].iter().all(|x| (0.0..=100.0).contains(x)));
+            }
+            assert_eq!(positions["a"], vec![50.0, 50.0]);
+            assert_ne!(positions["b"], vec![50.0, 50.0]);
+        }
+    }
+
+    #[test]
+    fn test_group_mobility() {
+        let mut info = mobility_info("group");
+        info.groups = Some(vec![vec!["a".to_string(), "b".to_string()]]);
+        info.group_radius = Some(1.0);
+        let mut mobility =
+            Mobility::new(&info, &ids(&["a", "b"]), &ids(&[]), Path::new(".")).unwrap();
+
+        let mut positions = HashMap::new();
+        positions.insert("a".to_string(), vec![50.0, 50.0]);
+        positions.insert("b".to_string(), vec![55.0, 50.0]);
+
+        for cycle in (10..1000).step_by(10) {
+            mobility.step(cycle, &mut positions);
+
+            // 範囲の端でなければ，メンバーはリーダーとの位置関係をradius以内のずれで保つ
+            let (a, b) = (&positions["a"], &positions["b"]);
+            if b.iter().all(|x| 0.0 < *x && *x < 100.0) {
+                assert!((b[0] - a[0] - 5.0).abs() <= 1.0 + 1e-9);
+                assert!((b[1] - a[1]).abs() <= 1.0 + 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_new_mobility() {
+        let node_ids = ids(&["a", "b"]);
+
+        let mut info = mobility_info("random_waypoint");
+        info.node_ids = Some(vec!["x".to_string()]);
+        assert!(Mobility::new(&info, &node_ids, &node_ids, Path::new(".")).is_err());
+
+        let mut info = mobility_info("random_waypoint");
+        info.min_speed = Some(2.0);
+        assert!(Mobility::new(&info, &node_ids, &node_ids, Path::new(".")).is_err());
+
+        let mut info = mobility_info("random_waypoint");
+        info.area = None;
+        assert!(Mobility::new(&info, &node_ids, &node_ids, Path::new(".")).is_err());
+
+        assert!(Mobility::new(
+            &mobility_info("group"),
+            &node_ids,
+            &node_ids,
+            Path::new(".")
+        )
+        .is_err());
+        assert!(Mobility::new(
+            &mobility_info("trajectory"),
+            &node_ids,
+            &node_ids,
+            Path::new(".")
+        )
+        .is_err());
+        assert!(Mobility::new(
+            &mobility_info("teleport"),
+            &node_ids,
+            &node_ids,
+            Path::new(".")
+        )
+        .is_err());
+    }
+}
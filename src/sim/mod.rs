@@ -1,20 +1,52 @@
+pub mod checkpoint;
+pub mod energy;
+pub mod failure;
+pub mod mobility;
+pub mod monitor;
 pub mod node;
-pub(crate) mod node_type;
+pub mod node_type;
 pub mod nodes;
+pub mod observer;
+pub mod phase;
+pub mod radio;
+pub mod stop;
+pub mod topology;
+pub mod tree;
 
-use crate::file::InputFile;
+use crate::file::{InputFile, NodeInfo};
+use crate::hardware::constants::{DEFAULT_TTL, PACKET_HEADER_BYTES};
 use crate::hardware::switching::Switching;
-use crate::log::aggregate_log;
+use crate::log::{
+    aggregate_broadcast_log, aggregate_class_log, aggregate_failure_log, aggregate_join_reject_log,
+    aggregate_link_change_log, aggregate_log, aggregate_reassembly_log, aggregate_rejoin_log,
+    aggregate_transport_log, count_delivered_bits, enable_events, get_loop_log, get_topology_log,
+    take_events,
+};
+use crate::network::arbiter::Arbiter;
 use crate::network::core_functions::packets::InjectionPacket;
+use crate::network::transport::Transport;
 
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::{error, path::Path, path::PathBuf};
 
+use self::energy::EnergyModel;
+use self::failure::{FailureModel, NodeEvent};
+use self::mobility::Mobility;
+use self::monitor::Monitor;
 use self::node::Node;
 use self::node_type::NodeType;
 use self::nodes::Nodes;
+use self::observer::SimObserver;
+use self::phase::Phases;
+use self::radio::RadioModel;
+use self::stop::{StopConditions, StopReason, StopReport};
+use self::topology::TopologyEvent;
+use self::tree::{alive_neighbors, spanning_trees, tree_overlap, SpanningTree};
 
 use crate::network::vid::add_to_vid_table;
+use crate::rng::seed_rng;
+use crate::validate::{validate, Problem, Severity};
 
 pub struct SimBuilder {
     pub path: PathBuf,
@@ -27,55 +59,199 @@ impl SimBuilder {
         }
     }
     pub fn build(&self) -> Result<Sim, Box<dyn error::Error>> {
-        let input = InputFile::new(self.path.clone());
+        let input = InputFile::new(self.path.clone())?;
+        // trajectory_fileは入力ファイルと同じディレクトリから探す
+        let base_dir = self.path.parent().unwrap_or(Path::new("."));
+
+        // 入力に誤りがあれば実行する前にすべて報告する
+        // 以降はvalidateで確かめた入力を組み立てるだけ
+        let (errors, warnings): (Vec<Problem>, Vec<Problem>) = validate(&input, base_dir)
+            .into_iter()
+            .partition(|problem| problem.severity == Severity::Error);
+        for warning in warnings.iter() {
+            eprintln!("{warning}");
+        }
+        if !errors.is_empty() {
+            let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+            return Err(errors.join("\n").into());
+        }
+        // ノードのidなどもこの乱数から作るので最初にシードを設定する
+        if let Some(seed) = input.seed {
+            seed_rng(seed);
+        }
 
         let switching = input.switching.parse::<Switching>()?;
         let routing = input.routing.unwrap_or("default".to_string());
 
+        let assign_address = input.address_mode.as_deref() != Some("random");
+        let header_bytes = input.header_bytes.unwrap_or(PACKET_HEADER_BYTES);
+        let ttl = input.ttl.unwrap_or(DEFAULT_TTL);
+        let loop_detection = input.loop_detection.unwrap_or(false);
+        let arbiter = match &input.qos {
+            Some(info) => Arbiter::new(info, input.channel_num)?,
+            None => Arbiter::strict_priority(input.channel_num),
+        };
+
+        let node_ids: HashSet<String> = input.nodes.iter().map(|n| n.node_id.clone()).collect();
+        let topology_events = input
+            .topology_events
+            .iter()
+            .flatten()
+            .map(|info| Ok((info.cycle_num, TopologyEvent::new(info, &node_ids)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        let node_events = input
+            .node_events
+            .iter()
+            .flatten()
+            .map(|info| Ok((info.cycle_num, NodeEvent::new(info, &node_ids)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        let non_coordinator_ids: HashSet<String> = input
+            .nodes
+            .iter()
+            .filter(|n| NodeType::new(&n.node_type) != NodeType::Coordinator)
+            .map(|n| n.node_id.clone())
+            .collect();
+        let failure_model = input
+            .failure_model
+            .as_ref()
+            .map(|info| FailureModel::new(info, &node_ids, &non_coordinator_ids))
+            .transpose()?;
+        let mobility = input
+            .mobility
+            .as_ref()
+            .map(|info| Mobility::new(info, &node_ids, &non_coordinator_ids, base_dir))
+            .transpose()?;
+
+        let phases = input
+            .phases
+            .as_ref()
+            .map(|info| Phases::new(info, input.total_cycles, &input.packets))
+            .transpose()?;
+
         let nodes: Vec<Node> = input
             .nodes
             .iter()
             .map(|node| {
-                let packets = input
+                // 同じサイクルのパケットも入力の順にすべて生成する
+                let mut packets: HashMap<u32, Vec<InjectionPacket>> = HashMap::new();
+                input
                     .packets
                     .iter()
                     .filter(|packet| packet.src_id == node.node_id)
-                    .map(|packet| {
-                        (
-                            packet.cycle_num,
-                            InjectionPacket {
+                    // ドレイン中は新規パケットを生成しない
+                    .filter(|packet| {
+                        phases
+                            .as_ref()
+                            .is_none_or(|phases| phases.injects(packet.cycle_num))
+                    })
+                    .for_each(|packet| {
+                        packets
+                            .entry(packet.cycle_num)
+                            .or_default()
+                            .push(InjectionPacket {
                                 source_id: packet.src_id.clone(),
                                 dest_id: packet.dest_id.clone(),
                                 message: packet.msg.clone(),
-                            },
-                        )
-                    })
-                    .collect::<HashMap<u32, InjectionPacket>>();
+                                dest_ids: packet.dest_ids.clone().unwrap_or_default(),
+                                inject_cycle: Some(packet.cycle_num),
+                                priority: packet.priority.unwrap_or(0),
+                                size_bytes: packet.size_bytes,
+                                payload: packet.payload.clone(),
+                                class: packet.class.clone(),
+                                segment: None,
+                            })
+                    });
 
-                Node::new(
+                let mut node = Node::new(
                     &node.node_id,
                     input.channel_num,
                     &switching.clone(),
                     &routing,
                     &NodeType::new(&node.node_type),
                     &packets,
-                )
+                );
+                node.set_address_assignment(assign_address);
+                node.set_header_bytes(header_bytes);
+                node.set_transport(
+                    input
+                        .transport
+                        .as_ref()
+                        .map(|info| Transport::new(&node.id, info)),
+                );
+                node.set_arbiter(&arbiter);
+                node.set_ttl(ttl);
+                node.set_loop_detection(loop_detection);
+                node
             })
             .collect();
 
         add_to_vid_table(u32::MAX, "broadcast");
         // print_vid_table();
+        let mut nodes = match (&input.neighbors, &input.radio) {
+            (Some(neighbors), None) => Nodes::new(&nodes, neighbors),
+            (None, Some(radio_info)) => {
+                let radio = RadioModel::new(radio_info)?;
+                Nodes::from_positions(&nodes, &node_positions(&input.nodes), &radio)
+            }
+            // どちらか一方だけがあることはvalidateで確かめてある
+            _ => unreachable!(),
+        };
+        nodes.set_topology_events(&topology_events);
+        nodes.set_node_events(&node_events);
+        nodes.failure_model = failure_model;
+        nodes.mobility = mobility;
+        nodes.energy = input
+            .energy
+            .as_ref()
+            .map(|info| {
+                let node_ids: Vec<String> = input.nodes.iter().map(|n| n.node_id.clone()).collect();
+                EnergyModel::new(info, &node_ids)
+            })
+            .transpose()?;
+
+        let monitor = input
+            .monitor
+            .as_ref()
+            .map(|info| Monitor::new(info, input.node_num))
+            .transpose()?;
+
+        let stop_conditions = input
+            .stop_conditions
+            .as_ref()
+            .map(|info| StopConditions::new(info, &nodes))
+            .transpose()?;
+
         Ok(Sim {
             node_num: input.node_num,
-            nodes: Nodes::new(&nodes, &input.neighbors),
+            nodes,
             total_cycles: input.total_cycles,
             channel_num: input.channel_num,
             cur_cycles: 0,
-            log_range: input.log_range.unwrap_or(vec![0, input.total_cycles]),
+            // 計測区間があればその区間に送信を始めたパケットを集計する
+            log_range: input.log_range.unwrap_or(match &phases {
+                Some(phases) => vec![phases.measurement_begin, phases.measurement_end],
+                None => vec![0, input.total_cycles],
+            }),
+            phases,
+            stop_conditions,
+            monitor,
+            tree_report: input.tree_report.unwrap_or(false),
+            stop_reason: StopReason::TotalCycles,
+            observers: Vec::new(),
         })
     }
 }
 
+// 全ノードの座標を取り出す
+// 座標がそろっていることはvalidateで確かめてある
+fn node_positions(nodes: &[NodeInfo]) -> HashMap<String, Vec<f64>> {
+    nodes
+        .iter()
+        .filter_map(|node| Some((node.node_id.clone(), node.position.clone()?)))
+        .collect()
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Sim {
     pub node_num: u32,
     pub total_cycles: u32,
@@ -83,23 +259,220 @@ pub struct Sim {
     pub channel_num: u8,
     pub nodes: Nodes,
     pub log_range: Vec<u32>,
+    pub phases: Option<Phases>,
+    pub stop_conditions: Option<StopConditions>,
+    pub monitor: Option<Monitor>,
+    // 実行後にチャネルごとの木を出力するかどうか
+    pub tree_report: bool,
+    // シミュレーションが終わった理由
+    pub stop_reason: StopReason,
+    // チェックポイントには保存しないので再開後に登録し直す
+    #[serde(skip)]
+    observers: Vec<Box<dyn SimObserver>>,
 }
 
 impl Sim {
+    // 1サイクル実行する
+    // 終了条件を満たしたか，total_cyclesに達したらその理由を返す
+    pub fn step(&mut self) -> Option<StopReason> {
+        if self.cur_cycles >= self.total_cycles {
+            self.stop_reason = StopReason::TotalCycles;
+            return Some(StopReason::TotalCycles);
+        }
+
+        self.nodes.run_cycle(self.cur_cycles);
+        self.notify_observers();
+        self.cur_cycles += 1;
+        if let Some(monitor) = &mut self.monitor {
+            monitor.check(self.cur_cycles, &self.nodes);
+        }
+
+        // 終了条件を満たしたらtotal_cyclesより前に終える
+        let reason = self
+            .stop_conditions
+            .as_mut()
+            .and_then(|stop_conditions| stop_conditions.check(self.cur_cycles, &self.nodes))
+            // 計測したパケットがすべて届いたらドレインを終える
+            .or_else(|| self.phases.as_ref()?.check(self.cur_cycles))
+            .or((self.cur_cycles >= self.total_cycles).then_some(StopReason::TotalCycles));
+        if let Some(reason) = reason {
+            self.stop_reason = reason;
+        }
+        reason
+    }
+
+    // cur_cyclesがcycleになるまで実行する
+    // 途中で終了条件を満たしたらその理由を返す
+    pub fn run_until(&mut self, cycle: u32) -> Option<StopReason> {
+        while self.cur_cycles < cycle {
+            if let Some(reason) = self.step() {
+                return Some(reason);
+            }
+        }
+        None
+    }
+
+    // 実行中に新規パケットを生成する
+    // 次のstepで送信元ノードに渡され，入力で指定したパケットと同じように扱われる
+    pub fn inject_packet(
+        &mut self,
+        src_id: &str,
+        dest_id: &str,
+        message: &str,
+    ) -> Result<(), String> {
+        if dest_id != "broadcast" && self.node(dest_id).is_none() {
+            return Err(format!("unknown destination node: {dest_id}"));
+        }
+        let Some(node) = self.nodes.nodes.iter_mut().find(|node| node.id == src_id) else {
+            return Err(format!("unknown source node: {src_id}"));
+        };
+        if !node.alive {
+            return Err(format!("node {src_id} has failed"));
+        }
+        if let Some(phases) = &mut self.phases {
+            phases.record_injection(self.cur_cycles, dest_id)?;
+        }
+        if let Some(stop_conditions) = &mut self.stop_conditions {
+            stop_conditions.record_injection(self.cur_cycles, dest_id);
+        }
+
+        node.network.send_new_packet(&InjectionPacket {
+            source_id: src_id.to_string(),
+            dest_id: dest_id.to_string(),
+            message: message.to_string(),
+            dest_ids: vec![],
+            inject_cycle: Some(self.cur_cycles),
+            priority: 0,
+            size_bytes: None,
+            payload: None,
+            class: None,
+            segment: None,
+        })
+    }
+
+    // 以降のstepで起きたイベントを受け取るオブザーバを登録する
+    pub fn add_observer(&mut self, observer: Box<dyn SimObserver>) {
+        enable_events();
+        self.observers.push(observer);
+    }
+
+    // このサイクルに起きたイベントをオブザーバに渡す
+    fn notify_observers(&mut self) {
+        if self.observers.is_empty() {
+            return;
+        }
+        for event in take_events() {
+            for observer in self.observers.iter_mut() {
+                observer.on_event(self.cur_cycles, &event);
+            }
+        }
+    }
+
+    pub fn node(&self, node_id: &str) -> Option<&Node> {
+        self.nodes.nodes.iter().find(|node| node.id == node_id)
+    }
+
+    // 生きているノードの親からチャネルごとの木を作る
+    pub fn spanning_trees(&self) -> Vec<SpanningTree> {
+        spanning_trees(&self.nodes)
+    }
+
     pub fn run(&mut self) {
         // シミュレーションを実行する
-        while self.cur_cycles < self.total_cycles {
-            self.nodes.run_cycle(self.cur_cycles);
-            self.cur_cycles += 1;
-        }
+        self.run_until(self.total_cycles);
+        println!(
+            "{:?}",
+            StopReport {
+                reason: self.stop_reason,
+                cycles: self.cur_cycles,
+            }
+        );
 
         println!("{:?}", aggregate_log(self.log_range[0], self.log_range[1]));
+        if let Some(phases) = &self.phases {
+            println!("{:?}", phases.report());
+        }
+        if let Some(energy) = &self.nodes.energy {
+            println!("{:?}", energy.report(count_delivered_bits()));
+        }
+
+        let node_ids: Vec<String> = self
+            .nodes
+            .nodes
+            .iter()
+            .map(|node| node.id.clone())
+            .collect();
+        for report in aggregate_broadcast_log(&node_ids) {
+            println!("{:?}", report);
+        }
+
+        for topology_log in get_topology_log() {
+            println!("{:?}", topology_log);
+        }
+
+        let link_change_report = aggregate_link_change_log();
+        if link_change_report.update_count > 0 {
+            println!("{:?}", link_change_report);
+        }
+
+        let failure_report = aggregate_failure_log();
+        if failure_report.failure_count > 0 {
+            println!("{:?}", failure_report);
+        }
+
+        let rejoin_report = aggregate_rejoin_log();
+        if rejoin_report.rejoin_count > 0 {
+            println!("{:?}", rejoin_report);
+        }
+
+        let join_reject_report = aggregate_join_reject_log();
+        if join_reject_report.reject_count > 0 {
+            println!("{:?}", join_reject_report);
+        }
+
+        let transport_report = aggregate_transport_log(self.cur_cycles);
+        if transport_report.sent_segments > 0 {
+            println!("{:?}", transport_report);
+        }
+
+        if let Some(monitor) = &self.monitor {
+            for stall_report in monitor.reports() {
+                println!("{:?}", stall_report);
+            }
+        }
+
+        for loop_log in get_loop_log() {
+            println!("{:?}", loop_log);
+        }
+
+        for class_report in aggregate_class_log() {
+            println!("{:?}", class_report);
+        }
+
+        let reassembly_report = aggregate_reassembly_log();
+        if reassembly_report.partial + reassembly_report.duplicate + reassembly_report.dropped > 0 {
+            println!("{:?}", reassembly_report);
+        }
+
+        if self.tree_report {
+            let trees = self.spanning_trees();
+            let neighbors = alive_neighbors(&self.nodes);
+            for tree in trees.iter() {
+                println!("{:?}", tree);
+                println!("{:?}", tree.report(&neighbors));
+            }
+            if trees.len() > 1 {
+                println!("{:?}", tree_overlap(&trees));
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::log::count_finished_packets;
+    use crate::network::BufferOccupancy;
 
     #[test]
     fn test_sim_build() {
@@ -115,11 +488,156 @@ mod tests {
         assert_eq!(sim.nodes.nodes[0].id, "node1");
         assert_eq!(sim.nodes.nodes[0].node_type, NodeType::Coordinator);
         assert_eq!(sim.nodes.nodes[0].packets.len(), 1);
-        assert_eq!(sim.nodes.nodes[0].packets[&40].source_id, "node1");
-        assert_eq!(sim.nodes.nodes[0].packets[&40].dest_id, "node2");
-        assert_eq!(sim.nodes.nodes[0].packets[&40].message, "Hello, World!");
+        assert_eq!(sim.nodes.nodes[0].packets[&40][0].source_id, "node1");
+        assert_eq!(sim.nodes.nodes[0].packets[&40][0].dest_id, "node2");
+        assert_eq!(sim.nodes.nodes[0].packets[&40][0].message, "Hello, World!");
         assert_eq!(sim.nodes.nodes[1].id, "node2");
         assert_eq!(sim.nodes.nodes[1].node_type, NodeType::Router);
         assert_eq!(sim.nodes.nodes[1].packets.len(), 0);
     }
+
+    #[test]
+    fn test_sim_build_from_positions() {
+        let path = PathBuf::from("tests/run/auto/6_p.json");
+        let sim = SimBuilder::new(&path).build().unwrap();
+
+        // 格子状に並んだノードの上下左右は必ず隣接する
+        let neighbors = &sim.nodes.neighbors;
+        assert!(neighbors["0"].contains(&"1".to_string()));
+        assert!(neighbors["0"].contains(&"5".to_string()));
+        assert!(!neighbors["0"].contains(&"2".to_string()));
+        for (node_id, list) in neighbors.iter() {
+            for neighbor in list {
+                assert!(neighbors[neighbor].contains(node_id));
+            }
+        }
+    }
+
+    #[test]
+    fn test_step_and_inject_packet() {
+        let path = PathBuf::from("tests/run/auto/1_c.json");
+        let mut sim = SimBuilder::new(&path).build().unwrap();
+
+        assert_eq!(sim.step(), None);
+        assert_eq!(sim.cur_cycles, 1);
+        assert_eq!(sim.node("node2").unwrap().cur_cycle(), 0);
+
+        assert_eq!(sim.run_until(30), None);
+        assert_eq!(sim.cur_cycles, 30);
+
+        // 参加したノードは親への経路を持つ
+        let routing_table = sim.node("node2").unwrap().network.routing_table();
+        assert_eq!(routing_table.parent_ids, vec![Some("node1".to_string())]);
+        let routing_table = sim.node("node1").unwrap().network.routing_table();
+        assert_eq!(routing_table.children_ids, vec![vec!["node2".to_string()]]);
+        assert_eq!(routing_table.roots, vec![true]);
+        let trees = sim.spanning_trees();
+        assert_eq!(trees[0].root_id.as_deref(), Some("node1"));
+        assert_eq!(trees[0].parent_ids["node2"], "node1");
+
+        assert!(sim.inject_packet("node2", "node3", "hello").is_err());
+        sim.inject_packet("node2", "node1", "hello").unwrap();
+        let occupancy = sim.node("node2").unwrap().network.buffer_occupancy();
+        assert_eq!(occupancy.queued_packets, 1);
+
+        assert_eq!(sim.run_until(100), Some(StopReason::TotalCycles));
+        assert_eq!(sim.cur_cycles, 50);
+        assert_eq!(sim.step(), Some(StopReason::TotalCycles));
+        assert_eq!(sim.cur_cycles, 50);
+        assert_eq!(
+            sim.node("node2").unwrap().network.buffer_occupancy(),
+            BufferOccupancy::default()
+        );
+        // 入力で指定したパケットと実行中に生成したパケットが届いた
+        assert_eq!(count_finished_packets(), 2);
+    }
+
+    #[test]
+    fn test_observer() {
+        use self::observer::SimEvent;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        struct Recorder(Rc<RefCell<Vec<(u32, SimEvent)>>>);
+        impl SimObserver for Recorder {
+            fn on_event(&mut self, cycle: u32, event: &SimEvent) {
+                self.0.borrow_mut().push((cycle, event.clone()));
+            }
+        }
+
+        let path = PathBuf::from("tests/run/auto/1_c.json");
+        let mut sim = SimBuilder::new(&path).build().unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        sim.add_observer(Box::new(Recorder(events.clone())));
+        sim.run_until(sim.total_cycles);
+
+        let events = events.borrow();
+        assert!(events.iter().any(|(_, event)| *event
+            == SimEvent::NodeJoined {
+                node_id: "node2".to_string()
+            }));
+        assert!(events
+            .iter()
+            .any(|(_, event)| matches!(event, SimEvent::FlitSent { .. })));
+        assert!(events
+            .iter()
+            .any(|(_, event)| matches!(event, SimEvent::StateTransition { .. })));
+
+        // 入力で指定したパケットは40サイクル目に生成され，その後届く
+        let (cycle, _) = events
+            .iter()
+            .find(|(_, event)| matches!(event, SimEvent::PacketInjected { .. }))
+            .unwrap();
+        assert_eq!(*cycle, 40);
+        let delivered: Vec<&(u32, SimEvent)> = events
+            .iter()
+            .filter(|(_, event)| matches!(event, SimEvent::PacketDelivered { .. }))
+            .collect();
+        assert_eq!(delivered.len(), 1);
+        let (
+            cycle,
+            SimEvent::PacketDelivered {
+                node_id, message, ..
+            },
+        ) = delivered[0]
+        else {
+            unreachable!();
+        };
+        assert!(*cycle >= 40);
+        assert_eq!(node_id, "node2");
+        assert_eq!(message, "Hello, World!");
+    }
+
+    #[test]
+    fn test_multiple_packets_per_cycle() {
+        use self::observer::SimEvent;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        struct Delivered(Rc<RefCell<Vec<String>>>);
+        impl SimObserver for Delivered {
+            fn on_event(&mut self, _: u32, event: &SimEvent) {
+                if let SimEvent::PacketDelivered { message, .. } = event {
+                    self.0.borrow_mut().push(message.clone());
+                }
+            }
+        }
+
+        let path = PathBuf::from("tests/run/auto/2_q.json");
+        let mut sim = SimBuilder::new(&path).build().unwrap();
+        // 同じサイクルのパケットも上書きされずに残る
+        let packets = &sim.nodes.nodes[1].packets[&100];
+        assert_eq!(packets.len(), 3);
+        // ペイロードは作らず，バイト数とパターンだけを持つ
+        assert_eq!(packets[0].message, "first");
+        assert_eq!(packets[0].size_bytes, Some(300));
+        assert_eq!(packets[0].payload.as_deref(), Some("zeros"));
+
+        let delivered = Rc::new(RefCell::new(Vec::new()));
+        sim.add_observer(Box::new(Delivered(delivered.clone())));
+        sim.run_until(sim.total_cycles);
+
+        // 優先度の高いパケットが先に送られる
+        assert_eq!(*delivered.borrow(), vec!["second", "first", "third"]);
+    }
 }
@@ -0,0 +1,503 @@
+use crate::file::MonitorInfo;
+use crate::hardware::constants::MAX_RESEND_TIMES;
+use crate::log::get_in_flight_packets;
+use crate::network::flit::Flit;
+use crate::network::packet_log_id;
+use crate::sim::node::{CycleNum, Node, NodeId};
+use crate::sim::nodes::Nodes;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StallKind {
+    // バッファのフリットが，互いに相手のパケットに押さえられた入力を待って進まない
+    Deadlock,
+    // 中継され続けているのに宛先に届かない
+    Livelock,
+}
+
+// 検出したデッドロック・ライブロックに関わるノード，チャネル，パケット
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StallReport {
+    pub kind: StallKind,
+    pub cycle: CycleNum,
+    // デッドロックでは待ち関係の順のバッファのノード，ライブロックでは通った順
+    pub node_ids: Vec<NodeId>,
+    pub channel_ids: Vec<u8>,
+    pub packet_ids: Vec<String>,
+}
+
+// 待ち関係のグラフの頂点，ノードのチャネルの送信バッファ
+type BufferId = (NodeId, u8);
+
+// 送信バッファの先頭で進めずにいるフリットと，それが待っているバッファ
+#[derive(Debug, Clone, PartialEq)]
+struct BlockedBuffer {
+    flit: Flit,
+    // ハードウェアの再送(MAX_RESEND_TIMES回)を超えても送れていないか
+    beyond_resend: bool,
+    waits_for: Vec<BufferId>,
+}
+
+// interval_cyclesごとにチャネルの待ち関係のグラフとパケットの経路を調べる
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Monitor {
+    interval_cycles: u32,
+    livelock_hops: usize,
+    // 前回調べたときに各送信バッファの先頭にあったフリット
+    held_flits: HashMap<BufferId, Flit>,
+    // 前回調べたときに届いていなかったパケットのホップ数
+    hops: HashMap<String, usize>,
+    // 同じデッドロックやライブロックを繰り返し報告しない
+    reported: HashSet<Vec<String>>,
+    reports: Vec<StallReport>,
+}
+
+impl Monitor {
+    pub fn new(info: &MonitorInfo, node_num: u32) -> Result<Self, String> {
+        if info.interval_cycles == 0 {
+            return Err("monitor: interval_cycles must be positive".to_string());
+        }
+
+        Ok(Self {
+            interval_cycles: info.interval_cycles,
+            livelock_hops: info.livelock_hops.unwrap_or(node_num) as usize,
+            held_flits: HashMap::new(),
+            hops: HashMap::new(),
+            reported: HashSet::new(),
+            reports: Vec::new(),
+        })
+    }
+
+    pub fn reports(&self) -> &[StallReport] {
+        &self.reports
+    }
+
+    // cur_cyclesサイクルを実行し終えたときに呼ぶ
+    pub fn check(&mut self, cur_cycles: CycleNum, nodes: &Nodes) {
+        if !cur_cycles.is_multiple_of(self.interval_cycles) {
+            return;
+        }
+
+        let buffers = blocked_buffers(nodes);
+        self.find_deadlocks(cur_cycles, &buffers);
+        self.held_flits = buffers
+            .into_iter()
+            .map(|(buffer_id, buffer)| (buffer_id, buffer.flit))
+            .collect();
+
+        self.find_livelocks(cur_cycles);
+    }
+
+    // 前回から同じフリットで止まり，再送も使い切ったバッファの間で，待ち関係の閉路を探す
+    // 衝突による一時的な再送の繰り返しはハードウェアの再送で解消するので報告しない
+    fn find_deadlocks(&mut self, cur_cycles: CycleNum, buffers: &HashMap<BufferId, BlockedBuffer>) {
+        let stuck: HashMap<&BufferId, &BlockedBuffer> = buffers
+            .iter()
+            .filter(|(buffer_id, buffer)| {
+                buffer.beyond_resend && self.held_flits.get(*buffer_id) == Some(&buffer.flit)
+            })
+            .collect();
+
+        for cycle in wait_for_cycles(&stuck) {
+            let flits: Vec<&Flit> = cycle
+                .iter()
+                .map(|buffer_id| &stuck[buffer_id].flit)
+                .collect();
+            let report = StallReport {
+                kind: StallKind::Deadlock,
+                cycle: cur_cycles,
+                node_ids: cycle.iter().map(|(node_id, _)| node_id.clone()).collect(),
+                channel_ids: cycle.iter().map(|(_, channel_id)| *channel_id).collect(),
+                packet_ids: flits.iter().map(|flit| packet_log_id(flit)).collect(),
+            };
+            self.report(report);
+        }
+    }
+
+    // 前回から中継されて，ホップ数がlivelock_hopsを超えたのに届いていないパケットを探す
+    fn find_livelocks(&mut self, cur_cycles: CycleNum) {
+        let mut hops = HashMap::new();
+
+        for packet in get_in_flight_packets() {
+            let packet_hops = packet.route_info.len().saturating_sub(1);
+            let moved = self
+                .hops
+                .get(&packet.packet_id)
+                .is_some_and(|prev_hops| *prev_hops < packet_hops);
+            if moved && packet_hops > self.livelock_hops {
+                let mut node_ids: Vec<NodeId> = Vec::new();
+                for node_id in packet.route_info.iter() {
+                    if !node_ids.contains(node_id) {
+                        node_ids.push(node_id.clone());
+                    }
+                }
+                self.report(StallReport {
+                    kind: StallKind::Livelock,
+                    cycle: cur_cycles,
+                    node_ids,
+                    channel_ids: vec![packet.channel_id],
+                    packet_ids: vec![packet.packet_id.clone()],
+                });
+            }
+            hops.insert(packet.packet_id, packet_hops);
+        }
+
+        self.hops = hops;
+    }
+
+    fn report(&mut self, report: StallReport) {
+        if self.reported.insert(report.packet_ids.clone()) {
+            self.reports.push(report);
+        }
+    }
+}
+
+// 送信バッファの先頭で進めずにいるフリットと，それを妨げているバッファを集める
+// フリットは受信側の入力が他のパケットに押さえられていると受け取られず，
+// 自分の入力が押さえられているとackを受け取れないので，押さえているパケットの送信元のバッファを待つ
+// ハードウェアの送信は1フリットずつなので，他のチャネルのフリットは送信中のチャネルを待つ
+fn blocked_buffers(nodes: &Nodes) -> HashMap<BufferId, BlockedBuffer> {
+    let alive: HashMap<&NodeId, &Node> = nodes
+        .nodes
+        .iter()
+        .filter(|node| node.alive)
+        .map(|node| (&node.id, node))
+        .collect();
+
+    let mut buffers = HashMap::new();
+    for node in alive.values() {
+        let flit = &node.hardware.retransmission_buffer;
+        if flit.is_empty() || flit.is_ack() {
+            continue;
+        }
+        let next_id = flit.get_next_id().unwrap();
+        if next_id == "broadcast" {
+            continue;
+        }
+        let channel_id = flit.get_channel_id().unwrap();
+
+        let mut waits_for = Vec::new();
+        if let Some((prev_id, held_channel_id, packet_id)) = alive
+            .get(&next_id)
+            .and_then(|next_node| next_node.hardware.held_input())
+        {
+            if prev_id != node.id || Some(packet_id) != flit.get_packet_id() {
+                waits_for.push((prev_id, held_channel_id));
+            }
+        }
+        if let Some((prev_id, held_channel_id, _)) = node.hardware.held_input() {
+            waits_for.push((prev_id, held_channel_id));
+        }
+        buffers.insert(
+            (node.id.clone(), channel_id),
+            BlockedBuffer {
+                flit: flit.clone(),
+                beyond_resend: node.hardware.send_times() > MAX_RESEND_TIMES as u32,
+                waits_for,
+            },
+        );
+
+        for (queued_channel_id, queued_flit) in node.network.head_flits() {
+            if queued_channel_id == channel_id {
+                continue;
+            }
+            buffers.insert(
+                (node.id.clone(), queued_channel_id),
+                BlockedBuffer {
+                    flit: queued_flit,
+                    beyond_resend: true,
+                    waits_for: vec![(node.id.clone(), channel_id)],
+                },
+            );
+        }
+    }
+    buffers
+}
+
+// 止まっているバッファの待ち関係で閉路になっているものを返す
+fn wait_for_cycles(stuck: &HashMap<&BufferId, &BlockedBuffer>) -> Vec<Vec<BufferId>> {
+    let mut buffer_ids: Vec<&BufferId> = stuck.keys().copied().collect();
+    buffer_ids.sort();
+
+    // falseなら探索中，trueなら探索済み
+    let mut visited: HashMap<&BufferId, bool> = HashMap::new();
+    let mut cycles = Vec::new();
+    for start in buffer_ids {
+        if !visited.contains_key(start) {
+            let mut path = Vec::new();
+            visit(start, stuck, &mut visited, &mut path, &mut cycles);
+        }
+    }
+    cycles
+}
+
+// 深さ優先探索で，探索中のバッファに戻る辺があれば閉路として記録する
+fn visit<'a>(
+    buffer_id: &'a BufferId,
+    stuck: &HashMap<&'a BufferId, &'a BlockedBuffer>,
+    visited: &mut HashMap<&'a BufferId, bool>,
+    path: &mut Vec<&'a BufferId>,
+    cycles: &mut Vec<Vec<BufferId>>,
+) {
+    visited.insert(buffer_id, false);
+    path.push(buffer_id);
+    for waits_for in stuck[buffer_id].waits_for.iter() {
+        let Some((&next_id, _)) = stuck.get_key_value(waits_for) else {
+            continue;
+        };
+        match visited.get(next_id) {
+            None => visit(next_id, stuck, visited, path, cycles),
+            Some(false) => {
+                let position = path.iter().position(|id| *id == next_id).unwrap();
+                cycles.push(path[position..].iter().map(|id| (*id).clone()).collect());
+            }
+            Some(true) => {}
+        }
+    }
+    path.pop();
+    visited.insert(buffer_id, true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::constants::DEFAULT_TTL;
+    use crate::hardware::switching::Switching;
+    use crate::log::{
+        post_new_packet_log, update_packet_log, NewPacketLogInfo, UpdatePacketLogInfo,
+    };
+    use crate::network::flit::{DataFlit, HeaderFlit};
+    use crate::sim::node_type::NodeType;
+
+    fn flit(prev_id: &str, next_id: &str, packet_id: u32) -> Flit {
+        Flit::Data(DataFlit {
+            source_id: prev_id.to_string(),
+            dest_id: "z".to_string(),
+            next_id: next_id.to_string(),
+            prev_id: prev_id.to_string(),
+            flit_num: 1,
+            resend_num: 0,
+            data: vec![],
+            packet_id,
+            channel_id: 1,
+            ttl: DEFAULT_TTL,
+        })
+    }
+
+    fn new_monitor() -> Monitor {
+        let info = MonitorInfo {
+            interval_cycles: 10,
+            livelock_hops: Some(3),
+        };
+        Monitor::new(&info, 5).unwrap()
+    }
+
+    fn buffer(flit: Flit, beyond_resend: bool, waits_for: &[&str]) -> BlockedBuffer {
+        BlockedBuffer {
+            flit,
+            beyond_resend,
+            waits_for: waits_for
+                .iter()
+                .map(|node_id| (node_id.to_string(), 1))
+                .collect(),
+        }
+    }
+
+    fn buffers(entries: Vec<(&str, BlockedBuffer)>) -> HashMap<BufferId, BlockedBuffer> {
+        entries
+            .into_iter()
+            .map(|(node_id, buffer)| ((node_id.to_string(), 1), buffer))
+            .collect()
+    }
+
+    fn held_flits(buffers: &HashMap<BufferId, BlockedBuffer>) -> HashMap<BufferId, Flit> {
+        buffers
+            .iter()
+            .map(|(buffer_id, buffer)| (buffer_id.clone(), buffer.flit.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_find_deadlocks() {
+        let mut monitor = new_monitor();
+        // a -> b -> c -> aが閉路で，dはaを待っているだけ
+        let blocked = buffers(vec![
+            ("a", buffer(flit("a", "b", 0), true, &["b"])),
+            ("b", buffer(flit("b", "c", 0), true, &["c"])),
+            ("c", buffer(flit("c", "a", 0), true, &["a"])),
+            ("d", buffer(flit("d", "a", 0), true, &["a"])),
+        ]);
+
+        // 初回は進んでいるかどうか分からない
+        monitor.find_deadlocks(10, &blocked);
+        assert!(monitor.reports().is_empty());
+        monitor.held_flits = held_flits(&blocked);
+
+        monitor.find_deadlocks(20, &blocked);
+        monitor.find_deadlocks(30, &blocked);
+        assert_eq!(monitor.reports().len(), 1);
+        let report = &monitor.reports()[0];
+        assert_eq!(report.kind, StallKind::Deadlock);
+        assert_eq!(report.cycle, 20);
+        assert_eq!(report.node_ids, vec!["a", "b", "c"]);
+        assert_eq!(report.channel_ids, vec![1, 1, 1]);
+        assert_eq!(report.packet_ids, vec!["a_0", "b_0", "c_0"]);
+
+        // bが次のフリットに進んだら閉路は解消する
+        let mut monitor = new_monitor();
+        monitor.held_flits = held_flits(&blocked);
+        let mut moved = blocked.clone();
+        moved.insert(
+            ("b".to_string(), 1),
+            buffer(flit("b", "c", 1), true, &["c"]),
+        );
+        monitor.find_deadlocks(20, &moved);
+        assert!(monitor.reports().is_empty());
+    }
+
+    #[test]
+    fn test_transient_retransmission_not_reported() {
+        // aとbが互いに相手へ送り，衝突して再送し合っているが，再送の回数内に収まっている
+        let mut monitor = new_monitor();
+        let retrying = buffers(vec![
+            ("a", buffer(flit("a", "b", 0), false, &["b"])),
+            ("b", buffer(flit("b", "a", 0), false, &["a"])),
+        ]);
+        monitor.held_flits = held_flits(&retrying);
+        monitor.find_deadlocks(20, &retrying);
+        assert!(monitor.reports().is_empty());
+
+        // 再送を使い切っても，相手の入力が押さえられていなければ待ち関係はない
+        let unheld = buffers(vec![
+            ("a", buffer(flit("a", "b", 0), true, &[])),
+            ("b", buffer(flit("b", "a", 0), true, &[])),
+        ]);
+        monitor.held_flits = held_flits(&unheld);
+        monitor.find_deadlocks(30, &unheld);
+        assert!(monitor.reports().is_empty());
+    }
+
+    fn header(prev_id: &str, next_id: &str, packet_id: u32) -> Flit {
+        Flit::Header(HeaderFlit {
+            source_id: prev_id.to_string(),
+            dest_id: next_id.to_string(),
+            next_id: next_id.to_string(),
+            prev_id: prev_id.to_string(),
+            packet_id,
+            flits_len: 3,
+            channel_id: 1,
+            ttl: DEFAULT_TTL,
+            data: vec![],
+        })
+    }
+
+    #[test]
+    fn test_check_held_inputs() {
+        // aとbが互いに相手へパケットを送っている途中で，
+        // 入力が相手のパケットに押さえられているのでackを受け取れない
+        let mut neighbors = HashMap::new();
+        neighbors.insert("a".to_string(), vec!["b".to_string()]);
+        neighbors.insert("b".to_string(), vec!["a".to_string()]);
+        let node = |id: &str, node_type: &NodeType| {
+            Node::new(
+                id,
+                2,
+                &Switching::Blocking,
+                "default",
+                node_type,
+                &HashMap::new(),
+            )
+        };
+        let mut nodes = Nodes::new(
+            &[
+                node("a", &NodeType::Coordinator),
+                node("b", &NodeType::Router),
+            ],
+            &neighbors,
+        );
+        for (id, other) in [("a", "b"), ("b", "a")] {
+            let node = nodes.nodes.iter_mut().find(|node| node.id == id).unwrap();
+            node.hardware.receive_flit(&header(other, id, 0)).unwrap();
+            node.hardware.send_flit(&flit(id, other, 0)).unwrap();
+        }
+
+        let mut monitor = new_monitor();
+        monitor.check(10, &nodes);
+        assert!(monitor.reports().is_empty());
+
+        // 再送の回数内なら，前回から止まっていても報告しない
+        for node in nodes.nodes.iter_mut() {
+            node.hardware.count_transmission();
+        }
+        monitor.check(20, &nodes);
+        assert!(monitor.reports().is_empty());
+
+        for node in nodes.nodes.iter_mut() {
+            for _ in 0..MAX_RESEND_TIMES {
+                node.hardware.count_transmission();
+            }
+        }
+        monitor.check(30, &nodes);
+        monitor.check(40, &nodes);
+        assert_eq!(monitor.reports().len(), 1);
+        let report = &monitor.reports()[0];
+        assert_eq!(report.kind, StallKind::Deadlock);
+        assert_eq!(report.cycle, 30);
+        assert_eq!(report.node_ids, vec!["a", "b"]);
+        assert_eq!(report.channel_ids, vec![1, 1]);
+        assert_eq!(report.packet_ids, vec!["a_0", "b_0"]);
+    }
+
+    #[test]
+    fn test_find_livelocks() {
+        let mut monitor = new_monitor();
+        post_new_packet_log(&NewPacketLogInfo {
+            packet_id: "a_0".to_string(),
+            from_id: "a".to_string(),
+            dest_id: "z".to_string(),
+            flits_len: 1,
+            message: "test".to_string(),
+            size_bytes: None,
+            is_control: false,
+            segment_id: None,
+            channel_id: 0,
+            inject_cycle: None,
+            class: None,
+        })
+        .unwrap();
+        let forward = |node_id: &str| {
+            update_packet_log(
+                "a_0",
+                &UpdatePacketLogInfo {
+                    send_cycle: Some(0),
+                    last_receive_cycle: None,
+                    route_info: Some(node_id.to_string()),
+                    is_delivered: None,
+                    flit_log: None,
+                },
+            )
+            .unwrap();
+        };
+
+        forward("a");
+        forward("b");
+        forward("c");
+        monitor.find_livelocks(10);
+        // b -> cを行き来し続ける
+        forward("b");
+        forward("c");
+        monitor.find_livelocks(20);
+        assert_eq!(monitor.reports().len(), 1);
+        let report = &monitor.reports()[0];
+        assert_eq!(report.kind, StallKind::Livelock);
+        assert_eq!(report.node_ids, vec!["a", "b", "c"]);
+        assert_eq!(report.packet_ids, vec!["a_0"]);
+
+        // 進まなければ報告しない，同じパケットも繰り返し報告しない
+        monitor.find_livelocks(30);
+        forward("b");
+        monitor.find_livelocks(40);
+        assert_eq!(monitor.reports().len(), 1);
+    }
+}
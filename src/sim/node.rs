@@ -1,24 +1,44 @@
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use crate::hardware::constants::{DEFAULT_TTL, PACKET_HEADER_BYTES};
 use crate::hardware::state::State;
 use crate::hardware::switching::Switching;
 use crate::hardware::Hardware;
+use crate::log::{post_event, post_failure_log, post_failure_loss_log, update_failure_log};
+use crate::network::arbiter::Arbiter;
 use crate::network::core_functions::packets::InjectionPacket;
 use crate::network::flit::Flit;
-use crate::network::Network;
+use crate::network::transport::Transport;
+use crate::network::{packet_log_id, Network};
 use crate::sim::node_type::NodeType;
+use crate::sim::observer::SimEvent;
 
 pub type NodeId = String;
 pub type CycleNum = u32;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Node {
     pub id: NodeId,
     pub node_type: NodeType,
     pub network: Network,
     pub hardware: Hardware,
-    pub packets: HashMap<CycleNum, InjectionPacket>,
+    // サイクルごとに生成するパケット，入力の順に生成する
+    pub packets: HashMap<CycleNum, Vec<InjectionPacket>>,
+    // falseなら故障中で，送受信も状態の更新もしない
+    pub alive: bool,
     cur_cycle: u32,
+    // 再起動時にNetwork/Hardwareを作り直すための設定
+    vc_num: u8,
+    switching: Switching,
+    rf_kind: String,
+    assign_address: bool,
+    header_bytes: u32,
+    // 再起動時はACK待ちなどを捨てて作り直す
+    transport: Option<Transport>,
+    arbiter: Arbiter,
+    ttl: u8,
+    loop_detection: bool,
 }
 
 impl Node {
@@ -28,7 +48,7 @@ impl Node {
         switching: &Switching,
         rf_kind: &str,
         node_type: &NodeType,
-        packets: &HashMap<CycleNum, InjectionPacket>,
+        packets: &HashMap<CycleNum, Vec<InjectionPacket>>,
     ) -> Self {
         Self {
             id: id.to_string(),
@@ -36,10 +56,101 @@ impl Node {
             network: Network::new(id, vc_num, switching, rf_kind, node_type),
             hardware: Hardware::new(id, switching),
             packets: packets.clone(),
+            alive: true,
             cur_cycle: 0,
+            vc_num,
+            switching: switching.clone(),
+            rf_kind: rf_kind.to_string(),
+            assign_address: true,
+            header_bytes: PACKET_HEADER_BYTES,
+            transport: None,
+            arbiter: Arbiter::strict_priority(vc_num),
+            ttl: DEFAULT_TTL,
+            loop_detection: false,
         }
     }
 
+    // 最後に更新したサイクル
+    pub fn cur_cycle(&self) -> u32 {
+        self.cur_cycle
+    }
+
+    pub fn state(&self) -> &State {
+        self.hardware.state.get()
+    }
+
+    pub fn set_address_assignment(&mut self, assign_address: bool) {
+        self.assign_address = assign_address;
+        self.network.set_address_assignment(assign_address);
+    }
+
+    pub fn set_header_bytes(&mut self, header_bytes: u32) {
+        self.header_bytes = header_bytes;
+        self.network.set_header_bytes(header_bytes);
+    }
+
+    pub fn set_transport(&mut self, transport: Option<Transport>) {
+        self.transport = transport.clone();
+        self.network.set_transport(transport);
+    }
+
+    pub fn set_arbiter(&mut self, arbiter: &Arbiter) {
+        self.arbiter = arbiter.clone();
+        self.network.set_arbiter(arbiter.clone());
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+        self.network.set_ttl(ttl);
+    }
+
+    pub fn set_loop_detection(&mut self, loop_detection: bool) {
+        self.loop_detection = loop_detection;
+        self.network.set_loop_detection(loop_detection);
+    }
+
+    // ノードを故障させる
+    // 保持していたパケットは故障で失われたとして記録する
+    pub fn fail(&mut self, cur_cycle: u32) {
+        if !self.alive {
+            return;
+        }
+
+        self.network.drop_held_packets();
+        let retransmission_flit = &self.hardware.retransmission_buffer;
+        if !retransmission_flit.is_empty() && !retransmission_flit.is_ack() {
+            post_failure_loss_log(&packet_log_id(retransmission_flit));
+        }
+
+        self.alive = false;
+        post_failure_log(&self.id, cur_cycle);
+    }
+
+    // 状態を初期化して再起動する
+    pub fn restart(&mut self, cur_cycle: u32) {
+        if self.alive {
+            return;
+        }
+
+        self.network = Network::new(
+            &self.id,
+            self.vc_num,
+            &self.switching,
+            &self.rf_kind,
+            &self.node_type,
+        );
+        self.network.set_address_assignment(self.assign_address);
+        self.network.set_header_bytes(self.header_bytes);
+        self.network.set_transport(self.transport.clone());
+        self.network.set_arbiter(self.arbiter.clone());
+        self.network.set_ttl(self.ttl);
+        self.network.set_loop_detection(self.loop_detection);
+        self.hardware = Hardware::new(&self.id, &self.switching);
+
+        self.alive = true;
+        update_failure_log(&self.id, cur_cycle);
+    }
+
     pub fn send_flit(&mut self) -> Result<Flit, Box<dyn std::error::Error>> {
         // retransmission_bufferから取り出し，送信する
         let x = self.hardware.retransmission_buffer.clone();
@@ -47,6 +158,19 @@ impl Node {
             return Err("retransmission_buffer is empty".into());
         }
 
+        let send_times = self.hardware.count_transmission();
+        post_event(|| SimEvent::FlitSent {
+            node_id: self.id.clone(),
+            flit: x.clone(),
+        });
+        if send_times > 1 {
+            post_event(|| SimEvent::Retransmission {
+                node_id: self.id.clone(),
+                flit: x.clone(),
+                send_times,
+            });
+        }
+
         if x.is_broadcast() {
             self.hardware.retransmission_buffer.clear();
         }
@@ -54,9 +178,8 @@ impl Node {
     }
 
     pub fn send_ack(&mut self) -> Result<Flit, Box<dyn std::error::Error>> {
-        let ack = self.hardware.send_ack().map_err(|e| {
+        let ack = self.hardware.send_ack().inspect_err(|_| {
             dbg!("error occured while sending a flit: {e:?}");
-            e
         })?;
 
         Ok(ack)
@@ -65,10 +188,9 @@ impl Node {
     pub fn update(&mut self, cur_cycle: u32) -> Result<(), Box<dyn std::error::Error>> {
         self.cur_cycle = cur_cycle;
 
-        // packetsにcur_cycleが含まれていたら
-        if let Some(packet) = self.packets.get(&cur_cycle) {
-            // 新規パケットを生成
-            self.network.send_new_packet(packet);
+        // packetsにcur_cycleが含まれていたら新規パケットを生成
+        for packet in self.packets.get(&cur_cycle).into_iter().flatten() {
+            self.network.send_new_packet(packet)?;
         }
 
         self.network.update(cur_cycle);
@@ -77,9 +199,8 @@ impl Node {
         if self.hardware.retransmission_buffer.is_empty() {
             // network.send_flit_bufferからフリットを取り出す
             if let Some(flit) = self.network.send_flit() {
-                self.hardware.send_flit(&flit).map_err(|e| {
+                self.hardware.send_flit(&flit).inspect_err(|_| {
                     dbg!("error occured while sending a flit: {e:?}");
-                    e
                 })?;
             }
         }
@@ -87,6 +208,14 @@ impl Node {
         // ハードウェアの状態を更新
         self.hardware.update_state()?;
 
+        // 切れたリンクを経由する経路を修復する
+        for flit in self.hardware.take_lost_links() {
+            post_failure_loss_log(&packet_log_id(&flit));
+            if let Some(next_id) = flit.get_next_id() {
+                self.network.handle_link_loss(&next_id);
+            }
+        }
+
         Ok(())
     }
 
@@ -94,6 +223,16 @@ impl Node {
         self.hardware.set_state(&State::Receiving);
 
         if let Some(flit) = self.hardware.receive_flit(flit)? {
+            post_event(|| match &flit {
+                Flit::Ack(ack_flit) => SimEvent::Ack {
+                    node_id: self.id.clone(),
+                    from_id: ack_flit.source_id.clone(),
+                },
+                _ => SimEvent::FlitReceived {
+                    node_id: self.id.clone(),
+                    flit: flit.clone(),
+                },
+            });
             self.network.receive_flit(&flit, 0);
         }
         Ok(())
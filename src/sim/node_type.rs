@@ -1,4 +1,10 @@
-#[derive(Debug, Clone, PartialEq)]
+use crate::network::core_functions::packets::InjectionPacket;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum NodeType {
     Coordinator,
     Router,
@@ -15,4 +21,97 @@ impl NodeType {
             _ => NodeType::UserType(node_type.to_string()),
         }
     }
+
+    // 参加プロトコルと転送の振る舞いを決めるノード種別
+    // UserTypeは登録されたフックのbase_typeに従う
+    pub fn base_type(&self) -> NodeType {
+        match self {
+            NodeType::UserType(name) => get_user_type_hook(name)
+                .unwrap_or_else(|| panic!("user type {name} is not registered"))
+                .base_type(),
+            _ => self.clone(),
+        }
+    }
+
+    pub fn user_type_hook(&self) -> Option<Arc<dyn UserTypeHook>> {
+        match self {
+            NodeType::UserType(name) => get_user_type_hook(name),
+            _ => None,
+        }
+    }
+}
+
+// ユーザー定義のノード種別に振る舞いを追加するためのフック
+pub trait UserTypeHook: Send + Sync {
+    // RouterかEndDeviceのどちらとして参加・転送するか
+    fn base_type(&self) -> NodeType {
+        NodeType::Router
+    }
+
+    // 自分宛てのユーザーパケットを受信したときに呼ばれる
+    // 返したパケットは新規パケットとして送信される
+    fn on_receive(&self, _node_id: &str, _source_id: &str, _message: &str) -> Vec<InjectionPacket> {
+        vec![]
+    }
+
+    // 他ノード宛てのユーザーパケットを中継するかどうか
+    fn forwards(&self, _node_id: &str, _source_id: &str, _dest_id: &str) -> bool {
+        true
+    }
+}
+
+static USER_TYPE_HOOKS: Lazy<Mutex<HashMap<String, Arc<dyn UserTypeHook>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn register_user_type(name: &str, hook: Arc<dyn UserTypeHook>) -> Result<(), String> {
+    if let NodeType::Router | NodeType::EndDevice = hook.base_type() {
+        USER_TYPE_HOOKS
+            .lock()
+            .expect("failed to lock USER_TYPE_HOOKS")
+            .insert(name.to_string(), hook);
+        return Ok(());
+    }
+
+    Err(format!(
+        "base type of user type {name} must be router or end_device"
+    ))
+}
+
+pub fn get_user_type_hook(name: &str) -> Option<Arc<dyn UserTypeHook>> {
+    USER_TYPE_HOOKS
+        .lock()
+        .expect("failed to lock USER_TYPE_HOOKS")
+        .get(name)
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Sensor;
+
+    impl UserTypeHook for Sensor {
+        fn base_type(&self) -> NodeType {
+            NodeType::EndDevice
+        }
+    }
+
+    struct Invalid;
+
+    impl UserTypeHook for Invalid {
+        fn base_type(&self) -> NodeType {
+            NodeType::Coordinator
+        }
+    }
+
+    #[test]
+    fn test_register_user_type() {
+        register_user_type("sensor", Arc::new(Sensor)).unwrap();
+        assert!(register_user_type("invalid", Arc::new(Invalid)).is_err());
+
+        assert_eq!(NodeType::new("sensor").base_type(), NodeType::EndDevice);
+        assert_eq!(NodeType::new("router").base_type(), NodeType::Router);
+        assert!(NodeType::new("invalid").user_type_hook().is_none());
+    }
 }
@@ -1,14 +1,38 @@
 use crate::hardware::state::State;
-use crate::log::{post_collision_info, NewCollisionInfo};
+use crate::log::{
+    post_collision_info, post_event, post_link_change_log, post_topology_log, NewCollisionInfo,
+};
 use crate::network::flit::Flit;
-use crate::sim::node::{Node, NodeId};
-use std::collections::HashMap;
+use crate::sim::energy::EnergyModel;
+use crate::sim::failure::{FailureModel, NodeEvent};
+use crate::sim::mobility::Mobility;
+use crate::sim::node::{CycleNum, Node, NodeId};
+use crate::sim::observer::SimEvent;
+use crate::sim::radio::RadioModel;
+use crate::sim::topology::TopologyEvent;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 
+#[derive(Serialize, Deserialize)]
 pub struct Nodes {
     pub nodes: Vec<Node>,
     pub flit_buffers: HashMap<NodeId, Vec<Flit>>,
     // ノードの隣接情報を保持するHashMap
     pub neighbors: HashMap<NodeId, Vec<NodeId>>,
+    // ノードの座標と電波のモデル
+    // 隣接情報を位置から求める場合のみ設定される
+    pub positions: HashMap<NodeId, Vec<f64>>,
+    pub radio: Option<RadioModel>,
+    // ノードの移動モデル
+    pub mobility: Option<Mobility>,
+    // サイクルごとに適用するトポロジ変更
+    pub topology_events: HashMap<CycleNum, Vec<TopologyEvent>>,
+    // サイクルごとに適用するノードの故障・復旧
+    pub node_events: HashMap<CycleNum, Vec<NodeEvent>>,
+    // ランダムな故障・復旧のモデル
+    pub failure_model: Option<FailureModel>,
+    // 消費エネルギーのモデル
+    pub energy: Option<EnergyModel>,
 }
 
 impl Nodes {
@@ -17,30 +41,100 @@ impl Nodes {
             nodes: nodes.to_owned(),
             flit_buffers: HashMap::new(),
             neighbors: neighbors.clone(),
+            positions: HashMap::new(),
+            radio: None,
+            mobility: None,
+            topology_events: HashMap::new(),
+            node_events: HashMap::new(),
+            failure_model: None,
+            energy: None,
+        }
+    }
+
+    // ノードの位置と電波のモデルから隣接情報を求める
+    pub fn from_positions(
+        nodes: &[Node],
+        positions: &HashMap<NodeId, Vec<f64>>,
+        radio: &RadioModel,
+    ) -> Self {
+        let mut nodes = Self::new(nodes, &HashMap::new());
+        nodes.positions = positions.clone();
+        nodes.radio = Some(radio.clone());
+        nodes.update_neighbors();
+        nodes
+    }
+
+    // 現在の位置から隣接情報を計算し直す
+    pub fn update_neighbors(&mut self) {
+        let Some(radio) = &self.radio else {
+            return;
+        };
+
+        let positions: Vec<(NodeId, Vec<f64>)> = self
+            .nodes
+            .iter()
+            .filter_map(|node| {
+                let position = self.positions.get(&node.id)?;
+                Some((node.id.clone(), position.clone()))
+            })
+            .collect();
+        self.neighbors = radio.neighbors(&positions);
+    }
+
+    pub fn set_topology_events(&mut self, events: &[(CycleNum, TopologyEvent)]) {
+        for (cycle, event) in events {
+            self.topology_events
+                .entry(*cycle)
+                .or_default()
+                .push(event.clone());
+        }
+    }
+
+    pub fn set_node_events(&mut self, events: &[(CycleNum, NodeEvent)]) {
+        for (cycle, event) in events {
+            self.node_events
+                .entry(*cycle)
+                .or_default()
+                .push(event.clone());
         }
     }
 
     pub fn run_cycle(&mut self, cur_cycle: u32) {
+        // このサイクルのトポロジ変更を適用する
+        self.apply_topology_events(cur_cycle);
+
+        // ノードを動かして隣接情報を計算し直す
+        self.apply_mobility(cur_cycle);
+
+        // このサイクルに故障・復旧するノードを処理する
+        self.apply_node_events(cur_cycle);
+
         // 各ノードの状態を更新する
         self.update_nodes(cur_cycle);
 
         // 各ノードのメッセージを処理する
         self.message_handle(cur_cycle);
+
+        // このサイクルにいた状態の分のエネルギーを数える
+        self.record_energy(cur_cycle);
     }
 
     fn message_handle(&mut self, cur_cycle: u32) {
         // 送信状態のノードはflitを送信
-        for node in self.nodes.iter_mut() {
+        for node in self.nodes.iter_mut().filter(|node| node.alive) {
             // todo 後でnodeに切り出す
             match node.hardware.state.get() {
                 State::Sending => {
                     let flit = node.send_flit().unwrap();
+                    if let Some(energy) = &mut self.energy {
+                        energy.record_transmission(&node.id, &flit);
+                    }
                     // flit_buffersに追加
                     if let Some(receiver_id) = flit.get_next_id() {
                         // broadcastの場合はneighborsを見て，nodeに隣接するノードすべてに配信する
                         if receiver_id == "broadcast" {
-                            let neighbors = self.neighbors.get(&node.id).unwrap();
-                            neighbors.iter().for_each(|neighbor| {
+                            let neighbors = self.neighbors.get(&node.id).into_iter().flatten();
+                            neighbors.for_each(|neighbor| {
                                 let buffer = self.flit_buffers.entry(neighbor.clone()).or_default();
                                 buffer.push(flit.clone());
                             });
@@ -56,6 +150,9 @@ impl Nodes {
                 State::ReplyAck => {
                     let ack = node.send_ack().unwrap();
                     if ack.is_ack() {
+                        if let Some(energy) = &mut self.energy {
+                            energy.record_transmission(&node.id, &ack);
+                        }
                         // flit_buffersに追加
                         if let Some(receiver_id) = ack.get_dest_id() {
                             let buffer = self.flit_buffers.entry(receiver_id).or_default();
@@ -68,7 +165,8 @@ impl Nodes {
         }
 
         // バッファにあるメッセージを受信
-        for node in self.nodes.iter_mut() {
+        // 故障中のノードは受信しない
+        for node in self.nodes.iter_mut().filter(|node| node.alive) {
             // todo nodeに切り出す
             let flits = self.flit_buffers.get(&node.id);
 
@@ -83,6 +181,9 @@ impl Nodes {
                 let flit = &flits[0];
 
                 if let State::Idle | State::Waiting(_) = node.hardware.state.get() {
+                    if let Some(energy) = &mut self.energy {
+                        energy.record_reception(&node.id, flit);
+                    }
                     // 状態を受信中に変更
                     let _ = node.receive_flit(flit).map_err(|e| {
                         panic!(
@@ -92,9 +193,15 @@ impl Nodes {
                     });
                 }
             } else if flits.len() >= 2 {
+                let from_ids: Vec<NodeId> =
+                    flits.iter().map(|f| f.get_prev_id().unwrap()).collect();
+                post_event(|| SimEvent::Collision {
+                    node_id: node.id.clone(),
+                    from_ids: from_ids.clone(),
+                });
                 post_collision_info(&NewCollisionInfo {
                     cycle: cur_cycle,
-                    from_ids: flits.iter().map(|f| f.get_prev_id().unwrap()).collect(),
+                    from_ids,
                     dest_id: node.id.clone(),
                 });
             }
@@ -103,9 +210,75 @@ impl Nodes {
         // flit_buffersをクリア
         self.flit_buffers.clear();
     }
+
+    // 故障中のノードはエネルギーを消費しない
+    fn record_energy(&mut self, cur_cycle: u32) {
+        let Some(energy) = &mut self.energy else {
+            return;
+        };
+        for node in self.nodes.iter().filter(|node| node.alive) {
+            energy.record_cycle(&node.id, node.hardware.state.get(), cur_cycle);
+        }
+    }
+
+    fn apply_topology_events(&mut self, cur_cycle: u32) {
+        let Some(events) = self.topology_events.remove(&cur_cycle) else {
+            return;
+        };
+
+        for event in events.iter() {
+            event.apply(&mut self.neighbors);
+            post_topology_log(cur_cycle, &event.describe());
+        }
+    }
+
+    fn apply_mobility(&mut self, cur_cycle: u32) {
+        let Some(mobility) = &mut self.mobility else {
+            return;
+        };
+        if !mobility.step(cur_cycle, &mut self.positions) {
+            return;
+        }
+
+        let before = links(&self.neighbors);
+        self.update_neighbors();
+        let after = links(&self.neighbors);
+        post_link_change_log(
+            cur_cycle,
+            after.difference(&before).count() as u32,
+            before.difference(&after).count() as u32,
+        );
+    }
+
+    fn apply_node_events(&mut self, cur_cycle: u32) {
+        let mut events = self.node_events.remove(&cur_cycle).unwrap_or_default();
+        if let Some(model) = &self.failure_model {
+            events.extend(
+                self.nodes
+                    .iter()
+                    .filter_map(|node| model.next_event(&node.id, node.alive)),
+            );
+        }
+
+        for event in events.iter() {
+            let Some(node) = self
+                .nodes
+                .iter_mut()
+                .find(|node| node.id == event.node_id())
+            else {
+                continue;
+            };
+            match event {
+                NodeEvent::Fail(_) => node.fail(cur_cycle),
+                NodeEvent::Restart(_) => node.restart(cur_cycle),
+            }
+        }
+    }
+
     fn update_nodes(&mut self, cur_cycle: u32) {
         // 各ノードの状態を更新する
-        for node in self.nodes.iter_mut() {
+        // 故障中のノードは何もしない
+        for node in self.nodes.iter_mut().filter(|node| node.alive) {
             let _ = node.update(cur_cycle).map_err(|e| {
                 panic!(
                     "node: {}, cur_cycle: {} update error: {:?}",
@@ -116,6 +289,22 @@ impl Nodes {
     }
 }
 
+// 隣接情報に含まれるリンクの集合
+fn links(neighbors: &HashMap<NodeId, Vec<NodeId>>) -> HashSet<(NodeId, NodeId)> {
+    neighbors
+        .iter()
+        .flat_map(|(a, list)| {
+            list.iter().map(move |b| {
+                if a < b {
+                    (a.clone(), b.clone())
+                } else {
+                    (b.clone(), a.clone())
+                }
+            })
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -129,14 +318,14 @@ mod tests {
         // node1からnode2へのパケットを作成
         add_to_vid_table(u32::MAX, "broadcast");
 
-        let packets: HashMap<u32, InjectionPacket> = HashMap::new();
+        let packets: HashMap<u32, Vec<InjectionPacket>> = HashMap::new();
 
         let mut neighbors = HashMap::new();
         neighbors.insert("node1".to_string(), vec!["node2".to_string()]);
         neighbors.insert("node2".to_string(), vec!["node1".to_string()]);
 
         let mut nodes = Nodes::new(
-            &vec![
+            &[
                 Node::new(
                     "node1",
                     1,
@@ -0,0 +1,67 @@
+use crate::hardware::state::State;
+use crate::network::flit::Flit;
+use crate::sim::node::NodeId;
+
+// シミュレーション中に起きたイベント
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimEvent {
+    // 送信元ノードに新規パケットが渡された
+    PacketInjected {
+        node_id: NodeId,
+        dest_id: NodeId,
+        message: String,
+    },
+    // ノードがフリットを送信した(再送を含む)
+    FlitSent {
+        node_id: NodeId,
+        flit: Flit,
+    },
+    // ノードがフリットを受信した
+    FlitReceived {
+        node_id: NodeId,
+        flit: Flit,
+    },
+    // 2つ以上のフリットが同時に届いて受信できなかった
+    Collision {
+        node_id: NodeId,
+        from_ids: Vec<NodeId>,
+    },
+    // 送信したフリットへのackを受信した
+    Ack {
+        node_id: NodeId,
+        from_id: NodeId,
+    },
+    // ackが届かなかったフリットを再送した，send_timesは何回目の送信か
+    Retransmission {
+        node_id: NodeId,
+        flit: Flit,
+        send_times: u32,
+    },
+    // ハードウェアの状態が変わった(Waitingの残りサイクルの変化は除く)
+    StateTransition {
+        node_id: NodeId,
+        from: State,
+        to: State,
+    },
+    // ネットワークに参加した(再参加を含む)
+    NodeJoined {
+        node_id: NodeId,
+    },
+    // 親を失うなどしてネットワークから外れた
+    NodeLeft {
+        node_id: NodeId,
+    },
+    // ユーザーパケットが宛先に届いた
+    PacketDelivered {
+        node_id: NodeId,
+        source_id: NodeId,
+        packet_id: String,
+        message: String,
+    },
+}
+
+// Simに登録してイベントを受け取るオブザーバ
+// イベントは1サイクルごとに，起きた順に渡される
+pub trait SimObserver {
+    fn on_event(&mut self, cycle: u32, event: &SimEvent);
+}
@@ -0,0 +1,151 @@
+use crate::file::{PacketInfo, PhasesInfo};
+use crate::log::{aggregate_measurement_log, MeasurementReport};
+use crate::sim::node::CycleNum;
+use crate::sim::stop::StopReason;
+use serde::{Deserialize, Serialize};
+
+// ウォームアップ，計測，ドレインの区間
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Phases {
+    // 計測区間は[measurement_begin, measurement_end)
+    pub measurement_begin: CycleNum,
+    pub measurement_end: CycleNum,
+    // ドレインを打ち切るサイクル
+    drain_limit: Option<CycleNum>,
+    // 計測区間に生成されるユーザーのunicastパケットの数
+    measured_packets: u32,
+}
+
+impl Phases {
+    pub fn new(
+        info: &PhasesInfo,
+        total_cycles: u32,
+        packets: &[PacketInfo],
+    ) -> Result<Self, String> {
+        if info.measurement_cycles == 0 {
+            return Err("phases: measurement_cycles must be positive".to_string());
+        }
+        let measurement_begin = info.warmup_cycles;
+        let measurement_end = info
+            .warmup_cycles
+            .checked_add(info.measurement_cycles)
+            .filter(|end| *end <= total_cycles)
+            .ok_or("phases: warm-up and measurement must end within total_cycles")?;
+
+        let mut phases = Self {
+            measurement_begin,
+            measurement_end,
+            drain_limit: info
+                .drain_limit_cycles
+                .map(|cycles| measurement_end.saturating_add(cycles)),
+            measured_packets: 0,
+        };
+        phases.measured_packets = packets
+            .iter()
+            .filter(|packet| packet.dest_id != "broadcast" && phases.is_measured(packet.cycle_num))
+            .count() as u32;
+        Ok(phases)
+    }
+
+    // cycleに生成するパケットを計測するか
+    pub fn is_measured(&self, cycle: CycleNum) -> bool {
+        (self.measurement_begin..self.measurement_end).contains(&cycle)
+    }
+
+    // cycleに新規パケットを生成するか，ドレイン中は生成しない
+    pub fn injects(&self, cycle: CycleNum) -> bool {
+        cycle < self.measurement_end
+    }
+
+    // 実行中に生成されたパケットを数える，ドレイン中は生成できない
+    pub fn record_injection(&mut self, cycle: CycleNum, dest_id: &str) -> Result<(), String> {
+        if !self.injects(cycle) {
+            return Err(format!(
+                "phases: cannot inject packets while draining (cycle {cycle})"
+            ));
+        }
+        if dest_id != "broadcast" && self.is_measured(cycle) {
+            self.measured_packets += 1;
+        }
+        Ok(())
+    }
+
+    // cur_cyclesサイクルを実行し終えた時点でドレインが終わったならその理由を返す
+    pub fn check(&self, cur_cycles: u32) -> Option<StopReason> {
+        if cur_cycles < self.measurement_end {
+            return None;
+        }
+
+        let report = self.report();
+        let finished_packets =
+            report.delivered_packets + report.lost_by_failure_packets + report.lost_by_loop_packets;
+        if finished_packets >= self.measured_packets {
+            return Some(StopReason::Drained);
+        }
+        if self.drain_limit.is_some_and(|limit| cur_cycles >= limit) {
+            return Some(StopReason::DrainLimit);
+        }
+        None
+    }
+
+    pub fn report(&self) -> MeasurementReport {
+        aggregate_measurement_log(
+            self.measurement_begin,
+            self.measurement_end,
+            self.measured_packets,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(cycle_num: u32, dest_id: &str) -> PacketInfo {
+        PacketInfo {
+            cycle_num,
+            src_id: "a".to_string(),
+            dest_id: dest_id.to_string(),
+            msg: "test".to_string(),
+            dest_ids: None,
+            size_bytes: None,
+            payload: None,
+            priority: None,
+            class: None,
+        }
+    }
+
+    #[test]
+    fn test_new_phases() {
+        let mut info = PhasesInfo {
+            warmup_cycles: 10,
+            measurement_cycles: 20,
+            drain_limit_cycles: Some(5),
+        };
+        let packets = [
+            packet(5, "b"),
+            packet(10, "b"),
+            packet(20, "broadcast"),
+            packet(29, "b"),
+            packet(30, "b"),
+        ];
+        let phases = Phases::new(&info, 100, &packets).unwrap();
+
+        assert_eq!(phases.measured_packets, 2);
+        assert!(!phases.is_measured(9));
+        assert!(phases.is_measured(29));
+        assert!(phases.injects(29));
+        assert!(!phases.injects(30));
+
+        // 計測区間が終わるまでは止めない
+        assert_eq!(phases.check(29), None);
+        // 計測したパケットが届かないままドレインの上限に達した
+        assert_eq!(phases.check(35), Some(StopReason::DrainLimit));
+
+        info.measurement_cycles = 0;
+        assert!(Phases::new(&info, 100, &packets).is_err());
+
+        info.measurement_cycles = 100;
+        assert!(Phases::new(&info, 100, &packets).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use crate::file::RadioInfo;
+use crate::rng::sim_rng;
+use crate::sim::node::NodeId;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// ノードの位置から隣接関係を決める電波のモデル
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RadioModel {
+    // 距離がrange以下ならリンクがある
+    UnitDisk {
+        range: f64,
+    },
+    // 距離がinner_range以下なら必ず，range以下ならlink_probabilityの確率でリンクがある
+    QuasiUnitDisk {
+        inner_range: f64,
+        range: f64,
+        link_probability: f64,
+    },
+}
+
+impl RadioModel {
+    pub fn new(info: &RadioInfo) -> Result<Self, String> {
+        if info.range <= 0.0 {
+            return Err("radio: range must be positive".to_string());
+        }
+
+        match info.model.as_str() {
+            "unit_disk" => Ok(RadioModel::UnitDisk { range: info.range }),
+            "quasi_unit_disk" => {
+                let (Some(inner_range), Some(link_probability)) =
+                    (info.inner_range, info.link_probability)
+                else {
+                    return Err(
+                        "radio: quasi_unit_disk needs inner_range and link_probability".to_string(),
+                    );
+                };
+                if !(0.0..=info.range).contains(&inner_range) {
+                    return Err("radio: inner_range must be between 0 and range".to_string());
+                }
+                if !(0.0..=1.0).contains(&link_probability) {
+                    return Err("radio: link_probability must be between 0 and 1".to_string());
+                }
+                Ok(RadioModel::QuasiUnitDisk {
+                    inner_range,
+                    range: info.range,
+                    link_probability,
+                })
+            }
+            model => Err(format!("radio: unknown model {model}")),
+        }
+    }
+
+    // distanceだけ離れた2ノードの間にリンクがあるか
+    pub fn is_linked(&self, distance: f64) -> bool {
+        match self {
+            RadioModel::UnitDisk { range } => distance <= *range,
+            RadioModel::QuasiUnitDisk {
+                inner_range,
+                range,
+                link_probability,
+            } => {
+                if distance <= *inner_range {
+                    true
+                } else if distance <= *range {
+                    sim_rng().gen::<f64>() < *link_probability
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    // positionsの順に隣接ノードのリストを作る
+    // リンクは双方向で，ノードの組ごとに一度だけ判定する
+    pub fn neighbors(&self, positions: &[(NodeId, Vec<f64>)]) -> HashMap<NodeId, Vec<NodeId>> {
+        let mut neighbors: HashMap<NodeId, Vec<NodeId>> = positions
+            .iter()
+            .map(|(node_id, _)| (node_id.clone(), Vec::new()))
+            .collect();
+
+        for (i, (a, a_position)) in positions.iter().enumerate() {
+            for (b, b_position) in positions.iter().skip(i + 1) {
+                if self.is_linked(distance(a_position, b_position)) {
+                    neighbors.get_mut(a).unwrap().push(b.clone());
+                    neighbors.get_mut(b).unwrap().push(a.clone());
+                }
+            }
+        }
+        neighbors
+    }
+}
+
+// 2次元または3次元のユークリッド距離
+pub fn distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| (x - y).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positions(list: &[(&str, &[f64])]) -> Vec<(NodeId, Vec<f64>)> {
+        list.iter()
+            .map(|(id, position)| (id.to_string(), position.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_unit_disk_neighbors() {
+        // a - b - c が一直線に並ぶ
+        let positions = positions(&[("a", &[0.0, 0.0]), ("b", &[3.0, 4.0]), ("c", &[6.0, 8.0])]);
+        let radio = RadioModel::UnitDisk { range: 5.0 };
+        let neighbors = radio.neighbors(&positions);
+
+        assert_eq!(neighbors["a"], vec!["b".to_string()]);
+        assert_eq!(neighbors["b"], vec!["a".to_string(), "c".to_string()]);
+        assert_eq!(neighbors["c"], vec!["b".to_string()]);
+    }
+
+    #[test]
+    fn test_quasi_unit_disk_neighbors() {
+        let positions = positions(&[
+            ("a", &[0.0, 0.0, 0.0]),
+            ("b", &[1.0, 0.0, 0.0]),
+            ("c", &[0.0, 3.0, 0.0]),
+        ]);
+
+        // inner_rangeを超えるリンクは確率0なら張られない
+        let radio = RadioModel::QuasiUnitDisk {
+            inner_range: 1.0,
+            range: 5.0,
+            link_probability: 0.0,
+        };
+        let neighbors = radio.neighbors(&positions);
+        assert_eq!(neighbors["a"], vec!["b".to_string()]);
+        assert!(neighbors["c"].is_empty());
+
+        // 確率1ならrange以下のリンクはすべて張られる
+        let radio = RadioModel::QuasiUnitDisk {
+            inner_range: 1.0,
+            range: 5.0,
+            link_probability: 1.0,
+        };
+        let neighbors = radio.neighbors(&positions);
+        assert_eq!(neighbors["c"], vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn test_new_radio_model() {
+        let mut info = RadioInfo {
+            model: "unit_disk".to_string(),
+            range: 10.0,
+            inner_range: None,
+            link_probability: None,
+        };
+        assert_eq!(
+            RadioModel::new(&info),
+            Ok(RadioModel::UnitDisk { range: 10.0 })
+        );
+
+        info.model = "quasi_unit_disk".to_string();
+        assert!(RadioModel::new(&info).is_err());
+
+        info.inner_range = Some(5.0);
+        info.link_probability = Some(0.5);
+        assert_eq!(
+            RadioModel::new(&info),
+            Ok(RadioModel::QuasiUnitDisk {
+                inner_range: 5.0,
+                range: 10.0,
+                link_probability: 0.5,
+            })
+        );
+
+        info.inner_range = Some(20.0);
+        assert!(RadioModel::new(&info).is_err());
+    }
+}
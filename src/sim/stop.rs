@@ -0,0 +1,189 @@
+use crate::file::StopConditionsInfo;
+use crate::log::{aggregate_log, count_finished_packets};
+use crate::sim::node::CycleNum;
+use crate::sim::nodes::Nodes;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+// シミュレーションが終わった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopReason {
+    TotalCycles,
+    AllJoined,
+    AllDelivered,
+    SteadyLatency,
+    WallClock,
+    // 計測区間に生成したパケットがすべて届いた(または故障で失われた)
+    Drained,
+    // 計測したパケットが届く前にドレインの上限に達した
+    DrainLimit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopReport {
+    pub reason: StopReason,
+    // 実行したサイクル数
+    pub cycles: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StopConditions {
+    all_joined: bool,
+    all_delivered: bool,
+    // (区間のサイクル数, 許容する相対差)
+    steady_latency: Option<(u32, f64)>,
+    wall_clock: Option<Duration>,
+    // チェックポイントから再開したときは再開した時刻から数える
+    #[serde(skip, default = "Instant::now")]
+    started: Instant,
+    // ユーザーのunicastパケットの数と，最後に生成されるサイクル
+    injected_packets: u32,
+    last_injection_cycle: CycleNum,
+    // 直前の区間の平均遅延
+    prev_latency: Option<f64>,
+}
+
+impl StopConditions {
+    pub fn new(info: &StopConditionsInfo, nodes: &Nodes) -> Result<Self, String> {
+        let steady_latency = match &info.steady_latency {
+            Some(steady) if steady.window_cycles == 0 || steady.tolerance < 0.0 => {
+                return Err(
+                    "stop conditions: window_cycles must be positive and tolerance non-negative"
+                        .to_string(),
+                );
+            }
+            Some(steady) => Some((steady.window_cycles, steady.tolerance)),
+            None => None,
+        };
+        let wall_clock = match info.wall_clock_seconds {
+            Some(seconds) if seconds <= 0.0 => {
+                return Err("stop conditions: wall_clock_seconds must be positive".to_string());
+            }
+            Some(seconds) => Some(Duration::from_secs_f64(seconds)),
+            None => None,
+        };
+
+        let unicast_cycles: Vec<CycleNum> = nodes
+            .nodes
+            .iter()
+            .flat_map(|node| node.packets.iter())
+            .flat_map(|(cycle, packets)| packets.iter().map(move |packet| (cycle, packet)))
+            .filter(|(_, packet)| packet.dest_id != "broadcast")
+            .map(|(cycle, _)| *cycle)
+            .collect();
+
+        Ok(Self {
+            all_joined: info.all_joined.unwrap_or(false),
+            all_delivered: info.all_delivered.unwrap_or(false),
+            steady_latency,
+            wall_clock,
+            started: Instant::now(),
+            injected_packets: unicast_cycles.len() as u32,
+            last_injection_cycle: unicast_cycles.into_iter().max().unwrap_or(0),
+            prev_latency: None,
+        })
+    }
+
+    // 実行中に生成されたパケットを数える
+    pub fn record_injection(&mut self, cycle: CycleNum, dest_id: &str) {
+        if dest_id != "broadcast" {
+            self.injected_packets += 1;
+            self.last_injection_cycle = self.last_injection_cycle.max(cycle);
+        }
+    }
+
+    // cur_cyclesサイクルを実行し終えた時点で終わるべきならその理由を返す
+    pub fn check(&mut self, cur_cycles: u32, nodes: &Nodes) -> Option<StopReason> {
+        if self.all_joined
+            && nodes
+                .nodes
+                .iter()
+                .filter(|node| node.alive)
+                .all(|node| node.network.is_joined())
+        {
+            return Some(StopReason::AllJoined);
+        }
+
+        if self.all_delivered
+            && cur_cycles > self.last_injection_cycle
+            && count_finished_packets() >= self.injected_packets
+        {
+            return Some(StopReason::AllDelivered);
+        }
+
+        if let Some((window_cycles, tolerance)) = self.steady_latency {
+            if cur_cycles.is_multiple_of(window_cycles) {
+                let begin = cur_cycles - window_cycles;
+                let latency = aggregate_log(begin, cur_cycles)["average_cycle"];
+                let prev_latency = self.prev_latency.replace(latency);
+                if prev_latency.is_some_and(|prev| is_steady(prev, latency, tolerance)) {
+                    return Some(StopReason::SteadyLatency);
+                }
+            }
+        }
+
+        if self
+            .wall_clock
+            .is_some_and(|budget| self.started.elapsed() >= budget)
+        {
+            return Some(StopReason::WallClock);
+        }
+
+        None
+    }
+}
+
+// 2つの区間の平均遅延の相対差がtolerance以内か
+// パケットが届かなかった区間(NaN)は定常とみなさない
+fn is_steady(prev: f64, cur: f64, tolerance: f64) -> bool {
+    if prev.is_nan() || cur.is_nan() || prev <= 0.0 {
+        return false;
+    }
+    (cur - prev).abs() / prev <= tolerance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::SteadyLatencyInfo;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_is_steady() {
+        assert!(is_steady(10.0, 10.5, 0.1));
+        assert!(!is_steady(10.0, 12.0, 0.1));
+        assert!(!is_steady(f64::NAN, 10.0, 0.1));
+        assert!(!is_steady(10.0, f64::NAN, 0.1));
+    }
+
+    #[test]
+    fn test_new_stop_conditions() {
+        let nodes = Nodes::new(&[], &HashMap::new());
+        let mut info = StopConditionsInfo {
+            all_joined: Some(true),
+            all_delivered: None,
+            steady_latency: Some(SteadyLatencyInfo {
+                window_cycles: 100,
+                tolerance: 0.1,
+            }),
+            wall_clock_seconds: None,
+        };
+        let mut stop_conditions = StopConditions::new(&info, &nodes).unwrap();
+
+        // ノードがなければ全ノードが参加済み
+        assert_eq!(
+            stop_conditions.check(1, &nodes),
+            Some(StopReason::AllJoined)
+        );
+
+        info.wall_clock_seconds = Some(0.0);
+        assert!(StopConditions::new(&info, &nodes).is_err());
+
+        info.wall_clock_seconds = None;
+        info.steady_latency = Some(SteadyLatencyInfo {
+            window_cycles: 0,
+            tolerance: 0.1,
+        });
+        assert!(StopConditions::new(&info, &nodes).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use crate::file::TopologyEventInfo;
+use crate::sim::node::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+// シミュレーション中にネットワークの形を変えるイベント
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TopologyEvent {
+    AddLinks(Vec<(NodeId, NodeId)>),
+    RemoveLinks(Vec<(NodeId, NodeId)>),
+    // node_idsのノード群をlinksで既存のネットワークにつなぐ
+    Attach {
+        node_ids: Vec<NodeId>,
+        links: Vec<(NodeId, NodeId)>,
+    },
+    // node_idsのノード群と，それ以外のノードとのリンクをすべて切る
+    // node_ids同士のリンクは残す
+    Detach(Vec<NodeId>),
+}
+
+impl TopologyEvent {
+    pub fn new(info: &TopologyEventInfo, node_ids: &HashSet<NodeId>) -> Result<Self, String> {
+        let links = info.links.clone().unwrap_or_default();
+        let event_node_ids = info.node_ids.clone().unwrap_or_default();
+
+        for node_id in links
+            .iter()
+            .flat_map(|(a, b)| [a, b])
+            .chain(event_node_ids.iter())
+        {
+            if !node_ids.contains(node_id) {
+                return Err(format!(
+                    "topology event at cycle {}: unknown node {node_id}",
+                    info.cycle_num
+                ));
+            }
+        }
+
+        let event = match info.event.as_str() {
+            "add_link" if !links.is_empty() => TopologyEvent::AddLinks(links),
+            "remove_link" if !links.is_empty() => TopologyEvent::RemoveLinks(links),
+            "attach" if !event_node_ids.is_empty() && !links.is_empty() => TopologyEvent::Attach {
+                node_ids: event_node_ids,
+                links,
+            },
+            "detach" if !event_node_ids.is_empty() => TopologyEvent::Detach(event_node_ids),
+            "add_link" | "remove_link" => {
+                return Err(format!(
+                    "topology event at cycle {}: {} needs links",
+                    info.cycle_num, info.event
+                ));
+            }
+            "attach" => {
+                return Err(format!(
+                    "topology event at cycle {}: attach needs node_ids and links",
+                    info.cycle_num
+                ));
+            }
+            "detach" => {
+                return Err(format!(
+                    "topology event at cycle {}: detach needs node_ids",
+                    info.cycle_num
+                ));
+            }
+            event => {
+                return Err(format!(
+                    "topology event at cycle {}: unknown event {event}",
+                    info.cycle_num
+                ));
+            }
+        };
+
+        Ok(event)
+    }
+
+    // neighborsにイベントを適用する
+    pub fn apply(&self, neighbors: &mut HashMap<NodeId, Vec<NodeId>>) {
+        match self {
+            TopologyEvent::AddLinks(links) | TopologyEvent::Attach { links, .. } => {
+                for (a, b) in links {
+                    add_link(neighbors, a, b);
+                }
+            }
+            TopologyEvent::RemoveLinks(links) => {
+                for (a, b) in links {
+                    remove_link(neighbors, a, b);
+                }
+            }
+            TopologyEvent::Detach(node_ids) => {
+                let cut_links: Vec<(NodeId, NodeId)> = node_ids
+                    .iter()
+                    .flat_map(|node_id| {
+                        neighbors
+                            .get(node_id)
+                            .into_iter()
+                            .flatten()
+                            .filter(|neighbor| !node_ids.contains(neighbor))
+                            .map(move |neighbor| (node_id.clone(), neighbor.clone()))
+                    })
+                    .collect();
+                for (a, b) in cut_links {
+                    remove_link(neighbors, &a, &b);
+                }
+            }
+        }
+    }
+
+    // ログに記録する説明
+    pub fn describe(&self) -> String {
+        let links_to_string = |links: &[(NodeId, NodeId)]| {
+            links
+                .iter()
+                .map(|(a, b)| format!("{a}-{b}"))
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+
+        match self {
+            TopologyEvent::AddLinks(links) => format!("add_link {}", links_to_string(links)),
+            TopologyEvent::RemoveLinks(links) => {
+                format!("remove_link {}", links_to_string(links))
+            }
+            TopologyEvent::Attach { node_ids, links } => format!(
+                "attach {} via {}",
+                node_ids.join(","),
+                links_to_string(links)
+            ),
+            TopologyEvent::Detach(node_ids) => format!("detach {}", node_ids.join(",")),
+        }
+    }
+}
+
+fn add_link(neighbors: &mut HashMap<NodeId, Vec<NodeId>>, a: &str, b: &str) {
+    for (from, to) in [(a, b), (b, a)] {
+        let list = neighbors.entry(from.to_string()).or_default();
+        if !list.iter().any(|node_id| node_id == to) {
+            list.push(to.to_string());
+        }
+    }
+}
+
+fn remove_link(neighbors: &mut HashMap<NodeId, Vec<NodeId>>, a: &str, b: &str) {
+    for (from, to) in [(a, b), (b, a)] {
+        if let Some(list) = neighbors.get_mut(from) {
+            list.retain(|node_id| node_id != to);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(a: &str, b: &str) -> (NodeId, NodeId) {
+        (a.to_string(), b.to_string())
+    }
+
+    #[test]
+    fn test_apply_topology_event() {
+        // a - b - c - d
+        let mut neighbors = HashMap::new();
+        for (a, b) in [("a", "b"), ("b", "c"), ("c", "d")] {
+            add_link(&mut neighbors, a, b);
+        }
+
+        // c, dをまとめて切り離す
+        TopologyEvent::Detach(vec!["c".to_string(), "d".to_string()]).apply(&mut neighbors);
+        assert_eq!(neighbors["b"], vec!["a".to_string()]);
+        assert_eq!(neighbors["c"], vec!["d".to_string()]);
+
+        // aにつなぎ直す
+        TopologyEvent::Attach {
+            node_ids: vec!["c".to_string(), "d".to_string()],
+            links: vec![link("d", "a")],
+        }
+        .apply(&mut neighbors);
+        assert_eq!(neighbors["a"], vec!["b".to_string(), "d".to_string()]);
+
+        TopologyEvent::RemoveLinks(vec![link("a", "b")]).apply(&mut neighbors);
+        assert!(neighbors["b"].is_empty());
+
+        TopologyEvent::AddLinks(vec![link("b", "c")]).apply(&mut neighbors);
+        assert_eq!(neighbors["c"], vec!["d".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn test_new_topology_event() {
+        let node_ids: HashSet<NodeId> = ["a", "b"].iter().map(|s| s.to_string()).collect();
+        let mut info = TopologyEventInfo {
+            cycle_num: 10,
+            event: "remove_link".to_string(),
+            links: Some(vec![link("a", "b")]),
+            node_ids: None,
+        };
+        assert_eq!(
+            TopologyEvent::new(&info, &node_ids),
+            Ok(TopologyEvent::RemoveLinks(vec![link("a", "b")]))
+        );
+
+        info.links = Some(vec![link("a", "x")]);
+        assert!(TopologyEvent::new(&info, &node_ids).is_err());
+
+        info.event = "detach".to_string();
+        info.links = None;
+        assert!(TopologyEvent::new(&info, &node_ids).is_err());
+    }
+}
@@ -0,0 +1,386 @@
+use crate::network::core_functions::RoutingTable;
+use crate::sim::node::NodeId;
+use crate::sim::nodes::Nodes;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+// 参加プロトコルが作ったチャネルごとの木，親へのポインタで表す
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpanningTree {
+    pub channel_id: u8,
+    // 根がいなければNone
+    pub root_id: Option<NodeId>,
+    // 根から辿れる根以外のノードの親
+    pub parent_ids: BTreeMap<NodeId, NodeId>,
+    // 生きているが根から辿れないノード
+    pub detached_ids: Vec<NodeId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TreeReport {
+    pub channel_id: u8,
+    pub root_id: Option<NodeId>,
+    // 根を含む木のノード数
+    pub tree_nodes: u32,
+    pub detached_nodes: u32,
+    // 根から最も遠いノードまでのホップ数
+    pub depth: u32,
+    // 根以外のノードの深さの平均
+    pub average_depth: f64,
+    pub child_counts: BTreeMap<NodeId, u32>,
+    pub max_children: u32,
+    // 木の他のノードへの，木の上のホップ数と最短経路のホップ数の比の平均
+    pub stretches: BTreeMap<NodeId, f64>,
+    pub average_stretch: f64,
+    pub max_stretch: f64,
+}
+
+// 木どうしでのリンクの重なり
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TreeOverlapReport {
+    // いずれかの木で使われているリンクの数
+    pub tree_links: u32,
+    // 2つ以上の木で使われているリンクの数
+    pub shared_links: u32,
+}
+
+// 生きているノードのルーティング表からチャネルごとの木を作る
+pub fn spanning_trees(nodes: &Nodes) -> Vec<SpanningTree> {
+    let tables: Vec<(NodeId, RoutingTable)> = nodes
+        .nodes
+        .iter()
+        .filter(|node| node.alive)
+        .map(|node| (node.id.clone(), node.network.routing_table()))
+        .collect();
+    build_trees(&tables)
+}
+
+// 生きているノードの間のリンク
+pub fn alive_neighbors(nodes: &Nodes) -> HashMap<NodeId, Vec<NodeId>> {
+    let alive_ids: HashSet<&NodeId> = nodes
+        .nodes
+        .iter()
+        .filter(|node| node.alive)
+        .map(|node| &node.id)
+        .collect();
+    nodes
+        .neighbors
+        .iter()
+        .filter(|(node_id, _)| alive_ids.contains(node_id))
+        .map(|(node_id, list)| {
+            let list = list
+                .iter()
+                .filter(|neighbor| alive_ids.contains(neighbor))
+                .cloned()
+                .collect();
+            (node_id.clone(), list)
+        })
+        .collect()
+}
+
+fn build_trees(tables: &[(NodeId, RoutingTable)]) -> Vec<SpanningTree> {
+    let channel_num = tables
+        .iter()
+        .map(|(_, table)| table.parent_ids.len())
+        .max()
+        .unwrap_or(0);
+    let mut node_ids: Vec<&NodeId> = tables.iter().map(|(node_id, _)| node_id).collect();
+    node_ids.sort();
+
+    (0..channel_num)
+        .map(|channel| {
+            let root_id = node_ids
+                .iter()
+                .copied()
+                .find(|node_id| {
+                    tables
+                        .iter()
+                        .any(|(id, table)| id == *node_id && table.roots[channel])
+                })
+                .cloned();
+            let parents: HashMap<&NodeId, &NodeId> = tables
+                .iter()
+                .filter_map(|(node_id, table)| {
+                    table.parent_ids[channel]
+                        .as_ref()
+                        .map(|parent_id| (node_id, parent_id))
+                })
+                .collect();
+
+            let mut parent_ids = BTreeMap::new();
+            let mut detached_ids = Vec::new();
+            for &node_id in node_ids.iter() {
+                if Some(node_id) == root_id.as_ref() {
+                    continue;
+                }
+                if reaches_root(node_id, root_id.as_ref(), &parents) {
+                    parent_ids.insert(node_id.clone(), parents[node_id].clone());
+                } else {
+                    detached_ids.push(node_id.clone());
+                }
+            }
+
+            SpanningTree {
+                channel_id: channel as u8,
+                root_id,
+                parent_ids,
+                detached_ids,
+            }
+        })
+        .collect()
+}
+
+// 親を辿って根に着くか，親が閉路になっていたら着かない
+fn reaches_root(
+    node_id: &NodeId,
+    root_id: Option<&NodeId>,
+    parents: &HashMap<&NodeId, &NodeId>,
+) -> bool {
+    let Some(root_id) = root_id else {
+        return false;
+    };
+    let mut cur = node_id;
+    for _ in 0..parents.len() {
+        match parents.get(cur) {
+            Some(&parent_id) if parent_id == root_id => return true,
+            Some(&parent_id) => cur = parent_id,
+            None => return false,
+        }
+    }
+    false
+}
+
+impl SpanningTree {
+    // 根を含む木のノード
+    pub fn node_ids(&self) -> Vec<&NodeId> {
+        self.root_id.iter().chain(self.parent_ids.keys()).collect()
+    }
+
+    // 子から親へのリンク，idの小さい方を先にする
+    pub fn links(&self) -> Vec<(&NodeId, &NodeId)> {
+        self.parent_ids
+            .iter()
+            .map(|(node_id, parent_id)| {
+                if node_id < parent_id {
+                    (node_id, parent_id)
+                } else {
+                    (parent_id, node_id)
+                }
+            })
+            .collect()
+    }
+
+    // ノードから根までのノード，自分と根を含む
+    fn path_to_root<'a>(&'a self, node_id: &'a NodeId) -> Vec<&'a NodeId> {
+        let mut path = vec![node_id];
+        let mut cur = node_id;
+        while let Some(parent_id) = self.parent_ids.get(cur) {
+            path.push(parent_id);
+            cur = parent_id;
+        }
+        path
+    }
+
+    // neighborsは最短経路を求めるためのリンク
+    pub fn report(&self, neighbors: &HashMap<NodeId, Vec<NodeId>>) -> TreeReport {
+        let node_ids = self.node_ids();
+        let paths: HashMap<&NodeId, Vec<&NodeId>> = node_ids
+            .iter()
+            .map(|&node_id| (node_id, self.path_to_root(node_id)))
+            .collect();
+
+        let depths: Vec<u32> = self
+            .parent_ids
+            .keys()
+            .map(|node_id| paths[node_id].len() as u32 - 1)
+            .collect();
+
+        let mut child_counts: BTreeMap<NodeId, u32> = node_ids
+            .iter()
+            .map(|&node_id| (node_id.clone(), 0))
+            .collect();
+        for parent_id in self.parent_ids.values() {
+            *child_counts.get_mut(parent_id).unwrap() += 1;
+        }
+
+        let mut stretches = BTreeMap::new();
+        for &node_id in node_ids.iter() {
+            let hops = shortest_hops(node_id, neighbors);
+            let ratios: Vec<f64> = node_ids
+                .iter()
+                .filter(|&&other_id| other_id != node_id)
+                .filter_map(|&other_id| {
+                    let shortest = *hops.get(other_id)?;
+                    let tree_hops = tree_hops(&paths[node_id], &paths[other_id]);
+                    Some(tree_hops as f64 / shortest as f64)
+                })
+                .collect();
+            if !ratios.is_empty() {
+                stretches.insert(node_id.clone(), average(&ratios));
+            }
+        }
+        let stretch_values: Vec<f64> = stretches.values().copied().collect();
+
+        TreeReport {
+            channel_id: self.channel_id,
+            root_id: self.root_id.clone(),
+            tree_nodes: node_ids.len() as u32,
+            detached_nodes: self.detached_ids.len() as u32,
+            depth: depths.iter().copied().max().unwrap_or(0),
+            average_depth: average(&depths.iter().map(|&d| d as f64).collect::<Vec<_>>()),
+            max_children: child_counts.values().copied().max().unwrap_or(0),
+            child_counts,
+            average_stretch: average(&stretch_values),
+            max_stretch: stretch_values.iter().copied().fold(0.0, f64::max),
+            stretches,
+        }
+    }
+}
+
+pub fn tree_overlap(trees: &[SpanningTree]) -> TreeOverlapReport {
+    let mut counts: HashMap<(&NodeId, &NodeId), u32> = HashMap::new();
+    for tree in trees.iter() {
+        for link in tree.links() {
+            *counts.entry(link).or_insert(0) += 1;
+        }
+    }
+    TreeOverlapReport {
+        tree_links: counts.len() as u32,
+        shared_links: counts.values().filter(|&&count| count >= 2).count() as u32,
+    }
+}
+
+// 根までの経路の共通部分を除いた，2つのノードの間の木の上のホップ数
+fn tree_hops(path: &[&NodeId], other_path: &[&NodeId]) -> usize {
+    let common = path
+        .iter()
+        .rev()
+        .zip(other_path.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    path.len() + other_path.len() - 2 * common
+}
+
+// 幅優先探索で各ノードまでの最短のホップ数を求める
+fn shortest_hops<'a>(
+    node_id: &'a NodeId,
+    neighbors: &'a HashMap<NodeId, Vec<NodeId>>,
+) -> HashMap<&'a NodeId, u32> {
+    let mut hops = HashMap::from([(node_id, 0)]);
+    let mut queue = VecDeque::from([node_id]);
+    while let Some(cur) = queue.pop_front() {
+        let cur_hops = hops[cur];
+        for neighbor in neighbors.get(cur).into_iter().flatten() {
+            if !hops.contains_key(neighbor) {
+                hops.insert(neighbor, cur_hops + 1);
+                queue.push_back(neighbor);
+            }
+        }
+    }
+    hops
+}
+
+fn average(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(parent_ids: &[Option<&str>], roots: &[bool]) -> RoutingTable {
+        RoutingTable {
+            parent_ids: parent_ids
+                .iter()
+                .map(|parent_id| parent_id.map(str::to_string))
+                .collect(),
+            roots: roots.to_vec(),
+            children_ids: vec![vec![]; parent_ids.len()],
+            routes: vec![HashMap::new(); parent_ids.len()],
+        }
+    }
+
+    fn neighbors(links: &[(&str, &str)]) -> HashMap<NodeId, Vec<NodeId>> {
+        let mut neighbors: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
+        for (a, b) in links.iter() {
+            neighbors
+                .entry(a.to_string())
+                .or_default()
+                .push(b.to_string());
+            neighbors
+                .entry(b.to_string())
+                .or_default()
+                .push(a.to_string());
+        }
+        neighbors
+    }
+
+    #[test]
+    fn test_build_trees() {
+        // チャネル0はaが根，チャネル1はbが根
+        // eはチャネル0で親がいない，fとgは親が閉路になっている
+        let tables: Vec<(NodeId, RoutingTable)> = [
+            ("a", table(&[None, Some("b")], &[true, false])),
+            ("b", table(&[Some("a"), None], &[false, true])),
+            ("c", table(&[Some("b"), Some("b")], &[false, false])),
+            ("d", table(&[Some("c"), Some("b")], &[false, false])),
+            ("e", table(&[None, Some("d")], &[false, false])),
+            ("f", table(&[Some("g"), Some("e")], &[false, false])),
+            ("g", table(&[Some("f"), Some("e")], &[false, false])),
+        ]
+        .into_iter()
+        .map(|(node_id, table)| (node_id.to_string(), table))
+        .collect();
+
+        let trees = build_trees(&tables);
+        assert_eq!(trees.len(), 2);
+        assert_eq!(trees[0].root_id.as_deref(), Some("a"));
+        assert_eq!(trees[0].node_ids(), vec!["a", "b", "c", "d"]);
+        assert_eq!(trees[0].detached_ids, vec!["e", "f", "g"]);
+        assert_eq!(trees[1].root_id.as_deref(), Some("b"));
+        assert_eq!(trees[1].node_ids().len(), 7);
+        assert!(trees[1].detached_ids.is_empty());
+
+        // a-b，b-cは両方の木で使われる
+        let overlap = tree_overlap(&trees);
+        assert_eq!(overlap.tree_links, 7);
+        assert_eq!(overlap.shared_links, 2);
+    }
+
+    #[test]
+    fn test_tree_report() {
+        // a - b - c - dの経路と，a - dのリンクがある
+        let tree = SpanningTree {
+            channel_id: 0,
+            root_id: Some("a".to_string()),
+            parent_ids: [("b", "a"), ("c", "b"), ("d", "c")]
+                .into_iter()
+                .map(|(node_id, parent_id)| (node_id.to_string(), parent_id.to_string()))
+                .collect(),
+            detached_ids: vec!["e".to_string()],
+        };
+        let report = tree.report(&neighbors(&[
+            ("a", "b"),
+            ("b", "c"),
+            ("c", "d"),
+            ("a", "d"),
+        ]));
+
+        assert_eq!(report.tree_nodes, 4);
+        assert_eq!(report.detached_nodes, 1);
+        assert_eq!(report.depth, 3);
+        assert_eq!(report.average_depth, 2.0);
+        assert_eq!(report.child_counts["a"], 1);
+        assert_eq!(report.child_counts["d"], 0);
+        assert_eq!(report.max_children, 1);
+        // aからb，c，dへは1，2，3ホップで，最短は1，2，1ホップ
+        assert_eq!(report.stretches["a"], (1.0 + 1.0 + 3.0) / 3.0);
+        // bからa，c，dへは1，1，2ホップで，最短も同じ
+        assert_eq!(report.stretches["b"], 1.0);
+        assert_eq!(report.max_stretch, 5.0 / 3.0);
+    }
+}
@@ -16,12 +16,19 @@ macro_rules! recsimu_dbg {
 }
 
 // pathで指定されたjsonファイルを読み込む
+// 読み込めなければ問題のある位置をエラーに含める
 pub fn read_json<T>(path: PathBuf) -> Result<T, Box<dyn error::Error>>
 where
     for<'de> T: Deserialize<'de>,
 {
     let json_str = std::fs::read_to_string(path)?;
-    let json: T = serde_json::from_str(&json_str)?;
+    let deserializer = &mut serde_json::Deserializer::from_str(&json_str);
+    let json: T = serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        match e.path().to_string().as_str() {
+            "." => format!("$: {}", e.inner()),
+            path => format!("$.{path}: {}", e.inner()),
+        }
+    })?;
     Ok(json)
 }
 
@@ -0,0 +1,495 @@
+use crate::file::InputFile;
+use crate::hardware::switching::Switching;
+use crate::network::arbiter::Arbitration;
+use crate::sim::energy::EnergyModel;
+use crate::sim::failure::{FailureModel, NodeEvent};
+use crate::sim::mobility::Mobility;
+use crate::sim::node::NodeId;
+use crate::sim::node_type::NodeType;
+use crate::sim::phase::Phases;
+use crate::sim::radio::RadioModel;
+use crate::sim::topology::TopologyEvent;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+// Errorがあるとシミュレーションを実行できない
+// Warningは実行できるが，入力の誤りの可能性がある
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// 入力ファイルの問題と，そのJSONでの位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{severity}: {}: {}", self.path, self.message)
+    }
+}
+
+impl Problem {
+    fn error(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    fn warning(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+const ROUTINGS: [&str; 3] = ["default", "multi_tree", "up_down"];
+const ADDRESS_MODES: [&str; 2] = ["assigned", "random"];
+const PAYLOADS: [&str; 3] = ["dots", "zeros", "random"];
+
+// 入力ファイルの問題をすべて返す
+// SimBuilder::buildはここで誤りがないことを確かめてから組み立てる
+// mobilityのtrajectory_fileはbase_dirからの相対パス
+pub fn validate(input: &InputFile, base_dir: &Path) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    if input.node_num as usize != input.nodes.len() {
+        problems.push(Problem::error(
+            "$.node_num",
+            format!(
+                "{} does not match the number of nodes ({})",
+                input.node_num,
+                input.nodes.len()
+            ),
+        ));
+    }
+
+    if let Err(e) = input.switching.parse::<Switching>() {
+        problems.push(Problem::error("$.switching", e));
+    }
+
+    let routing = input.routing.as_deref().unwrap_or("default");
+    if !ROUTINGS.contains(&routing) {
+        problems.push(Problem::error(
+            "$.routing",
+            format!("unknown routing {routing}, expected one of {ROUTINGS:?}"),
+        ));
+    }
+
+    if let Some(mode) = &input.address_mode {
+        if !ADDRESS_MODES.contains(&mode.as_str()) {
+            problems.push(Problem::error(
+                "$.address_mode",
+                format!("unknown address mode {mode}, expected one of {ADDRESS_MODES:?}"),
+            ));
+        }
+    }
+
+    let mut node_ids = HashSet::new();
+    for (i, node) in input.nodes.iter().enumerate() {
+        if !node_ids.insert(node.node_id.as_str()) {
+            problems.push(Problem::error(
+                format!("$.nodes[{i}].node_id"),
+                format!("duplicate node id {}", node.node_id),
+            ));
+        }
+        // ユーザー定義のノード種別はフックが登録されている必要がある
+        let node_type = NodeType::new(&node.node_type);
+        if let NodeType::UserType(name) = &node_type {
+            if node_type.user_type_hook().is_none() {
+                problems.push(Problem::error(
+                    format!("$.nodes[{i}].node_type"),
+                    format!("unknown node type {name}"),
+                ));
+            }
+        }
+    }
+
+    // multi_treeはチャネルごとにコーディネータが1つ必要
+    if routing == "multi_tree" {
+        let coordinators = input
+            .nodes
+            .iter()
+            .filter(|node| NodeType::new(&node.node_type) == NodeType::Coordinator)
+            .count();
+        if coordinators != input.channel_num as usize {
+            problems.push(Problem::error(
+                "$.nodes",
+                format!(
+                    "multi_tree needs channel_num ({}) coordinators, found {coordinators}",
+                    input.channel_num
+                ),
+            ));
+        }
+    }
+
+    if let Some(neighbors) = &input.neighbors {
+        // キーの順に並べて，出力の順番をそろえる
+        let mut entries: Vec<_> = neighbors.iter().collect();
+        entries.sort();
+        for (node_id, list) in entries {
+            if !node_ids.contains(node_id.as_str()) {
+                problems.push(Problem::error(
+                    format!("$.neighbors[\"{node_id}\"]"),
+                    format!("unknown node {node_id}"),
+                ));
+            }
+            for (i, neighbor_id) in list.iter().enumerate() {
+                let path = format!("$.neighbors[\"{node_id}\"][{i}]");
+                if !node_ids.contains(neighbor_id.as_str()) {
+                    problems.push(Problem::error(path, format!("unknown node {neighbor_id}")));
+                } else if !neighbors
+                    .get(neighbor_id)
+                    .is_some_and(|list| list.contains(node_id))
+                {
+                    problems.push(Problem::error(
+                        path,
+                        format!("{neighbor_id} does not list {node_id} as a neighbor"),
+                    ));
+                }
+            }
+        }
+    }
+
+    // 隣接関係はneighborsで直接与えるか，radioとノードの位置から求める
+    match (&input.neighbors, &input.radio) {
+        (Some(_), Some(_)) => {
+            problems.push(Problem::error(
+                "$.radio",
+                "specify either neighbors or radio",
+            ));
+        }
+        (None, None) => {
+            problems.push(Problem::error(
+                "$.neighbors",
+                "either neighbors or radio is required",
+            ));
+        }
+        _ => {}
+    }
+    if let Some(radio) = &input.radio {
+        if let Err(e) = RadioModel::new(radio) {
+            problems.push(Problem::error("$.radio", e));
+        }
+        let mut dimension = None;
+        for (i, node) in input.nodes.iter().enumerate() {
+            let path = format!("$.nodes[{i}].position");
+            let Some(position) = &node.position else {
+                problems.push(Problem::error(path, "position is required with radio"));
+                continue;
+            };
+            if position.len() != 2 && position.len() != 3 {
+                problems.push(Problem::error(path, "position must be 2D or 3D"));
+            } else if *dimension.get_or_insert(position.len()) != position.len() {
+                problems.push(Problem::error(
+                    path,
+                    "positions must have the same dimension",
+                ));
+            }
+        }
+    }
+
+    let event_node_ids: HashSet<NodeId> = node_ids.iter().map(|id| id.to_string()).collect();
+    let non_coordinator_ids: HashSet<NodeId> = input
+        .nodes
+        .iter()
+        .filter(|node| NodeType::new(&node.node_type) != NodeType::Coordinator)
+        .map(|node| node.node_id.clone())
+        .collect();
+    if let Some(mobility) = &input.mobility {
+        if input.radio.is_none() {
+            problems.push(Problem::error(
+                "$.mobility",
+                "mobility needs radio and node positions",
+            ));
+        }
+        if let Err(e) = Mobility::new(mobility, &event_node_ids, &non_coordinator_ids, base_dir) {
+            problems.push(Problem::error("$.mobility", e));
+        }
+    }
+    for (i, info) in input.topology_events.iter().flatten().enumerate() {
+        if let Err(e) = TopologyEvent::new(info, &event_node_ids) {
+            problems.push(Problem::error(format!("$.topology_events[{i}]"), e));
+        }
+    }
+    for (i, info) in input.node_events.iter().flatten().enumerate() {
+        if let Err(e) = NodeEvent::new(info, &event_node_ids) {
+            problems.push(Problem::error(format!("$.node_events[{i}]"), e));
+        }
+    }
+    if let Some(failure_model) = &input.failure_model {
+        if let Err(e) = FailureModel::new(failure_model, &event_node_ids, &non_coordinator_ids) {
+            problems.push(Problem::error("$.failure_model", e));
+        }
+    }
+    if let Some(phases) = &input.phases {
+        if let Err(e) = Phases::new(phases, input.total_cycles, &input.packets) {
+            problems.push(Problem::error("$.phases", e));
+        }
+    }
+    if let Some(energy) = &input.energy {
+        if let Err(e) = EnergyModel::new(energy, &[]) {
+            problems.push(Problem::error("$.energy", e));
+        }
+    }
+
+    if let Some(transport) = &input.transport {
+        if transport.window_size == 0 {
+            problems.push(Problem::error(
+                "$.transport.window_size",
+                "must be at least 1",
+            ));
+        }
+        if transport.retransmit_cycles == 0 {
+            problems.push(Problem::error(
+                "$.transport.retransmit_cycles",
+                "must be at least 1",
+            ));
+        }
+    }
+
+    if input
+        .monitor
+        .as_ref()
+        .is_some_and(|monitor| monitor.interval_cycles == 0)
+    {
+        problems.push(Problem::error(
+            "$.monitor.interval_cycles",
+            "must be at least 1",
+        ));
+    }
+
+    if input.ttl == Some(0) {
+        problems.push(Problem::error("$.ttl", "must be at least 1"));
+    }
+
+    let mut class_names = HashSet::new();
+    if let Some(qos) = &input.qos {
+        if let Some(Err(e)) = qos.arbitration.as_deref().map(str::parse::<Arbitration>) {
+            problems.push(Problem::error("$.qos.arbitration", e));
+        }
+        // multi_treeは仮想チャネルで木を選ぶ
+        if routing == "multi_tree" && !qos.classes.is_empty() {
+            problems.push(Problem::error(
+                "$.qos.classes",
+                "multi_tree routing does not support traffic classes",
+            ));
+        }
+        for (i, class) in qos.classes.iter().enumerate() {
+            if !class_names.insert(class.name.as_str()) {
+                problems.push(Problem::error(
+                    format!("$.qos.classes[{i}].name"),
+                    format!("duplicate class {}", class.name),
+                ));
+            }
+            if class.channel >= input.channel_num {
+                problems.push(Problem::error(
+                    format!("$.qos.classes[{i}].channel"),
+                    format!(
+                        "{} is not below channel_num ({})",
+                        class.channel, input.channel_num
+                    ),
+                ));
+            }
+            if class.weight == Some(0) {
+                problems.push(Problem::error(
+                    format!("$.qos.classes[{i}].weight"),
+                    "must be at least 1",
+                ));
+            }
+            // チャネルの優先度はひとつに決まる必要がある
+            let priority = class.priority.unwrap_or(0);
+            if let Some(other) = qos.classes[..i]
+                .iter()
+                .find(|c| c.channel == class.channel && c.priority.unwrap_or(0) != priority)
+            {
+                problems.push(Problem::error(
+                    format!("$.qos.classes[{i}].priority"),
+                    format!(
+                        "differs from class {} on channel {}",
+                        other.name, class.channel
+                    ),
+                ));
+            }
+        }
+    }
+
+    for (i, packet) in input.packets.iter().enumerate() {
+        if !node_ids.contains(packet.src_id.as_str()) {
+            problems.push(Problem::error(
+                format!("$.packets[{i}].src_id"),
+                format!("unknown node {}", packet.src_id),
+            ));
+        }
+        if packet.dest_id != "broadcast" && !node_ids.contains(packet.dest_id.as_str()) {
+            problems.push(Problem::error(
+                format!("$.packets[{i}].dest_id"),
+                format!("unknown node {}", packet.dest_id),
+            ));
+        }
+        for (j, dest_id) in packet.dest_ids.iter().flatten().enumerate() {
+            if !node_ids.contains(dest_id.as_str()) {
+                problems.push(Problem::error(
+                    format!("$.packets[{i}].dest_ids[{j}]"),
+                    format!("unknown node {dest_id}"),
+                ));
+            }
+        }
+        if packet
+            .size_bytes
+            .is_some_and(|size| (size as usize) < packet.msg.len())
+        {
+            problems.push(Problem::error(
+                format!("$.packets[{i}].size_bytes"),
+                format!("msg is longer than {} bytes", packet.size_bytes.unwrap()),
+            ));
+        }
+        if let Some(payload) = &packet.payload {
+            if !PAYLOADS.contains(&payload.as_str()) {
+                problems.push(Problem::error(
+                    format!("$.packets[{i}].payload"),
+                    format!("unknown payload {payload}, expected one of {PAYLOADS:?}"),
+                ));
+            } else if packet.size_bytes.is_none() {
+                problems.push(Problem::error(
+                    format!("$.packets[{i}].payload"),
+                    "payload needs size_bytes",
+                ));
+            }
+        }
+        if let Some(class) = &packet.class {
+            if !class_names.contains(class.as_str()) {
+                problems.push(Problem::error(
+                    format!("$.packets[{i}].class"),
+                    format!("unknown class {class}"),
+                ));
+            }
+        }
+        // 実行が終わった後のパケットは生成されないだけなので警告にする
+        if packet.cycle_num >= input.total_cycles {
+            problems.push(Problem::warning(
+                format!("$.packets[{i}].cycle_num"),
+                format!(
+                    "{} is not below total_cycles ({}), the packet is never injected",
+                    packet.cycle_num, input.total_cycles
+                ),
+            ));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::{QosInfo, TrafficClassInfo};
+    use crate::utils::read_json;
+    use std::path::PathBuf;
+
+    fn paths(problems: &[Problem]) -> Vec<&str> {
+        problems
+            .iter()
+            .map(|problem| problem.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut input: InputFile = read_json(PathBuf::from("tests/run/auto/1_c.json")).unwrap();
+        assert_eq!(validate(&input, Path::new("tests/run/auto")), vec![]);
+
+        input.node_num = 3;
+        input.routing = Some("multi_tree".to_string());
+        input.channel_num = 2;
+        // node1からnode2への片方向のリンクと，存在しないノードへのリンク
+        let neighbors = input.neighbors.as_mut().unwrap();
+        neighbors
+            .get_mut("node1")
+            .unwrap()
+            .push("node9".to_string());
+        neighbors.get_mut("node2").unwrap().clear();
+        input.packets[0].dest_id = "node9".to_string();
+        input.packets[0].cycle_num = input.total_cycles;
+
+        let problems = validate(&input, Path::new("tests/run/auto"));
+        assert_eq!(
+            paths(&problems),
+            vec![
+                "$.node_num",
+                "$.nodes",
+                "$.neighbors[\"node1\"][0]",
+                "$.neighbors[\"node1\"][1]",
+                "$.packets[0].dest_id",
+                "$.packets[0].cycle_num",
+            ]
+        );
+        assert_eq!(problems[5].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn test_validate_node_type() {
+        let mut input: InputFile = read_json(PathBuf::from("tests/run/auto/1_c.json")).unwrap();
+        input.nodes[1].node_type = "rooter".to_string();
+
+        let problems = validate(&input, Path::new("tests/run/auto"));
+        assert_eq!(paths(&problems), vec!["$.nodes[1].node_type"]);
+        assert_eq!(problems[0].message, "unknown node type rooter");
+    }
+
+    #[test]
+    fn test_validate_qos() {
+        let mut input: InputFile = read_json(PathBuf::from("tests/run/auto/1_c.json")).unwrap();
+        input.channel_num = 2;
+        input.qos = Some(QosInfo {
+            arbitration: Some("fifo".to_string()),
+            classes: vec![
+                TrafficClassInfo {
+                    name: "voice".to_string(),
+                    channel: 2,
+                    priority: None,
+                    weight: None,
+                },
+                TrafficClassInfo {
+                    name: "voice".to_string(),
+                    channel: 1,
+                    priority: Some(1),
+                    weight: Some(0),
+                },
+                TrafficClassInfo {
+                    name: "video".to_string(),
+                    channel: 1,
+                    priority: None,
+                    weight: None,
+                },
+            ],
+        });
+        input.packets[0].class = Some("bulk".to_string());
+
+        assert_eq!(
+            paths(&validate(&input, Path::new("tests/run/auto"))),
+            vec![
+                "$.qos.arbitration",
+                "$.qos.classes[0].channel",
+                "$.qos.classes[1].name",
+                "$.qos.classes[1].weight",
+                "$.qos.classes[2].priority",
+                "$.packets[0].class",
+            ]
+        );
+    }
+}